@@ -0,0 +1,90 @@
+#!/bin/sh
+# Stands in for a board when trying out the worker's support of real hardware, e.g.
+#   MINI_WORKER_HARDWARE_RUNNER=/path/to/mini_ci/scripts/simulated_device.sh mini_worker
+#
+# Implements the interface the worker expects from a hardware runner:
+#   simulated_device.sh flash <build_dir> <test_name>
+#   simulated_device.sh capture <build_dir> <test_name>
+#   simulated_device.sh reset
+#
+# The result of a test only depends on its name: tests whose name contains "fail" fail, the ones
+# containing "hang" never finish, which exercises the timeout, and all the other ones pass.
+#
+# When SIMULATED_DEVICE_SERIAL names a tty, the board writes its output there once reset, like a
+# board on its UART, instead of through `capture`. A pseudo-terminal pair stands in for the serial
+# cable, e.g. `socat pty,raw,echo=0,link=/tmp/board_uart pty,raw,echo=0,link=/tmp/worker_uart` with
+# SIMULATED_DEVICE_SERIAL=/tmp/board_uart and MINI_WORKER_SERIAL_DEVICE=/tmp/worker_uart.
+
+set -eu
+
+state_dir="${SIMULATED_DEVICE_STATE_DIR:-/tmp/simulated_device}"
+mkdir -p "$state_dir"
+
+# prints what the board outputs when running a test. Returns 1 for a failing test, 2 for one which hangs
+run_board() {
+    echo "booting"
+    echo "running test $1"
+    case "$1" in
+        *hang*)
+            return 2
+            ;;
+        *fail*)
+            echo "TEST FAILED"
+            return 1
+            ;;
+        *)
+            echo "TEST PASSED"
+            ;;
+    esac
+}
+
+usage() {
+    echo "usage: $0 flash|capture <build_dir> <test_name>" >&2
+    echo "       $0 reset" >&2
+    exit 2
+}
+
+case "${1:-}" in
+    flash)
+        [ $# -eq 3 ] || usage
+        echo "flashing test $3 from $2"
+        rm -f "$state_dir/booted"
+        printf '%s\n' "$3" > "$state_dir/flashed_test"
+        ;;
+    reset)
+        [ $# -eq 1 ] || usage
+        if [ ! -f "$state_dir/flashed_test" ]; then
+            echo "nothing got flashed on the board" >&2
+            exit 1
+        fi
+        echo "resetting the board"
+        touch "$state_dir/booted"
+        if [ -n "${SIMULATED_DEVICE_SERIAL:-}" ]; then
+            # the board runs on its own, after the reset command returned
+            (sleep 0.2; run_board "$(cat "$state_dir/flashed_test")" > "$SIMULATED_DEVICE_SERIAL" || true) > /dev/null 2>&1 &
+        fi
+        ;;
+    capture)
+        [ $# -eq 3 ] || usage
+        # like a serial console opened before the board boots
+        while [ ! -f "$state_dir/booted" ]; do
+            sleep 0.1
+        done
+        flashed_test="$(cat "$state_dir/flashed_test")"
+        if [ "$flashed_test" != "$3" ]; then
+            echo "the board runs $flashed_test instead of $3"
+            exit 1
+        fi
+        status=0
+        run_board "$3" || status=$?
+        if [ "$status" -eq 2 ]; then
+            while true; do
+                sleep 1
+            done
+        fi
+        exit "$status"
+        ;;
+    *)
+        usage
+        ;;
+esac
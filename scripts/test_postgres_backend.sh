@@ -0,0 +1,181 @@
+#!/bin/sh
+# Runs the queries of the server against a PostgreSQL started for the occasion, e.g.
+#   ./scripts/test_postgres_backend.sh
+#
+# A throwaway cluster is created with initdb in a temporary directory, and listens on
+# 127.0.0.1:${MINI_CI_TEST_POSTGRES_PORT:-54329}. The server is built with the `postgres` feature, in
+# its own target directory, and started on port 3000, which must be free. The script then goes
+# through what workers and users do: posting jobs, several workers asking for tasks at the same time,
+# reporting outputs, tests, diagnostics and coverage, browsing the pages, and pruning the logs. It
+# exits with 1 on the first unexpected reply. The initdb and pg_ctl of the PostgreSQL install must be
+# in the PATH. When run as root, PostgreSQL runs as the `postgres` user.
+
+set -eu
+
+repo_dir="$(cd "$(dirname "$0")/.." && pwd)"
+port="${MINI_CI_TEST_POSTGRES_PORT:-54329}"
+server="http://localhost:3000"
+commit="0123456789abcdef0123456789abcdef01234567"
+
+tmp_dir="$(mktemp -d /tmp/mini_ci_postgres_test.XXXXXX)"
+server_pid=""
+
+as_postgres() {
+    if [ "$(id -u)" = 0 ]; then
+        (cd "$tmp_dir" && runuser -u postgres -- "$@")
+    else
+        "$@"
+    fi
+}
+
+cleanup() {
+    if [ -n "$server_pid" ]; then
+        kill "$server_pid" 2>/dev/null || true
+    fi
+    as_postgres pg_ctl -D "$tmp_dir/data" -m immediate stop >/dev/null 2>&1 || true
+    rm -rf "$tmp_dir"
+}
+trap cleanup EXIT
+
+fail() {
+    echo "FAILED: $*"
+    if [ -f "$tmp_dir/server.log" ]; then
+        echo "--- server log:"
+        grep -v "DEBUG" "$tmp_dir/server.log" | tail -n 20
+    fi
+    exit 1
+}
+
+# POSTs the form to the endpoint, and checks the reply is the expected one
+expect_reply() {
+    endpoint="$1"
+    form="$2"
+    expected="$3"
+    reply="$(curl -s -d "$form" "$server$endpoint")"
+    [ "$reply" = "$expected" ] || fail "$endpoint with [$form] replied [$reply] instead of [$expected]"
+}
+
+# GETs the page, and checks it got served without database error
+expect_page() {
+    status="$(curl -s -o "$tmp_dir/page" -w '%{http_code}' "$server$1")"
+    [ "$status" = 200 ] || fail "GET $1 returned $status"
+    if grep -q "Error occurred\|Error: " "$tmp_dir/page"; then
+        fail "GET $1 reported an error: $(grep -m 1 "Error" "$tmp_dir/page")"
+    fi
+}
+
+echo "Building the server with the postgres feature"
+CARGO_TARGET_DIR="$repo_dir/target/postgres" RUSTC_BOOTSTRAP=1 \
+    cargo build --quiet --manifest-path "$repo_dir/Cargo.toml" --features postgres --bin mini_ci
+mini_ci="$repo_dir/target/postgres/debug/mini_ci"
+
+echo "Starting PostgreSQL in $tmp_dir"
+mkdir "$tmp_dir/data"
+if [ "$(id -u)" = 0 ]; then
+    chown postgres "$tmp_dir" "$tmp_dir/data"
+fi
+as_postgres initdb --auth=trust --username=mini_ci -D "$tmp_dir/data" >/dev/null
+as_postgres pg_ctl -D "$tmp_dir/data" -l "$tmp_dir/postgres.log" -w \
+    -o "-c listen_addresses=127.0.0.1 -p $port -k $tmp_dir" start >/dev/null
+
+if curl -s -o /dev/null "$server/"; then
+    fail "another server already listens on port 3000"
+fi
+# the database is created by the server when missing
+export MINI_CI_DATABASE_URL="postgres://mini_ci@127.0.0.1:$port/mini_ci_test"
+(cd "$tmp_dir" && exec "$mini_ci" > "$tmp_dir/server.log" 2>&1) &
+server_pid=$!
+i=0
+until curl -s -o /dev/null "$server/"; do
+    i=$((i + 1))
+    [ "$i" -lt 50 ] || fail "the server didn't start"
+    kill -0 "$server_pid" 2>/dev/null || fail "the server exited"
+    sleep 0.2
+done
+
+echo "Posting jobs"
+for branch in main feature; do
+    curl -s -o /dev/null -d "commit_to_use=$commit&branch=$branch&tests_to_run=AllTests&compile_with_gccFromDistro=true&run_tests_on_qemu=true&measure_coverage=true&run_static_analyser=true&run_clang_tidy=true&run_clang_format=true&warnings_baseline_branch=main" \
+        "$server/add_job"
+done
+expect_page "/"
+
+echo "Claiming the tasks from several workers at once"
+worker_form="accept_static_analyser_task=true&accept_clang_tidy_task=true&accept_clang_format_task=true&accept_compile_with_gcc_from_distro=true&accept_run_tests_on_qemu=true&max_tasks=2&version=test"
+curl_pids=""
+for worker in 1 2 3 4 5 6; do
+    curl -s -d "$worker_form&hostname=worker_$worker" "$server/request_task" > "$tmp_dir/claimed_$worker" &
+    curl_pids="$curl_pids $!"
+done
+for pid in $curl_pids; do
+    wait "$pid" || fail "request_task of a worker failed"
+done
+# the tasks locked by a request are skipped by the others, which get them when asking again
+for worker in 1 2 3 4 5 6; do
+    curl -s -d "$worker_form&hostname=worker_$worker" "$server/request_task" > "$tmp_dir/claimed_again_$worker"
+done
+grep -h "^Task id: " "$tmp_dir"/claimed_* | sort > "$tmp_dir/claimed"
+nr_claims="$(wc -l < "$tmp_dir/claimed")"
+nr_tasks="$(sort -u "$tmp_dir/claimed" | wc -l)"
+[ "$nr_claims" = "$nr_tasks" ] || fail "some tasks were given to several workers: $(uniq -d "$tmp_dir/claimed" | tr '\n' ' ')"
+[ "$nr_tasks" = 8 ] || fail "expected the 8 tasks to be claimed, got $nr_tasks"
+expect_page "/workers"
+
+test_task="$(grep -h -B 2 "^Type: Tests" "$tmp_dir"/claimed_* | grep -m 1 "^Task id: " | cut -d ' ' -f 3)"
+[ -n "$test_task" ] || fail "no test task got claimed"
+
+echo "Reporting the progress of task $test_task"
+expect_reply /update_task "task_id=$test_task&return_status=Running&output=compiling%0A&seq=1" OK
+# sent again since the worker didn't get the reply
+expect_reply /update_task "task_id=$test_task&return_status=Running&output=compiling%0A&seq=1" OK
+expect_reply /add_test_list_to_job "task_id=$test_task&tests_to_add=ok_test fail/test&targets=qemu" OK
+expect_reply /report_test_change "task_id=$test_task&test_name=ok_test&target=Qemu&operation=Start" OK
+expect_reply /report_test_change "task_id=$test_task&test_name=ok_test&target=Qemu&operation=Finish&status=Success&output=passed" OK
+expect_reply /report_test_change "task_id=$test_task&test_name=fail/test&target=Qemu&operation=Start" OK
+expect_reply /report_test_change "task_id=$test_task&test_name=fail/test&target=Qemu&operation=Finish&status=Failed&output=failed" OK
+expect_reply /add_task_artifact "task_id=$test_task&name=notes.txt&content=some notes" OK
+expect_reply /add_task_diagnostics "task_id=$test_task&diagnostics=main.c%091%095%09warning%09-Wunused%09unused x" OK
+expect_reply /add_task_coverage "task_id=$test_task&coverage=main.c%0910%098%094%092&baseline_commits=$commit" OK
+reply="$(curl -s -d "task_id=$test_task&baseline_commits=$commit" "$server/compare_task_diagnostics")"
+case "$reply" in
+    Baseline:*) ;;
+    *) fail "/compare_task_diagnostics replied [$reply]" ;;
+esac
+expect_reply /update_task "task_id=$test_task&return_status=Failed&ret_code=2&output=done%0A&peak_memory_bytes=1048576&cpu_time_ms=1500" OK
+
+for task in $(cut -d ' ' -f 3 "$tmp_dir/claimed"); do
+    if [ "$task" != "$test_task" ]; then
+        expect_reply /update_task "task_id=$task&return_status=Success&ret_code=0&output=fine%0A" OK
+    fi
+done
+
+echo "Browsing the pages"
+job="$(curl -s "$server/" | grep -o 'href="/build/[0-9]*"' | head -n 1 | grep -o '[0-9]*')"
+expect_page "/"
+expect_page "/build/1"
+expect_page "/build/2"
+expect_page "/build/1/diagnostics"
+expect_page "/metrics"
+expect_page "/workers"
+for build in 1 2; do
+    if curl -s "$server/build/$build" | grep -q "task_id: $test_task<br>"; then
+        expect_page "/build/$build/task/$test_task/output"
+        [ "$(grep -c compiling "$tmp_dir/page")" = 1 ] || fail "the output sent twice got appended twice"
+        expect_page "/build/$build/task/$test_task/test/qemu/fail%2Ftest/output?tail=1"
+        expect_page "/build/$build/task/$test_task/artifact/notes.txt"
+    fi
+done
+[ -n "$job" ] || fail "no build listed on the main page"
+
+echo "Pruning the logs"
+# each job is the last one of its branch
+"$mini_ci" prune --keep-logs-of-last-jobs 1 > "$tmp_dir/prune.log" 2>&1 || fail "pruning failed: $(cat "$tmp_dir/prune.log")"
+expect_page "/build/1"
+if grep -q "Logs were pruned" "$tmp_dir/page"; then
+    fail "the logs of the last job of a branch got pruned"
+fi
+"$mini_ci" prune --keep-logs-of-last-jobs 0 > "$tmp_dir/prune.log" 2>&1 || fail "pruning failed: $(cat "$tmp_dir/prune.log")"
+expect_page "/build/1"
+grep -q "Logs were pruned" "$tmp_dir/page" || fail "the logs of job 1 didn't get pruned"
+
+echo "The postgres backend passed"
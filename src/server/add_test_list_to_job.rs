@@ -1,15 +1,21 @@
 use axum::extract::State;
 use axum::Form;
+use axum::http::StatusCode;
 use axum::response::Html;
 use serde::Deserialize;
-use sqlx::{FromRow, Pool, QueryBuilder, Sqlite};
+use sqlx::{FromRow, QueryBuilder};
+use crate::common::get_status_of_database_error;
+use crate::db::DbPool;
 use crate::post_job::PostJobForm;
+use crate::update_task;
 
 #[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
 pub struct PostTestListToJobForm {
     task_id: i64,
     tests_to_add: String,
     targets: String,
+    // the list is numbered along with the other reports of the task, so it isn't added twice
+    seq: Option<i64>,
 }
 
 #[derive(FromRow)]
@@ -17,7 +23,7 @@ struct RowID {
     id: i64,
 }
 
-pub async fn add_test_list_to_job(State(db): State<Pool<Sqlite>>, form: Form<PostTestListToJobForm>) -> Html<String> {
+pub async fn add_test_list_to_job(State(db): State<DbPool>, form: Form<PostTestListToJobForm>) -> (StatusCode, Html<String>) {
     let tests_to_add = form.tests_to_add
         .split_whitespace()
         .collect::<Vec<_>>();
@@ -26,19 +32,30 @@ pub async fn add_test_list_to_job(State(db): State<Pool<Sqlite>>, form: Form<Pos
         .split_whitespace()
         .collect::<Vec<_>>();
 
-    let mut tx = db
-        .begin()
-        .await
-        .expect("Error when starting a sql transaction");
+    let tx = db.begin().await;
+    let Ok(mut tx) = tx else {
+        let e = tx.err().unwrap();
+        return (get_status_of_database_error(&e), Html(format!("Error when starting a sql transaction: {e:?}")));
+    };
 
+    match update_task::mark_report_as_applied(&mut *tx, form.task_id, form.seq).await {
+        Ok(true) => (),
+        Ok(false) => {
+            println!("Ignoring test list {:?} of task {}, it was already added", form.seq, form.task_id);
+            return (StatusCode::OK, Html(String::from("OK")));
+        }
+        Err(e) => return (get_status_of_database_error(&e),
+                          Html(format!("Error: failed to check if the test list was already added to task {task_id}: {e:?}",
+                                       task_id = form.task_id))),
+    }
 
     for target in &targets {
         let target_id = match *target {
             "qemu" => 1,
             "real_hardware" => 2,
             x => {
-                return Html(format!("Error, unknown target. Only 'qemu' and 'real_hardware' are accepted. Got [{x_str}]",
-                                    x_str = html_escape::encode_safe(x)));
+                return (StatusCode::BAD_REQUEST, Html(format!("Error, unknown target. Only 'qemu' and 'real_hardware' are accepted. Got [{x_str}]",
+                                    x_str = html_escape::encode_safe(x))));
             }
         };
         for test in &tests_to_add {
@@ -55,18 +72,17 @@ pub async fn add_test_list_to_job(State(db): State<Pool<Sqlite>>, form: Form<Pos
 
             let Ok(RowID { id: test_run_id }) = query_res else {
                 // no need to manually call rollback. It is done automatically on Drop
-                return Html(format!(
-                    "Error occurred while inserting a test_run into database: {:?}",
-                    query_res.err()
-                ));
+                let e = query_res.err().unwrap();
+                return (get_status_of_database_error(&e), Html(format!(
+                    "Error occurred while inserting a test_run into database: {e:?}"
+                )));
             };
         }
     }
 
-    tx.commit()
-        .await
-        .expect("error occurred when trying to commit a transaction");
-
+    if let Err(e) = tx.commit().await {
+        return (get_status_of_database_error(&e), Html(format!("Error occurred when trying to commit a transaction: {e:?}")));
+    }
 
-    Html(String::from("OK"))
+    (StatusCode::OK, Html(String::from("OK")))
 }
@@ -0,0 +1,233 @@
+use crate::common::encode_html_with_escape_codepoint;
+
+// Converts the terminal control sequences found in the output of commands such as ninja, gcc or
+// ctest into html. The SGR sequences (colours, bold, ...) become <span> with classes defined in
+// the style sheet of the page, since the CSP forbids inline styles. Every other control sequence
+// (cursor movements, window titles, ...) is simply stripped.
+//
+// Only the html rendering is affected. The raw output endpoints keep returning the text exactly as
+// saved in the database.
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+struct SgrState {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    // index in the 16 colours palette
+    foreground: Option<u8>,
+    background: Option<u8>,
+}
+
+impl SgrState {
+    fn css_classes(&self) -> Option<String> {
+        let mut classes = Vec::new();
+        if self.bold {
+            classes.push(String::from("ansi_bold"));
+        }
+        if self.italic {
+            classes.push(String::from("ansi_italic"));
+        }
+        if self.underline {
+            classes.push(String::from("ansi_underline"));
+        }
+        if let Some(x) = self.foreground {
+            classes.push(format!("ansi_fg_{x}"));
+        }
+        if let Some(x) = self.background {
+            classes.push(format!("ansi_bg_{x}"));
+        }
+
+        classes.into_iter().reduce(|a, b| format!("{a} {b}"))
+    }
+
+    // extended colours are given as either 5;n (256 colours) or 2;r;g;b (true colour).
+    // Only the ones falling in the 16 colours palette are kept.
+    fn get_extended_colour(params: &mut impl Iterator<Item=u16>) -> Option<u8> {
+        match params.next() {
+            Some(5) => match params.next() {
+                Some(x) if x < 16 => Some(x as u8),
+                _ => None,
+            },
+            Some(2) => {
+                let _ = params.next();
+                let _ = params.next();
+                let _ = params.next();
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn apply(&mut self, params: &str) {
+        // an empty parameter is the same as 0, i.e. `ESC[m` is a reset
+        let mut params = params
+            .split(';')
+            .map(|x| x.parse::<u16>().unwrap_or(0));
+
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = SgrState::default(),
+                1 => self.bold = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                21 | 22 => self.bold = false,
+                23 => self.italic = false,
+                24 => self.underline = false,
+                30..=37 => self.foreground = Some((param - 30) as u8),
+                38 => self.foreground = Self::get_extended_colour(&mut params),
+                39 => self.foreground = None,
+                40..=47 => self.background = Some((param - 40) as u8),
+                48 => self.background = Self::get_extended_colour(&mut params),
+                49 => self.background = None,
+                90..=97 => self.foreground = Some((param - 90 + 8) as u8),
+                100..=107 => self.background = Some((param - 100 + 8) as u8),
+                _ => (), // unsupported, e.g. blinking
+            }
+        }
+    }
+}
+
+enum ControlSequence<'a> {
+    Sgr(&'a str),
+    Other,
+}
+
+// `text` starts right after an escape character. Returns the sequence found and the number of
+// bytes it spans (not counting the escape character).
+fn parse_control_sequence(text: &str) -> (ControlSequence<'_>, usize) {
+    let mut chars = text.char_indices();
+    match chars.next() {
+        None => (ControlSequence::Other, 0),
+        Some((_, '[')) => {
+            // CSI: parameters bytes, then intermediate bytes, then one final byte
+            for (pos, c) in chars {
+                if ('\u{40}'..='\u{7e}').contains(&c) {
+                    let params = &text[1..pos];
+                    let is_sgr = (c == 'm') && params.chars().all(|x| x.is_ascii_digit() || (x == ';'));
+                    let sequence = if is_sgr { ControlSequence::Sgr(params) } else { ControlSequence::Other };
+                    return (sequence, pos + 1);
+                }
+                if !('\u{20}'..='\u{3f}').contains(&c) {
+                    // malformed sequence. Stop right there to not swallow the text after it
+                    return (ControlSequence::Other, pos);
+                }
+            }
+            (ControlSequence::Other, text.len())
+        }
+        Some((_, ']')) => {
+            // OSC: terminated by either BEL or ESC \
+            let end = text.find(|c| (c == '\u{07}') || (c == '\u{1b}'));
+            match end {
+                None => (ControlSequence::Other, text.len()),
+                Some(pos) if text[pos..].starts_with('\u{07}') => (ControlSequence::Other, pos + 1),
+                Some(pos) if text[pos..].starts_with("\u{1b}\\") => (ControlSequence::Other, pos + 2),
+                Some(pos) => (ControlSequence::Other, pos),
+            }
+        }
+        Some((_, c)) if ('\u{20}'..='\u{2f}').contains(&c) => {
+            // e.g. ESC ( B to select the character set: intermediate bytes then one final byte
+            for (pos, c) in chars {
+                if !('\u{20}'..='\u{2f}').contains(&c) {
+                    return (ControlSequence::Other, pos + c.len_utf8());
+                }
+            }
+            (ControlSequence::Other, text.len())
+        }
+        Some((_, c)) => (ControlSequence::Other, c.len_utf8()),
+    }
+}
+
+pub(crate) fn ansi_to_html(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut state = SgrState::default();
+    // classes of the span currently opened in the html, if any
+    let mut opened_span: Option<String> = None;
+
+    let mut remaining = text;
+    loop {
+        let (plain_text, after) = match remaining.find('\u{1b}') {
+            None => (remaining, None),
+            Some(pos) => (&remaining[..pos], Some(&remaining[(pos + 1)..])),
+        };
+
+        if !plain_text.is_empty() {
+            let wanted_span = state.css_classes();
+            if wanted_span != opened_span {
+                if opened_span.is_some() {
+                    res.push_str("</span>");
+                }
+                if let Some(classes) = &wanted_span {
+                    res.push_str(format!("<span class=\"{classes}\">").as_str());
+                }
+                opened_span = wanted_span;
+            }
+            res.push_str(encode_html_with_escape_codepoint(plain_text).as_str());
+        }
+
+        let Some(after) = after else {
+            break;
+        };
+
+        let (sequence, len) = parse_control_sequence(after);
+        if let ControlSequence::Sgr(params) = sequence {
+            state.apply(params);
+        }
+        remaining = &after[len..];
+    }
+
+    if opened_span.is_some() {
+        res.push_str("</span>");
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_without_control_sequences_is_only_escaped() {
+        assert_eq!(ansi_to_html("a < b\n"), "a &lt; b\n");
+    }
+
+    #[test]
+    fn colours_become_spans_closed_on_reset() {
+        assert_eq!(ansi_to_html("\u{1b}[01;31mred\u{1b}[m plain"),
+                   "<span class=\"ansi_bold ansi_fg_1\">red</span> plain");
+        assert_eq!(ansi_to_html("\u{1b}[92mbright\u{1b}[0m"), "<span class=\"ansi_fg_10\">bright</span>");
+    }
+
+    #[test]
+    fn a_change_of_colour_opens_a_new_span() {
+        assert_eq!(ansi_to_html("\u{1b}[31ma\u{1b}[44mb"),
+                   "<span class=\"ansi_fg_1\">a</span><span class=\"ansi_fg_1 ansi_bg_4\">b</span>");
+    }
+
+    #[test]
+    fn a_span_left_open_is_closed_at_the_end() {
+        assert_eq!(ansi_to_html("\u{1b}[4munderlined"), "<span class=\"ansi_underline\">underlined</span>");
+    }
+
+    #[test]
+    fn extended_colours_are_kept_only_in_the_16_colours_palette() {
+        assert_eq!(ansi_to_html("\u{1b}[38;5;9ma"), "<span class=\"ansi_fg_9\">a</span>");
+        assert_eq!(ansi_to_html("\u{1b}[38;5;200ma"), "a");
+        // the true colour components aren't taken for other parameters
+        assert_eq!(ansi_to_html("\u{1b}[38;2;1;2;3;1ma"), "<span class=\"ansi_bold\">a</span>");
+    }
+
+    #[test]
+    fn other_control_sequences_are_stripped() {
+        assert_eq!(ansi_to_html("\u{1b}[2K\u{1b}[1Aline"), "line");
+        assert_eq!(ansi_to_html("\u{1b}]0;title\u{07}text"), "text");
+        assert_eq!(ansi_to_html("\u{1b}]0;title\u{1b}\\text"), "text");
+        assert_eq!(ansi_to_html("\u{1b}(Btext"), "text");
+    }
+
+    #[test]
+    fn a_truncated_sequence_doesnt_swallow_the_text() {
+        assert_eq!(ansi_to_html("a\u{1b}["), "a");
+        assert_eq!(ansi_to_html("a\u{1b}[12\nb"), "a\nb");
+    }
+}
@@ -1,4 +1,6 @@
+use axum::http::StatusCode;
 use serde::Deserialize;
+use sqlx::error::ErrorKind;
 use sqlx::FromRow;
 
 #[derive(Debug, Deserialize, FromRow, Clone, Eq, Hash, PartialEq)]
@@ -8,6 +10,8 @@ pub(crate) struct TaskProperties {
     pub(super) ret_code: Option<i64>,
     pub(super) task_type: i64,
     pub(super) output: Option<String>,
+    pub(super) peak_memory_bytes: Option<i64>,
+    pub(super) cpu_time_ms: Option<i64>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -92,6 +96,14 @@ pub(crate) fn is_valid_git_hash(hash: &str) -> bool {
         && hash.chars().all(|c| c.is_ascii_hexdigit())
 }
 
+// stricter than git, the name ends up in the command line of git on the workers
+pub(crate) fn is_valid_branch_name(name: &str) -> bool {
+    !name.is_empty() && (name.len() <= 200)
+        && !name.starts_with(['-', '/', '.']) && !name.ends_with(['/', '.'])
+        && !name.contains("..") && !name.contains("//") && !name.ends_with(".lock")
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "/._-+".contains(c))
+}
+
 #[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
 pub(crate) enum JobStatus {
     Pending = 1,
@@ -100,6 +112,7 @@ pub(crate) enum JobStatus {
     Failed = 4,
     Timeout = 5,
     Skipped = 6,
+    ResourceLimit = 7,
 }
 
 impl JobStatus {
@@ -111,18 +124,64 @@ impl JobStatus {
             4 => JobStatus::Failed,
             5 => JobStatus::Timeout,
             6 => JobStatus::Skipped,
+            7 => JobStatus::ResourceLimit,
             _ => panic!(),
         }
     }
 }
 
+// Makes any text usable as a single segment of the path of a URL, e.g. a test name containing `/`
+// or `#`. Everything but the unreserved characters of RFC 3986 is percent-encoded, and so are the
+// dots of `.` and `..`, which browsers would resolve as relative paths.
+pub(crate) fn encode_url_path_segment(text: &str) -> String {
+    let is_dot_segment = (text == ".") || (text == "..");
+    text.bytes()
+        .map(|x| match x {
+            b'.' if is_dot_segment => String::from("%2E"),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => String::from(x as char),
+            x => format!("%{x:02X}"),
+        })
+        .collect()
+}
+
+// Workers send a report again until the reply is "OK" or has a 4xx status, so a 4xx is for the
+// reports the database will never take, e.g. about a test the job doesn't have. Other failures of
+// the database, e.g. while it is busy, get a 500 and the report is applied when it comes again.
+pub(crate) fn get_status_of_database_error(e: &sqlx::Error) -> StatusCode {
+    match e {
+        sqlx::Error::RowNotFound => StatusCode::BAD_REQUEST,
+        sqlx::Error::Database(e) => match e.kind() {
+            ErrorKind::UniqueViolation | ErrorKind::ForeignKeyViolation
+            | ErrorKind::NotNullViolation | ErrorKind::CheckViolation => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// This is used to avoid html issues of the type "invalid UTF-8 codepoint"
+// For example, the codepoint corresponding to escape U+001b can easily
+// appear in the output of a command, when said command prints to console
+// with control characters (e.g. when printing text with colours). The set
+// of UTF-8 codepoints valid in HTML is a subset of the valid utf-8. This
+// here will ensure to encode the utf8 codepoints outside of the printable
+// range to not trigger such errors
+pub(crate) fn encode_html_with_escape_codepoint(text: &str) -> String {
+    html_escape::encode_safe(text)
+        .escape_default()
+        .to_string()
+        .replace("\\n", "\n")
+        .replace("\\r", "\r")
+        .replace("\\t", "\t")
+}
+
 pub(crate) const DOCTYPE: &'static str = "<!DOCTYPE html>";
 
 pub(crate) const URL_OF_GIT_SERVER_FOR_BROWSER_SHOWING_COMMITS: &'static str = "https://url_of_git_server_for_example_cgit/up/to/commit";
 
 pub(crate) fn get_head_with_title(title: &str) -> String {
     let csp = "<meta http-equiv=\"Content-Security-Policy\"
-content=\"default-src 'none'; style-src 'sha256-t41UEOSNtwoa6bChlZkb5ZnBfwmjo9nr4aOy5/HX55U='\">";
+content=\"default-src 'none'; style-src 'sha256-2s/725Y9HeRkLJLcRGT8TNRH35C01eMION4WHI97fmA='\">";
 // this disables issuing a network request to load the favicon.
 // triggers an error on the console log in edge, since loading this is against the CSP "img-src: none"
 // but at least it doesn't make a network request to load the favicon. Firefox doesn't show an error
@@ -794,6 +853,10 @@ hr {
  background-color: #4a3f01;
 }
 
+.ResourceLimit {
+ background-color: #5E2150;
+}
+
 .link_button {
     -webkit-border-radius: 4px;
     -moz-border-radius: 4px;
@@ -820,6 +883,132 @@ hr {
 
 .center_text {
   text-align: center;
+}
+
+.ansi_bold {
+ font-weight:bold
+}
+.ansi_italic {
+ font-style:italic
+}
+.ansi_underline {
+ text-decoration:underline
+}
+.ansi_fg_0 {
+ color:#2e3436
+}
+.ansi_fg_1 {
+ color:#cc0000
+}
+.ansi_fg_2 {
+ color:#4e9a06
+}
+.ansi_fg_3 {
+ color:#c4a000
+}
+.ansi_fg_4 {
+ color:#3465a4
+}
+.ansi_fg_5 {
+ color:#75507b
+}
+.ansi_fg_6 {
+ color:#06989a
+}
+.ansi_fg_7 {
+ color:#d3d7cf
+}
+.ansi_fg_8 {
+ color:#555753
+}
+.ansi_fg_9 {
+ color:#ef2929
+}
+.ansi_fg_10 {
+ color:#8ae234
+}
+.ansi_fg_11 {
+ color:#fce94f
+}
+.ansi_fg_12 {
+ color:#729fcf
+}
+.ansi_fg_13 {
+ color:#ad7fa8
+}
+.ansi_fg_14 {
+ color:#34e2e2
+}
+.ansi_fg_15 {
+ color:#eeeeec
+}
+.ansi_bg_0 {
+ background-color:#2e3436
+}
+.ansi_bg_1 {
+ background-color:#cc0000
+}
+.ansi_bg_2 {
+ background-color:#4e9a06
+}
+.ansi_bg_3 {
+ background-color:#c4a000
+}
+.ansi_bg_4 {
+ background-color:#3465a4
+}
+.ansi_bg_5 {
+ background-color:#75507b
+}
+.ansi_bg_6 {
+ background-color:#06989a
+}
+.ansi_bg_7 {
+ background-color:#d3d7cf
+}
+.ansi_bg_8 {
+ background-color:#555753
+}
+.ansi_bg_9 {
+ background-color:#ef2929
+}
+.ansi_bg_10 {
+ background-color:#8ae234
+}
+.ansi_bg_11 {
+ background-color:#fce94f
+}
+.ansi_bg_12 {
+ background-color:#729fcf
+}
+.ansi_bg_13 {
+ background-color:#ad7fa8
+}
+.ansi_bg_14 {
+ background-color:#34e2e2
+}
+.ansi_bg_15 {
+ background-color:#eeeeec
 }</style>"###;
     format!("<head><meta charset=\"UTF-8\"><title>{title}</title>{csp}{no_fav_icon}{style}</head>")
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_url_path_segment_escapes_the_reserved_characters() {
+        assert_eq!(encode_url_path_segment("fail/test"), "fail%2Ftest");
+        assert_eq!(encode_url_path_segment("a b?c#d%"), "a%20b%3Fc%23d%25");
+        assert_eq!(encode_url_path_segment("ok_test-1.2~"), "ok_test-1.2~");
+        assert_eq!(encode_url_path_segment("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn encode_url_path_segment_escapes_the_dot_segments() {
+        assert_eq!(encode_url_path_segment("."), "%2E");
+        assert_eq!(encode_url_path_segment(".."), "%2E%2E");
+        assert_eq!(encode_url_path_segment("..."), "...");
+    }
+}
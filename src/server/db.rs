@@ -0,0 +1,73 @@
+// Storage backend. SQLite by default, PostgreSQL when built with the `postgres` cargo feature.
+//
+// The queries of the server modules are written in the subset of SQL both databases understand.
+// For example `||` is only ever used to concatenate text, which is standard SQL, and never as a
+// boolean `or` the way SQLite tolerates it. The few pieces which can't be written the same way for
+// both, e.g. getting the current time, are provided below as macros expanding to string literals,
+// so they can be used in `concat!` and queries stay &'static str.
+//
+// Timestamps are saved as text, in UTC, formatted as `YYYY-MM-DD HH:MM:SS` in both databases. This
+// is what SQLite's CURRENT_TIMESTAMP produces and lets them be compared as strings.
+
+#[cfg(not(feature = "postgres"))]
+pub(crate) type Db = sqlx::Sqlite;
+#[cfg(feature = "postgres")]
+pub(crate) type Db = sqlx::Postgres;
+
+pub(crate) type DbPool = sqlx::Pool<Db>;
+pub(crate) type DbConnection = <Db as sqlx::Database>::Connection;
+
+#[cfg(not(feature = "postgres"))]
+pub(crate) const DEFAULT_DB_URL: &'static str = "sqlite://./ci_db.sqlite";
+#[cfg(feature = "postgres")]
+pub(crate) const DEFAULT_DB_URL: &'static str = "postgres://mini_ci@localhost/mini_ci";
+
+// environment variable to use another database than the default one
+pub(crate) const DB_URL_ENV_VAR: &'static str = "MINI_CI_DATABASE_URL";
+
+// current time, in UTC
+#[cfg(not(feature = "postgres"))]
+macro_rules! sql_now {
+    () => { "CURRENT_TIMESTAMP" };
+}
+#[cfg(feature = "postgres")]
+macro_rules! sql_now {
+    () => { "to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')" };
+}
+
+// time it was, in UTC, `$days` days ago. `$days` is the placeholder of an integer parameter
+#[cfg(not(feature = "postgres"))]
+macro_rules! sql_days_ago {
+    ($days:literal) => { concat!("datetime('now', '-' || ", $days, " || ' days')") };
+}
+#[cfg(feature = "postgres")]
+macro_rules! sql_days_ago {
+    ($days:literal) => { concat!("to_char((now() AT TIME ZONE 'UTC') - (", $days, " * INTERVAL '1 day'), 'YYYY-MM-DD HH24:MI:SS')") };
+}
+
+// number of seconds since the unix epoch of the timestamp `$timestamp`, as an integer. `$timestamp`
+// is either a column or another of these macros, e.g. `sql_unix_seconds!(sql_now!())`
+#[cfg(not(feature = "postgres"))]
+macro_rules! sql_unix_seconds {
+    ($timestamp:expr) => { concat!("CAST(strftime('%s', ", $timestamp, ") AS BIGINT)") };
+}
+#[cfg(feature = "postgres")]
+macro_rules! sql_unix_seconds {
+    ($timestamp:expr) => { concat!("CAST(EXTRACT(EPOCH FROM CAST(", $timestamp, " AS TIMESTAMP)) AS BIGINT)") };
+}
+
+// locks the selected rows of `$table` until the end of the transaction, skipping the ones another
+// transaction locked already. SQLite has a single writer at a time, so there is nothing to lock.
+#[cfg(not(feature = "postgres"))]
+macro_rules! sql_lock_rows_of {
+    ($table:literal) => { "" };
+}
+#[cfg(feature = "postgres")]
+macro_rules! sql_lock_rows_of {
+    ($table:literal) => { concat!("FOR UPDATE OF ", $table, " SKIP LOCKED") };
+}
+
+pub(crate) use sql_now;
+pub(crate) use sql_lock_rows_of;
+pub(crate) use sql_days_ago;
+pub(crate) use sql_unix_seconds;
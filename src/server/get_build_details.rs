@@ -1,18 +1,26 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
-use crate::common::{Compiler, DOCTYPE, get_head_with_title, is_valid_git_hash, JobStatus, TaskProperties, TaskType};
+use crate::ansi_colours::ansi_to_html;
+use crate::common::{Compiler, DOCTYPE, encode_html_with_escape_codepoint, encode_url_path_segment, get_head_with_title, is_valid_git_hash, JobStatus, TaskProperties, TaskType};
 use axum::extract::{Path, State};
 use axum::response::Html;
 use serde::Deserialize;
-use sqlx::{FromRow, Pool, Sqlite};
+use sqlx::FromRow;
+use crate::db::DbPool;
+use crate::task_artifacts::get_artifact_links;
+use crate::task_coverage::get_coverage_summary;
+use crate::task_diagnostics::get_diagnostics_summary;
 use std::fmt::{Debug, Formatter};
 use tracing_subscriber::fmt::format;
 
 #[derive(Debug, Deserialize, FromRow, Clone, Eq, Hash, PartialEq)]
 pub struct JobProperties {
     commit_id: String,
+    branch: Option<String>,
     added_at: String,
     status: i64,
+    pinned: i64,
+    logs_pruned_at: Option<String>,
 }
 
 #[derive(FromRow)]
@@ -23,6 +31,8 @@ struct TestSetup {
     mentioned_tests: Option<String>,
     run_tests_on_qemu: i64,
     run_tests_on_real_hardware: i64,
+    test_timeout_secs: Option<i64>,
+    with_coverage: i64,
 }
 
 fn compiler_str_from_id(compiler_id: i64) -> &'static str {
@@ -44,6 +54,8 @@ impl Debug for TestSetup {
             mentioned_tests,
             run_tests_on_qemu,
             run_tests_on_real_hardware,
+            test_timeout_secs,
+            with_coverage,
         } = self;
         let compiler_str = compiler_str_from_id(*compiler_id);
         let tests_to_run = match required_tests {
@@ -80,13 +92,25 @@ impl Debug for TestSetup {
             (false, false) => { "None." }
         };
 
+        let test_timeout = match test_timeout_secs {
+            Some(x) => format!("{x} seconds"),
+            None => String::from("default of the worker"),
+        };
+
+        let coverage = match with_coverage {
+            0 => "not measured",
+            _ => "measured on qemu",
+        };
+
         let tests_to_run = encode_html_with_escape_codepoint(tests_to_run.as_str());
         write!(
             f,
             "TestSetup id: {id}<br>
 Compiler: {compiler_str}<br>
 Tests to run: {tests_to_run}<br>
-Targets to run tests on: {targets}")
+Targets to run tests on: {targets}<br>
+Timeout of each test: {test_timeout}<br>
+Code coverage: {coverage}")
     }
 }
 
@@ -122,7 +146,16 @@ fn get_target_str_from_id(target_id: i64) -> &'static str {
     }
 }
 
-fn get_tr(test_run: &TestRunQuery) -> String {
+// name of the target as used in the url of the raw output of a test run
+fn get_target_url_name_from_id(target_id: i64) -> &'static str {
+    match target_id {
+        1 => "qemu",
+        2 => "real_hardware",
+        _ => "unknown_platform"
+    }
+}
+
+fn get_tr(build_id: i64, task_id: i64, test_run: &TestRunQuery) -> String {
     let TestRunQuery { test_name, started_at, finished_at, status, ret_code, output, target_id } = test_run;
     let class_v = JobStatus::from_i64(*status);
     let class_v = format!("{class_v:?}");
@@ -143,7 +176,10 @@ fn get_tr(test_run: &TestRunQuery) -> String {
     };
 
     let target = get_target_str_from_id(*target_id).replace(" ", "_");
-    let output = encode_html_with_escape_codepoint(output.as_str());
+    let output = ansi_to_html(output.as_str());
+    let raw_output_url = format!("/build/{build_id}/task/{task_id}/test/{t}/{n}/output",
+                                 t = get_target_url_name_from_id(*target_id),
+                                 n = html_escape::encode_double_quoted_attribute(encode_url_path_segment(test_name).as_str()));
 
     format!(
         "<td class=\"{class_v}\" title=\"{target}\">
@@ -157,6 +193,7 @@ output:
 
 {output}
 </pre>
+<a href=\"{raw_output_url}\" title=\"raw_output\">raw output</a>
 </details>
 </td>")
 }
@@ -167,13 +204,15 @@ output:
 // valid in HTML 4.01 and easily produced by commands which outputs terminal control sequence,
 // for example to display text with colours in the terminal
 
-async fn format_task(task: &TaskProperties, db: Pool<Sqlite>) -> String {
+async fn format_task(build_id: i64, task: &TaskProperties, db: DbPool) -> String {
     let TaskProperties {
         id,
         status,
         ret_code,
         task_type,
         output,
+        peak_memory_bytes,
+        cpu_time_ms,
     } = task;
 
     let task_type = TaskType::from_i64(*task_type);
@@ -185,17 +224,25 @@ async fn format_task(task: &TaskProperties, db: Pool<Sqlite>) -> String {
         String::from("")
     };
 
+    let resource_usage_str = match (peak_memory_bytes, cpu_time_ms) {
+        (Some(memory), Some(cpu)) => format!("peak memory: {} MiB, cpu time: {}.{:03} s<br>", memory / (1024 * 1024), cpu / 1000, cpu % 1000),
+        (None, Some(cpu)) => format!("cpu time: {}.{:03} s<br>", cpu / 1000, cpu % 1000),
+        _ => String::from(""),
+    };
+
     let task_id = id;
     let h1_title = format!("<h1 class=\"post-title\">task: {task_type:?}</h1>");
+    let artifacts_str = get_artifact_links(&db, build_id, *task_id).await;
+    let diagnostics_str = get_diagnostics_summary(&db, build_id, *task_id).await;
 
     let output = match output {
         None => { None }
-        Some(s) => { Some(encode_html_with_escape_codepoint(s.as_str())) }
+        Some(s) => { Some(ansi_to_html(s.as_str())) }
     };
 
     let TaskType::Tests = task_type else {
         let task_output_str = if let Some(output) = output {
-            format!("<blockquote><details><summary>output: </summary><pre title=\"output\">{output}</pre></details></blockquote><br>")
+            format!("<blockquote><details><summary>output: </summary><pre title=\"output\">{output}</pre><a href=\"/build/{build_id}/task/{task_id}/output\" title=\"raw_output\">raw output</a></details></blockquote><br>")
         } else {
             String::from("")
         };
@@ -203,6 +250,9 @@ async fn format_task(task: &TaskProperties, db: Pool<Sqlite>) -> String {
             "task_id: {task_id}<br>
 status: {status_str}<br>
 {ret_code_str}
+{resource_usage_str}
+{artifacts_str}
+{diagnostics_str}
 {task_output_str}");
 
         return format!("{h1_title}<br><div class=\"{status_str}\" title=\"{task_type:?}\">{task_detail}</div>");
@@ -210,7 +260,7 @@ status: {status_str}<br>
 
     let test_setup =
         sqlx::query_as::<_, TestSetup>(
-            "SELECT id, compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware
+            "SELECT id, compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware, test_timeout_secs, with_coverage
         FROM test_setup
         WHERE task_id = $1;"
         )
@@ -222,14 +272,17 @@ status: {status_str}<br>
         let task_detail = format!(
             "task_id: {task_id}<br>
 status: {status_str}<br>
-{ret_code_str}");
+{ret_code_str}
+{resource_usage_str}");
         return format!(
             "{h1_title}<br><div title=\"{task_type:?}\">{task_detail}<br>Failed to extract the test setup for task with id {task_id}<br></div>"
         );
     };
 
+    let coverage_str = get_coverage_summary(&db, *task_id).await;
+
     let task_output_str = if let Some(output) = output {
-        format!("<blockquote><details><summary>compile output: </summary><pre title=\"compile_output\">{output}</pre></details></blockquote>")
+        format!("<blockquote><details><summary>compile output: </summary><pre title=\"compile_output\">{output}</pre><a href=\"/build/{build_id}/task/{task_id}/output\" title=\"raw_output\">raw output</a></details></blockquote>")
     } else {
         String::from("")
     };
@@ -238,7 +291,11 @@ status: {status_str}<br>
         "task_id: {task_id}<br>
 status: {status_str}<br>
 {ret_code_str}
+{resource_usage_str}
+{artifacts_str}
 {test_setup:?}
+{coverage_str}
+{diagnostics_str}
 {task_output_str}");
 
     let test_runs = sqlx::query_as::<_, TestRunQuery>(
@@ -278,7 +335,7 @@ status: {status_str}<br>
             let values_str = values
                 .iter()
                 .map(|x| {
-                    get_tr(*x)
+                    get_tr(build_id, *task_id, *x)
                 })
                 .reduce(|a, b| format!("{a}{b}"))
                 .unwrap();
@@ -311,28 +368,12 @@ status: {status_str}<br>
     format!("{h1_title}<br><div title=\"{task_type:?}_{compiler_str}\" class=\"{status_str}\">{task_detail}{table}</div>")
 }
 
-// This is used to avoid html issues of the type "invalid UTF-8 codepoint"
-// For example, the codepoint corresponding to escape U+001b can easily
-// appear in the output of a command, when said command prints to console
-// with control characters (e.g. when printing text with colours). The set
-// of UTF-8 codepoints valid in HTML is a subset of the valid utf-8. This
-// here will ensure to encode the utf8 codepoints outside of the printable
-// range to not trigger such errors
-fn encode_html_with_escape_codepoint(text: &str) -> String {
-    html_escape::encode_safe(text)
-        .escape_default()
-        .to_string()
-        .replace("\\n", "\n")
-        .replace("\\r", "\r")
-        .replace("\\t", "\t")
-}
-
 pub async fn get_build_details(
-    State(db): State<Pool<Sqlite>>,
+    State(db): State<DbPool>,
     Path(build_id): Path<i64>,
 ) -> Html<String> {
     let query_res = sqlx::query_as::<_, JobProperties>(
-        "SELECT commit_id, added_at, status FROM JOBS
+        "SELECT commit_id, branch, added_at, status, pinned, logs_pruned_at FROM JOBS
         WHERE id = $1;",
     )
         .bind(build_id)
@@ -342,7 +383,10 @@ pub async fn get_build_details(
     let Ok(JobProperties {
                status,
                commit_id,
+               branch,
                added_at,
+               pinned,
+               logs_pruned_at,
            }) = query_res
         else {
             return Html(format!("Error, there is no job with id {build_id}"));
@@ -350,8 +394,31 @@ pub async fn get_build_details(
 
     let status = JobStatus::from_i64(status);
 
+    let pinned = pinned != 0;
+    let (pin_button_text, pin_value) = if pinned {
+        ("Unpin this build (its logs can then be pruned)", "false")
+    } else {
+        ("Pin this build (its logs will never be pruned)", "true")
+    };
+    let pin_form = format!(
+        "<form action=\"/pin_job\" method=\"post\">
+<input type=\"hidden\" name=\"job_id\" value=\"{build_id}\">
+<input type=\"hidden\" name=\"pinned\" value=\"{pin_value}\">
+<button type=\"submit\" class=\"link_button\">{pin_button_text}</button>
+</form>");
+
+    let branch_str = match branch {
+        None => String::from(""),
+        Some(branch) => format!(" of branch {}", html_escape::encode_safe(branch.as_str())),
+    };
+
+    let logs_pruned_str = match logs_pruned_at {
+        None => String::from(""),
+        Some(time) => format!("<br>\nLogs were pruned at {time} UTC. Only the summary is kept"),
+    };
+
     let tasks = sqlx::query_as::<_, TaskProperties>(
-        "SELECT id, status, ret_code, task_type, output
+        "SELECT id, status, ret_code, task_type, output, peak_memory_bytes, cpu_time_ms
         FROM tasks
         WHERE job_id = $1;",
     )
@@ -371,7 +438,7 @@ pub async fn get_build_details(
 
     let mut all_tasks_str = String::from("");
     for task in tasks {
-        let cur_task = format_task(&task, db.clone()).await;
+        let cur_task = format_task(build_id, &task, db.clone()).await;
         all_tasks_str = format!("{all_tasks_str}\n<br><br>\n{cur_task}")
     }
 
@@ -388,10 +455,13 @@ pub async fn get_build_details(
 <br>
 Was added at {added_at} UTC
 <br>
-Building from commit {commit_id}
+Building from commit {commit_id}{branch_str}
 <br>
 status is: {status:?}
 <br>
+pinned: {pinned}{logs_pruned_str}
+<br>
+{pin_form}
 <br>
 {all_tasks_str}
 </body>
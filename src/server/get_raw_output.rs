@@ -0,0 +1,254 @@
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use sqlx::FromRow;
+use crate::db::DbPool;
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct RawOutputQuery {
+    // only keep the last `tail` lines of the output, like `tail -n`
+    tail: Option<usize>,
+}
+
+#[derive(FromRow)]
+struct RawOutput {
+    output: String,
+}
+
+const TEXT_PLAIN: &'static str = "text/plain; charset=utf-8";
+
+fn text_error(status: StatusCode, msg: String) -> Response {
+    (status, [(header::CONTENT_TYPE, TEXT_PLAIN)], msg).into_response()
+}
+
+fn keep_last_lines(text: &str, nr_lines: usize) -> &str {
+    if nr_lines == 0 {
+        return "";
+    }
+
+    // a trailing newline terminates the last line, it doesn't start a new one
+    let without_trailing_newline = text.strip_suffix('\n').unwrap_or(text);
+    let start = without_trailing_newline
+        .rmatch_indices('\n')
+        .nth(nr_lines - 1)
+        .map(|(pos, _)| pos + 1)
+        .unwrap_or(0);
+
+    &text[start..]
+}
+
+enum ByteRange {
+    Full,
+    Partial { first: usize, last: usize },
+    Unsatisfiable,
+}
+
+// Only a single range of the form `bytes=first-last`, `bytes=first-` or `bytes=-suffix_len` is
+// supported. Anything else is ignored and the whole content gets returned, which is what
+// RFC 9110 allows a server to do.
+fn parse_range(range_header: Option<&str>, total_len: usize) -> ByteRange {
+    let Some(range_header) = range_header else {
+        return ByteRange::Full;
+    };
+
+    let Some(range) = range_header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+
+    if range.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let Some((first, last)) = range.split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (first, last) = (first.trim(), last.trim());
+    let (first, last) = match (first.is_empty(), last.is_empty()) {
+        (true, true) => return ByteRange::Full,
+        (true, false) => {
+            // suffix range: the last N bytes
+            let Ok(suffix_len) = last.parse::<usize>() else {
+                return ByteRange::Full;
+            };
+            if (suffix_len == 0) || (total_len == 0) {
+                return ByteRange::Unsatisfiable;
+            }
+            (total_len.saturating_sub(suffix_len), total_len - 1)
+        }
+        (false, true) => {
+            let Ok(first) = first.parse::<usize>() else {
+                return ByteRange::Full;
+            };
+            (first, total_len.saturating_sub(1))
+        }
+        (false, false) => {
+            let (Ok(first), Ok(last)) = (first.parse::<usize>(), last.parse::<usize>()) else {
+                return ByteRange::Full;
+            };
+            if last < first {
+                return ByteRange::Full;
+            }
+            (first, std::cmp::min(last, total_len.saturating_sub(1)))
+        }
+    };
+
+    if first >= total_len {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Partial { first, last }
+}
+
+fn make_text_response(output: String, query: &RawOutputQuery, headers: &HeaderMap) -> Response {
+    let output = match query.tail {
+        None => output.as_str(),
+        Some(n) => keep_last_lines(output.as_str(), n),
+    };
+
+    let total_len = output.len();
+    let range_header = headers
+        .get(header::RANGE)
+        .and_then(|x| x.to_str().ok());
+
+    match parse_range(range_header, total_len) {
+        ByteRange::Full => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, String::from(TEXT_PLAIN)),
+                (header::ACCEPT_RANGES, String::from("bytes"))],
+            Vec::from(output.as_bytes()),
+        ).into_response(),
+        ByteRange::Partial { first, last } => (
+            StatusCode::PARTIAL_CONTENT,
+            [(header::CONTENT_TYPE, String::from(TEXT_PLAIN)),
+                (header::ACCEPT_RANGES, String::from("bytes")),
+                (header::CONTENT_RANGE, format!("bytes {first}-{last}/{total_len}"))],
+            // the range is expressed in bytes, so it might split a multibyte utf-8 codepoint.
+            Vec::from(&output.as_bytes()[first..=last]),
+        ).into_response(),
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_TYPE, String::from(TEXT_PLAIN)),
+                (header::ACCEPT_RANGES, String::from("bytes")),
+                (header::CONTENT_RANGE, format!("bytes */{total_len}"))],
+            Vec::new(),
+        ).into_response(),
+    }
+}
+
+pub async fn get_task_raw_output(
+    State(db): State<DbPool>,
+    Path((job_id, task_id)): Path<(i64, i64)>,
+    Query(query): Query<RawOutputQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let query_res = sqlx::query_as::<_, RawOutput>(
+        "SELECT output FROM tasks
+        WHERE (id = $1) AND (job_id = $2);",
+    )
+        .bind(task_id)
+        .bind(job_id)
+        .fetch_optional(&db)
+        .await;
+
+    let Ok(query_res) = query_res else {
+        return text_error(StatusCode::INTERNAL_SERVER_ERROR,
+                          format!("Error occurred while reading the database {:?}\n", query_res.err()));
+    };
+
+    let Some(RawOutput { output }) = query_res else {
+        return text_error(StatusCode::NOT_FOUND,
+                          format!("Error, there is no task with id {task_id} in job {job_id}\n"));
+    };
+
+    make_text_response(output, &query, &headers)
+}
+
+pub async fn get_test_run_raw_output(
+    State(db): State<DbPool>,
+    Path((job_id, task_id, target, test_name)): Path<(i64, i64, String, String)>,
+    Query(query): Query<RawOutputQuery>,
+    headers: HeaderMap,
+) -> Response {
+    // same naming as the one used by the workers when adding the list of tests to a job
+    let target_id = match target.as_str() {
+        "qemu" => 1,
+        "real_hardware" => 2,
+        x => {
+            return text_error(StatusCode::NOT_FOUND,
+                              format!("Error, unknown target. Only 'qemu' and 'real_hardware' are accepted. Got [{x}]\n"));
+        }
+    };
+
+    let query_res = sqlx::query_as::<_, RawOutput>(
+        "SELECT test_run.output FROM test_run
+        JOIN tasks ON tasks.id = test_run.task_id
+        WHERE (test_run.task_id = $1) AND (tasks.job_id = $2)
+          AND (test_run.test_name = $3) AND (test_run.target_id = $4);",
+    )
+        .bind(task_id)
+        .bind(job_id)
+        .bind(&test_name)
+        .bind(target_id)
+        .fetch_optional(&db)
+        .await;
+
+    let Ok(query_res) = query_res else {
+        return text_error(StatusCode::INTERNAL_SERVER_ERROR,
+                          format!("Error occurred while reading the database {:?}\n", query_res.err()));
+    };
+
+    let Some(RawOutput { output }) = query_res else {
+        return text_error(StatusCode::NOT_FOUND,
+                          format!("Error, there is no test named [{test_name}] on target {target} in task {task_id} of job {job_id}\n"));
+    };
+
+    make_text_response(output, &query, &headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_last_lines_counts_a_trailing_newline_as_the_end_of_the_last_line() {
+        assert_eq!(keep_last_lines("a\nb\nc\n", 2), "b\nc\n");
+        assert_eq!(keep_last_lines("a\nb\nc", 2), "b\nc");
+        assert_eq!(keep_last_lines("a\nb\nc\n", 1), "c\n");
+    }
+
+    #[test]
+    fn keep_last_lines_keeps_everything_when_there_are_fewer_lines() {
+        assert_eq!(keep_last_lines("a\nb\n", 5), "a\nb\n");
+        assert_eq!(keep_last_lines("", 3), "");
+        assert_eq!(keep_last_lines("a\nb\n", 0), "");
+    }
+
+    #[test]
+    fn parse_range_of_the_supported_forms() {
+        assert!(matches!(parse_range(Some("bytes=2-5"), 10), ByteRange::Partial { first: 2, last: 5 }));
+        assert!(matches!(parse_range(Some("bytes=2-"), 10), ByteRange::Partial { first: 2, last: 9 }));
+        assert!(matches!(parse_range(Some("bytes=-3"), 10), ByteRange::Partial { first: 7, last: 9 }));
+        // past the end, the range stops at the last byte
+        assert!(matches!(parse_range(Some("bytes=8-20"), 10), ByteRange::Partial { first: 8, last: 9 }));
+        assert!(matches!(parse_range(Some("bytes=-20"), 10), ByteRange::Partial { first: 0, last: 9 }));
+    }
+
+    #[test]
+    fn parse_range_unsatisfiable() {
+        assert!(matches!(parse_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable));
+        assert!(matches!(parse_range(Some("bytes=-0"), 10), ByteRange::Unsatisfiable));
+        assert!(matches!(parse_range(Some("bytes=-5"), 0), ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn parse_range_ignores_what_is_not_supported() {
+        assert!(matches!(parse_range(None, 10), ByteRange::Full));
+        assert!(matches!(parse_range(Some("lines=1-2"), 10), ByteRange::Full));
+        assert!(matches!(parse_range(Some("bytes=1-2,4-5"), 10), ByteRange::Full));
+        assert!(matches!(parse_range(Some("bytes=-"), 10), ByteRange::Full));
+        assert!(matches!(parse_range(Some("bytes=5-2"), 10), ByteRange::Full));
+        assert!(matches!(parse_range(Some("bytes=a-2"), 10), ByteRange::Full));
+    }
+}
@@ -2,7 +2,9 @@ use std::io::Read;
 use axum::extract::{Path, State};
 use axum::response::Html;
 use serde::Deserialize;
-use sqlx::{Error, FromRow, Pool, Sqlite};
+use sqlx::{Error, FromRow};
+use crate::db::DbPool;
+use crate::task_coverage::get_coverage_totals;
 use crate::common::{DOCTYPE, get_head_with_title, is_valid_git_hash, JobStatus, URL_OF_GIT_SERVER_FOR_BROWSER_SHOWING_COMMITS};
 
 #[derive(Debug, Deserialize, FromRow, Clone, Eq, Hash, PartialEq)]
@@ -13,13 +15,17 @@ pub struct JobProperty {
     status: i64,
 }
 
-pub async fn list_job_queue(State(db): State<Pool<Sqlite>>) -> Html<String> {
+pub async fn list_job_queue(State(db): State<DbPool>) -> Html<String> {
     list_job_queue_with_max_id(State(db), Path(i64::MAX)).await
 }
 
-async fn format_vec_to_html(rows: Vec<JobProperty>) -> Html<String> {
+async fn format_vec_to_html(db: &DbPool, rows: Vec<JobProperty>) -> Html<String> {
     let smallest_id = rows.last().map(|x| x.id);
     let biggest_id = rows.first().map(|x| x.id);
+    let coverage_totals = match (smallest_id, biggest_id) {
+        (Some(min), Some(max)) => get_coverage_totals(db, min, max).await,
+        _ => Default::default(),
+    };
 
     let table_in = rows
         .into_iter()
@@ -29,6 +35,7 @@ async fn format_vec_to_html(rows: Vec<JobProperty>) -> Html<String> {
             let added_at = r.added_at;
             let status = r.status;
             let status = JobStatus::from_i64(status);
+            let coverage = coverage_totals.get(&id).map_or("", |x| x.as_str());
 
             if !is_valid_git_hash(commit.as_str()) { panic!() } // commit must have been validated before entering database
 
@@ -46,6 +53,9 @@ async fn format_vec_to_html(rows: Vec<JobProperty>) -> Html<String> {
   <td title=\"status\">
     {status:?}
   </td>
+  <td title=\"coverage\">
+    {coverage}
+  </td>
 </tr>")
         })
         .reduce(|x, y| format!("{x}\n{y}"))
@@ -66,6 +76,7 @@ async fn format_vec_to_html(rows: Vec<JobProperty>) -> Html<String> {
         "{DOCTYPE}<html lang=\"en-GB\">{html_head}<body>
 <h1 class=\"post-title\">Add a build</h1>
 <a href=\"/add_job\" class=\"link_button display_inline_block\">Click here to post a new job</a>
+<a href=\"/workers\" class=\"link_button display_inline_block\">View the workers</a>
 <br>
 <br>
 <h1 class=\"post-title\">Current build list</h1>
@@ -75,6 +86,7 @@ async fn format_vec_to_html(rows: Vec<JobProperty>) -> Html<String> {
       <td>commit id</td>
       <td>job added at</td>
       <td>status</td>
+      <td>coverage</td>
     </tr>
     {table_in}
     </table>
@@ -85,7 +97,7 @@ async fn format_vec_to_html(rows: Vec<JobProperty>) -> Html<String> {
     ))
 }
 
-async fn format_quert_res(query_res: Result<Vec<JobProperty>, Error>) -> Html<String> {
+async fn format_quert_res(db: &DbPool, query_res: Result<Vec<JobProperty>, Error>) -> Html<String> {
     let Ok(rows) = query_res else {
         return Html(format!(
             "Error occurred while reading the database {:?}",
@@ -93,26 +105,26 @@ async fn format_quert_res(query_res: Result<Vec<JobProperty>, Error>) -> Html<St
         ));
     };
 
-    format_vec_to_html(rows).await
+    format_vec_to_html(db, rows).await
 }
 
-pub async fn list_job_queue_with_min_id(State(db): State<Pool<Sqlite>>, Path(min_id): Path<i64>) -> Html<String> {
+pub async fn list_job_queue_with_min_id(State(db): State<DbPool>, Path(min_id): Path<i64>) -> Html<String> {
     let query_res = sqlx::query_as::<_, JobProperty>(
         "SELECT * FROM (SELECT id, commit_id, added_at, status FROM JOBS
                             WHERE id >= $1
                             ORDER BY id
-                            LIMIT 50)
+                            LIMIT 50) AS oldest_jobs_from_min_id
         ORDER BY id DESC;",
     )
         .bind(min_id)
         .fetch_all(&db)
         .await;
 
-    format_quert_res(query_res).await
+    format_quert_res(&db, query_res).await
 }
 
 
-pub async fn list_job_queue_with_max_id(State(db): State<Pool<Sqlite>>,
+pub async fn list_job_queue_with_max_id(State(db): State<DbPool>,
                                         Path(max_id): Path<i64>, ) -> Html<String> {
     let query_res = sqlx::query_as::<_, JobProperty>(
         "SELECT id, commit_id, added_at, status FROM JOBS
@@ -124,5 +136,5 @@ pub async fn list_job_queue_with_max_id(State(db): State<Pool<Sqlite>>,
         .fetch_all(&db)
         .await;
 
-    format_quert_res(query_res).await
+    format_quert_res(&db, query_res).await
 }
@@ -8,37 +8,84 @@
 #![feature(async_closure)]
 #![feature(future_join)]
 
+mod ansi_colours;
 mod common;
+mod db;
 mod get_build_details;
+mod get_raw_output;
 mod list_job_queue;
+mod metrics;
+mod migrations;
 mod post_job;
+mod reservations;
 mod request_task;
 mod update_task;
 mod add_test_list_to_job;
 mod report_test_change;
+mod retention;
+mod task_artifacts;
+mod task_baseline;
+mod task_coverage;
+mod task_diagnostics;
+mod workers;
 
 use axum::{
+    middleware,
     response::Html,
     routing::{get, post},
     Router,
 };
-use sqlx::{migrate::MigrateDatabase, Sqlite, SqlitePool};
+use sqlx::migrate::MigrateDatabase;
 use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
 use crate::list_job_queue::list_job_queue;
-
-const DB_URL: &str = "sqlite://./ci_db.sqlite";
+use crate::db::{Db, DB_URL_ENV_VAR, DbPool, DEFAULT_DB_URL};
+use crate::retention::RetentionPolicy;
 
 async fn add_job() -> Html<&'static str> {
     const ADD_JOB_PAGE: &'static str = include_str!("add_job.html");
     Html(ADD_JOB_PAGE)
 }
 
+fn print_usage() {
+    println!("usage: mini_ci [prune] [--keep-logs-for-days N] [--keep-logs-of-last-jobs N]
+
+Without subcommand, starts the web server and prunes old logs every hour.
+With the prune subcommand, prunes old logs once, vacuums the database and exits.
+The logs of a job are kept if at least one of the given rules says so. Without any rule, logs are kept forever.
+--keep-logs-of-last-jobs applies to each branch, the jobs posted without branch counting as one more branch.
+Pinned jobs are never pruned.
+
+The database to use can be set with the {DB_URL_ENV_VAR} environment variable. Defaults to {DEFAULT_DB_URL}");
+}
+
 #[tokio::main]
 async fn main() {
-    if !Sqlite::database_exists(DB_URL).await.unwrap_or(false) {
-        println!("Creating database {}", DB_URL);
-        match Sqlite::create_database(DB_URL).await {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (is_prune_command, args) = match args.first().map(|x| x.as_str()) {
+        Some("prune") => (true, &args[1..]),
+        Some("--help") | Some("-h") => {
+            print_usage();
+            return;
+        }
+        _ => (false, &args[..]),
+    };
+    let retention_policy = match RetentionPolicy::from_args(args) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{e}");
+            print_usage();
+            std::process::exit(1);
+        }
+    };
+
+    let db_url = std::env::var(DB_URL_ENV_VAR).unwrap_or(String::from(DEFAULT_DB_URL));
+    let db_url = db_url.as_str();
+
+    let is_new_database = !Db::database_exists(db_url).await.unwrap_or(false);
+    if is_new_database {
+        println!("Creating database {}", db_url);
+        match Db::create_database(db_url).await {
             Ok(_) => println!("Create db success"),
             Err(error) => panic!("error: {}", error),
         }
@@ -46,27 +93,62 @@ async fn main() {
         println!("Database already exists");
     }
 
-    let db = SqlitePool::connect(DB_URL).await.unwrap();
-    let create_schema = include_str!("create_schema.sql");
-    let result = sqlx::query(create_schema).execute(&db).await.unwrap();
-    println!("Create user table result: {:?}", result);
+    let db = DbPool::connect(db_url).await.unwrap();
+    if let Err(e) = migrations::run_migrations(&db).await {
+        println!("{e}");
+        std::process::exit(1);
+    }
+    if is_new_database {
+        // cheap on an empty database, and allows incremental vacuums after pruning logs
+        retention::vacuum(&db, true).await.unwrap();
+    }
+
+    if is_prune_command {
+        let pruned_jobs = retention::prune_old_logs(&db, &retention_policy).await.unwrap();
+        println!("Pruned logs of {n} jobs: {pruned_jobs:?}", n = pruned_jobs.len());
+        retention::vacuum(&db, true).await.unwrap();
+        println!("Vacuumed the database");
+        return;
+    }
+
+    tokio::spawn(retention::run_background_pruner(db.clone(), retention_policy));
 
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    // routes used by the workers. Their latency is exposed on /metrics
+    let worker_routes = Router::new()
+        .route("/request_task", post(request_task::request_task))
+        .route("/update_task", post(update_task::update_task))
+        .route("/add_test_list_to_job", post(add_test_list_to_job::add_test_list_to_job))
+        .route("/report_test_change", post(report_test_change::report_test_change))
+        .route("/add_task_artifact", post(task_artifacts::add_task_artifact))
+        .route("/add_task_diagnostics", post(task_diagnostics::add_task_diagnostics))
+        .route("/compare_task_diagnostics", post(task_diagnostics::compare_task_diagnostics))
+        .route("/add_task_coverage", post(task_coverage::add_task_coverage))
+        .route_layer(middleware::from_fn(metrics::track_http_latency));
+
     // build our application with a single route
     let app = Router::new()
         .route("/", get(list_job_queue::list_job_queue))
         .route("/list_max_id/{max_id}", get(list_job_queue::list_job_queue_with_max_id))
         .route("/list_min_id/{min_id}", get(list_job_queue::list_job_queue_with_min_id))
         .route("/build/{id}", get(get_build_details::get_build_details))
+        .route("/build/{job_id}/task/{task_id}/output", get(get_raw_output::get_task_raw_output))
+        .route("/build/{job_id}/task/{task_id}/test/{target}/{test_name}/output", get(get_raw_output::get_test_run_raw_output))
+        .route("/build/{job_id}/task/{task_id}/artifact/{name}", get(task_artifacts::get_task_artifact))
+        .route("/build/{job_id}/task/{task_id}/diagnostics", get(task_diagnostics::get_task_diagnostics))
+        .route("/build/{job_id}/diagnostics", get(task_diagnostics::get_job_diagnostics))
         .route("/add_job", get(add_job))
-        .route("/request_task", post(request_task::request_task))
-        .route("/update_task", post(update_task::update_task))
         .route("/add_job", post(post_job::post_job))
-        .route("/add_test_list_to_job", post(add_test_list_to_job::add_test_list_to_job))
-        .route("/report_test_change", post(report_test_change::report_test_change))
+        .route("/pin_job", post(retention::pin_job))
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/workers", get(workers::list_workers))
+        .route("/set_worker_state", post(workers::set_worker_state))
+        .route("/reserve_worker", post(reservations::reserve_worker))
+        .route("/cancel_reservation", post(reservations::cancel_reservation))
+        .merge(worker_routes)
         .with_state(db)
         .layer(CompressionLayer::new())
         .layer(TraceLayer::new_for_http());
@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sqlx::FromRow;
+use crate::db::{DbPool, sql_unix_seconds};
+
+// Metrics in the prometheus text format. Everything about tasks and tests is computed from the
+// database at scrape time, so the values survive a restart of the server. Only the http latencies
+// are kept in memory, they are measured by a middleware on the routes used by the workers.
+
+const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4; charset=utf-8";
+
+// upper bounds, in seconds, of the buckets of the http latency histograms
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct LatencyHistogram {
+    // not cumulative. The number of requests falling in each bucket only
+    bucket_counts: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl LatencyHistogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(pos) = LATENCY_BUCKETS.iter().position(|x| seconds <= *x) {
+            self.bucket_counts[pos] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+// keyed by route and http status code
+static HTTP_LATENCIES: Mutex<BTreeMap<(String, u16), LatencyHistogram>> = Mutex::new(BTreeMap::new());
+
+pub(crate) async fn track_http_latency(request: Request, next: Next) -> Response {
+    // use the route and not the path, otherwise each id would end up as a distinct label
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(x) => String::from(x.as_str()),
+        None => String::from(request.uri().path()),
+    };
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let mut latencies = HTTP_LATENCIES.lock().unwrap();
+    latencies
+        .entry((route, response.status().as_u16()))
+        .or_default()
+        .observe(elapsed);
+
+    response
+}
+
+// label values are quoted, so backslashes, double quotes and new lines must be escaped
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn push_header(res: &mut String, name: &str, metric_type: &str, help: &str) {
+    res.push_str(format!("# HELP {name} {help}\n# TYPE {name} {metric_type}\n").as_str());
+}
+
+#[derive(FromRow)]
+struct TaskKindQueue {
+    kind: String,
+    queued: i64,
+    oldest_queued_at: Option<i64>,
+}
+
+#[derive(FromRow)]
+struct WorkerActivity {
+    worker: String,
+    running: i64,
+    last_started_at: Option<i64>,
+}
+
+#[derive(FromRow)]
+struct TaskKindDurations {
+    kind: String,
+    nr_tasks: i64,
+    queue_wait_seconds: i64,
+    run_seconds: i64,
+}
+
+#[derive(FromRow)]
+struct TestRunCount {
+    target: String,
+    status: String,
+    nr_runs: i64,
+}
+
+async fn push_queue_metrics(res: &mut String, db: &DbPool) -> Result<(), sqlx::Error> {
+    // tasks are created at the same time as their job, so the job's time is the enqueue time
+    let queues = sqlx::query_as::<_, TaskKindQueue>(concat!(
+        "SELECT tasks_kind.name AS kind,
+                COUNT(tasks.id) AS queued,
+                MIN(", sql_unix_seconds!("jobs.added_at"), ") AS oldest_queued_at
+        FROM tasks_kind
+        LEFT JOIN tasks ON (tasks.task_type = tasks_kind.id) AND (tasks.status = 1) -- pending
+        LEFT JOIN jobs ON jobs.id = tasks.job_id
+        GROUP BY tasks_kind.name
+        ORDER BY tasks_kind.name;"),
+    )
+        .fetch_all(db)
+        .await?;
+
+    push_header(res, "mini_ci_queued_tasks", "gauge", "Number of tasks waiting for a worker");
+    for queue in &queues {
+        let kind = escape_label_value(queue.kind.as_str());
+        res.push_str(format!("mini_ci_queued_tasks{{kind=\"{kind}\"}} {x}\n", x = queue.queued).as_str());
+    }
+
+    push_header(res, "mini_ci_oldest_queued_task_timestamp_seconds", "gauge",
+                "Time at which the oldest task still waiting for a worker was added");
+    for queue in &queues {
+        let Some(oldest_queued_at) = queue.oldest_queued_at else {
+            continue;
+        };
+        let kind = escape_label_value(queue.kind.as_str());
+        res.push_str(format!("mini_ci_oldest_queued_task_timestamp_seconds{{kind=\"{kind}\"}} {oldest_queued_at}\n").as_str());
+    }
+
+    Ok(())
+}
+
+async fn push_worker_metrics(res: &mut String, db: &DbPool) -> Result<(), sqlx::Error> {
+    let workers = sqlx::query_as::<_, WorkerActivity>(concat!(
+        "SELECT executed_on AS worker,
+                SUM(CASE WHEN status = 2 THEN 1 ELSE 0 END) AS running,
+                MAX(", sql_unix_seconds!("started_at"), ") AS last_started_at
+        FROM tasks
+        WHERE executed_on IS NOT NULL
+        GROUP BY executed_on
+        ORDER BY executed_on;"),
+    )
+        .fetch_all(db)
+        .await?;
+
+    push_header(res, "mini_ci_running_tasks", "gauge", "Number of tasks currently executed by a worker");
+    for worker in &workers {
+        let name = escape_label_value(worker.worker.as_str());
+        res.push_str(format!("mini_ci_running_tasks{{worker=\"{name}\"}} {x}\n", x = worker.running).as_str());
+    }
+
+    push_header(res, "mini_ci_worker_last_task_started_timestamp_seconds", "gauge",
+                "Time at which a worker started its most recent task");
+    for worker in &workers {
+        let Some(last_started_at) = worker.last_started_at else {
+            continue;
+        };
+        let name = escape_label_value(worker.worker.as_str());
+        res.push_str(format!("mini_ci_worker_last_task_started_timestamp_seconds{{worker=\"{name}\"}} {last_started_at}\n").as_str());
+    }
+
+    Ok(())
+}
+
+async fn push_duration_metrics(res: &mut String, db: &DbPool) -> Result<(), sqlx::Error> {
+    let durations = sqlx::query_as::<_, TaskKindDurations>(concat!(
+        "SELECT tasks_kind.name AS kind,
+                COUNT(tasks.id) AS nr_tasks,
+                CAST(COALESCE(SUM(", sql_unix_seconds!("tasks.started_at"), " - ", sql_unix_seconds!("jobs.added_at"), "), 0) AS BIGINT) AS queue_wait_seconds,
+                CAST(COALESCE(SUM(", sql_unix_seconds!("tasks.finished_at"), " - ", sql_unix_seconds!("tasks.started_at"), "), 0) AS BIGINT) AS run_seconds
+        FROM tasks_kind
+        LEFT JOIN tasks ON (tasks.task_type = tasks_kind.id)
+                       AND (tasks.started_at IS NOT NULL)
+                       AND (tasks.finished_at IS NOT NULL)
+        LEFT JOIN jobs ON jobs.id = tasks.job_id
+        GROUP BY tasks_kind.name
+        ORDER BY tasks_kind.name;"),
+    )
+        .fetch_all(db)
+        .await?;
+
+    push_header(res, "mini_ci_task_queue_wait_seconds", "summary",
+                "Time finished tasks spent waiting for a worker");
+    for duration in &durations {
+        let kind = escape_label_value(duration.kind.as_str());
+        res.push_str(format!("mini_ci_task_queue_wait_seconds_sum{{kind=\"{kind}\"}} {x}\n", x = duration.queue_wait_seconds).as_str());
+        res.push_str(format!("mini_ci_task_queue_wait_seconds_count{{kind=\"{kind}\"}} {x}\n", x = duration.nr_tasks).as_str());
+    }
+
+    push_header(res, "mini_ci_task_run_seconds", "summary",
+                "Time finished tasks took to be executed by a worker");
+    for duration in &durations {
+        let kind = escape_label_value(duration.kind.as_str());
+        res.push_str(format!("mini_ci_task_run_seconds_sum{{kind=\"{kind}\"}} {x}\n", x = duration.run_seconds).as_str());
+        res.push_str(format!("mini_ci_task_run_seconds_count{{kind=\"{kind}\"}} {x}\n", x = duration.nr_tasks).as_str());
+    }
+
+    Ok(())
+}
+
+async fn push_test_run_metrics(res: &mut String, db: &DbPool) -> Result<(), sqlx::Error> {
+    let test_runs = sqlx::query_as::<_, TestRunCount>(
+        "SELECT targets.name AS target,
+                job_status.human_name AS status,
+                COUNT(test_run.id) AS nr_runs
+        FROM test_run
+        JOIN targets ON targets.id = test_run.target_id
+        JOIN job_status ON job_status.id = test_run.status
+        WHERE (test_run.status != 1) AND (test_run.status != 2) -- only the finished ones
+        GROUP BY targets.name, job_status.human_name
+        ORDER BY targets.name, job_status.human_name;",
+    )
+        .fetch_all(db)
+        .await?;
+
+    push_header(res, "mini_ci_test_runs_total", "counter", "Number of finished test runs, per target and status");
+    for test_run in &test_runs {
+        let target = escape_label_value(test_run.target.as_str());
+        let status = escape_label_value(test_run.status.as_str());
+        res.push_str(format!("mini_ci_test_runs_total{{target=\"{target}\",status=\"{status}\"}} {x}\n", x = test_run.nr_runs).as_str());
+    }
+
+    Ok(())
+}
+
+fn push_http_latency_metrics(res: &mut String) {
+    push_header(res, "mini_ci_http_request_duration_seconds", "histogram",
+                "Time taken to answer the requests of the workers");
+
+    let latencies = HTTP_LATENCIES.lock().unwrap();
+    for ((route, status), histogram) in latencies.iter() {
+        let labels = format!("route=\"{route}\",status=\"{status}\"", route = escape_label_value(route.as_str()));
+        let mut cumulative_count = 0;
+        for (upper_bound, count) in LATENCY_BUCKETS.iter().zip(histogram.bucket_counts.iter()) {
+            cumulative_count += count;
+            res.push_str(format!("mini_ci_http_request_duration_seconds_bucket{{{labels},le=\"{upper_bound}\"}} {cumulative_count}\n").as_str());
+        }
+        res.push_str(format!("mini_ci_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {x}\n", x = histogram.count).as_str());
+        res.push_str(format!("mini_ci_http_request_duration_seconds_sum{{{labels}}} {x}\n", x = histogram.sum).as_str());
+        res.push_str(format!("mini_ci_http_request_duration_seconds_count{{{labels}}} {x}\n", x = histogram.count).as_str());
+    }
+}
+
+async fn push_database_metrics(res: &mut String, db: &DbPool) -> Result<(), sqlx::Error> {
+    push_queue_metrics(res, db).await?;
+    push_worker_metrics(res, db).await?;
+    push_duration_metrics(res, db).await?;
+    push_test_run_metrics(res, db).await?;
+    Ok(())
+}
+
+pub async fn get_metrics(State(db): State<DbPool>) -> Response {
+    let mut res = String::new();
+
+    if let Err(e) = push_database_metrics(&mut res, &db).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, CONTENT_TYPE)],
+                format!("Error occurred while reading the database {e:?}\n")).into_response();
+    }
+
+    push_http_latency_metrics(&mut res);
+
+    (StatusCode::OK, [(header::CONTENT_TYPE, CONTENT_TYPE)], res).into_response()
+}
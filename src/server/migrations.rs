@@ -0,0 +1,179 @@
+use sqlx::Executor;
+use crate::db::{DbConnection, DbPool, sql_now};
+
+// Forward-only migrations. A migration must never be edited once released, any further change
+// to the schema goes into a new one appended at the end of this list, with the next version.
+// Both backends must have the same list of migrations, only the SQL dialect differs.
+#[cfg(not(feature = "postgres"))]
+const MIGRATIONS: [(i64, &'static str, &'static str); 16] = [
+    (1, "initial schema", include_str!("migrations/sqlite/0001_initial_schema.sql")),
+    (2, "job pinning and log pruning", include_str!("migrations/sqlite/0002_job_retention.sql")),
+    (3, "worker registry", include_str!("migrations/sqlite/0003_workers.sql")),
+    (4, "worker states", include_str!("migrations/sqlite/0004_worker_state.sql")),
+    (5, "hardware reservations", include_str!("migrations/sqlite/0005_reservations.sql")),
+    (6, "several tasks per worker", include_str!("migrations/sqlite/0006_worker_tasks.sql")),
+    (7, "resource usage of tasks", include_str!("migrations/sqlite/0007_resource_usage.sql")),
+    (8, "timeout of the tests", include_str!("migrations/sqlite/0008_test_timeout.sql")),
+    (9, "artifacts of tasks", include_str!("migrations/sqlite/0009_task_artifacts.sql")),
+    (10, "diagnostics of tasks", include_str!("migrations/sqlite/0010_task_diagnostics.sql")),
+    (11, "baseline of the warnings", include_str!("migrations/sqlite/0011_warnings_baseline.sql")),
+    (12, "code coverage of the tests", include_str!("migrations/sqlite/0012_task_coverage.sql")),
+    (13, "numbering of the reports", include_str!("migrations/sqlite/0013_report_sequence.sql")),
+    (14, "branch of the jobs", include_str!("migrations/sqlite/0014_job_branch.sql")),
+    (15, "test setup of the tasks", include_str!("migrations/sqlite/0015_task_test_setup.sql")),
+    (16, "dropped diagnostics", include_str!("migrations/sqlite/0016_dropped_diagnostics.sql")),
+];
+#[cfg(feature = "postgres")]
+const MIGRATIONS: [(i64, &'static str, &'static str); 16] = [
+    (1, "initial schema", include_str!("migrations/postgres/0001_initial_schema.sql")),
+    (2, "job pinning and log pruning", include_str!("migrations/postgres/0002_job_retention.sql")),
+    (3, "worker registry", include_str!("migrations/postgres/0003_workers.sql")),
+    (4, "worker states", include_str!("migrations/postgres/0004_worker_state.sql")),
+    (5, "hardware reservations", include_str!("migrations/postgres/0005_reservations.sql")),
+    (6, "several tasks per worker", include_str!("migrations/postgres/0006_worker_tasks.sql")),
+    (7, "resource usage of tasks", include_str!("migrations/postgres/0007_resource_usage.sql")),
+    (8, "timeout of the tests", include_str!("migrations/postgres/0008_test_timeout.sql")),
+    (9, "artifacts of tasks", include_str!("migrations/postgres/0009_task_artifacts.sql")),
+    (10, "diagnostics of tasks", include_str!("migrations/postgres/0010_task_diagnostics.sql")),
+    (11, "baseline of the warnings", include_str!("migrations/postgres/0011_warnings_baseline.sql")),
+    (12, "code coverage of the tests", include_str!("migrations/postgres/0012_task_coverage.sql")),
+    (13, "numbering of the reports", include_str!("migrations/postgres/0013_report_sequence.sql")),
+    (14, "branch of the jobs", include_str!("migrations/postgres/0014_job_branch.sql")),
+    (15, "test setup of the tasks", include_str!("migrations/postgres/0015_task_test_setup.sql")),
+    (16, "dropped diagnostics", include_str!("migrations/postgres/0016_dropped_diagnostics.sql")),
+];
+
+fn latest_known_version() -> i64 {
+    MIGRATIONS.last().map(|x| x.0).unwrap_or(0)
+}
+
+async fn does_table_exist(tx: &mut DbConnection, table: &str) -> Result<bool, String> {
+    #[cfg(not(feature = "postgres"))]
+    let query = "SELECT count(*) FROM sqlite_master WHERE (type = 'table') AND (name = $1);";
+    #[cfg(feature = "postgres")]
+    let query = "SELECT count(*) FROM information_schema.tables
+        WHERE (table_schema = current_schema()) AND (table_name = $1);";
+
+    let query_res = sqlx::query_as::<_, (i64,)>(query)
+        .bind(table)
+        .fetch_one(&mut *tx)
+        .await;
+
+    match query_res {
+        Ok((count,)) => Ok(count != 0),
+        Err(e) => Err(format!("Error: failed to check if table {table} exists: {e:?}")),
+    }
+}
+
+// Databases created before the migrations existed have no schema_version table. The schema
+// was then created by running the initial schema at every start, and the retention columns
+// might have been added afterwards.
+#[cfg(not(feature = "postgres"))]
+async fn get_version_of_unversioned_database(tx: &mut DbConnection) -> Result<i64, String> {
+    if !does_table_exist(&mut *tx, "jobs").await? {
+        return Ok(0);
+    }
+
+    let query_res = sqlx::query_as::<_, (i64,)>(
+        "SELECT count(*) FROM pragma_table_info('jobs') WHERE name = 'pinned';")
+        .fetch_one(&mut *tx)
+        .await;
+
+    match query_res {
+        Ok((0,)) => Ok(1),
+        Ok(_) => Ok(2),
+        Err(e) => Err(format!("Error: failed to inspect the jobs table: {e:?}")),
+    }
+}
+
+// Postgres databases always had their schema versioned.
+#[cfg(feature = "postgres")]
+async fn get_version_of_unversioned_database(tx: &mut DbConnection) -> Result<i64, String> {
+    if does_table_exist(&mut *tx, "jobs").await? {
+        return Err(String::from("Error: the database has a jobs table but no schema_version table. It wasn't created by mini_ci"));
+    }
+    Ok(0)
+}
+
+async fn get_schema_version(tx: &mut DbConnection) -> Result<i64, String> {
+    if !does_table_exist(&mut *tx, "schema_version").await? {
+        let version = get_version_of_unversioned_database(&mut *tx).await?;
+
+        let query_res = sqlx::query(concat!(
+            "CREATE TABLE schema_version(
+                version BIGINT PRIMARY KEY NOT NULL,
+                description TEXT NOT NULL,
+                applied_at TEXT DEFAULT (", sql_now!(), ")
+            );"))
+            .execute(&mut *tx)
+            .await;
+        if let Err(e) = query_res {
+            return Err(format!("Error: failed to create the schema_version table: {e:?}"));
+        }
+
+        for (migration_version, description, _) in MIGRATIONS.iter().filter(|x| x.0 <= version) {
+            println!("Database already has migration {migration_version} ({description})");
+            record_migration(&mut *tx, *migration_version, description).await?;
+        }
+    }
+
+    let query_res = sqlx::query_as::<_, (Option<i64>,)>("SELECT max(version) FROM schema_version;")
+        .fetch_one(&mut *tx)
+        .await;
+
+    match query_res {
+        Ok((version,)) => Ok(version.unwrap_or(0)),
+        Err(e) => Err(format!("Error: failed to read the schema version: {e:?}")),
+    }
+}
+
+async fn record_migration(tx: &mut DbConnection, version: i64, description: &str) -> Result<(), String> {
+    let query_res = sqlx::query(
+        "INSERT INTO schema_version(version, description)
+        VALUES ($1, $2);")
+        .bind(version)
+        .bind(description)
+        .execute(&mut *tx)
+        .await;
+
+    match query_res {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error: failed to record migration {version}: {e:?}")),
+    }
+}
+
+// Brings the database schema up to date. All the pending migrations are applied in a single
+// transaction, so a failing migration leaves the database untouched.
+pub(crate) async fn run_migrations(db: &DbPool) -> Result<(), String> {
+    let tx = db.begin().await;
+    let Ok(mut tx) = tx else {
+        return Err(format!("Error when starting a sql transaction: {:?}", tx.err()));
+    };
+
+    let current_version = get_schema_version(&mut *tx).await?;
+    let latest_known_version = latest_known_version();
+    if current_version > latest_known_version {
+        return Err(format!(
+            "Error: the database schema is at version {current_version}, but this server only knows up to version {latest_known_version}. Refusing to run against a newer schema, update the server instead."
+        ));
+    }
+
+    for (version, description, migration) in MIGRATIONS.iter().filter(|x| x.0 > current_version) {
+        println!("Applying migration {version} ({description})");
+        // executed as a raw string since a migration can contain several statements
+        let query_res = (&mut *tx).execute(*migration).await;
+        if let Err(e) = query_res {
+            // no need to manually call rollback. It is done automatically on Drop
+            return Err(format!("Error: migration {version} ({description}) failed: {e:?}"));
+        }
+        record_migration(&mut *tx, *version, description).await?;
+    }
+
+    let res = tx.commit().await;
+    if let Err(e) = res {
+        return Err(format!("Error: failed to commit the migrations: {e:?}"));
+    }
+
+    println!("Database schema is at version {latest_known_version}");
+    Ok(())
+}
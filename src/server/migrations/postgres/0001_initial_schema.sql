@@ -0,0 +1,150 @@
+CREATE TABLE IF NOT EXISTS job_status (
+    id BIGINT PRIMARY KEY NOT NULL,
+    human_name TEXT UNIQUE NOT NULL
+);
+
+INSERT INTO job_status(id, human_name)
+VALUES
+    (1, 'pending'),
+    (2, 'running'),
+    (3, 'success'),
+    (4, 'failed'),
+    (5, 'timeout'),
+    (6, 'skipped')
+ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS jobs (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    commit_id VARCHAR(100) NOT NULL,
+    added_at TEXT DEFAULT (to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')),
+    status BIGINT DEFAULT 1,
+    email VARCHAR(100) DEFAULT NULL, -- unused. Was in prevision of sending notifications on completion
+    FOREIGN KEY(status) REFERENCES job_status(id) ON DELETE CASCADE,
+    CHECK ( ((email LIKE '%@%') AND (length(email) >= 3)) or (email is null ))
+);
+
+CREATE INDEX IF NOT EXISTS jobs_to_status ON jobs(status);
+
+CREATE TABLE IF NOT EXISTS tasks_kind(
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  name TEXT UNIQUE NOT NULL
+);
+
+INSERT INTO tasks_kind(id, name)
+  VALUES
+    (1, 'static_analyser'),
+    (2, 'clang-format'),
+    (3, 'clang-tidy'),
+    (4, 'tests')
+ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS targets(
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name TEXT UNIQUE NOT NULL
+);
+
+INSERT INTO targets
+  VALUES
+    (1, 'qemu'),
+    (2, 'real_hardware')
+ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS compilers(
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name TEXT UNIQUE NOT NULL
+);
+
+INSERT INTO compilers
+VALUES
+  (1, 'gcc_from_hardware_vendor'),
+  (2, 'gccFromDistro')
+ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS tasks (
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  job_id BIGINT NOT NULL, -- job this task belongs to
+  status BIGINT DEFAULT 1,
+  ret_code BIGINT DEFAULT NULL,
+  task_type BIGINT NOT NULL,
+  started_at TEXT DEFAULT NULL,
+  finished_at TEXT DEFAULT NULL,
+  executed_on TEXT DEFAULT NULL,
+  output TEXT NOT NULL DEFAULT '',
+
+  FOREIGN KEY (task_type) REFERENCES tasks_kind(id) ON DELETE CASCADE,
+  FOREIGN KEY (job_id) REFERENCES jobs(id) ON DELETE CASCADE,
+  FOREIGN KEY (status) REFERENCES job_status(id) ON DELETE CASCADE,
+  CHECK( (ret_code is null ) OR (ret_code BETWEEN 0 AND 255) ),
+  CHECK( (((status = 1) or (status = 2)) and (ret_code is null))
+         or ((status != 1) and (status != 2) and (ret_code is not null) )  )
+);
+
+CREATE INDEX IF NOT EXISTS task_to_job ON tasks(job_id DESC);
+
+CREATE TABLE IF NOT EXISTS test_type(
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE
+);
+
+INSERT INTO test_type
+VALUES
+  (1, 'All tests'),
+  (2, 'No tests, only compile'),
+  -- 3 left out on purpose as it corresponds to "not even compile"
+  (4, 'all tests except'),
+  (5, 'only specified tests')
+ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS test_setup(
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    task_id BIGINT NOT NULL,
+    compiler_id BIGINT NOT NULL,
+    required_tests BIGINT NOT NULL,
+    mentioned_tests TEXT DEFAULT NULL,
+    run_tests_on_qemu BIGINT DEFAULT 0,
+    run_tests_on_real_hardware BIGINT DEFAULT 0,
+
+    FOREIGN KEY (required_tests) REFERENCES test_type(id) ON DELETE CASCADE,
+    FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+    FOREIGN KEY (compiler_id) REFERENCES compilers(id) ON DELETE CASCADE,
+    CHECK( ((mentioned_tests is null) and ((required_tests = 1) or (required_tests = 2)))
+           or (mentioned_tests is not null))
+);
+
+CREATE INDEX IF NOT EXISTS test_to_task ON test_setup(task_id DESC);
+
+CREATE TABLE IF NOT EXISTS compile_output(
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  test_setup_id BIGINT NOT NULL,
+  started_at TEXT DEFAULT NULL,
+  finished_at TEXT DEFAULT NULL,
+  output TEXT DEFAULT NULL,
+  status BIGINT DEFAULT NULL,
+
+  FOREIGN KEY (test_setup_id) REFERENCES test_setup(id) ON DELETE CASCADE,
+  CHECK ( (finished_at is null) or (status is not null)),
+  CHECK ( (finished_at is null) or (started_at is not null))
+);
+
+CREATE INDEX IF NOT EXISTS compile_output_to_test_setup ON compile_output(test_setup_id DESC);
+
+CREATE TABLE IF NOT EXISTS test_run(
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  test_name TEXT NOT NULL,
+  started_at TEXT,
+  finished_at TEXT,
+  output TEXT NOT NULL DEFAULT '',
+  status BIGINT DEFAULT 1, -- pending
+  ret_code BIGINT,
+  target_id BIGINT NOT NULL,
+  task_id BIGINT NOT NULL,
+
+  FOREIGN KEY (status) REFERENCES job_status(id) ON DELETE CASCADE,
+  FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+  FOREIGN KEY (target_id) REFERENCES targets(id) ON DELETE CASCADE,
+  CHECK ( (ret_code is null) or (ret_code BETWEEN 0 AND 255) ),
+  CHECK ((finished_at is null) or (status is not null)),
+  CHECK ((finished_at is null) or (started_at is not null))
+);
+
+CREATE INDEX IF NOT EXISTS test_run_to_task ON test_run(task_id DESC, test_name ASC);
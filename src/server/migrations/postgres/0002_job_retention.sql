@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD COLUMN pinned BIGINT NOT NULL DEFAULT 0; -- pinned jobs never get their logs pruned
+ALTER TABLE jobs ADD COLUMN logs_pruned_at TEXT DEFAULT NULL;
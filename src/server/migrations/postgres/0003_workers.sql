@@ -0,0 +1,11 @@
+CREATE TABLE workers(
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  hostname TEXT UNIQUE NOT NULL,
+  version TEXT DEFAULT NULL, -- version of mini_worker, as sent by the worker. NULL for workers not sending it
+  capabilities TEXT NOT NULL DEFAULT '', -- space separated list of what the worker accepts to do
+  first_seen_at TEXT DEFAULT (to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')),
+  last_seen_at TEXT DEFAULT (to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')),
+  current_task_id BIGINT DEFAULT NULL, -- last task given to the worker, might be finished already
+
+  FOREIGN KEY (current_task_id) REFERENCES tasks(id) ON DELETE SET NULL
+);
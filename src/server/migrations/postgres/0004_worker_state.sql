@@ -0,0 +1,12 @@
+CREATE TABLE worker_state(
+    id BIGINT PRIMARY KEY NOT NULL,
+    name TEXT UNIQUE NOT NULL
+);
+
+INSERT INTO worker_state(id, name)
+VALUES
+    (1, 'active'),
+    (2, 'draining'), -- finishes its current task, then exits
+    (3, 'paused'); -- doesn't take new tasks until resumed
+
+ALTER TABLE workers ADD COLUMN state BIGINT NOT NULL DEFAULT 1 REFERENCES worker_state(id);
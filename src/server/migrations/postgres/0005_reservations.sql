@@ -0,0 +1,15 @@
+CREATE TABLE reservations(
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  hostname TEXT NOT NULL, -- worker reserved
+  device TEXT DEFAULT NULL, -- device of the worker reserved. NULL when reserving the whole worker
+  reserved_by TEXT NOT NULL,
+  note TEXT NOT NULL DEFAULT '',
+  starts_at TEXT NOT NULL,
+  ends_at TEXT NOT NULL,
+  created_at TEXT DEFAULT (to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')),
+
+  FOREIGN KEY (hostname) REFERENCES workers(hostname) ON DELETE CASCADE,
+  CHECK (starts_at < ends_at)
+);
+
+CREATE INDEX reservations_to_worker ON reservations(hostname, ends_at);
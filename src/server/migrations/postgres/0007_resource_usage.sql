@@ -0,0 +1,8 @@
+-- Tasks and tests killed for going over the resource limits of the worker get their own status
+INSERT INTO job_status(id, human_name)
+VALUES (7, 'resource limit');
+
+-- Resources used by the commands of a task, as measured by the worker. Unknown when the worker doesn't
+-- run them in cgroups.
+ALTER TABLE tasks ADD COLUMN peak_memory_bytes BIGINT DEFAULT NULL;
+ALTER TABLE tasks ADD COLUMN cpu_time_ms BIGINT DEFAULT NULL;
@@ -0,0 +1,2 @@
+-- Time after which the worker kills a test, when the job overrides the default of the worker
+ALTER TABLE test_setup ADD COLUMN test_timeout_secs BIGINT DEFAULT NULL;
@@ -0,0 +1,16 @@
+-- Whether the test tasks build with coverage instrumentation and collect what their tests exercised
+ALTER TABLE test_setup ADD COLUMN with_coverage BIGINT DEFAULT 0;
+-- Coverage of each file by a test task, as summarized by lcov
+CREATE TABLE task_coverage(
+  id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  task_id BIGINT NOT NULL,
+  file TEXT NOT NULL, -- relative to the checkout
+  lines_found BIGINT NOT NULL,
+  lines_hit BIGINT NOT NULL,
+  branches_found BIGINT NOT NULL,
+  branches_hit BIGINT NOT NULL,
+  FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+  UNIQUE (task_id, file)
+);
+-- Task of the previous build of the base branch the coverage gets compared with
+ALTER TABLE tasks ADD COLUMN coverage_baseline_task_id BIGINT DEFAULT NULL REFERENCES tasks(id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+-- Branch the commit of the job comes from, when given. The retention policy keeps the logs of the last
+-- jobs of each branch.
+ALTER TABLE jobs ADD COLUMN branch TEXT DEFAULT NULL;
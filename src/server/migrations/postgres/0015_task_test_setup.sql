@@ -0,0 +1,2 @@
+-- Test setup a test task got executed with, among the ones of its compilers, once a worker claimed it
+ALTER TABLE tasks ADD COLUMN test_setup_id BIGINT DEFAULT NULL REFERENCES test_setup(id) ON DELETE SET NULL;
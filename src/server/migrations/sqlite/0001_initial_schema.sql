@@ -1,8 +1,3 @@
-PRAGMA foreign_keys = ON;
-PRAGMA busy_timeout = 5000; -- Release lock after 5 seconds
-
-BEGIN;
-
 CREATE TABLE IF NOT EXISTS job_status (
     id INTEGER PRIMARY KEY NOT NULL,
     human_name TEXT UNIQUE NOT NULL
@@ -151,5 +146,3 @@ CREATE TABLE IF NOT EXISTS test_run(
 );
 
 CREATE INDEX IF NOT EXISTS test_run_to_task ON test_run(task_id DESC, test_name ASC);
-
-COMMIT;
\ No newline at end of file
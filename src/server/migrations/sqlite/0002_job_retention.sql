@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0; -- pinned jobs never get their logs pruned
+ALTER TABLE jobs ADD COLUMN logs_pruned_at DATETIME DEFAULT NULL;
@@ -0,0 +1,11 @@
+CREATE TABLE workers(
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  hostname TEXT UNIQUE NOT NULL,
+  version TEXT DEFAULT NULL, -- version of mini_worker, as sent by the worker. NULL for workers not sending it
+  capabilities TEXT NOT NULL DEFAULT '', -- space separated list of what the worker accepts to do
+  first_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+  last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+  current_task_id INTEGER DEFAULT NULL, -- last task given to the worker, might be finished already
+
+  FOREIGN KEY (current_task_id) REFERENCES tasks(id) ON DELETE SET NULL
+);
@@ -0,0 +1,13 @@
+CREATE TABLE worker_state(
+    id INTEGER PRIMARY KEY NOT NULL,
+    name TEXT UNIQUE NOT NULL
+);
+
+INSERT INTO worker_state(id, name)
+VALUES
+    (1, 'active'),
+    (2, 'draining'), -- finishes its current task, then exits
+    (3, 'paused'); -- doesn't take new tasks until resumed
+
+-- no foreign key to worker_state, sqlite refuses to add a column with both a foreign key and a default value
+ALTER TABLE workers ADD COLUMN state INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,15 @@
+CREATE TABLE reservations(
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  hostname TEXT NOT NULL, -- worker reserved
+  device TEXT DEFAULT NULL, -- device of the worker reserved. NULL when reserving the whole worker
+  reserved_by TEXT NOT NULL,
+  note TEXT NOT NULL DEFAULT '',
+  starts_at DATETIME NOT NULL,
+  ends_at DATETIME NOT NULL,
+  created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+  FOREIGN KEY (hostname) REFERENCES workers(hostname) ON DELETE CASCADE,
+  CHECK (starts_at < ends_at)
+);
+
+CREATE INDEX reservations_to_worker ON reservations(hostname, ends_at);
@@ -0,0 +1,18 @@
+-- A worker can execute several tasks at once. The tasks it executes now live in their own table,
+-- and workers.current_task_id isn't used anymore. It is kept since sqlite can't drop a column
+-- referencing another table.
+CREATE TABLE worker_tasks(
+  task_id INTEGER PRIMARY KEY NOT NULL,
+  hostname TEXT NOT NULL,
+
+  FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+  FOREIGN KEY (hostname) REFERENCES workers(hostname) ON DELETE CASCADE
+);
+
+CREATE INDEX worker_tasks_to_worker ON worker_tasks(hostname);
+
+INSERT INTO worker_tasks(task_id, hostname)
+SELECT current_task_id, hostname FROM workers
+WHERE current_task_id IS NOT NULL;
+
+UPDATE workers SET current_task_id = NULL;
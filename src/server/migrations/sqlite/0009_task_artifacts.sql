@@ -0,0 +1,9 @@
+-- Files made by a task for the user to download, like the patch fixing the formatting of the code
+CREATE TABLE task_artifacts(
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  task_id INTEGER NOT NULL,
+  name TEXT NOT NULL, -- file name, unique within the task
+  content TEXT NOT NULL,
+  FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+  UNIQUE (task_id, name)
+);
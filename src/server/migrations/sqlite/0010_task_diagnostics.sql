@@ -0,0 +1,15 @@
+-- Warnings and errors of the compilers and of clang-tidy, as found by the worker in the output of a task
+CREATE TABLE task_diagnostics(
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  task_id INTEGER NOT NULL,
+  file TEXT NOT NULL, -- relative to the checkout when in it
+  line INTEGER NOT NULL,
+  column_nr INTEGER DEFAULT NULL,
+  severity TEXT NOT NULL,
+  check_name TEXT DEFAULT NULL, -- e.g. -Wunused-variable or bugprone-use-after-move
+  message TEXT NOT NULL,
+  FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+  CHECK ( severity IN ('warning', 'error') )
+);
+
+CREATE INDEX task_diagnostics_to_task ON task_diagnostics(task_id);
@@ -0,0 +1,4 @@
+-- Branch whose latest successful build gives the findings the tasks of the job get compared with
+ALTER TABLE jobs ADD COLUMN warnings_baseline_branch TEXT DEFAULT NULL;
+-- Task of that build the findings got compared with, once the worker asked for the comparison
+ALTER TABLE tasks ADD COLUMN baseline_task_id INTEGER DEFAULT NULL REFERENCES tasks(id) ON DELETE SET NULL;
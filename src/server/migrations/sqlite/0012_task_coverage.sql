@@ -0,0 +1,16 @@
+-- Whether the test tasks build with coverage instrumentation and collect what their tests exercised
+ALTER TABLE test_setup ADD COLUMN with_coverage INTEGER DEFAULT 0;
+-- Coverage of each file by a test task, as summarized by lcov
+CREATE TABLE task_coverage(
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  task_id INTEGER NOT NULL,
+  file TEXT NOT NULL, -- relative to the checkout
+  lines_found INTEGER NOT NULL,
+  lines_hit INTEGER NOT NULL,
+  branches_found INTEGER NOT NULL,
+  branches_hit INTEGER NOT NULL,
+  FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+  UNIQUE (task_id, file)
+);
+-- Task of the previous build of the base branch the coverage gets compared with
+ALTER TABLE tasks ADD COLUMN coverage_baseline_task_id INTEGER DEFAULT NULL REFERENCES tasks(id) ON DELETE SET NULL;
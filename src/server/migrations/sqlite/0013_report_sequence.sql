@@ -0,0 +1,3 @@
+-- Number of the last report of the worker applied to the task. Outputs are appended, so a report sent
+-- again because its reply got lost must be recognised and ignored.
+ALTER TABLE tasks ADD COLUMN last_report_seq INTEGER DEFAULT NULL;
@@ -0,0 +1,2 @@
+-- Diagnostics of the task the worker dropped past its maximum per task, its findings can't be compared then
+ALTER TABLE tasks ADD COLUMN nr_dropped_diagnostics INTEGER NOT NULL DEFAULT 0;
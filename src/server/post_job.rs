@@ -1,11 +1,12 @@
 use crate::common::{DOCTYPE, get_head_with_title};
-use crate::common::is_valid_git_hash;
+use crate::common::{is_valid_branch_name, is_valid_git_hash};
 use crate::post_job::TestsToRun::{NoTestsOnlyCompile, NotEvenCompile};
 use axum::extract::State;
 use axum::response::Html;
 use axum::Form;
 use serde::Deserialize;
-use sqlx::{FromRow, Pool, Sqlite, SqliteConnection, SqliteExecutor};
+use sqlx::{Executor, FromRow};
+use crate::db::{Db, DbConnection, DbPool};
 use std::fmt::Debug;
 
 #[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
@@ -38,12 +39,26 @@ pub struct PostJobForm {
     run_tests_on_qemu: bool,
     #[serde(default = "return_false")]
     run_tests_on_real_hardware: bool,
+    // in seconds. Empty to use the default of the worker
+    #[serde(default)]
+    test_timeout_secs: String,
+    // the tests get built with coverage instrumentation, and their coverage collected on qemu
+    #[serde(default = "return_false")]
+    measure_coverage: bool,
     #[serde(default = "return_false")]
     run_static_analyser: bool,
     #[serde(default = "return_false")]
     run_clang_tidy: bool,
     #[serde(default = "return_false")]
     run_clang_format: bool,
+    // branch the commit comes from. Empty when unknown. The retention policy keeps the logs of the
+    // last jobs of each branch
+    #[serde(default)]
+    branch: String,
+    // the tasks fail when they find warnings the latest successful build of that branch didn't have.
+    // Empty to not compare
+    #[serde(default)]
+    warnings_baseline_branch: String,
     #[serde(default)]
     email_to_notify_on_completion: String,
 }
@@ -54,7 +69,7 @@ struct RowID {
 }
 
 async fn add_task(
-    tx: impl SqliteExecutor<'_>,
+    tx: impl Executor<'_, Database = Db>,
     add_task_b: bool,
     job_id: i64,
     job_kind: i64,
@@ -82,7 +97,7 @@ async fn add_task(
 }
 
 async fn add_static_analyser_task(
-    tx: impl SqliteExecutor<'_>,
+    tx: impl Executor<'_, Database = Db>,
     add_static_analyser_task: bool,
     job_id: i64,
 ) -> Result<(), Html<String>> {
@@ -90,7 +105,7 @@ async fn add_static_analyser_task(
 }
 
 async fn add_clang_format_task(
-    tx: impl SqliteExecutor<'_>,
+    tx: impl Executor<'_, Database = Db>,
     add_clang_format_task: bool,
     job_id: i64,
 ) -> Result<(), Html<String>> {
@@ -98,7 +113,7 @@ async fn add_clang_format_task(
 }
 
 async fn add_clang_tidy_task(
-    tx: impl SqliteExecutor<'_>,
+    tx: impl Executor<'_, Database = Db>,
     add_clang_tidy_task: bool,
     job_id: i64,
 ) -> Result<(), Html<String>> {
@@ -106,7 +121,7 @@ async fn add_clang_tidy_task(
 }
 
 async fn add_test_setup(
-    tx: &mut SqliteConnection,
+    tx: &mut DbConnection,
     form: &Form<PostJobForm>,
     job_id: i64,
 ) -> Result<(), Html<String>> {
@@ -148,6 +163,20 @@ async fn add_test_setup(
         return Err(Html(String::from("Error: asking to run only some tests, but the list of tests to run is empty. If you do not want to run any tests, use the OnlyCompile option, or NotEvenCompile.")));
     }
 
+    // the files of the coverage data are written by the tests when they exit, which only ends up in
+    // the build directory when they run on qemu
+    if form.measure_coverage && ((!need_target) || (!form.run_tests_on_qemu)) {
+        return Err(Html(String::from("Error: measuring the code coverage needs the tests to run on qemu")));
+    }
+
+    let test_timeout_secs = match form.test_timeout_secs.trim() {
+        "" => None,
+        x => match x.parse::<i64>() {
+            Ok(secs) if secs > 0 => Some(secs),
+            _ => return Err(Html(format!("Error: invalid timeout of the tests [{x}]. It must be a positive number of seconds, or be left empty"))),
+        },
+    };
+
     let mentioned_tests = match form.tests_to_run {
         TestsToRun::AllTests | TestsToRun::NoTestsOnlyCompile => None,
         NotEvenCompile => {
@@ -182,7 +211,7 @@ async fn add_test_setup(
         NoTestsOnlyCompile => None,
         NotEvenCompile => panic!(),
         TestsToRun::AllTests | TestsToRun::AllTestsExcept | TestsToRun::OnlySpecifiedTests => {
-            Some(form.run_tests_on_qemu)
+            Some(form.run_tests_on_qemu as i64)
         }
     };
 
@@ -190,14 +219,14 @@ async fn add_test_setup(
         NoTestsOnlyCompile => None,
         NotEvenCompile => panic!(),
         TestsToRun::AllTests | TestsToRun::AllTestsExcept | TestsToRun::OnlySpecifiedTests => {
-            Some(form.run_tests_on_real_hardware)
+            Some(form.run_tests_on_real_hardware as i64)
         }
     };
 
     if form.compile_with_gccFromDistro {
         let query_res = sqlx::query_as::<_, RowID>(
-            "INSERT INTO test_setup(task_id, compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware)
-        VALUES ($1, $2, $3, $4, $5, $6)
+            "INSERT INTO test_setup(task_id, compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware, test_timeout_secs, with_coverage)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
         RETURNING id;")
             .bind(task_id_for_tests)
             .bind(2) // shortcut for select id from compilers where name = 'gccFromDistro'
@@ -205,6 +234,8 @@ async fn add_test_setup(
             .bind(mentioned_tests)
             .bind(run_on_qemu)
             .bind(run_on_real_hardware)
+            .bind(test_timeout_secs)
+            .bind(form.measure_coverage as i64)
             .fetch_one(&mut *tx)
             .await;
 
@@ -259,8 +290,8 @@ async fn add_test_setup(
 
     if form.compile_with_gcc_from_hardware_vendor {
         let query_res = sqlx::query_as::<_, RowID>(
-            "INSERT INTO test_setup(task_id, compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware)
-        VALUES ($1, $2, $3, $4, $5, $6)
+            "INSERT INTO test_setup(task_id, compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware, test_timeout_secs, with_coverage)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
         RETURNING id;")
             .bind(task_id_for_tests)
             .bind(1) // shortcut for select id from compilers where name = 'gcc_from_hardware_vendor'
@@ -268,6 +299,8 @@ async fn add_test_setup(
             .bind(mentioned_tests)
             .bind(run_on_qemu)
             .bind(run_on_real_hardware)
+            .bind(test_timeout_secs)
+            .bind(form.measure_coverage as i64)
             .fetch_one(&mut *tx)
             .await;
 
@@ -280,7 +313,7 @@ async fn add_test_setup(
     Ok(())
 }
 
-pub async fn post_job(State(db): State<Pool<Sqlite>>, form: Form<PostJobForm>) -> Html<String> {
+pub async fn post_job(State(db): State<DbPool>, form: Form<PostJobForm>) -> Html<String> {
     if (!form.run_static_analyser)
         && (!form.run_clang_tidy)
         && (!form.run_clang_format)
@@ -293,6 +326,18 @@ pub async fn post_job(State(db): State<Pool<Sqlite>>, form: Form<PostJobForm>) -
         return Html(String::from("Error: invalid git hash given."));
     }
 
+    let branch = match form.branch.trim() {
+        "" => None,
+        x if is_valid_branch_name(x) => Some(x),
+        x => return Html(format!("Error: invalid branch name [{x}]")),
+    };
+
+    let warnings_baseline_branch = match form.warnings_baseline_branch.trim() {
+        "" => None,
+        x if is_valid_branch_name(x) => Some(x),
+        x => return Html(format!("Error: invalid branch name [{x}] to compare the warnings with")),
+    };
+
     let email = if form.email_to_notify_on_completion.is_empty() {
         None
     } else {
@@ -305,12 +350,14 @@ pub async fn post_job(State(db): State<Pool<Sqlite>>, form: Form<PostJobForm>) -
         .expect("Error when starting a sql transaction");
 
     let query_res = sqlx::query_as::<_, RowID>(
-        "INSERT INTO jobs(commit_id, email)
-            VALUES($1, $2)
+        "INSERT INTO jobs(commit_id, email, warnings_baseline_branch, branch)
+            VALUES($1, $2, $3, $4)
             RETURNING id;",
     )
         .bind(&form.commit_to_use)
         .bind(email)
+        .bind(warnings_baseline_branch)
+        .bind(branch)
         .fetch_one(&mut *tx)
         .await;
 
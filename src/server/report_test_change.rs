@@ -1,9 +1,13 @@
 use std::os::linux::raw::stat;
 use axum::extract::State;
 use axum::Form;
+use axum::http::StatusCode;
 use axum::response::Html;
 use serde::Deserialize;
-use sqlx::{FromRow, Pool, Sqlite};
+use sqlx::FromRow;
+use crate::common::get_status_of_database_error;
+use crate::db::{DbPool, sql_now};
+use crate::workers;
 use crate::update_task;
 
 #[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
@@ -26,6 +30,7 @@ pub(crate) enum FinishStatus {
     Failed,
     Timeout,
     Skipped,
+    ResourceLimit,
 }
 
 #[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
@@ -36,6 +41,7 @@ pub struct ReportTestChangeForm {
     operation: Operation,
     output: Option<String>,
     status: Option<FinishStatus>,
+    seq: Option<i64>,
 }
 
 #[derive(FromRow)]
@@ -43,74 +49,102 @@ struct RowID {
     id: i64,
 }
 
-pub async fn report_test_change(State(db): State<Pool<Sqlite>>, form: Form<ReportTestChangeForm>) -> Html<String> {
+pub async fn report_test_change(State(db): State<DbPool>, form: Form<ReportTestChangeForm>) -> (StatusCode, Html<String>) {
     println!("received form: {form:?}");
 
+    let tx = db.begin().await;
+    let Ok(mut tx) = tx else {
+        let e = tx.err().unwrap();
+        return (get_status_of_database_error(&e), Html(format!("Error when starting a sql transaction: {e:?}")));
+    };
+
+    let worker_state = match workers::record_activity_on_task(&mut *tx, form.task_id).await {
+        Ok(worker_state) => worker_state,
+        Err(e) => return (get_status_of_database_error(&e),
+                          Html(format!("Error: failed to record the activity of the worker executing task {task_id}: {e:?}",
+                                       task_id = form.task_id))),
+    };
+
+    // the reports of the tests are numbered along with the ones of their task
+    match update_task::mark_report_as_applied(&mut *tx, form.task_id, form.seq).await {
+        Ok(true) => (),
+        Ok(false) => {
+            println!("Ignoring report {:?} of task {}, it was already applied", form.seq, form.task_id);
+            return (StatusCode::OK, Html(workers::get_report_reply(worker_state)));
+        }
+        Err(e) => return (get_status_of_database_error(&e),
+                          Html(format!("Error: failed to check if the report was already applied to task {task_id}: {e:?}",
+                                       task_id = form.task_id))),
+    }
+
     let target_id = match form.target {
         Target::Qemu => { 1 }
         Target::RealHardware => { 2 }
     };
     match form.operation {
         Operation::Start => {
-            let query_res = sqlx::query_as::<_, RowID>(
+            let query_res = sqlx::query_as::<_, RowID>(concat!(
                 "UPDATE test_run
             SET status = 2, -- running
-                started_at = CURRENT_TIMESTAMP
+                started_at = ", sql_now!(), "
             WHERE (task_id = $1) AND (test_name = $2) AND (target_id = $3)
-        RETURNING id;")
+        RETURNING id;"))
                 .bind(form.task_id)
                 .bind(&form.test_name)
                 .bind(target_id)
-                .fetch_one(&db)
+                .fetch_one(&mut *tx)
                 .await;
 
             let Ok(RowID { id: updated_row_id }) = query_res else {
                 // no need to manually call rollback. It is done automatically on Drop
-                return Html(format!("Error failed to update a test run in the database to set it as started. Form  was {form} and err {query:?}",
-                                    form = html_escape::encode_safe(format!("{form:?}").as_str()),
-                                    query = query_res.err()));
+                let e = query_res.err().unwrap();
+                return (get_status_of_database_error(&e),
+                        Html(format!("Error failed to update a test run in the database to set it as started. Form  was {form} and err {e:?}",
+                                     form = html_escape::encode_safe(format!("{form:?}").as_str()))));
             };
-            Html(String::from("OK"))
         }
         Operation::Finish => {
             let Some(status) = &form.status else {
-                return Html(format!("Error: test_run supposedly finished but can't extract the status code. Form was: {form}",
-                                    form = html_escape::encode_safe(format!("{form:?}").as_str())));
+                return (StatusCode::BAD_REQUEST,
+                        Html(format!("Error: test_run supposedly finished but can't extract the status code. Form was: {form}",
+                                     form = html_escape::encode_safe(format!("{form:?}").as_str()))));
             };
             let (status_id, ret_code) = match status {
                 FinishStatus::Success => { (3, Some(i64::from(0))) }
                 FinishStatus::Failed => { (4, Some(1)) }
                 FinishStatus::Timeout => { (5, Some(124)) }
                 FinishStatus::Skipped => { (6, None) }
+                FinishStatus::ResourceLimit => { (7, Some(137)) }
             };
 
-            let query_res = sqlx::query_as::<_, RowID>(
+            let query_res = sqlx::query_as::<_, RowID>(concat!(
                 "UPDATE test_run
             SET status = $4,
                 ret_code = $5,
-                finished_at = CURRENT_TIMESTAMP
+                finished_at = ", sql_now!(), "
             WHERE (task_id = $1) AND (test_name = $2) AND (target_id = $3)
-        RETURNING id;")
+        RETURNING id;"))
                 .bind(form.task_id)
                 .bind(&form.test_name)
                 .bind(target_id)
                 .bind(status_id)
                 .bind(ret_code)
-                .fetch_one(&db)
+                .fetch_one(&mut *tx)
                 .await;
 
             let Ok(RowID { id: updated_row_id }) = query_res else {
                 // no need to manually call rollback. It is done automatically on Drop
-                return Html(format!("Error failed to update a test run in the database to set it as completed. Form  was {form} and err {query:?}",
-                                    form = html_escape::encode_safe(format!("{form:?}").as_str()),
-                                    query = query_res.err()));
+                let e = query_res.err().unwrap();
+                return (get_status_of_database_error(&e),
+                        Html(format!("Error failed to update a test run in the database to set it as completed. Form  was {form} and err {e:?}",
+                                     form = html_escape::encode_safe(format!("{form:?}").as_str()))));
             };
-            Html(String::from("OK"))
         }
         Operation::Progress => {
             let Some(output) = &form.output else {
-                return Html(format!("Can't change progress of a test run if no text is given. Form was: {form}",
-                                    form = html_escape::encode_safe(format!("{form:?}").as_str())));
+                return (StatusCode::BAD_REQUEST,
+                        Html(format!("Can't change progress of a test run if no text is given. Form was: {form}",
+                                     form = html_escape::encode_safe(format!("{form:?}").as_str()))));
             };
 
             let query_res = sqlx::query_as::<_, RowID>(
@@ -123,17 +157,21 @@ pub async fn report_test_change(State(db): State<Pool<Sqlite>>, form: Form<Repor
                 .bind(&form.test_name)
                 .bind(target_id)
                 .bind(output)
-                .fetch_one(&db)
+                .fetch_one(&mut *tx)
                 .await;
 
             let Ok(RowID { id: updated_row_id }) = query_res else {
                 // no need to manually call rollback. It is done automatically on Drop
-                return Html(format!("Error failed to update the output of a test run in the database. Form  was {form} and err: {query:?}",
-                                    form = html_escape::encode_safe(format!("{form:?}").as_str()),
-                                    query = query_res.err()));
+                let e = query_res.err().unwrap();
+                return (get_status_of_database_error(&e),
+                        Html(format!("Error failed to update the output of a test run in the database. Form  was {form} and err: {e:?}",
+                                     form = html_escape::encode_safe(format!("{form:?}").as_str()))));
             };
-
-            Html(String::from("OK"))
         }
     }
+
+    if let Err(e) = tx.commit().await {
+        return (get_status_of_database_error(&e), Html(format!("Error occurred when trying to commit a transaction: {e:?}")));
+    }
+    (StatusCode::OK, Html(workers::get_report_reply(worker_state)))
 }
@@ -2,7 +2,17 @@ use crate::common::{is_valid_git_hash, Compiler, RequiredTests, TaskType};
 use axum::extract::State;
 use axum::Form;
 use serde::Deserialize;
-use sqlx::{FromRow, Pool, Sqlite};
+use sqlx::FromRow;
+use crate::db::{DbPool, sql_lock_rows_of, sql_now};
+use crate::reservations;
+use crate::workers::{self, WorkerState};
+
+// upper bound on the number of tasks given to a worker at once
+const MAX_TASKS_PER_REQUEST: i64 = 32;
+// Candidate tasks looked at per task the worker can take. Some get skipped when the worker has no
+// slot left for their class, but locking every pending task would hide them all from the other
+// workers until the end of the request.
+const CANDIDATE_TASKS_PER_TASK: i64 = 4;
 
 fn return_false() -> bool {
     false
@@ -25,6 +35,37 @@ pub struct AcceptJobForm {
     #[serde(default = "return_false")]
     accept_run_tests_on_real_hardware: bool,
     hostname: String,
+    // version of mini_worker. Older workers don't send it
+    version: Option<String>,
+    // Number of tasks the worker has free slots for, one when not given. Tasks running tests on real
+    // hardware and the other ones can have their own limits within that number.
+    max_tasks: Option<i64>,
+    max_real_hardware_tasks: Option<i64>,
+    max_other_tasks: Option<i64>,
+    // space separated ids of the tasks the worker is still executing
+    #[serde(default)]
+    running_tasks: String,
+    // sent by a worker until it got a reply to a request since it started
+    #[serde(default = "return_false")]
+    just_started: bool,
+}
+
+impl AcceptJobForm {
+    // space separated list of what the worker accepts to do, as shown on the workers page
+    fn capabilities(&self) -> String {
+        [(self.accept_static_analyser_task, "static_analyser"),
+            (self.accept_clang_tidy_task, "clang_tidy"),
+            (self.accept_clang_format_task, "clang_format"),
+            (self.accept_compile_with_gcc_from_hardware_vendor, "gcc_from_hardware_vendor"),
+            (self.accept_compile_with_gcc_from_distro, "gcc_from_distro"),
+            (self.accept_run_tests_on_qemu, "qemu"),
+            (self.accept_run_tests_on_real_hardware, "real_hardware")]
+            .iter()
+            .filter(|(is_accepted, _)| *is_accepted)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
 #[derive(FromRow, Debug)]
@@ -37,85 +78,32 @@ struct TaskProperties {
     mentioned_tests: Option<String>,
     run_tests_on_qemu: Option<i64>,
     run_tests_on_real_hardware: Option<i64>,
+    test_timeout_secs: Option<i64>,
+    with_coverage: Option<i64>,
     git_hash: Option<String>,
+    warnings_baseline_branch: Option<String>,
 }
 
-pub async fn request_task(State(db): State<Pool<Sqlite>>, form: Form<AcceptJobForm>) -> String {
-    let mut tx = db
-        .begin()
-        .await
-        .expect("Error when starting a sql transaction");
-
-    let query_res = sqlx::query_as::<_, TaskProperties>(
-        "SELECT tasks.id, tasks.task_type, test_setup_id,
-                compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware,
-                git_hash
-    FROM tasks
-    LEFT JOIN (SELECT test_setup.id as test_setup_id,
-                      test_setup.task_id as test_setup_task_id,
-                      test_setup.compiler_id as compiler_id,
-                      test_setup.required_tests as required_tests,
-                      test_setup.mentioned_tests as mentioned_tests,
-                      test_setup.run_tests_on_qemu as run_tests_on_qemu,
-                      test_setup.run_tests_on_real_hardware as run_tests_on_real_hardware
-               FROM test_setup
-               WHERE (    (     ((compiler_id = 1) AND ($4 = 1)) -- gcc_from_hardware_vendor
-                             OR ((compiler_id = 2) AND ($5 = 1)) -- gcc_from_distro
-                           )
-                       AND
-                          (    (required_tests = 2) -- no tests, only compile
-                            OR (     ((run_tests_on_qemu = 0) OR ($6 = 1))
-                                 AND ((run_tests_on_real_hardware = 0) OR ($7 = 1))
-                               )
-                          )
-                     )
-               )
-    ON test_setup_task_id = tasks.id
-    JOIN (SELECT jobs.commit_id as git_hash, jobs.id as id_from_job_table
-          FROM jobs
-          WHERE (status = 1) || (status = 2) -- shorten the search space
-         )
-    ON tasks.job_id = id_from_job_table
-    WHERE status = 1 -- shortcut for pending
-    AND (    ((tasks.task_type = 1) AND ($1 = 1)) -- static_analyser
-          OR ((tasks.task_type = 2) AND ($2 = 1)) -- clang-format
-          OR ((tasks.task_type = 3) AND ($3 = 1)) -- clang-tidy
-          OR ((tasks.task_type = 4) AND (test_setup_id IS NOT NULL))-- tests and we already filtered the compilers
-        )
-    ORDER BY id
-    LIMIT 1;")
-        .bind(form.accept_static_analyser_task as u8)
-        .bind(form.accept_clang_format_task as u8)
-        .bind(form.accept_clang_tidy_task as u8)
-        .bind(form.accept_compile_with_gcc_from_hardware_vendor as u8)
-        .bind(form.accept_compile_with_gcc_from_distro as u8)
-        .bind(form.accept_run_tests_on_qemu as u8)
-        .bind(form.accept_run_tests_on_real_hardware as u8)
-        .fetch_optional(&mut *tx)
-        .await;
-
-    let Ok(task_properties) = query_res else {
-        return format!(
-            "Error occurred while trying to find a fitting task: {:?}",
-            query_res.err().unwrap()
-        );
-    };
-
-    let Some(task_properties) = task_properties else {
-        return String::from("no suitable task found. Maybe there are no tasks left to execute");
-    };
+// a task needs the board of the worker when it runs tests on real hardware
+fn needs_real_hardware(task_properties: &TaskProperties) -> bool {
+    (TaskType::from_i64(task_properties.task_type) == TaskType::Tests)
+        && (task_properties.required_tests != Some(2)) // not only compiling
+        && (task_properties.run_tests_on_real_hardware == Some(1))
+}
 
+// description of the task, as sent to the worker
+fn get_task_description(task_properties: TaskProperties) -> Result<String, String> {
     let task_id_to_run = task_properties.id;
     let task_type = TaskType::from_i64(task_properties.task_type);
 
     let Some(git_hash) = task_properties.git_hash else {
-        return String::from("Couldn't retrieve the git hash from the task");
+        return Err(String::from("Couldn't retrieve the git hash from the task"));
     };
 
     if !is_valid_git_hash(git_hash.as_str()) {
-        return format!(
+        return Err(format!(
             "Error, retrieved task {task_id_to_run} but the git hash ({git_hash}) is invalid"
-        );
+        ));
     }
 
     let task_details = match task_type {
@@ -124,21 +112,21 @@ pub async fn request_task(State(db): State<Pool<Sqlite>>, form: Form<AcceptJobFo
         }
         TaskType::Tests => {
             let Some(required_tests) = task_properties.required_tests else {
-                return String::from("Error: tests required but required tests are not specified");
+                return Err(String::from("Error: tests required but required tests are not specified"));
             };
             let Some(test_setup_id) = task_properties.test_setup_id else {
-                return String::from("Error: retrieved tests do not have an associated setup id");
+                return Err(String::from("Error: retrieved tests do not have an associated setup id"));
             };
             let mentioned_tests = task_properties.mentioned_tests;
             let required_tests =
                 RequiredTests::try_from_tag_and_string(required_tests, mentioned_tests);
             let Ok(required_tests_as_enum) = required_tests else {
-                return String::from(required_tests.err().unwrap());
+                return Err(String::from(required_tests.err().unwrap()));
             };
             let Some(compiler_id) = task_properties.compiler_id else {
-                return String::from(
+                return Err(String::from(
                     "Error: no compiler is specified. How do you want to compile tests?",
-                );
+                ));
             };
             let compiler = Compiler::from_i64(compiler_id);
 
@@ -150,19 +138,27 @@ Compiler: {compiler:?}"
             );
             let details = if required_tests_as_enum != RequiredTests::NoTestOnlyCompile {
                 let Some(run_tests_on_qemu) = task_properties.run_tests_on_qemu else {
-                    return String::from("Error: tests required but couldn't find out if they were to run on qemu or not");
+                    return Err(String::from("Error: tests required but couldn't find out if they were to run on qemu or not"));
                 };
                 let Some(run_tests_on_real_hardware) = task_properties.run_tests_on_real_hardware
                     else {
-                        return String::from("Error: tests required but couldn't find out if they were to run on real hardware or not");
+                        return Err(String::from("Error: tests required but couldn't find out if they were to run on real hardware or not"));
                     };
                 let run_tests_on_qemu = run_tests_on_qemu != 0;
                 let run_tests_on_real_hardware = run_tests_on_real_hardware != 0;
-                format!(
+                let details = format!(
                     "{details}
 Run tests on qemu: {run_tests_on_qemu:?}
 Run tests on real hardware: {run_tests_on_real_hardware:?}"
-                )
+                );
+                let details = match task_properties.test_timeout_secs {
+                    Some(test_timeout_secs) => format!("{details}\nTest timeout: {test_timeout_secs}"),
+                    None => details,
+                };
+                match task_properties.with_coverage {
+                    Some(1) => format!("{details}\nCoverage: true"),
+                    _ => details,
+                }
             } else {
                 details
             };
@@ -170,33 +166,202 @@ Run tests on real hardware: {run_tests_on_real_hardware:?}"
         }
     };
 
-    let query_res = sqlx::query::<_>(
-        "UPDATE tasks
-             SET started_at = CURRENT_TIMESTAMP,
-             status = 2, -- running
-             executed_on = $1
-             WHERE id = $2;",
-    )
-        .bind(&form.hostname)
-        .bind(task_id_to_run)
-        .execute(&mut *tx)
+    // clang-format has nothing to compare
+    let task_details = match (task_type, task_properties.warnings_baseline_branch) {
+        (TaskType::ClangFormat, _) | (_, None) => task_details,
+        (_, Some(branch)) => format!("{task_details}\nWarnings baseline: {branch}"),
+    };
+
+    Ok(format!(
+        "Task id: {task_id_to_run}
+Git Hash: {git_hash}
+{task_details}
+"
+    ))
+}
+
+pub async fn request_task(State(db): State<DbPool>, form: Form<AcceptJobForm>) -> String {
+    // done outside the transaction, so the worker is seen even when no task is found
+    let running_tasks = form.running_tasks
+        .split_whitespace()
+        .map(|x| x.parse::<i64>())
+        .collect::<Result<Vec<_>, _>>();
+    let Ok(running_tasks) = running_tasks else {
+        return format!("Error: invalid list of running tasks [{x}]", x = form.running_tasks);
+    };
+
+    let res = workers::register_worker(&db, &form.hostname, form.version.as_deref(), form.capabilities().as_str(), &running_tasks).await;
+    let Ok(worker_state) = res else {
+        return format!("Error: failed to register worker {h}: {e:?}", h = form.hostname, e = res.err());
+    };
+
+    match worker_state {
+        WorkerState::Active => (),
+        WorkerState::Paused => return String::from(workers::WORKER_PAUSED_REPLY),
+        // the worker stops taking tasks, and exits once done with the ones it executes. It stays
+        // draining until then, e.g. when the reply telling it to exit gets lost.
+        WorkerState::Draining if !form.just_started => return String::from(workers::WORKER_EXIT_REPLY),
+        WorkerState::Draining => {
+            // restarted after exiting, it takes tasks again
+            let res = workers::set_state(&db, &form.hostname, WorkerState::Active).await;
+            if let Err(e) = res {
+                return format!("Error: failed to reset the state of worker {h}: {e:?}", h = form.hostname);
+            }
+        }
+    }
+
+    // a reserved worker still executes the tasks not requiring real hardware
+    let is_reserved = reservations::is_worker_reserved(&db, &form.hostname).await;
+    let Ok(is_reserved) = is_reserved else {
+        return format!("Error: failed to check if worker {h} is reserved: {e:?}", h = form.hostname, e = is_reserved.err());
+    };
+    let max_tasks = form.max_tasks.unwrap_or(1).clamp(1, MAX_TASKS_PER_REQUEST);
+    let max_real_hardware_tasks = form.max_real_hardware_tasks.unwrap_or(max_tasks);
+    let max_other_tasks = form.max_other_tasks.unwrap_or(max_tasks);
+    let accept_run_tests_on_real_hardware = form.accept_run_tests_on_real_hardware && !is_reserved && (max_real_hardware_tasks > 0);
+
+    let tx = db.begin().await;
+    let Ok(mut tx) = tx else {
+        return format!("Error when starting a sql transaction: {e:?}", e = tx.err());
+    };
+
+    // Two workers asking at the same time would otherwise get the same tasks. The claim below only
+    // takes tasks still pending, and with PostgreSQL the rows locked by another request are skipped
+    // rather than waited for. The tasks that request leaves are given on the next poll. Only a few
+    // candidates are locked, so concurrent requests find the next ones.
+    let query_res = sqlx::query_as::<_, TaskProperties>(concat!(
+        "SELECT tasks.id, tasks.task_type, matching_test_setups.test_setup_id,
+                compiler_id, required_tests, mentioned_tests, run_tests_on_qemu, run_tests_on_real_hardware,
+                test_timeout_secs, with_coverage, git_hash, warnings_baseline_branch
+    FROM tasks
+    LEFT JOIN (SELECT test_setup.id as test_setup_id,
+                      test_setup.task_id as test_setup_task_id,
+                      test_setup.compiler_id as compiler_id,
+                      test_setup.required_tests as required_tests,
+                      test_setup.mentioned_tests as mentioned_tests,
+                      test_setup.run_tests_on_qemu as run_tests_on_qemu,
+                      test_setup.run_tests_on_real_hardware as run_tests_on_real_hardware,
+                      test_setup.test_timeout_secs as test_timeout_secs,
+                      test_setup.with_coverage as with_coverage
+               FROM test_setup
+               WHERE (    (     ((compiler_id = 1) AND ($4 = 1)) -- gcc_from_hardware_vendor
+                             OR ((compiler_id = 2) AND ($5 = 1)) -- gcc_from_distro
+                           )
+                       AND
+                          (    (required_tests = 2) -- no tests, only compile
+                            OR (     ((run_tests_on_qemu = 0) OR ($6 = 1))
+                                 AND ((run_tests_on_real_hardware = 0) OR ($7 = 1))
+                               )
+                          )
+                     )
+               ) AS matching_test_setups
+    ON test_setup_task_id = tasks.id
+    JOIN (SELECT jobs.commit_id as git_hash, jobs.id as id_from_job_table, jobs.warnings_baseline_branch
+          FROM jobs
+          WHERE (status = 1) OR (status = 2) -- shorten the search space
+         ) AS unfinished_jobs
+    ON tasks.job_id = id_from_job_table
+    WHERE status = 1 -- shortcut for pending
+    AND (    ((tasks.task_type = 1) AND ($1 = 1)) -- static_analyser
+          OR ((tasks.task_type = 2) AND ($2 = 1)) -- clang-format
+          OR ((tasks.task_type = 3) AND ($3 = 1)) -- clang-tidy
+          OR ((tasks.task_type = 4) AND (matching_test_setups.test_setup_id IS NOT NULL))-- tests and we already filtered the compilers
+        )
+    ORDER BY id
+    LIMIT $8
+    ", sql_lock_rows_of!("tasks"), ";"))
+        .bind(form.accept_static_analyser_task as i64)
+        .bind(form.accept_clang_format_task as i64)
+        .bind(form.accept_clang_tidy_task as i64)
+        .bind(form.accept_compile_with_gcc_from_hardware_vendor as i64)
+        .bind(form.accept_compile_with_gcc_from_distro as i64)
+        .bind(form.accept_run_tests_on_qemu as i64)
+        .bind(accept_run_tests_on_real_hardware as i64)
+        .bind(max_tasks * CANDIDATE_TASKS_PER_TASK)
+        .fetch_all(&mut *tx)
         .await;
 
-    let Ok(_query_res) = query_res else {
+    let Ok(candidate_tasks) = query_res else {
         return format!(
-            "Error: failed to update task {task_id_to_run} to set hostname to {h}.",
-            h = form.hostname
+            "Error occurred while trying to find a fitting task: {:?}",
+            query_res.err().unwrap()
         );
     };
 
-    tx.commit()
-        .await
-        .expect("error occurred when trying to commit a transaction");
+    let mut descriptions = Vec::new();
+    let mut nr_real_hardware_tasks = 0;
+    let mut nr_other_tasks = 0;
+    for task_properties in candidate_tasks {
+        if descriptions.len() as i64 >= max_tasks {
+            break;
+        }
+        let is_real_hardware_task = needs_real_hardware(&task_properties);
+        if is_real_hardware_task && (nr_real_hardware_tasks >= max_real_hardware_tasks) {
+            continue;
+        }
+        if !is_real_hardware_task && (nr_other_tasks >= max_other_tasks) {
+            continue;
+        }
 
-    format!(
-        "Task id: {task_id_to_run}
-Git Hash: {git_hash}
-{task_details}
-"
-    )
+        let task_id_to_run = task_properties.id;
+        // a test task has one setup per compiler, the one the worker gets is kept to compare the task
+        // with builds of the same compiler
+        let test_setup_id = task_properties.test_setup_id;
+        let description = get_task_description(task_properties);
+        let Ok(description) = description else {
+            return description.err().unwrap();
+        };
+
+        let query_res = sqlx::query::<_>(concat!(
+            "UPDATE tasks
+                 SET started_at = ", sql_now!(), ",
+                 status = 2, -- running
+                 executed_on = $1,
+                 test_setup_id = $3
+                 WHERE (id = $2) AND (status = 1);"),
+        )
+            .bind(&form.hostname)
+            .bind(task_id_to_run)
+            .bind(test_setup_id)
+            .execute(&mut *tx)
+            .await;
+
+        let Ok(query_res) = query_res else {
+            return format!(
+                "Error: failed to update task {task_id_to_run} to set hostname to {h}.",
+                h = form.hostname
+            );
+        };
+        // another worker claimed it since it got selected
+        if query_res.rows_affected() == 0 {
+            continue;
+        }
+        if is_real_hardware_task {
+            nr_real_hardware_tasks += 1;
+        } else {
+            nr_other_tasks += 1;
+        }
+
+        let res = workers::add_current_task(&mut *tx, &form.hostname, task_id_to_run).await;
+        if let Err(e) = res {
+            return format!(
+                "Error: failed to record that task {task_id_to_run} is executed by {h}: {e:?}",
+                h = form.hostname
+            );
+        }
+
+        descriptions.push(description);
+    }
+
+    if descriptions.is_empty() {
+        return String::from("no suitable task found. Maybe there are no tasks left to execute");
+    }
+
+    // the tasks stay pending, and get given on the next poll
+    if let Err(e) = tx.commit().await {
+        return format!("Error occurred when trying to commit a transaction: {e:?}");
+    }
+
+    // tasks are separated by an empty line
+    descriptions.join("\n")
 }
@@ -0,0 +1,374 @@
+use axum::extract::State;
+use axum::Form;
+use axum::response::Html;
+use serde::Deserialize;
+use sqlx::FromRow;
+use crate::common::{DOCTYPE, encode_html_with_escape_codepoint, get_head_with_title};
+use crate::db::{DbPool, sql_now};
+
+// Developers sometimes need exclusive access to a board, e.g. to debug a test failing only on real
+// hardware. They can reserve a worker for a time window, during which it isn't given any task
+// requiring real hardware. It still executes the other tasks, e.g. compilations or tests on qemu.
+// A reservation simply stops applying once its window is over.
+//
+// Workers drive a single board for now, so reserving one device of a worker is the same as
+// reserving the whole worker. The device is still recorded, to tell the others what is in use.
+
+#[derive(FromRow)]
+pub(crate) struct Reservation {
+    id: i64,
+    pub(crate) hostname: String,
+    device: Option<String>,
+    reserved_by: String,
+    note: String,
+    starts_at: String,
+    ends_at: String,
+    is_active: i64,
+}
+
+impl Reservation {
+    pub(crate) fn is_active(&self) -> bool {
+        self.is_active != 0
+    }
+
+    // short description, as shown on the line of the worker
+    pub(crate) fn summary(&self) -> String {
+        let reserved_by = encode_html_with_escape_codepoint(self.reserved_by.as_str());
+        let note = match self.note.as_str() {
+            "" => String::from(""),
+            x => format!(": {}", encode_html_with_escape_codepoint(x)),
+        };
+        let ends_at = self.ends_at.as_str();
+        let device = match &self.device {
+            Some(x) => format!(" ({})", encode_html_with_escape_codepoint(x.as_str())),
+            None => String::from(""),
+        };
+        format!("Reserved{device} by {reserved_by} until {ends_at} UTC{note}")
+    }
+}
+
+#[derive(FromRow)]
+struct RowCount {
+    count: i64,
+}
+
+#[derive(FromRow)]
+struct RowHostname {
+    hostname: String,
+}
+
+pub(crate) async fn is_worker_reserved(db: &DbPool, hostname: &str) -> Result<bool, sqlx::Error> {
+    let RowCount { count } = sqlx::query_as::<_, RowCount>(concat!(
+        "SELECT COUNT(*) AS count FROM reservations
+        WHERE (hostname = $1)
+          AND (starts_at <= ", sql_now!(), ")
+          AND (ends_at > ", sql_now!(), ");"),
+    )
+        .bind(hostname)
+        .fetch_one(db)
+        .await?;
+    Ok(count != 0)
+}
+
+// the ones which aren't over yet, the earliest first
+pub(crate) async fn get_reservations(db: &DbPool) -> Result<Vec<Reservation>, sqlx::Error> {
+    sqlx::query_as::<_, Reservation>(concat!(
+        "SELECT id, hostname, device, reserved_by, note, starts_at, ends_at,
+                CAST(CASE WHEN starts_at <= ", sql_now!(), " THEN 1 ELSE 0 END AS BIGINT) AS is_active
+        FROM reservations
+        WHERE ends_at > ", sql_now!(), "
+        ORDER BY starts_at, id;"),
+    )
+        .fetch_all(db)
+        .await
+}
+
+fn get_nr_days_in_month(year: u32, month: u32) -> u32 {
+    let is_leap_year = (year % 4 == 0) && ((year % 100 != 0) || (year % 400 == 0));
+    match month {
+        2 if is_leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Accepts `YYYY-MM-DD HH:MM`, optionally with seconds, and with a `T` instead of the space as sent
+// by the browsers for datetime-local inputs. Returns it as `YYYY-MM-DD HH:MM:SS`, the format
+// timestamps are saved as in the database, so they can be compared as strings.
+fn parse_timestamp(text: &str) -> Option<String> {
+    let text = text.trim().replacen('T', " ", 1);
+    let text = match text.len() {
+        16 => format!("{text}:00"),
+        19 => text,
+        _ => return None,
+    };
+
+    let is_well_formed = text.char_indices().all(|(pos, c)| match pos {
+        4 | 7 => c == '-',
+        10 => c == ' ',
+        13 | 16 => c == ':',
+        _ => c.is_ascii_digit(),
+    });
+    if !is_well_formed {
+        return None;
+    }
+
+    let get_number = |range: std::ops::Range<usize>| text[range].parse::<u32>().unwrap_or(0);
+    let month = get_number(5..7);
+    let is_valid = (1..=12).contains(&month)
+        && (1..=get_nr_days_in_month(get_number(0..4), month)).contains(&get_number(8..10))
+        && (get_number(11..13) < 24)
+        && (get_number(14..16) < 60)
+        && (get_number(17..19) < 60);
+    if !is_valid {
+        return None;
+    }
+
+    Some(text)
+}
+
+fn format_reservation(reservation: &Reservation) -> String {
+    let id = reservation.id;
+    let hostname = encode_html_with_escape_codepoint(reservation.hostname.as_str());
+    let device = match &reservation.device {
+        Some(x) => encode_html_with_escape_codepoint(x.as_str()),
+        None => String::from("whole worker"),
+    };
+    let reserved_by = encode_html_with_escape_codepoint(reservation.reserved_by.as_str());
+    let note = encode_html_with_escape_codepoint(reservation.note.as_str());
+    let starts_at = reservation.starts_at.as_str();
+    let ends_at = reservation.ends_at.as_str();
+    let (css_class, status) = if reservation.is_active() { ("Running", "ongoing") } else { ("Pending", "upcoming") };
+
+    format!(
+        "<tr class=\"{css_class}\" title=\"reservation_{id}\">
+  <td title=\"hostname\">{hostname}</td>
+  <td title=\"device\">{device}</td>
+  <td title=\"reserved_by\">{reserved_by}</td>
+  <td title=\"starts_at\">{starts_at} UTC</td>
+  <td title=\"ends_at\">{ends_at} UTC</td>
+  <td title=\"status\">{status}</td>
+  <td title=\"note\">{note}</td>
+  <td title=\"actions\"><form action=\"/cancel_reservation\" method=\"post\">
+<input type=\"hidden\" name=\"id\" value=\"{id}\">
+<button type=\"submit\" class=\"link_button\">Cancel</button>
+</form></td>
+</tr>")
+}
+
+// list of the reservations and form to add a new one, shown on the workers page
+pub(crate) fn format_reservations_section(reservations: &[Reservation], hostnames: &[String]) -> String {
+    let table_in = reservations
+        .iter()
+        .map(format_reservation)
+        .reduce(|x, y| format!("{x}\n{y}"))
+        .unwrap_or(String::from(""));
+
+    let hostname_options = hostnames
+        .iter()
+        .map(|x| {
+            let hostname = encode_html_with_escape_codepoint(x.as_str());
+            format!("<option value=\"{hostname}\">{hostname}</option>")
+        })
+        .reduce(|x, y| format!("{x}\n{y}"))
+        .unwrap_or(String::from(""));
+
+    format!(
+        "<h1 class=\"post-title\">Reservations</h1>
+  <table>
+    <tr>
+      <td>hostname</td>
+      <td>device</td>
+      <td>reserved by</td>
+      <td>starts at</td>
+      <td>ends at</td>
+      <td>status</td>
+      <td>note</td>
+      <td>actions</td>
+    </tr>
+    {table_in}
+  </table>
+<h2>Reserve a worker</h2>
+<form action=\"/reserve_worker\" method=\"post\">
+<label for=\"hostname\">Worker</label>
+<select id=\"hostname\" name=\"hostname\" required>
+{hostname_options}
+</select>
+<br>
+<label for=\"device\">Device (optional)</label>
+<input type=\"text\" id=\"device\" name=\"device\">
+<br>
+<label for=\"reserved_by\">Reserved by</label>
+<input type=\"text\" id=\"reserved_by\" name=\"reserved_by\" required>
+<br>
+<label for=\"starts_at\">From (UTC)</label>
+<input type=\"datetime-local\" id=\"starts_at\" name=\"starts_at\" required>
+<br>
+<label for=\"ends_at\">Until (UTC)</label>
+<input type=\"datetime-local\" id=\"ends_at\" name=\"ends_at\" required>
+<br>
+<label for=\"note\">Note</label>
+<input type=\"text\" id=\"note\" name=\"note\">
+<br>
+<button type=\"submit\" class=\"link_button\">Reserve</button>
+</form>")
+}
+
+fn make_result_page(title: &str) -> Html<String> {
+    let html_head = get_head_with_title(title);
+    Html(format!(
+        "{DOCTYPE}<html lang=\"en-GB\">{html_head}<body>
+<h1 class=\"post-title\">{title}</h1>
+<a href=\"/workers\" class=\"link_button\">View the workers</a>
+</body></html>"))
+}
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct ReserveWorkerForm {
+    hostname: String,
+    device: Option<String>,
+    reserved_by: String,
+    #[serde(default)]
+    note: String,
+    starts_at: String,
+    ends_at: String,
+}
+
+pub async fn reserve_worker(State(db): State<DbPool>, form: Form<ReserveWorkerForm>) -> Html<String> {
+    let (Some(starts_at), Some(ends_at)) = (parse_timestamp(&form.starts_at), parse_timestamp(&form.ends_at)) else {
+        return Html(String::from("Error: invalid time window. Expected times as YYYY-MM-DD HH:MM"));
+    };
+    if starts_at >= ends_at {
+        return Html(String::from("Error: the reservation must end after it starts"));
+    }
+    if form.reserved_by.trim().is_empty() {
+        return Html(String::from("Error: tell who the worker is reserved by"));
+    }
+    // the form sends an empty string when no device is given
+    let device = form.device.as_deref().map(|x| x.trim()).filter(|x| !x.is_empty());
+
+    let mut tx = db
+        .begin()
+        .await
+        .expect("Error when starting a sql transaction");
+
+    let query_res = sqlx::query_as::<_, RowCount>(
+        "SELECT COUNT(*) AS count FROM reservations
+        WHERE (hostname = $1) AND (starts_at < $3) AND (ends_at > $2);",
+    )
+        .bind(&form.hostname)
+        .bind(&starts_at)
+        .bind(&ends_at)
+        .fetch_one(&mut *tx)
+        .await;
+
+    let Ok(RowCount { count: nr_overlapping }) = query_res else {
+        return Html(format!("Error occurred while reading the database {:?}", query_res.err()));
+    };
+    if nr_overlapping != 0 {
+        return Html(format!(
+            "Error: worker {h} is already reserved during part of that time window",
+            h = encode_html_with_escape_codepoint(form.hostname.as_str())));
+    }
+
+    let query_res = sqlx::query_as::<_, RowHostname>(
+        "INSERT INTO reservations(hostname, device, reserved_by, note, starts_at, ends_at)
+        SELECT hostname, $2, $3, $4, $5, $6 FROM workers
+        WHERE hostname = $1
+        RETURNING hostname;",
+    )
+        .bind(&form.hostname)
+        .bind(device)
+        .bind(form.reserved_by.trim())
+        .bind(form.note.trim())
+        .bind(&starts_at)
+        .bind(&ends_at)
+        .fetch_optional(&mut *tx)
+        .await;
+
+    let Ok(query_res) = query_res else {
+        return Html(format!("Error occurred while updating the database {:?}", query_res.err()));
+    };
+    let Some(RowHostname { hostname }) = query_res else {
+        return Html(format!(
+            "Error, there is no worker with hostname {h}",
+            h = encode_html_with_escape_codepoint(form.hostname.as_str())));
+    };
+
+    tx.commit()
+        .await
+        .expect("error occurred when trying to commit a transaction");
+
+    let hostname = encode_html_with_escape_codepoint(hostname.as_str());
+    make_result_page(format!("Worker {hostname} reserved from {starts_at} to {ends_at} UTC").as_str())
+}
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct CancelReservationForm {
+    id: i64,
+}
+
+pub async fn cancel_reservation(State(db): State<DbPool>, form: Form<CancelReservationForm>) -> Html<String> {
+    let query_res = sqlx::query_as::<_, RowHostname>(
+        "DELETE FROM reservations
+        WHERE id = $1
+        RETURNING hostname;",
+    )
+        .bind(form.id)
+        .fetch_optional(&db)
+        .await;
+
+    let Ok(query_res) = query_res else {
+        return Html(format!("Error occurred while updating the database {:?}", query_res.err()));
+    };
+    let Some(RowHostname { hostname }) = query_res else {
+        return Html(format!("Error, there is no reservation with id {id}", id = form.id));
+    };
+
+    let hostname = encode_html_with_escape_codepoint(hostname.as_str());
+    make_result_page(format!("Reservation of worker {hostname} cancelled").as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_timestamp_of_the_accepted_forms() {
+        assert_eq!(parse_timestamp("2024-03-05 14:30").as_deref(), Some("2024-03-05 14:30:00"));
+        assert_eq!(parse_timestamp("2024-03-05T14:30").as_deref(), Some("2024-03-05 14:30:00"));
+        assert_eq!(parse_timestamp(" 2024-03-05T14:30:15 ").as_deref(), Some("2024-03-05 14:30:15"));
+    }
+
+    #[test]
+    fn parse_timestamp_rejects_malformed_ones() {
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("2024-03-05"), None);
+        assert_eq!(parse_timestamp("2024/03/05 14:30"), None);
+        assert_eq!(parse_timestamp("2024-03-05 14h30:00"), None);
+        assert_eq!(parse_timestamp("2024-03-05 14:30:00Z"), None);
+        assert_eq!(parse_timestamp("2024-03-05 1é:30"), None);
+    }
+
+    #[test]
+    fn parse_timestamp_rejects_out_of_range_fields() {
+        assert_eq!(parse_timestamp("2024-13-05 14:30"), None);
+        assert_eq!(parse_timestamp("2024-00-05 14:30"), None);
+        assert_eq!(parse_timestamp("2024-03-32 14:30"), None);
+        assert_eq!(parse_timestamp("2024-03-05 24:00"), None);
+        assert_eq!(parse_timestamp("2024-03-05 14:60"), None);
+        assert_eq!(parse_timestamp("2024-03-05 14:30:60"), None);
+    }
+
+    #[test]
+    fn parse_timestamp_checks_the_day_against_the_month() {
+        assert_eq!(parse_timestamp("2024-02-31 10:00"), None);
+        assert_eq!(parse_timestamp("2024-04-31 10:00"), None);
+        assert_eq!(parse_timestamp("2023-02-29 10:00"), None);
+        assert_eq!(parse_timestamp("1900-02-29 10:00"), None);
+        assert_eq!(parse_timestamp("2024-02-29 10:00").as_deref(), Some("2024-02-29 10:00:00"));
+        assert_eq!(parse_timestamp("2000-02-29 10:00").as_deref(), Some("2000-02-29 10:00:00"));
+        assert_eq!(parse_timestamp("2024-12-31 10:00").as_deref(), Some("2024-12-31 10:00:00"));
+    }
+}
@@ -0,0 +1,247 @@
+use std::time::Duration;
+use axum::extract::State;
+use axum::Form;
+use axum::response::Html;
+use serde::Deserialize;
+use sqlx::FromRow;
+use crate::db::{DbPool, sql_days_ago, sql_now};
+use crate::common::{DOCTYPE, get_head_with_title};
+
+// how often the background pruner enforces the retention policy
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Only the logs (output of tasks, compilations and test runs) and the artifacts of tasks get pruned.
+// The summaries, i.e. which tests ran, on which target, their status and return codes, and the
+// diagnostics found in the logs are kept forever.
+// A job's logs are kept as long as at least one of the enabled rules says so. With no rule
+// enabled, nothing is ever pruned.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub(crate) struct RetentionPolicy {
+    keep_logs_for_days: Option<i64>,
+    keep_logs_of_last_jobs: Option<i64>,
+}
+
+impl RetentionPolicy {
+    // accepts `--keep-logs-for-days N` and `--keep-logs-of-last-jobs N`
+    pub(crate) fn from_args(args: &[String]) -> Result<RetentionPolicy, String> {
+        let mut policy = RetentionPolicy::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.as_str() {
+                "--keep-logs-for-days" | "--keep-logs-of-last-jobs" => {
+                    let Some(value) = args.next() else {
+                        return Err(format!("Error: missing value after {arg}"));
+                    };
+                    let Ok(value) = value.parse::<i64>() else {
+                        return Err(format!("Error: expected a number after {arg}, got [{value}]"));
+                    };
+                    if value < 0 {
+                        return Err(format!("Error: {arg} can't be negative, got [{value}]"));
+                    }
+                    value
+                }
+                x => return Err(format!("Error: unknown argument [{x}]")),
+            };
+            match arg.as_str() {
+                "--keep-logs-for-days" => policy.keep_logs_for_days = Some(value),
+                _ => policy.keep_logs_of_last_jobs = Some(value),
+            }
+        }
+        Ok(policy)
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.keep_logs_for_days.is_some() || self.keep_logs_of_last_jobs.is_some()
+    }
+}
+
+#[derive(FromRow)]
+struct RowID {
+    id: i64,
+}
+
+// Returns the ids of the jobs whose logs got pruned
+pub(crate) async fn prune_old_logs(db: &DbPool, policy: &RetentionPolicy) -> Result<Vec<i64>, sqlx::Error> {
+    if !policy.is_enabled() {
+        return Ok(Vec::new());
+    }
+
+    let mut tx = db.begin().await?;
+
+    let pruned_jobs = sqlx::query_as::<_, RowID>(concat!(
+        "UPDATE jobs
+        SET logs_pruned_at = ", sql_now!(), "
+        WHERE (pinned = 0)
+          AND (logs_pruned_at IS NULL)
+          AND (status != 1) AND (status != 2) -- never prune a job which isn't finished
+          AND (($1 IS NULL) OR (added_at < ", sql_days_ago!("$1"), "))
+          AND (($2 IS NULL) OR (id NOT IN (
+              -- the last jobs of each branch, the ones without branch counting as one more branch
+              SELECT id FROM (SELECT id, ROW_NUMBER() OVER (PARTITION BY branch ORDER BY id DESC) AS nr_in_branch
+                              FROM jobs) AS jobs_by_branch
+              WHERE nr_in_branch <= $2)))
+        RETURNING id;"),
+    )
+        .bind(policy.keep_logs_for_days)
+        .bind(policy.keep_logs_of_last_jobs)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "UPDATE tasks SET output = ''
+        WHERE (output != '')
+          AND job_id IN (SELECT id FROM jobs WHERE logs_pruned_at IS NOT NULL);",
+    )
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "UPDATE test_run SET output = ''
+        WHERE (output != '')
+          AND task_id IN (SELECT tasks.id FROM tasks
+                          JOIN jobs ON jobs.id = tasks.job_id
+                          WHERE jobs.logs_pruned_at IS NOT NULL);",
+    )
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "DELETE FROM task_artifacts
+        WHERE task_id IN (SELECT tasks.id FROM tasks
+                          JOIN jobs ON jobs.id = tasks.job_id
+                          WHERE jobs.logs_pruned_at IS NOT NULL);",
+    )
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "UPDATE compile_output SET output = NULL
+        WHERE (output IS NOT NULL)
+          AND test_setup_id IN (SELECT test_setup.id FROM test_setup
+                                JOIN tasks ON tasks.id = test_setup.task_id
+                                JOIN jobs ON jobs.id = tasks.job_id
+                                WHERE jobs.logs_pruned_at IS NOT NULL);",
+    )
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(pruned_jobs.into_iter().map(|x| x.id).collect())
+}
+
+// Gives back the pages freed by the pruning to the file system. A full vacuum rewrites the whole
+// database and is also what switches it to the incremental mode, since the database is in WAL
+// mode right from its creation and the auto_vacuum pragma alone is then ignored.
+#[cfg(not(feature = "postgres"))]
+pub(crate) async fn vacuum(db: &DbPool, full: bool) -> Result<(), sqlx::Error> {
+    if full {
+        // the pragma only applies to the connection it is executed on
+        let mut connection = db.acquire().await?;
+        sqlx::query("PRAGMA auto_vacuum = INCREMENTAL;").execute(&mut *connection).await?;
+        sqlx::query("VACUUM;").execute(&mut *connection).await?;
+    } else {
+        sqlx::query("PRAGMA incremental_vacuum;").execute(db).await?;
+    }
+    Ok(())
+}
+
+// Postgres' autovacuum already reuses the space freed by the pruning. A full vacuum also gives it
+// back to the file system, but locks the tables while rewriting them.
+#[cfg(feature = "postgres")]
+pub(crate) async fn vacuum(db: &DbPool, full: bool) -> Result<(), sqlx::Error> {
+    let statement = if full { "VACUUM FULL;" } else { "VACUUM;" };
+    sqlx::query(statement).execute(db).await?;
+    Ok(())
+}
+
+pub(crate) async fn run_background_pruner(db: DbPool, policy: RetentionPolicy) {
+    if !policy.is_enabled() {
+        println!("No retention policy set, logs are kept forever");
+        return;
+    }
+
+    println!("Enforcing retention policy {policy:?}");
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match prune_old_logs(&db, &policy).await {
+            Ok(pruned_jobs) if pruned_jobs.is_empty() => (),
+            Ok(pruned_jobs) => {
+                println!("Pruned logs of jobs {pruned_jobs:?}");
+                if let Err(e) = vacuum(&db, false).await {
+                    println!("Error: incremental vacuum failed: {e:?}");
+                }
+            }
+            Err(e) => println!("Error: failed to prune old logs: {e:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct PinJobForm {
+    job_id: i64,
+    pinned: bool,
+}
+
+pub async fn pin_job(State(db): State<DbPool>, form: Form<PinJobForm>) -> Html<String> {
+    let query_res = sqlx::query_as::<_, RowID>(
+        "UPDATE jobs SET pinned = $2
+        WHERE id = $1
+        RETURNING id;",
+    )
+        .bind(form.job_id)
+        .bind(form.pinned as i64)
+        .fetch_optional(&db)
+        .await;
+
+    let Ok(query_res) = query_res else {
+        return Html(format!(
+            "Error occurred while updating the database {:?}",
+            query_res.err()
+        ));
+    };
+
+    let Some(RowID { id: job_id }) = query_res else {
+        return Html(format!("Error, there is no job with id {x}", x = form.job_id));
+    };
+
+    let action = if form.pinned { "pinned" } else { "unpinned" };
+    let html_head = get_head_with_title(format!("Job {job_id} {action}").as_str());
+    Html(format!(
+        "{DOCTYPE}<html lang=\"en-GB\">{html_head}<body>
+<h1 class=\"post-title\">Job {job_id} {action}</h1>
+<a href=\"/build/{job_id}\" class=\"link_button\">View build details</a>
+</body></html>"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| String::from(*x)).collect()
+    }
+
+    #[test]
+    fn from_args_without_rule_prunes_nothing() {
+        let policy = RetentionPolicy::from_args(&[]).unwrap();
+        assert_eq!(policy, RetentionPolicy::default());
+        assert!(!policy.is_enabled());
+    }
+
+    #[test]
+    fn from_args_with_both_rules() {
+        let policy = RetentionPolicy::from_args(&to_args(&["--keep-logs-of-last-jobs", "20", "--keep-logs-for-days", "0"])).unwrap();
+        assert_eq!(policy, RetentionPolicy { keep_logs_for_days: Some(0), keep_logs_of_last_jobs: Some(20) });
+        assert!(policy.is_enabled());
+    }
+
+    #[test]
+    fn from_args_rejects_bad_values() {
+        assert!(RetentionPolicy::from_args(&to_args(&["--keep-logs-for-days"])).is_err());
+        assert!(RetentionPolicy::from_args(&to_args(&["--keep-logs-for-days", "ten"])).is_err());
+        assert!(RetentionPolicy::from_args(&to_args(&["--keep-logs-of-last-jobs", "-1"])).is_err());
+        assert!(RetentionPolicy::from_args(&to_args(&["--keep-everything"])).is_err());
+    }
+}
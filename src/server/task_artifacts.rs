@@ -0,0 +1,114 @@
+use axum::extract::{Path, State};
+use axum::Form;
+use axum::http::{header, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use serde::Deserialize;
+use sqlx::FromRow;
+use crate::common::get_status_of_database_error;
+use crate::db::DbPool;
+
+// Artifacts are text files made by a task, e.g. the patch fixing the formatting of the code. They
+// are sent by the workers, and linked from the page of the build.
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct AddTaskArtifactForm {
+    task_id: i64,
+    name: String,
+    content: String,
+}
+
+#[derive(FromRow)]
+struct ArtifactName {
+    name: String,
+}
+
+#[derive(FromRow)]
+struct ArtifactContent {
+    content: String,
+}
+
+// the name ends up in urls and in the name of the downloaded file
+fn is_valid_artifact_name(name: &str) -> bool {
+    !name.is_empty() && (name.len() <= 64) && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+pub(crate) async fn add_task_artifact(State(db): State<DbPool>, form: Form<AddTaskArtifactForm>) -> (StatusCode, Html<String>) {
+    if !is_valid_artifact_name(form.name.as_str()) {
+        return (StatusCode::BAD_REQUEST,
+                Html(format!("Error: invalid artifact name [{}]. Only letters, digits, '.', '_' and '-' are allowed", form.name)));
+    }
+
+    // sending it again replaces it
+    let res = sqlx::query(
+        "INSERT INTO task_artifacts(task_id, name, content)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (task_id, name) DO UPDATE SET content = excluded.content;",
+    )
+        .bind(form.task_id)
+        .bind(&form.name)
+        .bind(&form.content)
+        .execute(&db)
+        .await;
+
+    match res {
+        Ok(_) => (StatusCode::OK, Html(String::from("OK"))),
+        Err(e) => (get_status_of_database_error(&e),
+                   Html(format!("Error: failed to add artifact {} to task {}: Err={e:?}", form.name, form.task_id))),
+    }
+}
+
+// Links to download the artifacts of the task, as shown on the page of the build
+pub(crate) async fn get_artifact_links(db: &DbPool, build_id: i64, task_id: i64) -> String {
+    let names = sqlx::query_as::<_, ArtifactName>(
+        "SELECT name FROM task_artifacts
+        WHERE task_id = $1
+        ORDER BY name;",
+    )
+        .bind(task_id)
+        .fetch_all(db)
+        .await
+        .unwrap_or_default();
+
+    if names.is_empty() {
+        return String::from("");
+    }
+
+    let links = names.iter()
+        .map(|ArtifactName { name }| format!("<a href=\"/build/{build_id}/task/{task_id}/artifact/{name}\" title=\"artifact\">{name}</a>"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("artifacts: {links}<br>")
+}
+
+pub async fn get_task_artifact(
+    State(db): State<DbPool>,
+    Path((job_id, task_id, name)): Path<(i64, i64, String)>,
+) -> Response {
+    let query_res = sqlx::query_as::<_, ArtifactContent>(
+        "SELECT task_artifacts.content FROM task_artifacts
+        JOIN tasks ON tasks.id = task_artifacts.task_id
+        WHERE (task_artifacts.task_id = $1) AND (tasks.job_id = $2) AND (task_artifacts.name = $3);",
+    )
+        .bind(task_id)
+        .bind(job_id)
+        .bind(&name)
+        .fetch_optional(&db)
+        .await;
+
+    let Ok(query_res) = query_res else {
+        return (StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error occurred while reading the database {:?}\n", query_res.err())).into_response();
+    };
+
+    let Some(ArtifactContent { content }) = query_res else {
+        return (StatusCode::NOT_FOUND,
+                format!("Error, there is no artifact named [{name}] in task {task_id} of job {job_id}\n")).into_response();
+    };
+
+    // the name got checked when the artifact was added
+    (StatusCode::OK,
+     [(header::CONTENT_TYPE, String::from("text/plain; charset=utf-8")),
+         (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{name}\""))],
+     content).into_response()
+}
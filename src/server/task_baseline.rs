@@ -0,0 +1,76 @@
+use sqlx::FromRow;
+use crate::db::DbPool;
+
+// The findings and the coverage of a task get compared with the ones of the same task in a previous
+// build of a base branch. The worker sends the last commits of that branch, and the latest of them
+// with a fitting task gives the baseline.
+
+// what the baseline is compared on, which tells the tasks that can be one
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum BaselineKind {
+    // successful tasks which kept all their diagnostics
+    Diagnostics = 1,
+    // tasks which sent their coverage
+    Coverage = 2,
+}
+
+#[derive(FromRow)]
+pub(crate) struct TaskToCompare {
+    pub job_id: i64,
+    pub task_type: i64,
+    // the one of the test setup the task got executed with, none for the tasks not running tests
+    pub compiler_id: Option<i64>,
+    pub nr_dropped_diagnostics: i64,
+}
+
+#[derive(FromRow)]
+pub(crate) struct BaselineTask {
+    pub task_id: i64,
+    pub job_id: i64,
+    pub commit_id: String,
+}
+
+pub(crate) async fn get_task_to_compare(db: &DbPool, task_id: i64) -> Result<Option<TaskToCompare>, sqlx::Error> {
+    // a test task has a setup per compiler of its job, only the one it got executed with counts
+    sqlx::query_as::<_, TaskToCompare>(
+        "SELECT tasks.job_id, tasks.task_type, test_setup.compiler_id, tasks.nr_dropped_diagnostics FROM tasks
+        LEFT JOIN test_setup ON test_setup.id = tasks.test_setup_id
+        WHERE tasks.id = $1;",
+    )
+        .bind(task_id)
+        .fetch_optional(db)
+        .await
+}
+
+// The latest task of the same kind, executed with the same compiler, among the given commits. Only
+// tasks of other jobs count, a job isn't its own baseline.
+pub(crate) async fn find_baseline_task(db: &DbPool, task: &TaskToCompare, commits: &[&str], kind: BaselineKind)
+                                       -> Result<Option<BaselineTask>, sqlx::Error> {
+    for commit in commits {
+        // jobs can be added with abbreviated hashes
+        let baseline = sqlx::query_as::<_, BaselineTask>(
+            "SELECT tasks.id AS task_id, tasks.job_id, jobs.commit_id FROM tasks
+            JOIN jobs ON jobs.id = tasks.job_id
+            LEFT JOIN test_setup ON test_setup.id = tasks.test_setup_id
+            WHERE (tasks.task_type = $1) AND (tasks.job_id != $2)
+              AND (COALESCE(test_setup.compiler_id, 0) = $3)
+              AND ($4 LIKE jobs.commit_id || '%')
+              AND (    (($5 = 1) AND (tasks.status = 3) AND (tasks.nr_dropped_diagnostics = 0)) -- diagnostics
+                    OR (($5 = 2) AND EXISTS (SELECT 1 FROM task_coverage WHERE task_coverage.task_id = tasks.id)) -- coverage
+                  )
+            ORDER BY tasks.id DESC
+            LIMIT 1;",
+        )
+            .bind(task.task_type)
+            .bind(task.job_id)
+            .bind(task.compiler_id.unwrap_or(0))
+            .bind(commit)
+            .bind(kind as i64)
+            .fetch_optional(db)
+            .await?;
+        if baseline.is_some() {
+            return Ok(baseline);
+        }
+    }
+    Ok(None)
+}
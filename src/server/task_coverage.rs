@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+use axum::extract::State;
+use axum::Form;
+use axum::http::StatusCode;
+use axum::response::Html;
+use serde::Deserialize;
+use sqlx::FromRow;
+use crate::common::{encode_html_with_escape_codepoint, get_status_of_database_error, is_valid_git_hash, TaskType};
+use crate::db::DbPool;
+use crate::task_baseline::{BaselineKind, BaselineTask, find_baseline_task, get_task_to_compare};
+
+// Test tasks of jobs asking for it are built with coverage instrumentation. Once the tests ran, the
+// worker summarizes with lcov what they exercised, and sends the number of lines and branches found
+// and hit in each file. The coverage is compared with the one of the previous build of the base
+// branch of the worker, the latest one among the commits it sends along.
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct AddTaskCoverageForm {
+    task_id: i64,
+    // one file per line: file, lines found, lines hit, branches found and branches hit separated by tabs
+    coverage: String,
+    // where the baseline is picked from, as for the comparison of the findings
+    #[serde(default)]
+    baseline_commits: String,
+}
+
+#[derive(FromRow)]
+struct FileCoverage {
+    file: String,
+    lines_found: i64,
+    lines_hit: i64,
+    branches_found: i64,
+    branches_hit: i64,
+}
+
+#[derive(FromRow)]
+struct JobCoverage {
+    job_id: i64,
+    lines_found: i64,
+    lines_hit: i64,
+    branches_found: i64,
+    branches_hit: i64,
+}
+
+fn parse_file_coverage(line: &str) -> Result<FileCoverage, String> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    let [file, counts @ ..] = fields.as_slice() else {
+        return Err(String::from("empty line"));
+    };
+    let counts = counts.iter()
+        .map(|x| x.parse::<i64>().ok().filter(|x| *x >= 0))
+        .collect::<Option<Vec<_>>>();
+    let Some([lines_found, lines_hit, branches_found, branches_hit]) = counts.as_deref() else {
+        return Err(format!("expected a file and 4 counts separated by tabs, got [{line}]"));
+    };
+    if (lines_hit > lines_found) || (branches_hit > branches_found) {
+        return Err(format!("more lines or branches hit than found in [{line}]"));
+    }
+    Ok(FileCoverage {
+        file: String::from(*file),
+        lines_found: *lines_found,
+        lines_hit: *lines_hit,
+        branches_found: *branches_found,
+        branches_hit: *branches_hit,
+    })
+}
+
+// Sending the coverage of a task again replaces it, and picks its baseline again
+pub(crate) async fn add_task_coverage(State(db): State<DbPool>, form: Form<AddTaskCoverageForm>) -> (StatusCode, Html<String>) {
+    let coverage = form.coverage
+        .lines()
+        .filter(|x| !x.is_empty())
+        .map(parse_file_coverage)
+        .collect::<Result<Vec<_>, _>>();
+    let Ok(coverage) = coverage else {
+        return (StatusCode::BAD_REQUEST,
+                Html(format!("Error: invalid coverage for task {}: {}", form.task_id, coverage.err().unwrap())));
+    };
+    let commits = form.baseline_commits
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    if let Some(commit) = commits.iter().find(|x| !is_valid_git_hash(x)) {
+        return (StatusCode::BAD_REQUEST, Html(format!("Error: invalid commit [{commit}] in the baseline commits")));
+    }
+
+    let task = get_task_to_compare(&db, form.task_id).await;
+    let task = match task {
+        Ok(Some(task)) if task.task_type == TaskType::Tests as i64 => task,
+        Ok(_) => return (StatusCode::BAD_REQUEST, Html(format!("Error: there is no test task {}", form.task_id))),
+        Err(e) => return (get_status_of_database_error(&e),
+                          Html(format!("Error: failed to find test task {}: {e:?}", form.task_id))),
+    };
+    let baseline = find_baseline_task(&db, &task, commits.as_slice(), BaselineKind::Coverage).await;
+    let baseline = match baseline {
+        Ok(baseline) => baseline,
+        Err(e) => return (get_status_of_database_error(&e),
+                          Html(format!("Error: failed to find the coverage baseline of task {}: {e:?}", form.task_id))),
+    };
+
+    let tx = db.begin().await;
+    let Ok(mut tx) = tx else {
+        let e = tx.err().unwrap();
+        return (get_status_of_database_error(&e), Html(format!("Error when starting a sql transaction: {e:?}")));
+    };
+
+    let res = sqlx::query("DELETE FROM task_coverage WHERE task_id = $1;")
+        .bind(form.task_id)
+        .execute(&mut *tx)
+        .await;
+    if let Err(e) = res {
+        return (get_status_of_database_error(&e),
+                Html(format!("Error: failed to remove the previous coverage of task {}: Err={e:?}", form.task_id)));
+    }
+
+    for file_coverage in &coverage {
+        let res = sqlx::query(
+            "INSERT INTO task_coverage(task_id, file, lines_found, lines_hit, branches_found, branches_hit)
+            VALUES ($1, $2, $3, $4, $5, $6);",
+        )
+            .bind(form.task_id)
+            .bind(&file_coverage.file)
+            .bind(file_coverage.lines_found)
+            .bind(file_coverage.lines_hit)
+            .bind(file_coverage.branches_found)
+            .bind(file_coverage.branches_hit)
+            .execute(&mut *tx)
+            .await;
+        if let Err(e) = res {
+            return (get_status_of_database_error(&e),
+                    Html(format!("Error: failed to add the coverage of file {} to task {}: Err={e:?}", file_coverage.file, form.task_id)));
+        }
+    }
+
+    let res = sqlx::query("UPDATE tasks SET coverage_baseline_task_id = $2 WHERE id = $1;")
+        .bind(form.task_id)
+        .bind(baseline.as_ref().map(|x| x.task_id))
+        .execute(&mut *tx)
+        .await;
+    if let Err(e) = res {
+        return (get_status_of_database_error(&e),
+                Html(format!("Error: failed to set the coverage baseline of task {}: Err={e:?}", form.task_id)));
+    }
+
+    match tx.commit().await {
+        Ok(_) => (StatusCode::OK, Html(String::from("OK"))),
+        Err(e) => (get_status_of_database_error(&e),
+                   Html(format!("Error: failed to add coverage to task {}: Err={e:?}", form.task_id))),
+    }
+}
+
+async fn get_coverage_of_task(db: &DbPool, task_id: i64) -> Result<Vec<FileCoverage>, sqlx::Error> {
+    sqlx::query_as::<_, FileCoverage>(
+        "SELECT file, lines_found, lines_hit, branches_found, branches_hit FROM task_coverage
+        WHERE task_id = $1
+        ORDER BY file;",
+    )
+        .bind(task_id)
+        .fetch_all(db)
+        .await
+}
+
+fn get_percentage(hit: i64, found: i64) -> Option<f64> {
+    (found != 0).then(|| (hit as f64) * 100.0 / (found as f64))
+}
+
+fn format_percentage(hit: i64, found: i64) -> String {
+    match get_percentage(hit, found) {
+        None => String::from("-"),
+        Some(x) => format!("{x:.1}% ({hit}/{found})"),
+    }
+}
+
+// in percentage points, the files the baseline didn't have are new
+fn format_change(percentage: Option<f64>, baseline_percentage: Option<Option<f64>>) -> String {
+    match (percentage, baseline_percentage) {
+        (_, None) => String::from("new"),
+        (Some(x), Some(Some(y))) => {
+            let change = format!("{:+.1}", x - y);
+            match change.as_str() {
+                "+0.0" | "-0.0" => String::from("="),
+                _ => change,
+            }
+        }
+        _ => String::from("-"),
+    }
+}
+
+fn get_totals(coverage: &[FileCoverage]) -> FileCoverage {
+    FileCoverage {
+        file: String::from("total"),
+        lines_found: coverage.iter().map(|x| x.lines_found).sum(),
+        lines_hit: coverage.iter().map(|x| x.lines_hit).sum(),
+        branches_found: coverage.iter().map(|x| x.branches_found).sum(),
+        branches_hit: coverage.iter().map(|x| x.branches_hit).sum(),
+    }
+}
+
+fn format_row(file_coverage: &FileCoverage, baseline: Option<Option<&FileCoverage>>) -> String {
+    let FileCoverage { file, lines_found, lines_hit, branches_found, branches_hit } = file_coverage;
+    let changes_str = match baseline {
+        None => String::from(""),
+        Some(baseline) => {
+            let lines_change = format_change(get_percentage(*lines_hit, *lines_found),
+                                             baseline.map(|x| get_percentage(x.lines_hit, x.lines_found)));
+            let branches_change = format_change(get_percentage(*branches_hit, *branches_found),
+                                                baseline.map(|x| get_percentage(x.branches_hit, x.branches_found)));
+            format!("<td>{lines_change}</td><td>{branches_change}</td>")
+        }
+    };
+    format!("<tr><td>{file}</td><td>{lines}</td><td>{branches}</td>{changes_str}</tr>\n",
+            file = encode_html_with_escape_codepoint(file.as_str()),
+            lines = format_percentage(*lines_hit, *lines_found),
+            branches = format_percentage(*branches_hit, *branches_found))
+}
+
+// The coverage of each file of the task as shown on the page of the build, along with how it changed
+// since the baseline
+pub(crate) async fn get_coverage_summary(db: &DbPool, task_id: i64) -> String {
+    let coverage = get_coverage_of_task(db, task_id).await.unwrap_or_default();
+    if coverage.is_empty() {
+        return String::from("");
+    }
+
+    let baseline = sqlx::query_as::<_, BaselineTask>(
+        "SELECT baseline.id AS task_id, baseline.job_id, jobs.commit_id FROM tasks
+        JOIN tasks AS baseline ON baseline.id = tasks.coverage_baseline_task_id
+        JOIN jobs ON jobs.id = baseline.job_id
+        WHERE tasks.id = $1;",
+    )
+        .bind(task_id)
+        .fetch_optional(db)
+        .await
+        .ok()
+        .flatten();
+    let baseline_coverage = match &baseline {
+        Some(baseline) => get_coverage_of_task(db, baseline.task_id).await.ok(),
+        None => None,
+    };
+    let baseline_by_file = baseline_coverage.as_ref().map(|x| x.iter()
+        .map(|x| (x.file.as_str(), x))
+        .collect::<HashMap<_, _>>());
+
+    let totals = get_totals(coverage.as_slice());
+    let baseline_totals = baseline_coverage.as_deref().map(get_totals);
+
+    let rows_str = coverage.iter()
+        .map(|x| format_row(x, baseline_by_file.as_ref().map(|b| b.get(x.file.as_str()).copied())))
+        .collect::<String>();
+    let totals_str = format_row(&totals, baseline_totals.as_ref().map(Some));
+
+    let (baseline_str, changes_header) = match (&baseline, &baseline_totals) {
+        (Some(BaselineTask { task_id: baseline_task_id, job_id: baseline_job_id, commit_id }), Some(baseline_totals)) => {
+            let commit_id = encode_html_with_escape_codepoint(commit_id.as_str());
+            (format!("<br>
+change since the baseline, in percentage points: lines {}, branches {}<br>
+baseline: task {baseline_task_id} of <a href=\"/build/{baseline_job_id}\">build {baseline_job_id}</a>, commit {commit_id}",
+                     format_change(get_percentage(totals.lines_hit, totals.lines_found),
+                                   Some(get_percentage(baseline_totals.lines_hit, baseline_totals.lines_found))),
+                     format_change(get_percentage(totals.branches_hit, totals.branches_found),
+                                   Some(get_percentage(baseline_totals.branches_hit, baseline_totals.branches_found)))),
+             "<td>lines change (points)</td><td>branches change (points)</td>")
+        }
+        _ => (String::from(""), ""),
+    };
+
+    format!("<blockquote><details><summary>coverage: lines {lines}, branches {branches}</summary>
+{nr_files} files{baseline_str}
+<table title=\"coverage_by_file\">
+<tr><td>file</td><td>lines</td><td>branches</td>{changes_header}</tr>
+{rows_str}{totals_str}</table></details></blockquote>",
+            lines = format_percentage(totals.lines_hit, totals.lines_found),
+            branches = format_percentage(totals.branches_hit, totals.branches_found),
+            nr_files = coverage.len())
+}
+
+// Total coverage of the jobs in the given range of ids, as shown in the list of builds. Only the
+// test task running the tests of a job measures its coverage.
+pub(crate) async fn get_coverage_totals(db: &DbPool, min_job_id: i64, max_job_id: i64) -> BTreeMap<i64, String> {
+    let totals = sqlx::query_as::<_, JobCoverage>(
+        "SELECT tasks.job_id,
+                CAST(SUM(lines_found) AS BIGINT) AS lines_found,
+                CAST(SUM(lines_hit) AS BIGINT) AS lines_hit,
+                CAST(SUM(branches_found) AS BIGINT) AS branches_found,
+                CAST(SUM(branches_hit) AS BIGINT) AS branches_hit
+        FROM task_coverage
+        JOIN tasks ON tasks.id = task_coverage.task_id
+        WHERE tasks.job_id BETWEEN $1 AND $2
+        GROUP BY tasks.job_id;",
+    )
+        .bind(min_job_id)
+        .bind(max_job_id)
+        .fetch_all(db)
+        .await
+        .unwrap_or_default();
+
+    totals.into_iter()
+        .map(|x| {
+            let lines = get_percentage(x.lines_hit, x.lines_found).map_or(String::from("-"), |x| format!("{x:.1}%"));
+            let branches = get_percentage(x.branches_hit, x.branches_found).map_or(String::from("-"), |x| format!("{x:.1}%"));
+            (x.job_id, format!("lines {lines}, branches {branches}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_file_coverage_of_a_line_sent_by_a_worker() {
+        let coverage = parse_file_coverage("src/main.c\t10\t8\t4\t0").unwrap();
+        assert_eq!(coverage.file, "src/main.c");
+        assert_eq!((coverage.lines_found, coverage.lines_hit, coverage.branches_found, coverage.branches_hit), (10, 8, 4, 0));
+    }
+
+    #[test]
+    fn parse_file_coverage_rejects_malformed_lines() {
+        assert!(parse_file_coverage("src/main.c\t10\t8\t4").is_err());
+        assert!(parse_file_coverage("src/main.c\t10\t8\t4\t2\t1").is_err());
+        assert!(parse_file_coverage("src/main.c\tten\t8\t4\t2").is_err());
+        assert!(parse_file_coverage("src/main.c\t10\t-1\t4\t2").is_err());
+        // more hit than found
+        assert!(parse_file_coverage("src/main.c\t8\t10\t4\t2").is_err());
+        assert!(parse_file_coverage("src/main.c\t10\t8\t2\t4").is_err());
+    }
+
+    #[test]
+    fn percentages_and_their_changes() {
+        assert_eq!(format_percentage(1, 3), "33.3% (1/3)");
+        assert_eq!(format_percentage(0, 0), "-");
+        assert_eq!(format_change(Some(50.0), Some(Some(25.0))), "+25.0");
+        assert_eq!(format_change(Some(50.0), Some(Some(50.01))), "=");
+        assert_eq!(format_change(Some(50.0), None), "new");
+        assert_eq!(format_change(None, Some(Some(50.0))), "-");
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{BTreeMap, HashMap};
+use axum::extract::{Path, State};
+use axum::Form;
+use axum::http::{header, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use serde::Deserialize;
+use sqlx::FromRow;
+use crate::common::{encode_html_with_escape_codepoint, get_status_of_database_error, is_valid_git_hash};
+use crate::db::DbPool;
+use crate::task_baseline::{BaselineKind, BaselineTask, find_baseline_task, get_task_to_compare};
+
+// Diagnostics are the warnings and errors of the compilers, clang-tidy and the static analyser. The
+// workers find them in the output of the commands, and send them once the command is over. They are
+// shown on the page of the build, and listed as tab separated values for scripts.
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct AddTaskDiagnosticsForm {
+    task_id: i64,
+    // one diagnostic per line: file, line, column, severity, check and message separated by tabs
+    diagnostics: String,
+    // the ones past the maximum the worker sends per task
+    #[serde(default)]
+    nr_dropped: i64,
+}
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct CompareTaskDiagnosticsForm {
+    task_id: i64,
+    // commits of the base branch, the most recent first, one per line
+    baseline_commits: String,
+}
+
+#[derive(FromRow)]
+struct DiagnosticRow {
+    task_id: i64,
+    file: String,
+    line: i64,
+    column_nr: Option<i64>,
+    severity: String,
+    check_name: Option<String>,
+    message: String,
+}
+
+impl DiagnosticRow {
+    fn get_location(&self) -> String {
+        match self.column_nr {
+            None => format!("{}:{}", self.file, self.line),
+            Some(column) => format!("{}:{}:{column}", self.file, self.line),
+        }
+    }
+
+    fn get_check_name(&self) -> &str {
+        self.check_name.as_deref().unwrap_or("(no check name)")
+    }
+
+    fn to_text(&self) -> String {
+        match &self.check_name {
+            None => format!("{}: {}: {}", self.get_location(), self.severity, self.message),
+            Some(check) => format!("{}: {}: {} [{check}]", self.get_location(), self.severity, self.message),
+        }
+    }
+}
+
+fn parse_diagnostic(task_id: i64, line: &str) -> Result<DiagnosticRow, String> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    let [file, line_nr, column, severity, check, message] = fields.as_slice() else {
+        return Err(format!("expected 6 fields separated by tabs, got {}", fields.len()));
+    };
+    let Ok(line_nr) = line_nr.parse::<i64>() else {
+        return Err(format!("invalid line number [{line_nr}]"));
+    };
+    let column_nr = match *column {
+        "" => None,
+        column => match column.parse::<i64>() {
+            Ok(x) => Some(x),
+            Err(_) => return Err(format!("invalid column [{column}]")),
+        },
+    };
+    if !["warning", "error"].contains(severity) {
+        return Err(format!("invalid severity [{severity}]"));
+    }
+    Ok(DiagnosticRow {
+        task_id,
+        file: String::from(*file),
+        line: line_nr,
+        column_nr,
+        severity: String::from(*severity),
+        check_name: (!check.is_empty()).then(|| String::from(*check)),
+        message: String::from(*message),
+    })
+}
+
+pub(crate) async fn add_task_diagnostics(State(db): State<DbPool>, form: Form<AddTaskDiagnosticsForm>) -> (StatusCode, Html<String>) {
+    let diagnostics = form.diagnostics
+        .lines()
+        .filter(|x| !x.is_empty())
+        .map(|x| parse_diagnostic(form.task_id, x))
+        .collect::<Result<Vec<_>, _>>();
+    let Ok(diagnostics) = diagnostics else {
+        return (StatusCode::BAD_REQUEST,
+                Html(format!("Error: invalid diagnostics for task {}: {}", form.task_id, diagnostics.err().unwrap())));
+    };
+
+    let tx = db.begin().await;
+    let Ok(mut tx) = tx else {
+        let e = tx.err().unwrap();
+        return (get_status_of_database_error(&e), Html(format!("Error when starting a sql transaction: {e:?}")));
+    };
+
+    // sending them again replaces them
+    let res = sqlx::query("DELETE FROM task_diagnostics WHERE task_id = $1;")
+        .bind(form.task_id)
+        .execute(&mut *tx)
+        .await;
+    if let Err(e) = res {
+        return (get_status_of_database_error(&e),
+                Html(format!("Error: failed to remove the previous diagnostics of task {}: Err={e:?}", form.task_id)));
+    }
+
+    let res = sqlx::query("UPDATE tasks SET nr_dropped_diagnostics = $2 WHERE id = $1;")
+        .bind(form.task_id)
+        .bind(form.nr_dropped)
+        .execute(&mut *tx)
+        .await;
+    if let Err(e) = res {
+        return (get_status_of_database_error(&e),
+                Html(format!("Error: failed to set the number of dropped diagnostics of task {}: Err={e:?}", form.task_id)));
+    }
+
+    for diagnostic in &diagnostics {
+        let res = sqlx::query(
+            "INSERT INTO task_diagnostics(task_id, file, line, column_nr, severity, check_name, message)
+            VALUES ($1, $2, $3, $4, $5, $6, $7);",
+        )
+            .bind(diagnostic.task_id)
+            .bind(&diagnostic.file)
+            .bind(diagnostic.line)
+            .bind(diagnostic.column_nr)
+            .bind(&diagnostic.severity)
+            .bind(&diagnostic.check_name)
+            .bind(&diagnostic.message)
+            .execute(&mut *tx)
+            .await;
+        if let Err(e) = res {
+            return (get_status_of_database_error(&e),
+                    Html(format!("Error: failed to add diagnostics to task {}: Err={e:?}", form.task_id)));
+        }
+    }
+
+    match tx.commit().await {
+        Ok(_) => (StatusCode::OK, Html(String::from("OK"))),
+        Err(e) => (get_status_of_database_error(&e),
+                   Html(format!("Error: failed to add diagnostics to task {}: Err={e:?}", form.task_id))),
+    }
+}
+
+async fn get_diagnostics_of_task(db: &DbPool, task_id: i64) -> Result<Vec<DiagnosticRow>, sqlx::Error> {
+    sqlx::query_as::<_, DiagnosticRow>(
+        "SELECT task_id, file, line, column_nr, severity, check_name, message FROM task_diagnostics
+        WHERE task_id = $1
+        ORDER BY file, line, column_nr, id;",
+    )
+        .bind(task_id)
+        .fetch_all(db)
+        .await
+}
+
+fn format_group<'a>(name: &str, diagnostics: &[&'a DiagnosticRow], to_line: impl Fn(&'a DiagnosticRow) -> String) -> String {
+    let lines = diagnostics.iter()
+        .map(|x| encode_html_with_escape_codepoint(to_line(x).as_str()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("<details><summary>{name} ({n})</summary><pre>{lines}</pre></details>",
+            name = encode_html_with_escape_codepoint(name),
+            n = diagnostics.len())
+}
+
+// Findings are matched on everything but their position, which moves whenever lines get added
+// above them. Findings appearing more often than in the baseline are new, and the ones appearing
+// less often got fixed.
+fn compare_diagnostics<'a>(diagnostics: &'a [DiagnosticRow], baseline: &'a [DiagnosticRow]) -> (Vec<&'a DiagnosticRow>, Vec<&'a DiagnosticRow>) {
+    fn get_key(x: &DiagnosticRow) -> (&str, &str, Option<&str>, &str) {
+        (x.file.as_str(), x.severity.as_str(), x.check_name.as_deref(), x.message.as_str())
+    }
+    fn get_extra<'a>(diagnostics: &'a [DiagnosticRow], others: &'a [DiagnosticRow]) -> Vec<&'a DiagnosticRow> {
+        let mut counts = HashMap::<_, usize>::new();
+        for x in others {
+            *counts.entry(get_key(x)).or_default() += 1;
+        }
+        diagnostics.iter()
+            .filter(|x| match counts.get_mut(&get_key(x)) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            })
+            .collect()
+    }
+    (get_extra(diagnostics, baseline), get_extra(baseline, diagnostics))
+}
+
+// Called by the workers once the diagnostics of a task are sent. Tells which baseline got used,
+// and lists the new findings.
+pub(crate) async fn compare_task_diagnostics(State(db): State<DbPool>, form: Form<CompareTaskDiagnosticsForm>) -> String {
+    let commits = form.baseline_commits
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    if let Some(commit) = commits.iter().find(|x| !is_valid_git_hash(x)) {
+        return format!("Error: invalid commit [{commit}] in the baseline commits");
+    }
+
+    let task = get_task_to_compare(&db, form.task_id).await;
+    let Ok(Some(task)) = task else {
+        return format!("Error: failed to find task {}: {:?}", form.task_id, task.err());
+    };
+    // which diagnostics are left depends on the order of the output of the commands
+    if task.nr_dropped_diagnostics != 0 {
+        return format!("Baseline: not compared, {} diagnostics of the task got dropped\n", task.nr_dropped_diagnostics);
+    }
+
+    let baseline = find_baseline_task(&db, &task, commits.as_slice(), BaselineKind::Diagnostics).await;
+    let Ok(baseline) = baseline else {
+        return format!("Error: failed to find the baseline of task {}: {:?}", form.task_id, baseline.err().unwrap());
+    };
+    let Some(baseline) = baseline else {
+        return String::from("Baseline: none\n");
+    };
+
+    let res = sqlx::query("UPDATE tasks SET baseline_task_id = $2 WHERE id = $1;")
+        .bind(form.task_id)
+        .bind(baseline.task_id)
+        .execute(&db)
+        .await;
+    if let Err(e) = res {
+        return format!("Error: failed to set the baseline of task {}: {e:?}", form.task_id);
+    }
+
+    let diagnostics = get_diagnostics_of_task(&db, form.task_id).await;
+    let baseline_diagnostics = get_diagnostics_of_task(&db, baseline.task_id).await;
+    let (Ok(diagnostics), Ok(baseline_diagnostics)) = (diagnostics, baseline_diagnostics) else {
+        return format!("Error: failed to get the diagnostics of task {} or of its baseline", form.task_id);
+    };
+    let (new_findings, fixed_findings) = compare_diagnostics(diagnostics.as_slice(), baseline_diagnostics.as_slice());
+    let new_findings_str = new_findings.iter()
+        .map(|x| format!("{}\n", x.to_text()))
+        .collect::<String>();
+    format!("Baseline: task {} of job {} on commit {}
+New findings: {}
+Fixed findings: {}
+{new_findings_str}", baseline.task_id, baseline.job_id, baseline.commit_id, new_findings.len(), fixed_findings.len())
+}
+
+// How the findings compare to the ones of the baseline, if the task got compared
+async fn get_comparison_summary(db: &DbPool, task_id: i64, diagnostics: &[DiagnosticRow]) -> String {
+    let baseline = sqlx::query_as::<_, BaselineTask>(
+        "SELECT baseline.id AS task_id, baseline.job_id, jobs.commit_id FROM tasks
+        JOIN tasks AS baseline ON baseline.id = tasks.baseline_task_id
+        JOIN jobs ON jobs.id = baseline.job_id
+        WHERE tasks.id = $1;",
+    )
+        .bind(task_id)
+        .fetch_optional(db)
+        .await;
+    let Ok(Some(BaselineTask { task_id: baseline_task_id, job_id: baseline_job_id, commit_id })) = baseline else {
+        return String::from("");
+    };
+    let Ok(baseline_diagnostics) = get_diagnostics_of_task(db, baseline_task_id).await else {
+        return String::from("");
+    };
+
+    let (new_findings, fixed_findings) = compare_diagnostics(diagnostics, baseline_diagnostics.as_slice());
+    let new_findings_str = format_group("new findings", new_findings.as_slice(), |x| x.to_text());
+    let fixed_findings_str = format_group("fixed findings", fixed_findings.as_slice(), |x| x.to_text());
+    let commit_id = encode_html_with_escape_codepoint(commit_id.as_str());
+    format!("<blockquote><details><summary>compared with the baseline: {n} new findings, {f} fixed</summary>
+baseline: task {baseline_task_id} of <a href=\"/build/{baseline_job_id}\">build {baseline_job_id}</a>, commit {commit_id}<br>
+<div title=\"new_findings\">{new_findings_str}</div>
+<div title=\"fixed_findings\">{fixed_findings_str}</div></details></blockquote>",
+            n = new_findings.len(), f = fixed_findings.len())
+}
+
+// The diagnostics of the task as shown on the page of the build, grouped by file and by check, along
+// with how they compare to the baseline
+pub(crate) async fn get_diagnostics_summary(db: &DbPool, build_id: i64, task_id: i64) -> String {
+    let diagnostics = get_diagnostics_of_task(db, task_id).await.unwrap_or_default();
+    let comparison_str = get_comparison_summary(db, task_id, diagnostics.as_slice()).await;
+    if diagnostics.is_empty() {
+        return comparison_str;
+    }
+
+    let nr_errors = diagnostics.iter().filter(|x| x.severity == "error").count();
+    let nr_warnings = diagnostics.len() - nr_errors;
+
+    let mut by_file = BTreeMap::<&str, Vec<&DiagnosticRow>>::new();
+    let mut by_check = BTreeMap::<&str, Vec<&DiagnosticRow>>::new();
+    for diagnostic in &diagnostics {
+        by_file.entry(diagnostic.file.as_str()).or_default().push(diagnostic);
+        by_check.entry(diagnostic.get_check_name()).or_default().push(diagnostic);
+    }
+    // within a file, the diagnostics of the same check go together
+    for diagnostics in by_file.values_mut() {
+        diagnostics.sort_by(|a, b| (a.get_check_name(), a.line, a.column_nr).cmp(&(b.get_check_name(), b.line, b.column_nr)));
+    }
+
+    let by_file_str = by_file.iter()
+        .map(|(file, diagnostics)| format_group(file, diagnostics.as_slice(), |x| {
+            format!("{}: {}: {} [{}]", x.get_location(), x.severity, x.message, x.get_check_name())
+        }))
+        .collect::<String>();
+    let by_check_str = by_check.iter()
+        .map(|(check, diagnostics)| format_group(check, diagnostics.as_slice(), |x| {
+            format!("{}: {}: {}", x.get_location(), x.severity, x.message)
+        }))
+        .collect::<String>();
+
+    format!("{comparison_str}<blockquote><details><summary>diagnostics: {nr_warnings} warnings, {nr_errors} errors</summary>
+<div title=\"diagnostics_by_file\"><details><summary>by file</summary>{by_file_str}</details></div>
+<div title=\"diagnostics_by_check\"><details><summary>by check</summary>{by_check_str}</details></div>
+<a href=\"/build/{build_id}/task/{task_id}/diagnostics\" title=\"raw_diagnostics\">raw diagnostics</a></details></blockquote>")
+}
+
+const TSV_HEADER: &'static str = "task_id\tfile\tline\tcolumn\tseverity\tcheck\tmessage\n";
+
+fn to_tsv(diagnostics: &[DiagnosticRow]) -> String {
+    let lines = diagnostics.iter()
+        .map(|x| format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\n", x.task_id, x.file, x.line,
+                         x.column_nr.map(|x| format!("{x}")).unwrap_or_default(),
+                         x.severity, x.check_name.as_deref().unwrap_or(""), x.message))
+        .collect::<String>();
+    format!("{TSV_HEADER}{lines}")
+}
+
+fn tsv_response(query_res: Result<Vec<DiagnosticRow>, sqlx::Error>) -> Response {
+    match query_res {
+        Ok(diagnostics) => (StatusCode::OK,
+                            [(header::CONTENT_TYPE, "text/tab-separated-values; charset=utf-8")],
+                            to_tsv(diagnostics.as_slice())).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR,
+                   format!("Error occurred while reading the database {e:?}\n")).into_response(),
+    }
+}
+
+pub async fn get_task_diagnostics(
+    State(db): State<DbPool>,
+    Path((job_id, task_id)): Path<(i64, i64)>,
+) -> Response {
+    let query_res = sqlx::query_as::<_, DiagnosticRow>(
+        "SELECT task_diagnostics.task_id, file, line, column_nr, severity, check_name, message FROM task_diagnostics
+        JOIN tasks ON tasks.id = task_diagnostics.task_id
+        WHERE (task_diagnostics.task_id = $1) AND (tasks.job_id = $2)
+        ORDER BY file, line, column_nr, task_diagnostics.id;",
+    )
+        .bind(task_id)
+        .bind(job_id)
+        .fetch_all(&db)
+        .await;
+    tsv_response(query_res)
+}
+
+pub async fn get_job_diagnostics(
+    State(db): State<DbPool>,
+    Path(job_id): Path<i64>,
+) -> Response {
+    let query_res = sqlx::query_as::<_, DiagnosticRow>(
+        "SELECT task_diagnostics.task_id, file, line, column_nr, severity, check_name, message FROM task_diagnostics
+        JOIN tasks ON tasks.id = task_diagnostics.task_id
+        WHERE tasks.job_id = $1
+        ORDER BY task_diagnostics.task_id, file, line, column_nr, task_diagnostics.id;",
+    )
+        .bind(job_id)
+        .fetch_all(&db)
+        .await;
+    tsv_response(query_res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_diagnostic_of_a_line_sent_by_a_worker() {
+        let row = parse_diagnostic(3, "src/main.c\t12\t5\twarning\t-Wunused-variable\tunused variable 'x'").unwrap();
+        assert_eq!(row.task_id, 3);
+        assert_eq!(row.to_text(), "src/main.c:12:5: warning: unused variable 'x' [-Wunused-variable]");
+
+        let row = parse_diagnostic(3, "main.c\t7\t\terror\t\tstdio.h: No such file").unwrap();
+        assert_eq!(row.column_nr, None);
+        assert_eq!(row.check_name, None);
+        assert_eq!(row.get_check_name(), "(no check name)");
+        assert_eq!(row.to_text(), "main.c:7: error: stdio.h: No such file");
+    }
+
+    #[test]
+    fn parse_diagnostic_rejects_malformed_lines() {
+        assert!(parse_diagnostic(3, "main.c\t7\t1\twarning\tmissing a field").is_err());
+        assert!(parse_diagnostic(3, "main.c\tseven\t1\twarning\t\tmessage").is_err());
+        assert!(parse_diagnostic(3, "main.c\t7\tfirst\twarning\t\tmessage").is_err());
+        assert!(parse_diagnostic(3, "main.c\t7\t1\tnote\t\tmessage").is_err());
+    }
+}
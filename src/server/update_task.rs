@@ -1,9 +1,12 @@
 use axum::extract::State;
 use axum::Form;
+use axum::http::StatusCode;
 use axum::response::Html;
 use serde::Deserialize;
-use sqlx::{Error, Pool, Sqlite};
-use sqlx::sqlite::SqliteRow;
+use sqlx::{Error, Executor};
+use crate::common::get_status_of_database_error;
+use crate::db::{Db, DbPool, sql_now};
+use crate::workers;
 
 
 #[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
@@ -14,6 +17,7 @@ enum ReturnStatus {
     Failed = 4,
     Timeout = 5,
     Skipped = 6,
+    ResourceLimit = 7,
 }
 
 #[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
@@ -22,9 +26,33 @@ pub struct UpdateTaskForm {
     return_status: ReturnStatus,
     ret_code: Option<i64>,
     output: String,
+    // sent along with the final status, when the worker measures them
+    peak_memory_bytes: Option<i64>,
+    cpu_time_ms: Option<i64>,
+    // number of the report among the ones of the task, see `mark_report_as_applied`
+    seq: Option<i64>,
 }
 
-async fn update_build(db: &Pool<Sqlite>, task_id: i64) {
+// Workers send a report again when its reply got lost, and outputs are appended, so a report must
+// not be applied twice. The worker numbers the reports of each task in the order it sends them,
+// and the ones up to the last number seen are already applied. Returns false for those. Reports
+// without a number are always applied.
+pub(crate) async fn mark_report_as_applied(executor: impl Executor<'_, Database = Db>, task_id: i64, seq: Option<i64>) -> Result<bool, sqlx::Error> {
+    let Some(seq) = seq else {
+        return Ok(true);
+    };
+    let res = sqlx::query(
+        "UPDATE tasks SET last_report_seq = $2
+        WHERE (id = $1) AND ((last_report_seq IS NULL) OR (last_report_seq < $2));",
+    )
+        .bind(task_id)
+        .bind(seq)
+        .execute(executor)
+        .await?;
+    Ok(res.rows_affected() != 0)
+}
+
+async fn update_build(db: &DbPool, task_id: i64) -> Result<(), sqlx::Error> {
     // since this function is called by update task, we know at least
     // one task belonging to the job has been started.
     // we want to know if there is still a task belonging to the job that hasn't
@@ -44,45 +72,65 @@ SET status = (
           select distinct
               CASE
                  WHEN finished_at IS NULL THEN 2  -- running
-                 WHEN (status = 3) || (status = 6) then 3 -- success
-                 WHEN (status = 4) || (status = 5) then 4 -- error
+                 WHEN (status = 3) OR (status = 6) then 3 -- success
+                 WHEN (status = 4) OR (status = 5) OR (status = 7) then 4 -- error
                  ELSE 1
               END task_status
           from tasks
           where tasks.job_id = (select job_id from tasks where id = $1)
-        )
-    )
+        ) AS task_statuses
+    ) AS min_max_statuses
 )
 WHERE id = (select job_id from tasks where id = $1);"
     )
         .bind(task_id)
         .execute(&*db)
-        .await
-        .expect("Failed to set the build status");
+        .await?;
+    Ok(())
 }
 
-pub(crate) async fn update_task(State(db): State<Pool<Sqlite>>, form: Form<UpdateTaskForm>) -> Html<String> {
+pub(crate) async fn update_task(State(db): State<DbPool>, form: Form<UpdateTaskForm>) -> (StatusCode, Html<String>) {
     println!("Received requested update: {form:?}");
 
     let ret_status = form.return_status.clone() as i64;
     let output = form.output.as_str();
     println!("Output is: {output}");
 
-    let mut tx = db
-        .begin()
-        .await
-        .expect("Error when starting a sql transaction");
+    let tx = db.begin().await;
+    let Ok(mut tx) = tx else {
+        let e = tx.err().unwrap();
+        return (get_status_of_database_error(&e), Html(format!("Error when starting a sql transaction: {e:?}")));
+    };
 
-    sqlx::query(
+    let worker_state = match workers::record_activity_on_task(&mut *tx, form.task_id).await {
+        Ok(worker_state) => worker_state,
+        Err(e) => return (get_status_of_database_error(&e),
+                          Html(format!("Error: failed to record the activity of the worker executing task {task_id}: {e:?}",
+                                       task_id = form.task_id))),
+    };
+
+    let is_new_report = match mark_report_as_applied(&mut *tx, form.task_id, form.seq).await {
+        Ok(is_new_report) => is_new_report,
+        Err(e) => return (get_status_of_database_error(&e),
+                          Html(format!("Error: failed to check if the report was already applied to task {task_id}: {e:?}",
+                                       task_id = form.task_id))),
+    };
+    if !is_new_report {
+        println!("Ignoring report {:?} of task {}, it was already applied", form.seq, form.task_id);
+        return (StatusCode::OK, Html(workers::get_report_reply(worker_state)));
+    }
+
+    let res = sqlx::query(concat!(
         "UPDATE tasks
-                     SET started_at = CURRENT_TIMESTAMP
-                    WHERE (id = $1) AND (started_at IS NULL);"
+                     SET started_at = ", sql_now!(), "
+                    WHERE (id = $1) AND (started_at IS NULL);")
     )
         .bind(form.task_id)
         .execute(&mut *tx)
-        .await
-        .expect("setting th start time must work");
-    ;
+        .await;
+    if let Err(e) = res {
+        return (get_status_of_database_error(&e), Html(format!("Error: failed to set the start time of the task: Err={e:?}")));
+    }
 
     let res = sqlx::query(
         "UPDATE tasks
@@ -97,28 +145,38 @@ pub(crate) async fn update_task(State(db): State<Pool<Sqlite>>, form: Form<Updat
         .bind(form.ret_code)
         .execute(&mut *tx)
         .await;
+    // the report is numbered as applied by the same transaction, which must then be rolled back
+    if let Err(e) = res {
+        return (get_status_of_database_error(&e), Html(format!("Error: failed to update table: Err={e:?}")));
+    }
 
     if form.return_status != ReturnStatus::Running {
-        sqlx::query(
+        let res = sqlx::query(concat!(
             "UPDATE tasks
-                     SET finished_at = CURRENT_TIMESTAMP
-                    WHERE id = $1;"
+                     SET finished_at = ", sql_now!(), ",
+                         peak_memory_bytes = $2,
+                         cpu_time_ms = $3
+                    WHERE id = $1;")
         )
             .bind(form.task_id)
+            .bind(form.peak_memory_bytes)
+            .bind(form.cpu_time_ms)
             .execute(&mut *tx)
-            .await
-            .expect("Setting finished time must work");
+            .await;
+        if let Err(e) = res {
+            return (get_status_of_database_error(&e), Html(format!("Error: failed to set the finish time of the task: Err={e:?}")));
+        }
     }
 
+    if let Err(e) = tx.commit().await {
+        return (get_status_of_database_error(&e), Html(format!("Error occurred when trying to commit a transaction: {e:?}")));
+    }
 
-    tx.commit()
-        .await
-        .expect("error occurred when trying to commit a transaction");
-
-    update_build(&db, form.task_id).await;
-
-    match res {
-        Ok(_) => Html(String::from("OK")),
-        Err(e) => Html(format!("Error: failed to update table: Err={e:?}"))
+    // the report is applied already, sending it again would be ignored. The status of the build
+    // gets fixed by the next report of one of its tasks
+    if let Err(e) = update_build(&db, form.task_id).await {
+        println!("Error: failed to set the status of the build of task {}: {e:?}", form.task_id);
     }
+
+    (StatusCode::OK, Html(workers::get_report_reply(worker_state)))
 }
\ No newline at end of file
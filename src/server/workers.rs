@@ -0,0 +1,387 @@
+use axum::extract::State;
+use axum::Form;
+use axum::response::Html;
+use serde::Deserialize;
+use sqlx::{Executor, FromRow};
+use crate::common::{DOCTYPE, encode_html_with_escape_codepoint, get_head_with_title};
+use crate::db::{Db, DbPool, sql_now, sql_unix_seconds};
+use crate::reservations::{self, Reservation};
+
+// Workers are known through the requests they send. A worker gets registered, or its entry
+// refreshed, each time it asks for a task. While it executes tasks, each report it sends about
+// one of them counts as a sign of life.
+
+// a worker without a task asks for a new one every few seconds
+const SECONDS_BEFORE_NOT_POLLING: i64 = 60;
+// a worker not heard of for that long is either dead, or can't reach the server anymore
+const SECONDS_BEFORE_STALE: i64 = 15 * 60;
+
+// Replies to request_task telling a worker not to take a task, also following the `OK` of the replies
+// to its reports. They must match the ones the workers expect
+pub(crate) const WORKER_PAUSED_REPLY: &'static str = "Worker paused";
+pub(crate) const WORKER_EXIT_REPLY: &'static str = "Worker drained, exit";
+
+// Set from the web interface, as opposed to the status which is deduced from what the worker does
+#[derive(Debug, Deserialize, Clone, Copy, Eq, Hash, PartialEq)]
+pub(crate) enum WorkerState {
+    Active = 1,
+    // finishes its current task, then exits. Same as sending it SIGINT
+    Draining = 2,
+    // doesn't take new tasks until resumed, but keeps polling the server
+    Paused = 3,
+}
+
+impl WorkerState {
+    pub(crate) fn from_i64(value: i64) -> Option<WorkerState> {
+        match value {
+            1 => Some(WorkerState::Active),
+            2 => Some(WorkerState::Draining),
+            3 => Some(WorkerState::Paused),
+            _ => None,
+        }
+    }
+
+    // an invalid value in the database fails the request, which then gets an error status
+    fn decode(value: i64) -> Result<WorkerState, sqlx::Error> {
+        WorkerState::from_i64(value)
+            .ok_or_else(|| sqlx::Error::Decode(format!("invalid worker state {value} in the database").into()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum WorkerStatus {
+    // executing at least one task
+    Busy,
+    // asking for tasks, but there is nothing for it to do
+    Idle,
+    // alive, but neither executing a task nor asking for one. E.g. when cleaning up after a task
+    Online,
+    Stale,
+}
+
+impl WorkerStatus {
+    // reuse the colours of the job statuses
+    fn css_class(&self) -> &'static str {
+        match self {
+            WorkerStatus::Busy => "Running",
+            WorkerStatus::Idle => "Success",
+            WorkerStatus::Online => "Pending",
+            WorkerStatus::Stale => "Failed",
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct RowState {
+    state: i64,
+}
+
+#[derive(FromRow)]
+struct RowTaskId {
+    task_id: i64,
+}
+
+// Called when a worker asks for tasks. The worker tells which tasks it is still executing, any other
+// task given to it before is over, even if it never reported it as finished, e.g. after a crash.
+// Returns the state the worker was set to from the web interface.
+pub(crate) async fn register_worker(db: &DbPool, hostname: &str, version: Option<&str>, capabilities: &str, running_tasks: &[i64]) -> Result<WorkerState, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let RowState { state } = sqlx::query_as::<_, RowState>(concat!(
+        "INSERT INTO workers(hostname, version, capabilities, last_seen_at)
+        VALUES ($1, $2, $3, ", sql_now!(), ")
+        ON CONFLICT (hostname) DO UPDATE
+        SET version = excluded.version,
+            capabilities = excluded.capabilities,
+            last_seen_at = excluded.last_seen_at
+        RETURNING state;"),
+    )
+        .bind(hostname)
+        .bind(version)
+        .bind(capabilities)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let given_tasks = sqlx::query_as::<_, RowTaskId>(
+        "SELECT task_id FROM worker_tasks
+        WHERE hostname = $1;",
+    )
+        .bind(hostname)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    for RowTaskId { task_id } in given_tasks.into_iter().filter(|x| !running_tasks.contains(&x.task_id)) {
+        sqlx::query("DELETE FROM worker_tasks WHERE task_id = $1;")
+            .bind(task_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let state = WorkerState::decode(state)?;
+    tx.commit().await?;
+    Ok(state)
+}
+
+pub(crate) async fn set_state(db: &DbPool, hostname: &str, state: WorkerState) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query(
+        "UPDATE workers SET state = $2
+        WHERE hostname = $1;",
+    )
+        .bind(hostname)
+        .bind(state as i64)
+        .execute(db)
+        .await?;
+    Ok(res.rows_affected() != 0)
+}
+
+pub(crate) async fn add_current_task(executor: impl Executor<'_, Database = Db>, hostname: &str, task_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO worker_tasks(hostname, task_id)
+        VALUES ($1, $2)
+        ON CONFLICT (task_id) DO UPDATE
+        SET hostname = excluded.hostname;",
+    )
+        .bind(hostname)
+        .bind(task_id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+// Called when a worker reports something about the task it executes. Returns the state the worker
+// was set to from the web interface, active if the task isn't known to be executed by a worker.
+pub(crate) async fn record_activity_on_task(executor: impl Executor<'_, Database = Db>, task_id: i64) -> Result<WorkerState, sqlx::Error> {
+    let row = sqlx::query_as::<_, RowState>(concat!(
+        "UPDATE workers SET last_seen_at = ", sql_now!(), "
+        WHERE hostname IN (SELECT hostname FROM worker_tasks WHERE task_id = $1)
+        RETURNING state;"),
+    )
+        .bind(task_id)
+        .fetch_optional(executor)
+        .await?;
+    row.map_or(Ok(WorkerState::Active), |x| WorkerState::decode(x.state))
+}
+
+// Reply to a report the server took. A worker busy with long tasks doesn't ask for new ones, so the
+// state it was set to comes along, after the `OK`.
+pub(crate) fn get_report_reply(state: WorkerState) -> String {
+    match state {
+        WorkerState::Active => String::from("OK"),
+        WorkerState::Paused => format!("OK\n{WORKER_PAUSED_REPLY}"),
+        WorkerState::Draining => format!("OK\n{WORKER_EXIT_REPLY}"),
+    }
+}
+
+#[derive(FromRow)]
+struct WorkerProperties {
+    hostname: String,
+    version: Option<String>,
+    capabilities: String,
+    first_seen_at: String,
+    last_seen_at: String,
+    seconds_since_last_seen: i64,
+    state: i64,
+}
+
+#[derive(FromRow)]
+struct RunningTask {
+    hostname: String,
+    task_id: i64,
+    job_id: i64,
+}
+
+impl WorkerProperties {
+    fn status(&self, running_tasks: &[&RunningTask]) -> WorkerStatus {
+        if self.seconds_since_last_seen > SECONDS_BEFORE_STALE {
+            return WorkerStatus::Stale;
+        }
+        if !running_tasks.is_empty() {
+            return WorkerStatus::Busy;
+        }
+        if self.seconds_since_last_seen > SECONDS_BEFORE_NOT_POLLING {
+            return WorkerStatus::Online;
+        }
+        WorkerStatus::Idle
+    }
+}
+
+fn format_worker(worker: &WorkerProperties, running_tasks: &[RunningTask], reservations: &[Reservation]) -> String {
+    let running_tasks = running_tasks
+        .iter()
+        .filter(|x| x.hostname == worker.hostname)
+        .collect::<Vec<_>>();
+    let status = worker.status(&running_tasks);
+    let css_class = status.css_class();
+    let hostname = encode_html_with_escape_codepoint(worker.hostname.as_str());
+    let version = match &worker.version {
+        Some(x) => encode_html_with_escape_codepoint(x.as_str()),
+        None => String::from("unknown"),
+    };
+    let capabilities = encode_html_with_escape_codepoint(worker.capabilities.as_str());
+    let state = WorkerState::from_i64(worker.state);
+    let state_forms = get_state_forms(worker.hostname.as_str(), state);
+    let state = match state {
+        Some(x) => format!("{x:?}"),
+        None => format!("invalid ({})", worker.state),
+    };
+    let reservation = reservations
+        .iter()
+        .find(|x| x.is_active() && (x.hostname == worker.hostname))
+        .map(|x| x.summary())
+        .unwrap_or(String::from("none"));
+    let first_seen_at = worker.first_seen_at.as_str();
+    let last_seen_at = worker.last_seen_at.as_str();
+    let current_tasks = running_tasks
+        .iter()
+        .map(|x| format!("<a href=\"/build/{job_id}\">task {task_id} of build {job_id}</a>", job_id = x.job_id, task_id = x.task_id))
+        .reduce(|x, y| format!("{x}<br>{y}"))
+        .unwrap_or(String::from("none"));
+
+    format!(
+        "<tr class=\"{css_class}\" title=\"worker_{hostname}\">
+  <td title=\"hostname\">{hostname}</td>
+  <td title=\"status\">{status:?}</td>
+  <td title=\"state\">{state}</td>
+  <td title=\"current_tasks\">{current_tasks}</td>
+  <td title=\"reservation\">{reservation}</td>
+  <td title=\"last_seen_at\">{last_seen_at} UTC</td>
+  <td title=\"first_seen_at\">{first_seen_at} UTC</td>
+  <td title=\"version\">{version}</td>
+  <td title=\"capabilities\">{capabilities}</td>
+  <td title=\"actions\">{state_forms}</td>
+</tr>")
+}
+
+// buttons to move the worker to the states it isn't in
+fn get_state_forms(hostname: &str, current_state: Option<WorkerState>) -> String {
+    let hostname = encode_html_with_escape_codepoint(hostname);
+    [(WorkerState::Active, "Resume"),
+        (WorkerState::Draining, "Drain"),
+        (WorkerState::Paused, "Pause")]
+        .iter()
+        .filter(|(state, _)| Some(*state) != current_state)
+        .map(|(state, button_text)| format!(
+            "<form action=\"/set_worker_state\" method=\"post\">
+<input type=\"hidden\" name=\"hostname\" value=\"{hostname}\">
+<input type=\"hidden\" name=\"state\" value=\"{state:?}\">
+<button type=\"submit\" class=\"link_button\">{button_text}</button>
+</form>"))
+        .reduce(|x, y| format!("{x}\n{y}"))
+        .unwrap_or(String::from(""))
+}
+
+pub async fn list_workers(State(db): State<DbPool>) -> Html<String> {
+    let query_res = sqlx::query_as::<_, WorkerProperties>(concat!(
+        "SELECT hostname, version, capabilities, first_seen_at, last_seen_at, state,
+                ", sql_unix_seconds!(sql_now!()), " - ", sql_unix_seconds!("last_seen_at"), " AS seconds_since_last_seen
+        FROM workers
+        ORDER BY hostname;"),
+    )
+        .fetch_all(&db)
+        .await;
+
+    let Ok(workers) = query_res else {
+        return Html(format!(
+            "Error occurred while reading the database {:?}",
+            query_res.err()
+        ));
+    };
+
+    let query_res = sqlx::query_as::<_, RunningTask>(
+        "SELECT worker_tasks.hostname, tasks.id AS task_id, tasks.job_id
+        FROM worker_tasks
+        JOIN tasks ON tasks.id = worker_tasks.task_id
+        WHERE tasks.status = 2 -- running
+        ORDER BY tasks.id;",
+    )
+        .fetch_all(&db)
+        .await;
+
+    let Ok(running_tasks) = query_res else {
+        return Html(format!(
+            "Error occurred while reading the database {:?}",
+            query_res.err()
+        ));
+    };
+
+    let query_res = reservations::get_reservations(&db).await;
+    let Ok(reservations) = query_res else {
+        return Html(format!(
+            "Error occurred while reading the database {:?}",
+            query_res.err()
+        ));
+    };
+
+    let table_in = workers
+        .iter()
+        .map(|x| format_worker(x, &running_tasks, &reservations))
+        .reduce(|x, y| format!("{x}\n{y}"))
+        .unwrap_or(String::from(""));
+
+    let hostnames = workers
+        .into_iter()
+        .map(|x| x.hostname)
+        .collect::<Vec<_>>();
+    let reservations_section = reservations::format_reservations_section(&reservations, &hostnames);
+
+    let html_head = get_head_with_title("Workers");
+    Html(format!(
+        "{DOCTYPE}<html lang=\"en-GB\">{html_head}<body>
+<h1 class=\"post-title\">Workers</h1>
+<a href=\"/\" class=\"link_button display_inline_block\">Back to the build list</a>
+<br>
+<br>
+  <table>
+    <tr>
+      <td>hostname</td>
+      <td>status</td>
+      <td>state</td>
+      <td>current tasks</td>
+      <td>reservation</td>
+      <td>last seen at</td>
+      <td>first seen at</td>
+      <td>version</td>
+      <td>capabilities</td>
+      <td>actions</td>
+    </tr>
+    {table_in}
+  </table>
+<br>
+{reservations_section}
+</body>
+</html>"
+    ))
+}
+
+#[derive(Debug, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct SetWorkerStateForm {
+    hostname: String,
+    state: WorkerState,
+}
+
+pub async fn set_worker_state(State(db): State<DbPool>, form: Form<SetWorkerStateForm>) -> Html<String> {
+    let query_res = set_state(&db, form.hostname.as_str(), form.state).await;
+    let Ok(is_known_worker) = query_res else {
+        return Html(format!(
+            "Error occurred while updating the database {:?}",
+            query_res.err()
+        ));
+    };
+
+    let hostname = encode_html_with_escape_codepoint(form.hostname.as_str());
+    if !is_known_worker {
+        return Html(format!("Error, there is no worker with hostname {hostname}"));
+    }
+
+    let state = match form.state {
+        WorkerState::Active => "active",
+        WorkerState::Draining => "draining",
+        WorkerState::Paused => "paused",
+    };
+    let html_head = get_head_with_title(format!("Worker {hostname} is {state}").as_str());
+    Html(format!(
+        "{DOCTYPE}<html lang=\"en-GB\">{html_head}<body>
+<h1 class=\"post-title\">Worker {hostname} is {state}</h1>
+<a href=\"/workers\" class=\"link_button\">View the workers</a>
+</body></html>"))
+}
@@ -0,0 +1,228 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use crate::common::{Compiler, report_task_data, try_lock_file};
+use crate::update_git_repo::get_branch_of;
+
+// ccache or sccache, passed to cmake as the launcher of the compilers
+pub(crate) const COMPILER_LAUNCHER_ENV_VAR: &'static str = "MINI_WORKER_COMPILER_LAUNCHER";
+pub(crate) const COMPILER_CACHE_DIR_ENV_VAR: &'static str = "MINI_WORKER_COMPILER_CACHE_DIR";
+const DEFAULT_COMPILER_CACHE_DIR: &'static str = "/var/tmp/mini_worker_compiler_cache";
+
+// when set, test tasks keep their checkout and build directory there, see PersistentBuildDir
+pub(crate) const PERSISTENT_BUILDS_DIR_ENV_VAR: &'static str = "MINI_WORKER_PERSISTENT_BUILDS_DIR";
+
+// in the build directory, ccache appends the result of each compilation of the task to it
+const CCACHE_STATS_LOG_NAME: &'static str = "ccache_stats.log";
+// in each persistent build directory, locked by the task using it
+const PERSISTENT_BUILD_DIR_LOCK_NAME: &'static str = "lock";
+
+fn get_compiler_name(compiler: &Compiler) -> &'static str {
+    match compiler {
+        Compiler::GccFromHardwareVendor => "gcc_from_hardware_vendor",
+        Compiler::GccFromDistro => "gcc_from_distro",
+    }
+}
+
+fn to_dir_name(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum LauncherKind {
+    Ccache,
+    Sccache,
+}
+
+pub(crate) struct CompilerCache {
+    kind: LauncherKind,
+    launcher: String,
+    // one per compiler and toolchain, so they don't evict each other's objects
+    dir: PathBuf,
+    // the counters of ccache are shared by all the tasks using the cache, the ones of the task are
+    // counted from this log instead
+    stats_log: PathBuf,
+}
+
+impl CompilerCache {
+    // Returns None when no launcher is configured
+    pub(crate) fn from_env(compiler: &Compiler, toolchain_file: &Path, build_dir: &Path) -> Option<CompilerCache> {
+        let launcher = std::env::var(COMPILER_LAUNCHER_ENV_VAR).ok().filter(|x| !x.is_empty())?;
+        let kind = match Path::new(launcher.as_str()).file_name().and_then(OsStr::to_str) {
+            Some("ccache") => LauncherKind::Ccache,
+            Some("sccache") => LauncherKind::Sccache,
+            _ => {
+                println!("Ignoring unsupported compiler launcher [{launcher}] in {COMPILER_LAUNCHER_ENV_VAR}. Expected ccache or sccache");
+                return None;
+            }
+        };
+
+        let root = std::env::var_os(COMPILER_CACHE_DIR_ENV_VAR)
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or(PathBuf::from(DEFAULT_COMPILER_CACHE_DIR));
+        let toolchain = toolchain_file.file_stem().unwrap_or_default().to_string_lossy();
+        let dir = root.join(format!("{}-{}", get_compiler_name(compiler), to_dir_name(toolchain.as_ref())));
+        // a persistent build directory still has the log of the previous task
+        let stats_log = build_dir.join(CCACHE_STATS_LOG_NAME);
+        let _ = std::fs::remove_file(stats_log.as_path());
+        Some(CompilerCache { kind, launcher, dir, stats_log })
+    }
+
+    pub(crate) fn get_dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    pub(crate) fn get_cmake_args(&self) -> Vec<String> {
+        vec![format!("-DCMAKE_C_COMPILER_LAUNCHER={}", self.launcher),
+             format!("-DCMAKE_CXX_COMPILER_LAUNCHER={}", self.launcher)]
+    }
+
+    pub(crate) fn get_envs(&self, src_dir: &Path) -> Vec<(&'static str, String)> {
+        let dir = self.dir.to_string_lossy().into_owned();
+        match self.kind {
+            // each task checks out the sources in its own directory. Paths are made relative to it,
+            // so the objects compiled by previous tasks can be reused.
+            LauncherKind::Ccache => vec![("CCACHE_DIR", dir),
+                                         ("CCACHE_BASEDIR", src_dir.to_string_lossy().into_owned()),
+                                         ("CCACHE_NOHASHDIR", String::from("1")),
+                                         ("CCACHE_STATSLOG", self.stats_log.to_string_lossy().into_owned())],
+            LauncherKind::Sccache => vec![("SCCACHE_DIR", dir)],
+        }
+    }
+
+    // Number of hits and misses counted so far, by the task with ccache, by all the tasks using the
+    // sccache server otherwise
+    pub(crate) fn get_counters(&self) -> Option<(u64, u64)> {
+        match self.kind {
+            LauncherKind::Ccache => self.get_counters_of_stats_log(),
+            LauncherKind::Sccache => self.get_counters_of_sccache_server(),
+        }
+    }
+
+    // The log has a `# <source>` line per compilation, followed by the names of its counters
+    fn get_counters_of_stats_log(&self) -> Option<(u64, u64)> {
+        let log = match std::fs::read_to_string(self.stats_log.as_path()) {
+            Ok(log) => log,
+            // nothing got compiled yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(_) => return None,
+        };
+        let mut hits = 0;
+        let mut misses = 0;
+        for line in log.lines() {
+            match line.trim() {
+                "direct_cache_hit" | "preprocessed_cache_hit" => hits += 1,
+                "cache_miss" => misses += 1,
+                _ => (),
+            }
+        }
+        Some((hits, misses))
+    }
+
+    fn get_counters_of_sccache_server(&self) -> Option<(u64, u64)> {
+        let output = std::process::Command::new(self.launcher.as_str())
+            .arg("--show-stats")
+            .env("SCCACHE_DIR", &self.dir)
+            .output();
+        let Ok(output) = output else {
+            return None;
+        };
+        if !output.status.success() {
+            return None;
+        }
+
+        let stats = String::from_utf8_lossy(output.stdout.as_ref()).into_owned();
+        let mut hits = 0;
+        let mut misses = 0;
+        for line in stats.lines() {
+            // the values are aligned on the right of the names
+            let (name, value) = match line.rsplit_once(char::is_whitespace) {
+                Some((name, value)) => (name.trim(), value.parse::<u64>().unwrap_or(0)),
+                None => continue,
+            };
+            match name {
+                "Cache hits" => hits += value,
+                "Cache misses" => misses += value,
+                _ => (),
+            }
+        }
+        Some((hits, misses))
+    }
+
+    // Tells how many compilations the cache spared since the counters given
+    pub(crate) fn report_statistics(&self, task_id: i64, counters_before: Option<(u64, u64)>) {
+        let (Some((hits_before, misses_before)), Some((hits, misses))) = (counters_before, self.get_counters()) else {
+            report_task_data(task_id, format!("Failed to get the statistics of {}", self.launcher).as_str());
+            return;
+        };
+        let hits = hits.saturating_sub(hits_before);
+        let misses = misses.saturating_sub(misses_before);
+        let hit_rate = (hits * 100).checked_div(hits + misses).unwrap_or(0);
+        let counted_for = match self.kind {
+            LauncherKind::Ccache => "",
+            LauncherKind::Sccache => ", counting the compilations of the other tasks using the sccache server meanwhile",
+        };
+        let msg = format!("Compiler cache {:?}: {hits} hits, {misses} misses, {hit_rate}% hit rate{counted_for}", self.dir);
+        report_task_data(task_id, msg.as_str());
+    }
+}
+
+// Test tasks reuse the checkout and build directory of the last task built from the same branch,
+// with the same compiler, so ninja only rebuilds what changed. A directory is used by one task
+// at a time, the others running meanwhile get fresh directories. The lock is a lock file in the
+// directory rather than a list of the process, so it holds for the workers sharing the directories.
+pub(crate) struct PersistentBuildDir {
+    dir: PathBuf,
+    branch: String,
+    // released when dropped, at the end of the task
+    _lock: File,
+}
+
+impl PersistentBuildDir {
+    pub(crate) fn get_root() -> Option<PathBuf> {
+        std::env::var_os(PERSISTENT_BUILDS_DIR_ENV_VAR)
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+    }
+
+    // Returns None when persistent directories aren't enabled, when the commit isn't on any
+    // branch, or when another task uses the directory
+    pub(crate) fn acquire(mirror_path: &OsStr, commit: &str, compiler: &Compiler) -> Option<PersistentBuildDir> {
+        let root = PersistentBuildDir::get_root()?;
+        let branch = get_branch_of(mirror_path, commit)?;
+        let dir = root.join(format!("{}-{}", to_dir_name(branch.as_str()), get_compiler_name(compiler)));
+
+        if let Err(e) = std::fs::create_dir_all(dir.as_path()) {
+            println!("Failed to create the persistent build directory {dir:?}: {e}");
+            return None;
+        }
+        let lock = match try_lock_file(dir.join(PERSISTENT_BUILD_DIR_LOCK_NAME).as_path()) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => return None,
+            Err(e) => {
+                println!("Failed to lock the persistent build directory {dir:?}: {e}");
+                return None;
+            }
+        };
+        Some(PersistentBuildDir { dir, branch, _lock: lock })
+    }
+
+    pub(crate) fn get_dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    pub(crate) fn get_src_dir(&self) -> PathBuf {
+        self.dir.join("src")
+    }
+
+    pub(crate) fn get_build_dir(&self) -> PathBuf {
+        self.dir.join("build")
+    }
+
+    pub(crate) fn get_branch(&self) -> &str {
+        self.branch.as_str()
+    }
+}
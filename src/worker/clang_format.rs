@@ -0,0 +1,156 @@
+use std::path::Path;
+use std::time::Instant;
+use crate::common::{FinishStatus, report_task_artifact, report_task_data};
+use crate::resource_limits::ResourceLimits;
+use crate::run_command::run_sandboxed_proc;
+use crate::sandbox::Sandbox;
+
+// The clang-format task formats the sources in the checkout of the task, using the .clang-format
+// files of the tested project, and fails when that changed anything. The diff is reported in the
+// output of the task, and as a patch to download from the page of the build.
+
+// path to the clang-format binary to use
+pub(crate) const CLANG_FORMAT_ENV_VAR: &'static str = "MINI_WORKER_CLANG_FORMAT";
+// "changed" checks the files changed by the commit, "all" every source tracked by git
+pub(crate) const CLANG_FORMAT_FILES_ENV_VAR: &'static str = "MINI_WORKER_CLANG_FORMAT_FILES";
+
+const PATCH_ARTIFACT_NAME: &'static str = "clang_format.patch";
+const SOURCE_EXTENSIONS: [&'static str; 14] = ["c", "h", "cc", "hh", "cpp", "hpp", "cxx", "hxx", "c++", "h++", "ipp", "tpp", "inl", "inc"];
+// keeps the command line of clang-format reasonably short
+const MAX_FILES_PER_CALL: usize = 100;
+// the server refuses forms above 2MB
+const MAX_PATCH_ARTIFACT_SIZE: usize = 1024 * 1024;
+// a bigger diff is cut in the output of the task, the artifact has all of it
+const MAX_PATCH_IN_OUTPUT: usize = 64 * 1024;
+
+fn run_git(task_dir: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(task_dir)
+        .args(args)
+        .output();
+    let Ok(output) = output else {
+        return Err(format!("fail to run git {}: error={}", args.join(" "), output.err().unwrap()));
+    };
+    if !output.status.success() {
+        return Err(format!("git {} failed with {}", args.join(" "), String::from_utf8_lossy(output.stderr.as_ref())));
+    }
+    Ok(output.stdout)
+}
+
+fn has_parent_commit(task_dir: &Path) -> bool {
+    run_git(task_dir, &["rev-parse", "--verify", "--quiet", "HEAD~1^{commit}"]).is_ok()
+}
+
+fn is_source_file(task_dir: &Path, file: &str) -> bool {
+    let Some(extension) = Path::new(file).extension() else {
+        return false;
+    };
+    let extension = extension.to_ascii_lowercase();
+    if !SOURCE_EXTENSIONS.iter().any(|x| extension == *x) {
+        return false;
+    }
+    // symlinks are checked through the file they point to, if tracked. Submodules show up as
+    // directories, their sources are not part of the commit.
+    std::fs::symlink_metadata(task_dir.join(file)).is_ok_and(|x| x.is_file())
+}
+
+// Relative paths of the sources to check
+fn get_files_to_check(task_id: i64, task_dir: &Path) -> Result<Vec<String>, String> {
+    let check_all = match std::env::var(CLANG_FORMAT_FILES_ENV_VAR) {
+        Err(_) => false,
+        Ok(x) if x == "changed" => false,
+        Ok(x) if x == "all" => true,
+        Ok(x) => {
+            println!("Ignoring invalid value [{x}] of {CLANG_FORMAT_FILES_ENV_VAR}, checking the files changed by the commit instead");
+            false
+        }
+    };
+
+    let listing = if check_all {
+        report_task_data(task_id, "Checking the formatting of all the sources\n");
+        run_git(task_dir, &["ls-files", "-z"])?
+    } else if has_parent_commit(task_dir) {
+        report_task_data(task_id, "Checking the formatting of the sources changed by the commit\n");
+        // with merges, that's what the merge brings to the first parent
+        run_git(task_dir, &["diff", "--name-only", "-z", "--no-renames", "--diff-filter=d", "HEAD~1", "HEAD"])?
+    } else {
+        report_task_data(task_id, "The commit has no parent, checking the formatting of all the sources\n");
+        run_git(task_dir, &["ls-files", "-z"])?
+    };
+
+    let files = listing.split(|x| *x == 0)
+        .filter_map(|x| std::str::from_utf8(x).ok())
+        .filter(|x| !x.is_empty() && is_source_file(task_dir, x))
+        .map(String::from)
+        .collect();
+    Ok(files)
+}
+
+fn truncate_at_char_boundary(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut len = max_len;
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+pub(crate) fn run_clang_format_task(task_id: i64, task_dir: &Path, sandbox: Option<&Sandbox>, limits: Option<&ResourceLimits>,
+                                    deadline: Option<Instant>) -> Result<FinishStatus, String> {
+    println!("Running clang_format in {}", String::from_utf8_lossy(task_dir.as_os_str().as_encoded_bytes()));
+
+    let files = get_files_to_check(task_id, task_dir)?;
+    if files.is_empty() {
+        report_task_data(task_id, "No source file to check\n");
+        return Ok(FinishStatus::Success);
+    }
+
+    let clang_format = std::env::var_os(CLANG_FORMAT_ENV_VAR).unwrap_or_else(|| "clang-format".into());
+    // clang-format finds the .clang-format file of each source from its path
+    let paths = files.iter()
+        .map(|x| task_dir.join(x).to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    for chunk in paths.chunks(MAX_FILES_PER_CALL) {
+        let mut params = vec!["-i", "--style=file"];
+        params.extend(chunk.iter().map(|x| x.as_str()));
+        let task_output = run_sandboxed_proc(task_id, sandbox, limits, deadline, clang_format.as_os_str(), params.as_slice(), &[], None);
+        if !task_output.success() {
+            report_task_data(task_id, "clang-format command failed");
+            return Ok(FinishStatus::Failed(2));
+        }
+    }
+
+    let changed_files = run_git(task_dir, &["diff", "--name-only"])?;
+    let changed_files = String::from_utf8_lossy(changed_files.as_ref()).into_owned();
+    if changed_files.is_empty() {
+        let msg = format!("The {} checked files are formatted correctly\n", files.len());
+        report_task_data(task_id, msg.as_str());
+        return Ok(FinishStatus::Success);
+    }
+    let nr_changed_files = changed_files.lines().count();
+    let msg = format!("{nr_changed_files} of the {} checked files are not formatted correctly:\n{changed_files}", files.len());
+    report_task_data(task_id, msg.as_str());
+
+    let patch = run_git(task_dir, &["diff", "--no-ext-diff", "--no-textconv", "--no-color"])?;
+    let patch = String::from_utf8_lossy(patch.as_ref()).into_owned();
+    let shown_patch = truncate_at_char_boundary(patch.as_str(), MAX_PATCH_IN_OUTPUT);
+    report_task_data(task_id, shown_patch);
+    if shown_patch.len() < patch.len() {
+        let msg = format!("\n[diff cut after {} of {} bytes]\n", shown_patch.len(), patch.len());
+        report_task_data(task_id, msg.as_str());
+    }
+
+    if patch.len() <= MAX_PATCH_ARTIFACT_SIZE {
+        report_task_artifact(task_id, PATCH_ARTIFACT_NAME, patch.as_str());
+        let msg = format!("\nApply {PATCH_ARTIFACT_NAME} from the page of the build with `git apply` to fix the formatting\n");
+        report_task_data(task_id, msg.as_str());
+    } else {
+        let msg = format!("\nThe diff is too big to be saved as {PATCH_ARTIFACT_NAME}, run clang-format locally to fix the formatting\n");
+        report_task_data(task_id, msg.as_str());
+    }
+
+    Ok(FinishStatus::Failed(1))
+}
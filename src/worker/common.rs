@@ -1,11 +1,18 @@
 use std::cell::OnceCell;
 use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::Path;
 use std::string::String;
 use std::ptr::hash;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::Duration;
 use serde::de::Unexpected::Str;
 use tokio::fs::read_to_string;
+use crate::reporter::{Report, send_report};
+use crate::resource_limits::ResourceUsage;
+use crate::target_runner::is_hardware_runner_configured;
 
 #[derive(Debug)]
 pub(crate) enum Compiler {
@@ -28,6 +35,10 @@ pub(crate) struct TestSetup {
     pub tests_to_run: RequestedTest,
     pub run_tests_on_qemu: bool,
     pub run_tests_on_real_hardware: bool,
+    // overrides the timeout of the worker for each test
+    pub test_timeout: Option<Duration>,
+    // the tests are built with coverage instrumentation, and the coverage sent once they ran
+    pub with_coverage: bool,
 }
 
 #[derive(Debug)]
@@ -43,25 +54,98 @@ pub(crate) struct Task {
     id: i64,
     git_hash: String,
     task_type: TaskKind,
-
+    // branch whose latest successful build has the findings this task must not add to
+    warnings_baseline: Option<String>,
 }
 
 // useful to avoid re cloning the project for each build job.
 pub(crate) const FOLDER_CONTAINING_A_GIT_DIR_TO_USE_AS_A_GIT_CACHE: &'static str = "/tmp/path/to/a/git/dir";
 pub(crate) const MINICI_SERVER_REQUEST_URL: &'static str = "http://localhost:3000/request_task";
+pub(crate) const MINICI_SERVER_UPDATE_TASK: &'static str = "http://localhost:3000/update_task";
 pub(crate) const MINICI_SERVER_REPORT_TEST_CHANGE: &'static str = "http://localhost:3000/report_test_change";
 pub(crate) const MINICI_SERVER_ADD_TEST_TEST_LIST: &'static str = "http://localhost:3000/add_test_list_to_job";
+pub(crate) const MINICI_SERVER_ADD_TASK_ARTIFACT: &'static str = "http://localhost:3000/add_task_artifact";
+pub(crate) const MINICI_SERVER_ADD_TASK_DIAGNOSTICS: &'static str = "http://localhost:3000/add_task_diagnostics";
+pub(crate) const MINICI_SERVER_COMPARE_TASK_DIAGNOSTICS: &'static str = "http://localhost:3000/compare_task_diagnostics";
+pub(crate) const MINICI_SERVER_ADD_TASK_COVERAGE: &'static str = "http://localhost:3000/add_task_coverage";
+
+// replies of the server when asking for a task, telling the worker not to take one. They also
+// follow the `OK` of the replies to the reports about tasks and tests
+pub(crate) const WORKER_PAUSED_REPLY: &'static str = "Worker paused";
+pub(crate) const WORKER_EXIT_REPLY: &'static str = "Worker drained, exit";
+
+// State the worker was set to from the web interface, as last heard of from the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WorkerState {
+    // until the server replied to a request for tasks. The state it had before the worker started,
+    // e.g. drained, no longer applies once the worker registers
+    Starting = 0,
+    Active = 1,
+    Paused = 2,
+    Draining = 3,
+}
+
+static WORKER_STATE: AtomicU8 = AtomicU8::new(WorkerState::Starting as u8);
+
+pub(crate) fn get_worker_state() -> WorkerState {
+    match WORKER_STATE.load(Ordering::SeqCst) {
+        0 => WorkerState::Starting,
+        1 => WorkerState::Active,
+        2 => WorkerState::Paused,
+        _ => WorkerState::Draining,
+    }
+}
+
+// from the reply to a request for tasks
+pub(crate) fn set_worker_state(state: WorkerState) {
+    WORKER_STATE.store(state as u8, Ordering::SeqCst);
+}
+
+// From the reply to a report. A worker busy with long tasks learns that way it got paused or drained,
+// without asking for tasks. The replies to the reports sent again from the journal of a previous
+// run may predate the registration of the worker, so they are ignored until then.
+pub(crate) fn set_worker_state_from_report_reply(extra_line: Option<&str>) {
+    if get_worker_state() == WorkerState::Starting {
+        return;
+    }
+    let state = match extra_line {
+        Some(WORKER_PAUSED_REPLY) => WorkerState::Paused,
+        Some(WORKER_EXIT_REPLY) => WorkerState::Draining,
+        _ => WorkerState::Active,
+    };
+    if state != get_worker_state() {
+        println!("The web server set the worker to {state:?}");
+    }
+    set_worker_state(state);
+}
 
-const HOSTNAME: &'static str = "TODO_RETRIEVE_REAL_HOSTNAME";
-pub(crate) const WORKER_CAPABILITIES: [(&'static str, &'static str);8] =
-[("hostname", HOSTNAME),
-("accept_static_analyser_task", "true"), // todo: hardcoded capabilities
+const WORKER_CAPABILITIES: [(&'static str, &'static str);6] =
+[("accept_static_analyser_task", "true"), // todo: hardcoded capabilities
 ("accept_clang_tidy_task", "true"),
 ("accept_clang_format_task", "true"),
 ("accept_compile_with_gcc_from_hardware_vendor", "true"),
 ("accept_compile_with_gcc_from_distro", "true"),
-("accept_run_tests_on_qemu", "true"),
-("accept_run_tests_on_real_hardware", "true")];
+("accept_run_tests_on_qemu", "true")];
+
+// shared by all the requests to the server, so connections are kept alive between them
+pub(crate) fn get_http_client() -> &'static reqwest::blocking::Client {
+    static HTTP_CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+    HTTP_CLIENT.get_or_init(reqwest::blocking::Client::new)
+}
+
+// form sent when asking for a task. The server identifies the worker by its hostname
+pub(crate) fn get_task_request_form() -> Vec<(&'static str, String)> {
+    let hostname = nix::unistd::gethostname()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or(String::from("unknown_hostname"));
+
+    let mut form = vec![("hostname", hostname),
+                        ("version", String::from(env!("CARGO_PKG_VERSION")))];
+    form.extend(WORKER_CAPABILITIES.iter().map(|(name, value)| (*name, String::from(*value))));
+    // tests on real hardware need a way to drive the board
+    form.push(("accept_run_tests_on_real_hardware", format!("{}", is_hardware_runner_configured())));
+    form
+}
 
 pub(crate) const STATIC_ANALYSER_SCRIPT_IN_TESTED_PROJECT: &'static str = "scripts/run_static_analyser.sh";
 pub(crate) const RUN_CLANG_TIDY_SCRIPT_IN_TESTED_PROJECT: &'static str = "scripts/run_clang_tidy.sh";
@@ -124,7 +208,13 @@ impl Task {
             _ => return Err(format!("Can't extract the task kind from {text}"))
         };
 
-        let res = Task { id: res_id, git_hash, task_type: task_kind };
+        // only given when the job sets it
+        let warnings_baseline = lines[3..]
+            .iter()
+            .find_map(|x| x.strip_prefix("Warnings baseline: "))
+            .map(|x| String::from(x.trim()));
+
+        let res = Task { id: res_id, git_hash, task_type: task_kind, warnings_baseline };
         Ok(res)
     }
     pub fn id(&self) -> i64 {
@@ -136,6 +226,16 @@ impl Task {
     pub fn task_type(&self) -> &TaskKind {
         &self.task_type
     }
+    pub fn warnings_baseline(&self) -> Option<&str> {
+        self.warnings_baseline.as_deref()
+    }
+    // such tasks need the board connected to the worker
+    pub fn uses_real_hardware(&self) -> bool {
+        match &self.task_type {
+            TaskKind::Test(setup) => setup.run_tests_on_real_hardware && (setup.tests_to_run != RequestedTest::NoTestsOnlyCompile),
+            _ => false,
+        }
+    }
 }
 
 fn get_test_setup(lines: &[&str]) -> Result<TestSetup, String> {
@@ -176,7 +276,7 @@ fn get_test_setup(lines: &[&str]) -> Result<TestSetup, String> {
     };
 
     if requested_tests == RequestedTest::NoTestsOnlyCompile {
-        return Ok(TestSetup { test_setup_id, tests_to_run: requested_tests, compiler, run_tests_on_qemu: false, run_tests_on_real_hardware: false });
+        return Ok(TestSetup { test_setup_id, tests_to_run: requested_tests, compiler, run_tests_on_qemu: false, run_tests_on_real_hardware: false, test_timeout: None, with_coverage: false });
     }
 
     if nr_lines < 5 {
@@ -203,7 +303,23 @@ fn get_test_setup(lines: &[&str]) -> Result<TestSetup, String> {
     }
     let run_on_real_hardware = run_on_real_hardware[0].ends_with("true");
 
-    Ok(TestSetup { test_setup_id, tests_to_run: requested_tests, compiler, run_tests_on_qemu: run_on_qemu, run_tests_on_real_hardware: run_on_real_hardware })
+    // only given when the job sets it
+    let test_timeout = lines[3..]
+        .iter()
+        .find_map(|x| x.strip_prefix("Test timeout: "));
+    let test_timeout = match test_timeout {
+        None => None,
+        Some(x) => match x.trim().parse::<u64>() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => return Err(format!("Can't extract the timeout of the tests from {x}")),
+        },
+    };
+
+    let with_coverage = lines[3..]
+        .iter()
+        .any(|x| *x == "Coverage: true");
+
+    Ok(TestSetup { test_setup_id, tests_to_run: requested_tests, compiler, run_tests_on_qemu: run_on_qemu, run_tests_on_real_hardware: run_on_real_hardware, test_timeout, with_coverage })
 }
 
 fn get_lines_from(prefix: &str, suffix: &str, encoded_lines: &str) -> Result<Vec<String>, String> {
@@ -234,67 +350,58 @@ pub(crate) fn is_valid_git_hash(hash: &str) -> bool {
         && hash.chars().all(|c| c.is_ascii_hexdigit())
 }
 
-
-pub(crate) fn report_task_data(task_id: i64, msg_str: &str) -> Result<(), String> {
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post("http://localhost:3000/update_task") // todo: hardcoded URL
-        .form(&[("task_id", format!("{task_id}")),
-            ("return_status", String::from("Running")),
-            ("output", String::from(msg_str))])
-        .send();
-    let Ok(res) = res else {
-        return Err(format!("failed update task with id: {task_id}, err:{}", res.err().unwrap()));
-    };
-
-    let inner_body = res.text_with_charset("utf-8");
-    let Ok(inner_body) = inner_body else {
-        return Err(format!("Error: failed to get text from request's reply. Err: {}", inner_body.err().unwrap()));
-    };
-
-    match inner_body.as_str() {
-        "OK" => Ok(()),
-        e => Err(format!("Error from server: {e}"))
+// Takes an exclusive flock on the file, creating it if needed, without waiting. Returns None when
+// another process holds it, be it another worker of the same machine. The lock lasts as long as the
+// returned file is open, and is released by the kernel if the worker gets killed.
+pub(crate) fn try_lock_file(path: &Path) -> std::io::Result<Option<File>> {
+    // only the lock matters, what the file contains is kept
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
+    let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if res == 0 {
+        return Ok(Some(file));
+    }
+    let e = std::io::Error::last_os_error();
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock => Ok(None),
+        _ => Err(e),
     }
 }
 
-pub(crate) fn report_task_error(task_id: i64, err_str: &str, ret_code: i64) -> Result<(), String> {
-    println!("Reporting task error: [{err_str}]");
-
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post("http://localhost:3000/update_task") // todo: hardcoded URL
-        .form(&[("task_id", format!("{task_id}")),
-            ("return_status", String::from("Failed")),
-            ("ret_code", format!("{ret_code}")),
-            ("output", String::from(err_str))])
-        .send();
-    let Ok(res) = res else {
-        let err_msg = format!("failed update task with id: {task_id}, err:{}", res.err().unwrap());
-        println!("Failed to report error: [{err_msg}]");
-        return Err(err_msg);
-    };
 
-    let inner_body = res.text_with_charset("utf-8");
-    let Ok(inner_body) = inner_body else {
-        let err_msg = format!("Error: failed to get text from request's reply. Err: {}", inner_body.err().unwrap());
-        println!("error {err_msg}");
-        return Err(err_msg);
-    };
+pub(crate) fn report_task_data(task_id: i64, msg_str: &str) {
+    send_report(Report::Task {
+        task_id,
+        return_status: String::from("Running"),
+        ret_code: None,
+        output: String::from(msg_str),
+        resource_usage: None,
+    });
+}
 
-    println!("Reported task error done. Answer from server: [{e}]", e = inner_body.as_str());
+pub(crate) fn report_task_error(task_id: i64, err_str: &str, ret_code: i64) {
+    println!("Reporting task error: [{err_str}]");
 
-    match inner_body.as_str() {
-        "OK" => Ok(()),
-        e => Err(format!("Error from server: {e}"))
-    }
+    send_report(Report::Task {
+        task_id,
+        return_status: String::from("Failed"),
+        ret_code: Some(ret_code),
+        output: String::from(err_str),
+        resource_usage: None,
+    });
 }
 
+#[derive(Clone, Copy)]
 pub(crate) enum FinishStatus {
     Success,
     Failed(i64),
     Timeout,
     Skipped,
+    // killed, or unable to start processes, because of the limits of its cgroup
+    ResourceLimit,
 }
 
 impl FinishStatus {
@@ -314,66 +421,54 @@ impl Debug for FinishStatus {
             FinishStatus::Failed(_) => { "Failed" }
             FinishStatus::Timeout => { "Timeout" }
             FinishStatus::Skipped => { "Skipped" }
+            FinishStatus::ResourceLimit => { "ResourceLimit" }
         };
         write!(f, "{}", s)
     }
 }
 
-pub(crate) fn report_task_finish(task_id: i64, msg_str: &str, end_status: FinishStatus) -> Result<(), String> {
+pub(crate) fn report_task_finish(task_id: i64, msg_str: &str, end_status: FinishStatus, resource_usage: Option<ResourceUsage>) {
     println!("Reporting task finished: {msg_str}");
 
-    let ret_code_str = match end_status {
+    let ret_code = match end_status {
         FinishStatus::Skipped
-        | FinishStatus::Success => { String::from("0") }
-        FinishStatus::Failed(e) => { format!("{e}") }
-        FinishStatus::Timeout => { String::from("124") }
-    };
-
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post("http://localhost:3000/update_task") // todo: hardcoded URL
-        .form(&[("task_id", format!("{task_id}")),
-            ("return_status", format!("{end_status:?}")),
-            ("ret_code", ret_code_str),
-            ("output", String::from(msg_str))])
-        .send();
-    let Ok(res) = res else {
-        return Err(format!("failed update task with id: {task_id}, err:{}", res.err().unwrap()));
-    };
-
-    let inner_body = res.text_with_charset("utf-8");
-    let Ok(inner_body) = inner_body else {
-        return Err(format!("Error: failed to get text from request's reply. Err: {}", inner_body.err().unwrap()));
+        | FinishStatus::Success => { 0 }
+        FinishStatus::Failed(e) => { e }
+        FinishStatus::Timeout => { 124 }
+        // as if killed by SIGKILL, like the OOM killer does
+        FinishStatus::ResourceLimit => { 137 }
     };
 
-    match inner_body.as_str() {
-        "OK" => Ok(()),
-        e => Err(format!("Error from server: {e}"))
-    }
+    send_report(Report::Task {
+        task_id,
+        return_status: format!("{end_status:?}"),
+        ret_code: Some(ret_code),
+        output: String::from(msg_str),
+        resource_usage,
+    });
 }
 
-pub(crate) fn report_task_started(task_id: i64) -> Result<(), String> {
-    let task_id = format!("{task_id}");
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post("http://localhost:3000/update_task") // todo: hardcoded URL
-        .form(&[("task_id", task_id.as_str()),
-            ("return_status", "Running"),
-            ("output", "")])
-        .send();
-    let Ok(res) = res else {
-        return Err(format!("failed update task with id: {task_id}, err:{}", res.err().unwrap()));
-    };
+// Artifacts are text files made by a task, downloadable from the page of the build. Sending one
+// with the same name again replaces it.
+pub(crate) fn report_task_artifact(task_id: i64, name: &str, content: &str) {
+    send_report(Report::Artifact {
+        task_id,
+        name: String::from(name),
+        content: String::from(content),
+    });
+}
 
-    let inner_body = res.text_with_charset("utf-8");
-    let Ok(inner_body) = inner_body else {
-        return Err(format!("Error: failed to get text from request's reply. Err: {}", inner_body.err().unwrap()));
-    };
+// Tells the server which tests the task runs, and on which targets, e.g. "qemu real_hardware"
+pub(crate) fn report_test_list(task_id: i64, tests: &[String], targets: &'static str) {
+    send_report(Report::TestList {
+        task_id,
+        tests: tests.join(" "),
+        targets,
+    });
+}
 
-    match inner_body.as_str() {
-        "OK" => Ok(()),
-        e => Err(format!("Error from server: {e}"))
-    }
+pub(crate) fn report_task_started(task_id: i64) {
+    report_task_data(task_id, "");
 }
 
 pub static mut TERM: OnceCell<AtomicU8> = OnceCell::new();
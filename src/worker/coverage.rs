@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::Instant;
+use crate::common::{Compiler, report_task_data};
+use crate::reporter::{Report, send_report};
+use crate::resource_limits::ResourceLimits;
+use crate::run_command::run_sandboxed_proc;
+use crate::sandbox::Sandbox;
+use crate::warnings_baseline::get_branch_commits;
+
+// Jobs can ask for the coverage of their tests. The test task is then built with `--coverage`, and
+// the tests write how often each line and branch ran next to the objects, in the build directory,
+// when they exit on qemu. lcov sums that up per source file, and the line and branch counts of the
+// files of the checkout are sent to the server, along with the commits of the base branch to find
+// the build the coverage gets compared with.
+
+// path to the lcov binary to use
+pub(crate) const LCOV_ENV_VAR: &'static str = "MINI_WORKER_LCOV";
+// gcov of the same version as each compiler, e.g. arm-none-eabi-gcov
+pub(crate) const GCOV_FOR_GCC_FROM_HARDWARE_VENDOR_ENV_VAR: &'static str = "MINI_WORKER_GCOV_FOR_GCC_FROM_HARDWARE_VENDOR";
+pub(crate) const GCOV_FOR_GCC_FROM_DISTRO_ENV_VAR: &'static str = "MINI_WORKER_GCOV_FOR_GCC_FROM_DISTRO";
+// branch whose previous build the coverage is compared with
+pub(crate) const COVERAGE_BASE_BRANCH_ENV_VAR: &'static str = "MINI_WORKER_COVERAGE_BASE_BRANCH";
+
+const DEFAULT_BASE_BRANCH: &'static str = "main";
+const TRACEFILE_NAME: &'static str = "coverage.info";
+
+// Given to cmake through the environment rather than as cache variables, so the flags set by the
+// toolchain file are kept. They are only read when configuring a fresh build directory.
+pub(crate) const COVERAGE_BUILD_ENVS: [(&'static str, &'static str); 3] = [
+    ("CFLAGS", "--coverage"),
+    ("CXXFLAGS", "--coverage"),
+    ("LDFLAGS", "--coverage"),
+];
+
+#[derive(Default)]
+struct FileCoverage {
+    lines_found: u64,
+    lines_hit: u64,
+    branches_found: u64,
+    branches_hit: u64,
+}
+
+fn get_gcov_tool(compiler: &Compiler) -> String {
+    let env_var = match compiler {
+        Compiler::GccFromHardwareVendor => GCOV_FOR_GCC_FROM_HARDWARE_VENDOR_ENV_VAR,
+        Compiler::GccFromDistro => GCOV_FOR_GCC_FROM_DISTRO_ENV_VAR,
+    };
+    std::env::var(env_var).ok().filter(|x| !x.is_empty()).unwrap_or_else(|| String::from("gcov"))
+}
+
+// The summary of each record of the tracefile made by lcov, keyed by the path of the source
+// relative to the checkout. Sources outside of it, e.g. the headers of the toolchain, and the ones
+// generated in the build directory are left out.
+fn parse_tracefile(tracefile: &str, src_dir: &Path, build_dir: &Path) -> BTreeMap<String, FileCoverage> {
+    let mut res = BTreeMap::<String, FileCoverage>::new();
+    let mut current = None;
+    for line in tracefile.lines() {
+        if let Some(file) = line.strip_prefix("SF:") {
+            let path = Path::new(file);
+            current = match path.strip_prefix(src_dir) {
+                Ok(relative) if !path.starts_with(build_dir) => Some(relative.to_string_lossy().replace('\t', " ")),
+                _ => None,
+            };
+            continue;
+        }
+        if line == "end_of_record" {
+            current = None;
+            continue;
+        }
+        let Some(file) = &current else {
+            continue;
+        };
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse::<u64>() else {
+            continue;
+        };
+        let coverage = res.entry(file.clone()).or_default();
+        match key {
+            "LF" => coverage.lines_found += value,
+            "LH" => coverage.lines_hit += value,
+            "BRF" => coverage.branches_found += value,
+            "BRH" => coverage.branches_hit += value,
+            _ => (),
+        }
+    }
+    res
+}
+
+// Runs once the tests are done, whether they passed or not
+pub(crate) fn collect_coverage(task_id: i64, src_dir: &Path, build_dir: &Path, compiler: &Compiler, git_mirror_path: &OsStr,
+                               sandbox: Option<&Sandbox>, limits: Option<&ResourceLimits>, deadline: Option<Instant>) -> Result<(), String> {
+    report_task_data(task_id, "Collecting the coverage of the tests");
+    let lcov = std::env::var_os(LCOV_ENV_VAR).unwrap_or_else(|| "lcov".into());
+    let gcov_tool = get_gcov_tool(compiler);
+    let build_dir_str = build_dir.to_string_lossy().into_owned();
+    let tracefile = build_dir.join(TRACEFILE_NAME);
+    let tracefile_str = tracefile.to_string_lossy().into_owned();
+    let params = ["--capture", "--directory", build_dir_str.as_str(), "--output-file", tracefile_str.as_str(),
+        "--gcov-tool", gcov_tool.as_str(), "--rc", "lcov_branch_coverage=1", "--quiet"];
+    let task_output = run_sandboxed_proc(task_id, sandbox, limits, deadline, lcov.as_os_str(), params.as_slice(), &[], None);
+    if !task_output.success() {
+        return Err(String::from("lcov failed to collect the coverage of the tests"));
+    }
+
+    let content = std::fs::read_to_string(tracefile.as_path());
+    let Ok(content) = content else {
+        return Err(format!("failed to read the coverage made by lcov in {tracefile_str}: {}", content.err().unwrap()));
+    };
+    let coverage = parse_tracefile(content.as_str(), src_dir, build_dir);
+    if coverage.is_empty() {
+        report_task_data(task_id, "The tests didn't record any coverage of the sources");
+        return Ok(());
+    }
+    let msg = format!("Got the coverage of {} sources", coverage.len());
+    report_task_data(task_id, msg.as_str());
+
+    let branch = std::env::var(COVERAGE_BASE_BRANCH_ENV_VAR).ok().filter(|x| !x.is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_BASE_BRANCH));
+    let baseline_commits = match get_branch_commits(git_mirror_path, branch.as_str()) {
+        Ok(commits) => commits,
+        Err(e) => {
+            let msg = format!("The coverage isn't compared with the one of branch {branch}: {e}");
+            report_task_data(task_id, msg.as_str());
+            Vec::new()
+        }
+    };
+
+    let coverage = coverage.iter()
+        .map(|(file, x)| format!("{file}\t{}\t{}\t{}\t{}\n", x.lines_found, x.lines_hit, x.branches_found, x.branches_hit))
+        .collect::<String>();
+    send_report(Report::Coverage { task_id, coverage, baseline_commits: baseline_commits.join("\n") });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tracefile_keeps_the_sources_of_the_checkout() {
+        let tracefile = "TN:\n\
+                         SF:/task/src/main.c\nFN:3,main\nDA:3,1\nDA:4,0\nLF:2\nLH:1\nBRF:2\nBRH:1\nend_of_record\n\
+                         SF:/usr/include/stdio.h\nLF:10\nLH:10\nend_of_record\n\
+                         SF:/task/src/build/generated.c\nLF:5\nLH:5\nend_of_record\n\
+                         SF:/task/src/lib/with\ttab.c\nLF:4\nLH:4\nBRF:0\nBRH:0\nend_of_record\n";
+        let coverage = parse_tracefile(tracefile, Path::new("/task/src"), Path::new("/task/src/build"));
+        assert_eq!(coverage.keys().collect::<Vec<_>>(), ["lib/with tab.c", "main.c"]);
+        let main = &coverage["main.c"];
+        assert_eq!((main.lines_found, main.lines_hit, main.branches_found, main.branches_hit), (2, 1, 2, 1));
+        let lib = &coverage["lib/with tab.c"];
+        assert_eq!((lib.lines_found, lib.lines_hit, lib.branches_found, lib.branches_hit), (4, 4, 0, 0));
+    }
+
+    #[test]
+    fn parse_tracefile_adds_up_the_records_of_the_same_source() {
+        let tracefile = "SF:/task/src/main.c\nLF:2\nLH:1\nend_of_record\n\
+                         SF:/task/src/main.c\nLF:2\nLH:2\nBRF:1\nBRH:0\nend_of_record\n\
+                         LF:100\nLH:100\n";
+        let coverage = parse_tracefile(tracefile, Path::new("/task/src"), Path::new("/task/src/build"));
+        let main = &coverage["main.c"];
+        assert_eq!((main.lines_found, main.lines_hit, main.branches_found, main.branches_hit), (4, 3, 1, 0));
+        assert_eq!(coverage.len(), 1);
+    }
+}
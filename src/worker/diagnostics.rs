@@ -0,0 +1,274 @@
+use std::path::{Component, Path, PathBuf};
+use crate::reporter::{Report, send_report};
+use crate::common::report_task_data;
+use crate::run_command::Message;
+
+// Warnings and errors of the compilers, of clang-tidy and of the static analyser are picked from
+// the output of the commands, and sent to the server along with the output. They look like
+// `<file>:<line>:<column>: <severity>: <message> [<check>]`, e.g.
+// `src/main.c:12:5: warning: unused variable 'x' [-Wunused-variable]`.
+
+// the server refuses forms above 2MB
+const MAX_DIAGNOSTICS_PER_TASK: usize = 2000;
+const MAX_MESSAGE_LEN: usize = 512;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Diagnostic {
+    // relative to the checkout when in it, so the same file has the same name in all the tasks
+    file: String,
+    line: u32,
+    column: Option<u32>,
+    severity: &'static str,
+    check: Option<String>,
+    message: String,
+}
+
+impl Diagnostic {
+    // one line per diagnostic, with its fields separated by tabs, as the server expects them
+    fn to_line(&self) -> String {
+        let column = self.column.map(|x| format!("{x}")).unwrap_or_default();
+        let check = self.check.as_deref().unwrap_or("");
+        format!("{}\t{}\t{column}\t{}\t{check}\t{}\n", self.file, self.line, self.severity, self.message)
+    }
+}
+
+pub(crate) struct DiagnosticsCollector {
+    src_dir: PathBuf,
+    // directory the command runs in, which the relative paths it prints start from
+    working_dir: PathBuf,
+    diagnostics: Vec<Diagnostic>,
+    nr_dropped: usize,
+}
+
+// Removes the terminal control sequences, e.g. the colours of `-fdiagnostics-color`
+fn strip_control_sequences(line: &str) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            res.push(c);
+            continue;
+        }
+        if chars.next() != Some('[') {
+            continue;
+        }
+        // the sequence ends with its final byte
+        for c in chars.by_ref() {
+            if ('\x40'..='\x7e').contains(&c) {
+                break;
+            }
+        }
+    }
+    res
+}
+
+// `file:line:column` or `file:line`
+fn parse_location(location: &str) -> Option<(&str, u32, Option<u32>)> {
+    let (rest, last) = location.rsplit_once(':')?;
+    let last = last.parse::<u32>().ok()?;
+    if let Some((file, line)) = rest.rsplit_once(':') {
+        if let Ok(line) = line.parse::<u32>() {
+            return (!file.is_empty()).then_some((file, line, Some(last)));
+        }
+    }
+    (!rest.is_empty()).then_some((rest, last, None))
+}
+
+// `[-Wunused-variable]`, `[-Werror=unused-variable]`, `[bugprone-use-after-move]`, or
+// `[bugprone-use-after-move,-warnings-as-errors]` at the end of the message
+fn split_check(message: &str) -> (&str, Option<String>) {
+    let Some(without_bracket) = message.strip_suffix(']') else {
+        return (message, None);
+    };
+    let Some((message_without_check, check)) = without_bracket.rsplit_once(" [") else {
+        return (message, None);
+    };
+    let is_check_name = !check.is_empty()
+        && check.chars().all(|c| c.is_ascii_alphanumeric() || "-_.,=+".contains(c));
+    if !is_check_name {
+        return (message, None);
+    }
+    let check = check.strip_suffix(",-warnings-as-errors").unwrap_or(check);
+    let check = match check.strip_prefix("-Werror=") {
+        Some(x) => format!("-W{x}"),
+        None => String::from(check),
+    };
+    (message_without_check, Some(check))
+}
+
+// the file is named as in the output, the collector makes it relative to the checkout
+fn parse_diagnostic(line: &str) -> Option<Diagnostic> {
+    // the first marker of the line is the one after the location
+    let (pos, marker, severity) = [(": warning: ", "warning"), (": error: ", "error"), (": fatal error: ", "error")]
+        .into_iter()
+        .filter_map(|(marker, severity)| line.find(marker).map(|pos| (pos, marker, severity)))
+        .min_by_key(|(pos, _, _)| *pos)?;
+
+    let (file, line_nr, column) = parse_location(&line[..pos])?;
+    let (message, check) = split_check(line[pos + marker.len()..].trim_end());
+    let mut message = message.replace('\t', " ");
+    if message.len() > MAX_MESSAGE_LEN {
+        let mut len = MAX_MESSAGE_LEN;
+        while !message.is_char_boundary(len) {
+            len -= 1;
+        }
+        message.truncate(len);
+    }
+    Some(Diagnostic { file: String::from(file), line: line_nr, column, severity, check, message })
+}
+
+// `..` and `.` are resolved without looking at the file system, the files can be gone already
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => { res.pop(); }
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+impl DiagnosticsCollector {
+    pub(crate) fn new(src_dir: &Path, working_dir: &Path) -> DiagnosticsCollector {
+        DiagnosticsCollector {
+            src_dir: normalize_path(src_dir),
+            working_dir: working_dir.to_path_buf(),
+            diagnostics: Vec::new(),
+            nr_dropped: 0,
+        }
+    }
+
+    fn get_file_name(&self, file: &str) -> String {
+        let path = normalize_path(self.working_dir.join(file).as_path());
+        let path = path.strip_prefix(&self.src_dir).unwrap_or(path.as_path());
+        path.to_string_lossy().replace('\t', " ")
+    }
+
+    pub(crate) fn add_message(&mut self, msg: &Message) {
+        match msg {
+            Message::STDOUT(s) | Message::STDERR(s) => self.add_line(s),
+            Message::SERIAL(_) => (),
+        }
+    }
+
+    pub(crate) fn add_line(&mut self, text: &str) {
+        let text = strip_control_sequences(text);
+        let Some(mut diagnostic) = parse_diagnostic(text.as_str()) else {
+            return;
+        };
+        diagnostic.file = self.get_file_name(diagnostic.file.as_str());
+        // the warnings of a header show up once per file including it
+        if self.diagnostics.contains(&diagnostic) {
+            return;
+        }
+        if self.diagnostics.len() >= MAX_DIAGNOSTICS_PER_TASK {
+            self.nr_dropped += 1;
+            return;
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    // Sends what got collected to the server, where it replaces what was sent before for the task. The
+    // server is told how many got dropped, since the findings can't be compared without them.
+    pub(crate) fn report(self, task_id: i64) {
+        if self.nr_dropped != 0 {
+            let msg = format!("Only the first {MAX_DIAGNOSTICS_PER_TASK} diagnostics are recorded, {} more got dropped\n", self.nr_dropped);
+            report_task_data(task_id, msg.as_str());
+        }
+        if self.diagnostics.is_empty() {
+            return;
+        }
+        let diagnostics = self.diagnostics.iter()
+            .map(|x| x.to_line())
+            .collect::<String>();
+        send_report(Report::Diagnostics { task_id, diagnostics, nr_dropped: self.nr_dropped });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reporter::take_reports_of_task;
+
+    #[test]
+    fn parse_diagnostic_of_gcc_and_clang_tidy() {
+        assert_eq!(parse_diagnostic("../src/main.c:12:5: warning: unused variable 'x' [-Wunused-variable]"),
+                   Some(Diagnostic { file: String::from("../src/main.c"), line: 12, column: Some(5), severity: "warning",
+                                     check: Some(String::from("-Wunused-variable")), message: String::from("unused variable 'x'") }));
+        assert_eq!(parse_diagnostic("/src/a.cpp:3:1: error: use after move [bugprone-use-after-move,-warnings-as-errors]"),
+                   Some(Diagnostic { file: String::from("/src/a.cpp"), line: 3, column: Some(1), severity: "error",
+                                     check: Some(String::from("bugprone-use-after-move")), message: String::from("use after move") }));
+        assert_eq!(parse_diagnostic("main.c:7: fatal error: stdio.h: No such file or directory"),
+                   Some(Diagnostic { file: String::from("main.c"), line: 7, column: None, severity: "error",
+                                     check: None, message: String::from("stdio.h: No such file or directory") }));
+    }
+
+    #[test]
+    fn parse_diagnostic_takes_the_werror_check_as_the_warning() {
+        let diagnostic = parse_diagnostic("main.c:3:1: error: expected ';' [-Werror=missing-semi]").unwrap();
+        assert_eq!(diagnostic.severity, "error");
+        assert_eq!(diagnostic.check.as_deref(), Some("-Wmissing-semi"));
+    }
+
+    #[test]
+    fn parse_diagnostic_keeps_brackets_which_arent_a_check() {
+        let diagnostic = parse_diagnostic("a.c:1:2: warning: comparing [a b]").unwrap();
+        assert_eq!(diagnostic.check, None);
+        assert_eq!(diagnostic.message, "comparing [a b]");
+        let diagnostic = parse_diagnostic("a.c:1:2: warning: ends with a bracket]").unwrap();
+        assert_eq!(diagnostic.check, None);
+        assert_eq!(diagnostic.message, "ends with a bracket]");
+    }
+
+    #[test]
+    fn parse_diagnostic_ignores_other_lines() {
+        assert_eq!(parse_diagnostic("[3/10] Building C object main.c.o"), None);
+        assert_eq!(parse_diagnostic("In file included from ../main.c:2:"), None);
+        assert_eq!(parse_diagnostic("warning: no location"), None);
+        assert_eq!(parse_diagnostic(":1:2: warning: no file"), None);
+        assert_eq!(parse_diagnostic("main.c:x: warning: no line"), None);
+    }
+
+    #[test]
+    fn strip_control_sequences_of_gcc_colours() {
+        assert_eq!(strip_control_sequences("\x1b[01m\x1b[Kmain.c:1:5:\x1b[m\x1b[K \x1b[01;35m\x1b[Kwarning: \x1b[m\x1b[Kunused"),
+                   "main.c:1:5: warning: unused");
+    }
+
+    #[test]
+    fn collector_names_the_files_relative_to_the_checkout_once_each() {
+        let mut collector = DiagnosticsCollector::new(Path::new("/task/src"), Path::new("/task/src/build"));
+        let line = "../inc/ok.h:1:5: warning: declaration of 'ok' shadows [-Wshadow]";
+        collector.add_line(line);
+        collector.add_line(line);
+        collector.add_line("/usr/include/stdio.h:10:1: warning: some system thing");
+        collector.add_line("\x1b[01m\x1b[K/task/src/main.c:3:1:\x1b[m\x1b[K \x1b[01;31m\x1b[Kerror: \x1b[m\x1b[Kexpected ';'");
+        collector.add_line("ninja: build stopped: subcommand failed.");
+        collector.report(2001);
+
+        let reports = take_reports_of_task(2001);
+        let [Report::Diagnostics { diagnostics, .. }] = reports.as_slice() else {
+            panic!("expected a single report of the diagnostics");
+        };
+        assert_eq!(diagnostics, "inc/ok.h\t1\t5\twarning\t-Wshadow\tdeclaration of 'ok' shadows\n\
+                                 /usr/include/stdio.h\t10\t1\twarning\t\tsome system thing\n\
+                                 main.c\t3\t1\terror\t\texpected ';'\n");
+    }
+    #[test]
+    fn collector_tells_how_many_diagnostics_got_dropped() {
+        let mut collector = DiagnosticsCollector::new(Path::new("/task"), Path::new("/task"));
+        for i in 0..MAX_DIAGNOSTICS_PER_TASK + 3 {
+            collector.add_line(format!("main.c:{}:1: warning: unused", i + 1).as_str());
+        }
+        collector.report(2002);
+
+        let reports = take_reports_of_task(2002);
+        let counts = reports.iter().find_map(|x| match x {
+            Report::Diagnostics { diagnostics, nr_dropped, .. } => Some((diagnostics.lines().count(), *nr_dropped)),
+            _ => None,
+        });
+        assert_eq!(counts, Some((MAX_DIAGNOSTICS_PER_TASK, 3)));
+    }
+}
@@ -4,6 +4,19 @@ mod update_git_repo;
 mod common;
 mod run_task;
 mod run_command;
+mod target_runner;
+mod serial_console;
+mod task_slots;
+mod reporter;
+mod report_journal;
+mod build_cache;
+mod sandbox;
+mod resource_limits;
+mod timeouts;
+mod clang_format;
+mod diagnostics;
+mod warnings_baseline;
+mod coverage;
 
 use std::ffi::OsStr;
 use std::process::ExitCode;
@@ -13,9 +26,14 @@ use std::thread::sleep;
 use std::time::{Duration, Instant};
 use reqwest;
 use reqwest::header::TE;
-use crate::common::{FOLDER_CONTAINING_A_GIT_DIR_TO_USE_AS_A_GIT_CACHE, get_exit_request_counter, is_exit_requested, MINICI_SERVER_REQUEST_URL, Task, TaskKind, TERM, WORKER_CAPABILITIES};
+use crate::common::{FOLDER_CONTAINING_A_GIT_DIR_TO_USE_AS_A_GIT_CACHE, get_exit_request_counter, get_http_client, is_exit_requested, MINICI_SERVER_REQUEST_URL, Task, TaskKind, TERM, get_task_request_form, get_worker_state, set_worker_state, WorkerState, WORKER_EXIT_REPLY, WORKER_PAUSED_REPLY};
+use crate::build_cache::PersistentBuildDir;
+use crate::reporter::{flush_reports, start_reporter};
 use crate::run_task::run_task;
-use crate::update_git_repo::{run_git_clone_in, run_git_remote_update_in};
+use crate::sandbox::{Sandbox, SANDBOX_ENV_VAR};
+use crate::resource_limits::setup_cgroups;
+use crate::task_slots::TaskSlots;
+use crate::update_git_repo::{get_git_mirror_path, prepare_git_mirror};
 
 fn set_signal_handler() -> Result<(), ExitCode> {
     let term = unsafe { TERM.get_or_init(|| { AtomicU8::new(0) }) };
@@ -41,9 +59,9 @@ fn main() -> ExitCode {
             let current_counter_value = get_exit_request_counter();
             if current_counter_value != last_seen_counter {
                 if current_counter_value == 1 {
-                    println!("Exit requested. Will wait for the ongoing task to finish.")
+                    println!("Exit requested. Will wait for the ongoing tasks to finish.")
                 } else {
-                    println!("Exit requested. Will cancel ongoing task and finish right now.");
+                    println!("Exit requested. Will cancel ongoing tasks and finish right now.");
                     return;
                 }
             }
@@ -52,63 +70,134 @@ fn main() -> ExitCode {
         }
     });
 
-    let tmp_git_mirror_dir = temp_dir::TempDir::with_prefix("Dir_for_mini_worker_gir_mirror_");
-    let Ok(tmp_git_mirror_dir) = tmp_git_mirror_dir else {
-        println!("failed to create a temporary dir: {}", tmp_git_mirror_dir.err().unwrap());
+    if is_exit_requested() { return ExitCode::SUCCESS; };
+
+    // reports left by a previous run are sent meanwhile
+    if let Err(e) = start_reporter() {
+        println!("Can't send the reports to the server: {e}");
+        return ExitCode::from(2);
+    }
+
+    let git_mirror_path = get_git_mirror_path();
+    let persistent_builds_dir = PersistentBuildDir::get_root();
+    let success = prepare_git_mirror(git_mirror_path.as_os_str(), OsStr::new(FOLDER_CONTAINING_A_GIT_DIR_TO_USE_AS_A_GIT_CACHE),
+                                     persistent_builds_dir.as_deref());
+    let Ok(()) = success else {
+        println!("Failed to prepare the git mirror: {}", success.err().unwrap());
         return ExitCode::from(2);
     };
-    println!("Creating directory [{d}] to use as git mirror", d = String::from_utf8_lossy(tmp_git_mirror_dir.path().as_os_str().as_encoded_bytes()));
 
-    if is_exit_requested() { return ExitCode::SUCCESS; };
+    // better to refuse to start than to run the tasks unsandboxed, or to fail all of them
+    if let Some(sandbox) = Sandbox::from_env(&[]) {
+        if let Err(e) = sandbox.check() {
+            println!("Sandboxing the tasks doesn't work on this machine: {e}. Set {SANDBOX_ENV_VAR}=0 to run them without sandbox");
+            return ExitCode::from(2);
+        }
+    }
 
-    let git_mirror_path = tmp_git_mirror_dir.path().as_os_str();
-    let success = run_git_clone_in(git_mirror_path, OsStr::new(FOLDER_CONTAINING_A_GIT_DIR_TO_USE_AS_A_GIT_CACHE));
-    let Ok(()) = success else {
-        println!("Failed to create a git mirror");
+    if let Err(e) = setup_cgroups() {
+        println!("Can't limit the resources of the tasks: {e}");
+        return ExitCode::from(2);
+    }
+
+    let task_slots = TaskSlots::from_env();
+    let Ok(mut task_slots) = task_slots else {
+        println!("{}", task_slots.err().unwrap());
         return ExitCode::from(2);
     };
+    println!("Executing up to {} tasks at once", task_slots.len());
 
-    'wait_loop: loop {
-        'work_loop: loop {
-            if is_exit_requested() { return ExitCode::SUCCESS; };
-
-            let client = reqwest::blocking::Client::new();
-            let res = client
-                .post(MINICI_SERVER_REQUEST_URL)
-                .form(&WORKER_CAPABILITIES)
-                .send();
-            let Ok(res) = res else {
-                println!("failed to ask for a task: {}", res.err().unwrap());
-                break 'work_loop;
-            };
+    let task_request_form = get_task_request_form();
+    let git_mirror_path = git_mirror_path.into_os_string();
+    let mut next_request_at = Instant::now();
+    loop {
+        if task_slots.reap_finished() > 0 {
+            // a slot is free, no need to wait before asking for a new task
+            next_request_at = Instant::now();
+        }
 
-            let task_str = res.text_with_charset("utf-8");
-            let Ok(task_str) = task_str else {
-                println!("Error: failed to get text from request's reply. Err: {}", task_str.err().unwrap());
-                break 'work_loop;
-            };
+        // the web server asked the worker to exit after its ongoing tasks
+        let is_draining = get_worker_state() == WorkerState::Draining;
+        if is_exit_requested() || is_draining {
+            if task_slots.is_idle() {
+                return ExitCode::SUCCESS;
+            }
+            sleep(Duration::from_millis(20));
+            continue;
+        }
+
+        // the replies to the reports tell when the worker gets resumed, until its tasks are done
+        if (get_worker_state() == WorkerState::Paused) && !task_slots.is_idle() {
+            sleep(Duration::from_millis(20));
+            continue;
+        }
+
+        let limits = task_slots.get_request_limits();
+        let (Some(limits), true) = (limits, Instant::now() >= next_request_at) else {
+            sleep(Duration::from_millis(20));
+            continue;
+        };
+        // wait before asking new tasks to avoid flooding the server with requests, unless a slot gets free
+        next_request_at = Instant::now() + Duration::from_secs(5);
+
+        let mut form = task_request_form.clone();
+        form.push(("max_tasks", format!("{}", limits.max_tasks)));
+        form.push(("max_real_hardware_tasks", format!("{}", limits.max_real_hardware_tasks)));
+        form.push(("max_other_tasks", format!("{}", limits.max_other_tasks)));
+        form.push(("running_tasks", task_slots.get_running_task_ids().iter().map(|x| format!("{x}")).collect::<Vec<_>>().join(" ")));
+        if get_worker_state() == WorkerState::Starting {
+            form.push(("just_started", String::from("true")));
+        }
 
-            println!("received task: {:?}", task_str);
-            let task = Task::from_str(task_str.as_str());
+        let res = get_http_client()
+            .post(MINICI_SERVER_REQUEST_URL)
+            .form(&form)
+            .send();
+        let Ok(res) = res else {
+            println!("failed to ask for a task: {}", res.err().unwrap());
+            continue;
+        };
+
+        let task_str = res.text_with_charset("utf-8");
+        let Ok(task_str) = task_str else {
+            println!("Error: failed to get text from request's reply. Err: {}", task_str.err().unwrap());
+            continue;
+        };
+
+        println!("received task: {:?}", task_str);
+        match task_str.as_str() {
+            WORKER_PAUSED_REPLY => {
+                println!("Worker paused from the web server. Waiting to be resumed.");
+                set_worker_state(WorkerState::Paused);
+                continue;
+            }
+            WORKER_EXIT_REPLY => {
+                println!("Worker drained from the web server. Exiting once the ongoing tasks are finished.");
+                set_worker_state(WorkerState::Draining);
+                continue;
+            }
+            x if x.starts_with("Error") => (),
+            _ => set_worker_state(WorkerState::Active),
+        }
+
+        // tasks are separated by an empty line
+        for task_str in task_str.split("\n\n").filter(|x| !x.trim().is_empty()) {
+            let task = Task::from_str(task_str);
             let Ok(task) = task else {
                 println!("Error while parsing task: {}", task.err().unwrap());
-                break 'work_loop;
+                continue;
             };
 
             println!("INFO: task parsed as {task:?}");
-            let res = run_task(task, git_mirror_path);
-            if let Err(e) = res {
-                println!("task failed with {e}");
+            let git_mirror_path = git_mirror_path.clone();
+            let res = task_slots.start(task, move |task| {
+                run_task(task, git_mirror_path.as_os_str());
+                // the slot is given back only once the server knows how the task ended
+                flush_reports();
+            });
+            if let Err(task) = res {
+                println!("Error: no free slot to execute task {}", task.id());
             }
         }
-        // wait before asking a new task to avoid flooding the server with requests
-        // but keep checking for the flag telling us to quit
-        let max_time_to_sleep = Duration::from_secs(5);
-        let instant_before_sleep = Instant::now();
-        while (Instant::now() - instant_before_sleep) < max_time_to_sleep {
-            sleep(Duration::from_millis(20));
-            if is_exit_requested() { return ExitCode::SUCCESS; };
-        }
     }
-    return ExitCode::SUCCESS;
 }
@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::common::try_lock_file;
+
+// Reports the server couldn't get are written to a journal on disk, and sent again once it is back.
+// Since the file outlives the worker, reports made before a restart still reach the server. Each
+// worker of a machine needs its own journal, otherwise one would send and remove the reports of
+// another, so the journal is locked for as long as the worker runs.
+pub(crate) const REPORT_JOURNAL_ENV_VAR: &'static str = "MINI_WORKER_REPORT_JOURNAL";
+const DEFAULT_REPORT_JOURNAL: &'static str = "/var/tmp/mini_worker_report_journal";
+
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// a request the server got but failed to handle that many times is dropped, which takes several
+// minutes with the delays between the attempts
+const MAX_FAILED_ATTEMPTS: u32 = 20;
+
+// what became of a request sent from the journal
+pub(crate) enum SendOutcome {
+    // the server took it, or refused it for good, it leaves the journal
+    Done,
+    // the server can't be reached, the request waits for it however long it takes
+    Unreachable,
+    // the server got the request but failed to handle it. Retried up to MAX_FAILED_ATTEMPTS times,
+    // so that a request it always fails on doesn't hold back the ones behind it forever
+    Failed,
+}
+
+// a request to the server, as written in the journal
+pub(crate) struct ServerRequest {
+    pub url: String,
+    pub form: Vec<(String, String)>,
+}
+
+// One request per line. The url and the form fields are separated by tabs, which are escaped in
+// the values like line returns are.
+fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '\t' => res.push_str("\\t"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => res.push('\\'),
+            Some('t') => res.push('\t'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(res)
+}
+
+impl ServerRequest {
+    fn to_journal_line(&self) -> String {
+        let mut line = escape(self.url.as_str());
+        for (key, value) in &self.form {
+            line += format!("\t{}\t{}", escape(key), escape(value)).as_str();
+        }
+        line.push('\n');
+        line
+    }
+
+    fn from_journal_line(line: &str) -> Option<ServerRequest> {
+        let mut fields = line.split('\t');
+        let url = unescape(fields.next()?)?;
+        let mut form = Vec::new();
+        while let Some(key) = fields.next() {
+            let value = fields.next()?;
+            form.push((unescape(key)?, unescape(value)?));
+        }
+        Some(ServerRequest { url, form })
+    }
+}
+
+pub(crate) struct ReportJournal {
+    path: PathBuf,
+    // held open to keep the lock
+    _lock: File,
+    requests: VecDeque<ServerRequest>,
+    retry_delay: Duration,
+    next_attempt_at: Instant,
+    // failed attempts of the first request
+    failed_attempts: u32,
+}
+
+impl ReportJournal {
+    // Loads the requests left by a previous run of the worker, if any
+    pub(crate) fn open() -> Result<ReportJournal, String> {
+        let path = std::env::var_os(REPORT_JOURNAL_ENV_VAR)
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or(PathBuf::from(DEFAULT_REPORT_JOURNAL));
+
+        // the journal itself gets replaced when rewritten, which would drop a lock taken on it
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock = match try_lock_file(PathBuf::from(lock_path).as_path()) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => return Err(format!("the report journal {path:?} is used by another worker. Set {REPORT_JOURNAL_ENV_VAR} to give each worker its own journal")),
+            Err(e) => return Err(format!("failed to lock the report journal {path:?}: {e}")),
+        };
+
+        let mut journal = ReportJournal {
+            path,
+            _lock: lock,
+            requests: VecDeque::new(),
+            retry_delay: FIRST_RETRY_DELAY,
+            next_attempt_at: Instant::now(),
+            failed_attempts: 0,
+        };
+
+        let mut content = String::new();
+        match File::open(&journal.path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => println!("Error: failed to open the report journal {:?}. Err={e}", journal.path),
+            Ok(mut file) => {
+                if let Err(e) = file.read_to_string(&mut content) {
+                    println!("Error: failed to read the report journal {:?}. Err={e}", journal.path);
+                }
+            }
+        }
+
+        // whatever follows the last line return was cut by a crash while writing it
+        let nr_lines = content.matches('\n').count();
+        for line in content.lines().take(nr_lines) {
+            match ServerRequest::from_journal_line(line) {
+                Some(request) => journal.requests.push_back(request),
+                None => println!("Ignoring invalid line in the report journal: [{line}]"),
+            }
+        }
+        if !content.ends_with('\n') && !content.is_empty() {
+            // otherwise the next request would be appended to the cut line
+            journal.rewrite();
+        }
+        if !journal.requests.is_empty() {
+            println!("Found {} reports not sent to the server yet in {:?}", journal.requests.len(), journal.path);
+        }
+        Ok(journal)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub(crate) fn get_next_attempt_at(&self) -> Instant {
+        self.next_attempt_at
+    }
+
+    // The request is kept in memory even if writing it fails, so it only gets lost if the worker
+    // stops before the server is back.
+    pub(crate) fn push(&mut self, request: ServerRequest) {
+        if self.requests.is_empty() {
+            self.retry_delay = FIRST_RETRY_DELAY;
+            self.next_attempt_at = Instant::now() + self.retry_delay;
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path);
+        let res = file.and_then(|mut file| {
+            file.write_all(request.to_journal_line().as_bytes())?;
+            file.sync_data()
+        });
+        if let Err(e) = res {
+            println!("Error: failed to write a report in the journal {:?}. Err={e}", self.path);
+        }
+        self.requests.push_back(request);
+    }
+
+    // Sends the requests in order, stopping at the first one which has to be tried again later
+    pub(crate) fn replay(&mut self, send: &mut dyn FnMut(&ServerRequest) -> SendOutcome) {
+        if self.requests.is_empty() || Instant::now() < self.next_attempt_at {
+            return;
+        }
+
+        let nr_requests = self.requests.len();
+        while let Some(request) = self.requests.front() {
+            match send(request) {
+                SendOutcome::Done => (),
+                SendOutcome::Unreachable => break,
+                SendOutcome::Failed => {
+                    self.failed_attempts += 1;
+                    if self.failed_attempts < MAX_FAILED_ATTEMPTS {
+                        break;
+                    }
+                    println!("Error: dropping a report to {} from the journal, the server failed to handle it {MAX_FAILED_ATTEMPTS} times", request.url);
+                }
+            }
+            self.failed_attempts = 0;
+            self.requests.pop_front();
+        }
+
+        if self.requests.is_empty() {
+            println!("All reports from the journal got sent to the server");
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                println!("Error: failed to remove the report journal {:?}. Err={e}", self.path);
+            }
+            return;
+        }
+
+        self.next_attempt_at = Instant::now() + self.retry_delay;
+        self.retry_delay = std::cmp::min(self.retry_delay * 2, MAX_RETRY_DELAY);
+        if self.requests.len() != nr_requests {
+            self.rewrite();
+        }
+    }
+
+    // Replaces the file at once, so a crash leaves either the old or the new list of requests
+    fn rewrite(&self) {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let content = self.requests.iter()
+            .map(|x| x.to_journal_line())
+            .collect::<String>();
+        let res = std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path));
+        if let Err(e) = res {
+            println!("Error: failed to rewrite the report journal {:?}. Err={e}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_and_unescape_round_trip() {
+        for text in ["", "plain", "tab\there", "lines\r\nof\noutput\n", "back\\slash\\t not a tab", "\\\\\t\n\r", "é ✓"] {
+            let escaped = escape(text);
+            assert!(!escaped.contains(['\t', '\n', '\r']), "[{escaped}] isn't on a single field");
+            assert_eq!(unescape(escaped.as_str()).as_deref(), Some(text));
+        }
+    }
+
+    #[test]
+    fn unescape_rejects_unknown_escapes() {
+        assert_eq!(unescape("\\x"), None);
+        assert_eq!(unescape("trailing\\"), None);
+    }
+
+    #[test]
+    fn requests_round_trip_through_a_journal_line() {
+        let request = ServerRequest {
+            url: String::from("http://localhost:3000/update_task"),
+            form: vec![(String::from("task_id"), String::from("12")),
+                       (String::from("output"), String::from("col1\tcol2\r\nnext line\\n\n")),
+                       (String::from("empty"), String::new())],
+        };
+        let line = request.to_journal_line();
+        assert_eq!(line.matches('\n').count(), 1);
+        assert!(line.ends_with('\n'));
+
+        let parsed = ServerRequest::from_journal_line(line.trim_end_matches('\n')).unwrap();
+        assert_eq!(parsed.url, request.url);
+        assert_eq!(parsed.form, request.form);
+    }
+
+    #[test]
+    fn from_journal_line_rejects_a_key_without_value() {
+        assert!(ServerRequest::from_journal_line("http://localhost:3000/update_task\ttask_id").is_none());
+    }
+
+    // without the lock of the journal of the worker, which tests running in parallel would share
+    fn make_journal(dir: &temp_dir::TempDir) -> ReportJournal {
+        ReportJournal {
+            _lock: File::create(dir.path().join("journal.lock")).unwrap(),
+            path: dir.path().join("journal"),
+            requests: VecDeque::new(),
+            retry_delay: FIRST_RETRY_DELAY,
+            next_attempt_at: Instant::now(),
+            failed_attempts: 0,
+        }
+    }
+
+    #[test]
+    fn requests_the_server_keeps_failing_on_get_dropped() {
+        let dir = temp_dir::TempDir::with_prefix("mini_worker_test_").unwrap();
+        let mut journal = make_journal(&dir);
+        for url in ["poison", "next"] {
+            journal.push(ServerRequest { url: String::from(url), form: Vec::new() });
+        }
+
+        let mut sent = Vec::new();
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            journal.next_attempt_at = Instant::now();
+            journal.replay(&mut |request| {
+                sent.push(request.url.clone());
+                match request.url.as_str() {
+                    "poison" => SendOutcome::Failed,
+                    _ => SendOutcome::Done,
+                }
+            });
+        }
+        assert!(journal.is_empty());
+        assert_eq!(sent.iter().filter(|x| *x == "poison").count(), MAX_FAILED_ATTEMPTS as usize);
+        assert_eq!(sent.last().map(String::as_str), Some("next"));
+    }
+
+    #[test]
+    fn requests_wait_for_an_unreachable_server() {
+        let dir = temp_dir::TempDir::with_prefix("mini_worker_test_").unwrap();
+        let mut journal = make_journal(&dir);
+        journal.push(ServerRequest { url: String::from("update_task"), form: Vec::new() });
+
+        for _ in 0..(2 * MAX_FAILED_ATTEMPTS) {
+            journal.next_attempt_at = Instant::now();
+            journal.replay(&mut |_| SendOutcome::Unreachable);
+        }
+        assert!(!journal.is_empty());
+    }
+}
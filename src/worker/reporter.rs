@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use crate::common::{get_http_client, is_immediate_exit_requested, set_worker_state_from_report_reply, MINICI_SERVER_ADD_TASK_ARTIFACT, MINICI_SERVER_ADD_TEST_TEST_LIST, MINICI_SERVER_ADD_TASK_COVERAGE, MINICI_SERVER_ADD_TASK_DIAGNOSTICS, MINICI_SERVER_REPORT_TEST_CHANGE, MINICI_SERVER_UPDATE_TASK};
+use crate::report_journal::{ReportJournal, SendOutcome, ServerRequest};
+use crate::resource_limits::ResourceUsage;
+
+// Reports are sent to the server by a background thread, so tasks keep running while the server
+// processes them. Outputs made close to each other are merged into a single request, and reports
+// reach the server in the order they were made. The ones the server can't get wait in the journal.
+// A report gets sent again when its reply is lost, so the ones appending outputs are numbered per
+// task, in the order they are sent, and the server ignores the numbers it already applied.
+
+// how long to wait for more reports before sending the pending ones
+const BATCH_WINDOW: Duration = Duration::from_millis(200);
+// the server refuses forms above 2MB
+const MAX_OUTPUT_PER_REPORT: usize = 512 * 1024;
+// stop waiting for more reports once that much output is pending
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+
+pub(crate) enum Report {
+    Task {
+        task_id: i64,
+        return_status: String,
+        ret_code: Option<i64>,
+        output: String,
+        resource_usage: Option<ResourceUsage>,
+    },
+    Test {
+        task_id: i64,
+        test_name: String,
+        target: &'static str,
+        operation: &'static str,
+        status: Option<String>,
+        output: Option<String>,
+    },
+    // the tests the task is about to run, which must reach the server before the reports about them
+    TestList {
+        task_id: i64,
+        // separated by spaces
+        tests: String,
+        targets: &'static str,
+    },
+    Artifact {
+        task_id: i64,
+        name: String,
+        content: String,
+    },
+    Diagnostics {
+        task_id: i64,
+        // one diagnostic per line
+        diagnostics: String,
+        // the ones past the maximum per task
+        nr_dropped: usize,
+    },
+    Coverage {
+        task_id: i64,
+        // one file per line
+        coverage: String,
+        // commits of the branch to compare the coverage with, one per line
+        baseline_commits: String,
+    },
+}
+
+impl Report {
+    // `seq` is the number of the report among the ones of its task
+    fn to_server_request(&self, seq: i64) -> ServerRequest {
+        let (url, form) = match self {
+            Report::Task { task_id, return_status, ret_code, output, resource_usage } => {
+                let mut form = vec![("task_id", format!("{task_id}")),
+                                    ("return_status", return_status.clone()),
+                                    ("output", output.clone()),
+                                    ("seq", format!("{seq}"))];
+                if let Some(ret_code) = ret_code {
+                    form.push(("ret_code", format!("{ret_code}")));
+                }
+                if let Some(resource_usage) = resource_usage {
+                    if let Some(peak_memory_bytes) = resource_usage.peak_memory_bytes {
+                        form.push(("peak_memory_bytes", format!("{peak_memory_bytes}")));
+                    }
+                    form.push(("cpu_time_ms", format!("{}", resource_usage.cpu_time_ms)));
+                }
+                (MINICI_SERVER_UPDATE_TASK, form)
+            }
+            Report::Test { task_id, test_name, target, operation, status, output } => {
+                let mut form = vec![("task_id", format!("{task_id}")),
+                                    ("test_name", test_name.clone()),
+                                    ("operation", String::from(*operation)),
+                                    ("target", String::from(*target)),
+                                    ("seq", format!("{seq}"))];
+                if let Some(status) = status {
+                    form.push(("status", status.clone()));
+                }
+                if let Some(output) = output {
+                    form.push(("output", output.clone()));
+                }
+                (MINICI_SERVER_REPORT_TEST_CHANGE, form)
+            }
+            Report::TestList { task_id, tests, targets } => {
+                let form = vec![("task_id", format!("{task_id}")),
+                                ("tests_to_add", tests.clone()),
+                                ("targets", String::from(*targets)),
+                                ("seq", format!("{seq}"))];
+                (MINICI_SERVER_ADD_TEST_TEST_LIST, form)
+            }
+            Report::Artifact { task_id, name, content } => {
+                let form = vec![("task_id", format!("{task_id}")),
+                                ("name", name.clone()),
+                                ("content", content.clone())];
+                (MINICI_SERVER_ADD_TASK_ARTIFACT, form)
+            }
+            Report::Diagnostics { task_id, diagnostics, nr_dropped } => {
+                let form = vec![("task_id", format!("{task_id}")),
+                                ("diagnostics", diagnostics.clone()),
+                                ("nr_dropped", format!("{nr_dropped}"))];
+                (MINICI_SERVER_ADD_TASK_DIAGNOSTICS, form)
+            }
+            Report::Coverage { task_id, coverage, baseline_commits } => {
+                let form = vec![("task_id", format!("{task_id}")),
+                                ("coverage", coverage.clone()),
+                                ("baseline_commits", baseline_commits.clone())];
+                (MINICI_SERVER_ADD_TASK_COVERAGE, form)
+            }
+        };
+        ServerRequest {
+            url: String::from(url),
+            form: form.into_iter().map(|(key, value)| (String::from(key), value)).collect(),
+        }
+    }
+
+    // reports about the same task, or the same test, must reach the server in order
+    fn is_same_stream_as(&self, other: &Report) -> bool {
+        match (self, other) {
+            (Report::Test { task_id: a, test_name: a_name, target: a_target, .. },
+                Report::Test { task_id: b, test_name: b_name, target: b_target, .. }) =>
+                a == b && a_name == b_name && a_target == b_target,
+            (Report::Test { .. }, _) | (_, Report::Test { .. }) => false,
+            // the artifacts, diagnostics and coverage of a task are there before it gets reported as finished
+            _ => self.get_task_id() == other.get_task_id(),
+        }
+    }
+
+    // no more reports come after the final status of the task
+    fn is_last_of_task(&self) -> bool {
+        matches!(self, Report::Task { return_status, .. } if return_status != "Running")
+    }
+
+    fn get_task_id(&self) -> i64 {
+        match self {
+            Report::Task { task_id, .. }
+            | Report::Test { task_id, .. }
+            | Report::TestList { task_id, .. }
+            | Report::Artifact { task_id, .. }
+            | Report::Diagnostics { task_id, .. }
+            | Report::Coverage { task_id, .. } => *task_id,
+        }
+    }
+
+    // only the output of running tasks and tests can be merged with the one of the next report
+    fn get_mergeable_output(&mut self) -> Option<&mut String> {
+        match self {
+            Report::Task { return_status, output, .. } if return_status == "Running" => Some(output),
+            Report::Test { operation: "Progress", output: Some(output), .. } => Some(output),
+            _ => None,
+        }
+    }
+
+    fn get_output_len(&self) -> usize {
+        match self {
+            Report::Task { output, .. } => output.len(),
+            Report::Test { output, .. } => output.as_ref().map_or(0, |x| x.len()),
+            Report::TestList { tests, .. } => tests.len(),
+            Report::Artifact { content, .. } => content.len(),
+            Report::Diagnostics { diagnostics, .. } => diagnostics.len(),
+            Report::Coverage { coverage, .. } => coverage.len(),
+        }
+    }
+}
+
+enum ReporterMessage {
+    Report(Report),
+    // answered once everything reported before got to the server, or to the journal
+    Flush(Sender<()>),
+    // answered once everything reported before got to the server, the journal included
+    WaitForDelivery(Sender<()>),
+}
+
+static REPORTER: OnceLock<Sender<ReporterMessage>> = OnceLock::new();
+
+#[cfg(not(test))]
+fn get_reporter() -> &'static Sender<ReporterMessage> {
+    REPORTER.get().expect("the reporter must be started before sending reports")
+}
+
+// The tests have no server to send the reports to. They are kept instead, for the tests to check
+// what their task reported.
+#[cfg(test)]
+static REPORTS_OF_TESTS: std::sync::Mutex<Vec<Report>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(test)]
+fn get_reporter() -> &'static Sender<ReporterMessage> {
+    REPORTER.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for msg in rx {
+                match msg {
+                    ReporterMessage::Report(report) => REPORTS_OF_TESTS.lock().unwrap().push(report),
+                    ReporterMessage::Flush(ack)
+                    | ReporterMessage::WaitForDelivery(ack) => { let _ = ack.send(()); }
+                }
+            }
+        });
+        tx
+    })
+}
+
+// The reports of the task made so far, in the order they were made
+#[cfg(test)]
+pub(crate) fn take_reports_of_task(task_id: i64) -> Vec<Report> {
+    flush_reports();
+    let mut reports = REPORTS_OF_TESTS.lock().unwrap();
+    let (of_task, others) = std::mem::take(&mut *reports).into_iter()
+        .partition(|x| x.get_task_id() == task_id);
+    *reports = others;
+    of_task
+}
+
+pub(crate) fn send_report(report: Report) {
+    get_reporter().send(ReporterMessage::Report(report)).expect("the reporter thread stopped");
+}
+
+// Takes the journal, and starts sending what is left in it without waiting for the first report
+pub(crate) fn start_reporter() -> Result<(), String> {
+    let journal = ReportJournal::open()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || run_reporter(rx, journal));
+    REPORTER.set(tx).map_err(|_| String::from("the reporter is already started"))
+}
+
+// Blocks until everything reported so far got to the server, or to the journal
+pub(crate) fn flush_reports() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    get_reporter().send(ReporterMessage::Flush(tx)).expect("the reporter thread stopped");
+    let _ = rx.recv();
+}
+
+// Blocks until everything reported so far got to the server, which takes as long as the server
+// can't be reached. Returns false if the worker is asked to stop immediately meanwhile.
+pub(crate) fn wait_for_reports_delivery() -> bool {
+    let (tx, rx) = std::sync::mpsc::channel();
+    get_reporter().send(ReporterMessage::WaitForDelivery(tx)).expect("the reporter thread stopped");
+    loop {
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => return true,
+            Err(RecvTimeoutError::Timeout) if is_immediate_exit_requested() => return false,
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+    }
+}
+
+// Appends the output to the last pending report of the same stream when possible. Merging never
+// moves an output after a report made later, so the order seen by the server stays the same.
+fn add_to_batch(batch: &mut Vec<Report>, mut report: Report) {
+    let last_of_stream = batch.iter_mut().rev().find(|x| x.is_same_stream_as(&report));
+    if let Some(last_of_stream) = last_of_stream {
+        if let (Some(pending_output), Some(output)) = (last_of_stream.get_mergeable_output(), report.get_mergeable_output()) {
+            if pending_output.len() + output.len() <= MAX_OUTPUT_PER_REPORT {
+                pending_output.push_str(output.as_str());
+                return;
+            }
+        }
+    }
+    batch.push(report);
+}
+
+fn run_reporter(rx: Receiver<ReporterMessage>, mut journal: ReportJournal) {
+    let client = get_http_client();
+    // number of the last report sent for each running task
+    let mut last_seqs = HashMap::<i64, i64>::new();
+    let mut delivery_waiters = Vec::<Sender<()>>::new();
+    loop {
+        journal.replay(&mut |request| match send(client, request) {
+            Ok(()) => SendOutcome::Done,
+            Err(DeliveryError::Rejected(e)) => {
+                println!("Error: the server rejected a report from the journal to {}. {e}", request.url);
+                SendOutcome::Done
+            }
+            Err(DeliveryError::Unreachable(_)) => SendOutcome::Unreachable,
+            Err(DeliveryError::Failed(e)) => {
+                println!("Error: the server failed to handle a report from the journal to {}. {e}", request.url);
+                SendOutcome::Failed
+            }
+        });
+        if journal.is_empty() {
+            for ack in delivery_waiters.drain(..) {
+                let _ = ack.send(());
+            }
+        }
+
+        // wake up in time to send the journal again
+        let msg = if journal.is_empty() {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(journal.get_next_attempt_at().saturating_duration_since(Instant::now()))
+        };
+        let mut msg = match msg {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let mut batch = Vec::new();
+        let mut flush_requests = Vec::new();
+        let mut pending_output = 0;
+        let deadline = Instant::now() + BATCH_WINDOW;
+        loop {
+            match msg {
+                ReporterMessage::Report(report) => {
+                    pending_output += report.get_output_len();
+                    add_to_batch(&mut batch, report);
+                }
+                ReporterMessage::Flush(ack) => flush_requests.push(ack),
+                // answered at the top of the loop, once the journal is empty
+                ReporterMessage::WaitForDelivery(ack) => delivery_waiters.push(ack),
+            }
+            if !flush_requests.is_empty() || !delivery_waiters.is_empty() || pending_output >= MAX_PENDING_OUTPUT {
+                break;
+            }
+            let Ok(next_msg) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) else {
+                break;
+            };
+            msg = next_msg;
+        }
+
+        for report in batch {
+            let seq = last_seqs.entry(report.get_task_id()).or_insert(0);
+            *seq += 1;
+            let request = report.to_server_request(*seq);
+            if report.is_last_of_task() {
+                last_seqs.remove(&report.get_task_id());
+            }
+            // reports go after the ones already waiting in the journal, to keep them in order
+            if !journal.is_empty() {
+                journal.push(request);
+                continue;
+            }
+            match send(client, &request) {
+                Ok(()) => (),
+                Err(DeliveryError::Rejected(e)) => println!("Error: the server rejected a report to {}. {e}", request.url),
+                Err(DeliveryError::Unreachable(e) | DeliveryError::Failed(e)) => {
+                    println!("Failed to send a report to {}, keeping it in the journal to try again later. Err: {e}", request.url);
+                    journal.push(request);
+                }
+            }
+        }
+        for ack in flush_requests {
+            let _ = ack.send(());
+        }
+    }
+}
+
+enum DeliveryError {
+    // the request didn't make it to the server, trying again once it is back will work
+    Unreachable(String),
+    // the request might have made it, but handling it failed or the reply was lost. Trying again might
+    // work, or fail the same way
+    Failed(String),
+    // the server explicitly refused it with a 4xx status, trying again won't work
+    Rejected(String),
+}
+
+// the page of a proxy in front of the server can be long
+const MAX_REPLY_IN_ERROR: usize = 200;
+
+fn send(client: &reqwest::blocking::Client, request: &ServerRequest) -> Result<(), DeliveryError> {
+    let res = client
+        .post(request.url.as_str())
+        .form(&request.form)
+        .send();
+    let res = match res {
+        Ok(res) => res,
+        Err(e) if e.is_connect() => return Err(DeliveryError::Unreachable(format!("{e}"))),
+        // e.g. the connection closed when the handler of the server panicked
+        Err(e) => return Err(DeliveryError::Failed(format!("{e}"))),
+    };
+
+    let status = res.status();
+    let inner_body = res.text_with_charset("utf-8");
+    let Ok(inner_body) = inner_body else {
+        return Err(DeliveryError::Failed(format!("failed to get text from request's reply. Err: {}", inner_body.err().unwrap())));
+    };
+
+    let mut lines = inner_body.lines();
+    if status.is_success() && (lines.next() == Some("OK")) {
+        // only the replies about tasks and tests carry the state of the worker
+        if [MINICI_SERVER_UPDATE_TASK, MINICI_SERVER_REPORT_TEST_CHANGE].contains(&request.url.as_str()) {
+            set_worker_state_from_report_reply(lines.next());
+        }
+        return Ok(());
+    }
+    let reply = match inner_body.char_indices().nth(MAX_REPLY_IN_ERROR) {
+        Some((end, _)) => format!("{}...", &inner_body[..end]),
+        None => inner_body,
+    };
+    if status.is_client_error() {
+        return Err(DeliveryError::Rejected(format!("Error from server ({status}): {reply}")));
+    }
+    // e.g. a 500 when the database failed, or a 502 from a proxy while the server restarts
+    Err(DeliveryError::Failed(format!("unexpected reply ({status}): {reply}")))
+}
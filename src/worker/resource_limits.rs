@@ -0,0 +1,301 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+// The commands of a task, and each test, run in their own cgroup, so one going over its limits only
+// takes itself down, and not the tasks or qemu instances running next to it. The cgroups are made in
+// MINI_WORKER_CGROUP, the cgroup of the worker by default, which must be delegated to the worker when
+// it doesn't run as root. Nothing is done unless one of these variables is set.
+pub(crate) const CGROUP_ENV_VAR: &'static str = "MINI_WORKER_CGROUP";
+// in bytes, or with a K, M or G suffix
+pub(crate) const MEMORY_LIMIT_ENV_VAR: &'static str = "MINI_WORKER_MEMORY_LIMIT";
+// in number of cpus, e.g. 1.5
+pub(crate) const CPU_LIMIT_ENV_VAR: &'static str = "MINI_WORKER_CPU_LIMIT";
+pub(crate) const PIDS_LIMIT_ENV_VAR: &'static str = "MINI_WORKER_PIDS_LIMIT";
+
+const CGROUP_FS: &'static str = "/sys/fs/cgroup";
+const CPU_PERIOD_USECS: u64 = 100_000;
+
+struct CgroupConfig {
+    root: PathBuf,
+    memory_max: Option<u64>,
+    cpu_max: Option<f64>,
+    pids_max: Option<u64>,
+}
+
+static CGROUP_CONFIG: OnceLock<CgroupConfig> = OnceLock::new();
+
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, unit) = match text.char_indices().last()? {
+        (i, 'K' | 'k') => (&text[..i], 1024),
+        (i, 'M' | 'm') => (&text[..i], 1024 * 1024),
+        (i, 'G' | 'g') => (&text[..i], 1024 * 1024 * 1024),
+        _ => (text, 1),
+    };
+    number.parse::<u64>().ok().filter(|x| *x > 0).map(|x| x * unit)
+}
+
+fn get_limit_from_env<T>(name: &str, parse: fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+    match std::env::var(name) {
+        Err(_) => Ok(None),
+        Ok(x) if x.is_empty() => Ok(None),
+        Ok(x) => match parse(x.as_str()) {
+            Some(limit) => Ok(Some(limit)),
+            None => Err(format!("Invalid value [{x}] of {name}")),
+        },
+    }
+}
+
+fn write_cgroup_file(dir: &Path, name: &str, content: &str) -> Result<(), String> {
+    std::fs::write(dir.join(name), content)
+        .map_err(|e| format!("Failed to write [{content}] to {:?}. Err={e}", dir.join(name)))
+}
+
+fn read_cgroup_file(dir: &Path, name: &str) -> Option<String> {
+    std::fs::read_to_string(dir.join(name)).ok()
+}
+
+// Reads a counter from files made of `<key> <value>` lines, like memory.events or cpu.stat
+fn read_cgroup_counter(dir: &Path, name: &str, key: &str) -> Option<u64> {
+    read_cgroup_file(dir, name)?
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(' ')?.trim().parse::<u64>().ok())
+}
+
+fn get_own_cgroup() -> Result<PathBuf, String> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup");
+    let Ok(cgroups) = cgroups else {
+        return Err(format!("Failed to read /proc/self/cgroup. Err={}", cgroups.err().unwrap()));
+    };
+    // the line of cgroup v2 is the one of hierarchy 0, without controllers
+    let Some(path) = cgroups.lines().find_map(|x| x.strip_prefix("0::")) else {
+        return Err(String::from("The worker isn't in any cgroup v2"));
+    };
+    Ok(Path::new(CGROUP_FS).join(path.trim_start_matches('/')))
+}
+
+// Kills what is left in the cgroup and removes it
+fn remove_cgroup(dir: &Path) -> Result<(), String> {
+    // cgroup.kill only exists since linux 5.14. Before that, the processes left are waited for
+    let _ = std::fs::write(dir.join("cgroup.kill"), "1");
+    let mut res = std::fs::remove_dir(dir);
+    for _ in 0..100 {
+        if res.is_ok() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        res = std::fs::remove_dir(dir);
+    }
+    res.map_err(|e| format!("Failed to remove cgroup {dir:?}. Err={e}"))
+}
+
+// Called once at startup. The worker moves itself to a leaf cgroup, since a cgroup can't both have
+// processes and give controllers to its children.
+pub(crate) fn setup_cgroups() -> Result<(), String> {
+    let memory_max = get_limit_from_env(MEMORY_LIMIT_ENV_VAR, parse_size)?;
+    let cpu_max = get_limit_from_env(CPU_LIMIT_ENV_VAR, |x| x.parse::<f64>().ok().filter(|x| *x > 0.0))?;
+    let pids_max = get_limit_from_env(PIDS_LIMIT_ENV_VAR, |x| x.parse::<u64>().ok().filter(|x| *x > 0))?;
+    let root = std::env::var_os(CGROUP_ENV_VAR).filter(|x| !x.is_empty()).map(PathBuf::from);
+    if root.is_none() && memory_max.is_none() && cpu_max.is_none() && pids_max.is_none() {
+        return Ok(());
+    }
+
+    let own_cgroup = get_own_cgroup()?;
+    let root = root.unwrap_or(own_cgroup.clone());
+    if !root.join("cgroup.controllers").exists() {
+        return Err(format!("{root:?} isn't a cgroup v2. Set {CGROUP_ENV_VAR} to one the worker can manage"));
+    }
+
+    if own_cgroup == root {
+        let worker_cgroup = root.join("worker");
+        if let Err(e) = std::fs::create_dir_all(&worker_cgroup) {
+            return Err(format!("Failed to create cgroup {worker_cgroup:?}. Err={e}"));
+        }
+        write_cgroup_file(worker_cgroup.as_path(), "cgroup.procs", format!("{}", std::process::id()).as_str())?;
+    }
+
+    // left by a previous run of the worker
+    if let Ok(entries) = std::fs::read_dir(&root) {
+        for entry in entries.flatten() {
+            if entry.file_name().as_bytes().starts_with(b"task_") {
+                if let Err(e) = remove_cgroup(entry.path().as_path()) {
+                    println!("{e}");
+                }
+            }
+        }
+    }
+
+    // the memory controller also gives the peak memory, it is fine to go without it if no limit is set
+    for (controller, is_required) in [("memory", memory_max.is_some()), ("cpu", cpu_max.is_some()), ("pids", pids_max.is_some())] {
+        let res = write_cgroup_file(root.as_path(), "cgroup.subtree_control", format!("+{controller}").as_str());
+        match res {
+            Err(e) if is_required => return Err(e),
+            Err(_) if controller == "memory" => println!("The memory controller isn't available in {root:?}, the peak memory of tasks won't be known"),
+            Err(_) => (),
+            Ok(()) => (),
+        }
+    }
+
+    println!("Running the tasks in cgroups of {root:?}");
+    let _ = CGROUP_CONFIG.set(CgroupConfig { root, memory_max, cpu_max, pids_max });
+    Ok(())
+}
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ResourceUsage {
+    pub peak_memory_bytes: Option<u64>,
+    pub cpu_time_ms: u64,
+}
+
+impl ResourceUsage {
+    pub(crate) fn to_text(&self) -> String {
+        let cpu_time = format!("{}.{:03} s", self.cpu_time_ms / 1000, self.cpu_time_ms % 1000);
+        match self.peak_memory_bytes {
+            Some(x) => format!("peak memory {} MiB, cpu time {cpu_time}", x / (1024 * 1024)),
+            None => format!("cpu time {cpu_time}"),
+        }
+    }
+}
+
+// The cgroups made for the commands and tests of one task
+pub(crate) struct ResourceLimits {
+    task_id: i64,
+    config: &'static CgroupConfig,
+    nr_cgroups: AtomicUsize,
+    usage: Mutex<ResourceUsage>,
+    // set when one of the task's own commands, as opposed to its tests, went over a limit
+    has_hit_limit: AtomicBool,
+}
+
+impl ResourceLimits {
+    // Returns None when the worker doesn't use cgroups
+    pub(crate) fn for_task(task_id: i64) -> Option<ResourceLimits> {
+        let config = CGROUP_CONFIG.get()?;
+        Some(ResourceLimits {
+            task_id,
+            config,
+            nr_cgroups: AtomicUsize::new(0),
+            usage: Mutex::new(ResourceUsage::default()),
+            has_hit_limit: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn create_cgroup(&self) -> Result<Cgroup<'_>, String> {
+        let n = self.nr_cgroups.fetch_add(1, Ordering::SeqCst);
+        let dir = self.config.root.join(format!("task_{}_{n}", self.task_id));
+        if let Err(e) = std::fs::create_dir(&dir) {
+            return Err(format!("Failed to create cgroup {dir:?}. Err={e}"));
+        }
+        if let Err(e) = self.set_limits(dir.as_path()) {
+            let _ = remove_cgroup(dir.as_path());
+            return Err(e);
+        }
+        Ok(Cgroup {
+            procs_path: CString::new(dir.join("cgroup.procs").as_os_str().as_bytes()).unwrap(),
+            dir,
+            limits: self,
+        })
+    }
+
+    fn set_limits(&self, dir: &Path) -> Result<(), String> {
+        let config = self.config;
+        if let Some(memory_max) = config.memory_max {
+            write_cgroup_file(dir, "memory.max", format!("{memory_max}").as_str())?;
+            // otherwise it would swap instead of getting killed
+            let _ = write_cgroup_file(dir, "memory.swap.max", "0");
+            // the whole process tree gets killed, not only its biggest process
+            write_cgroup_file(dir, "memory.oom.group", "1")?;
+        }
+        if let Some(cpu_max) = config.cpu_max {
+            let quota = (cpu_max * CPU_PERIOD_USECS as f64) as u64;
+            write_cgroup_file(dir, "cpu.max", format!("{quota} {CPU_PERIOD_USECS}").as_str())?;
+        }
+        if let Some(pids_max) = config.pids_max {
+            write_cgroup_file(dir, "pids.max", format!("{pids_max}").as_str())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn mark_limit_hit(&self) {
+        self.has_hit_limit.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn has_hit_limit(&self) -> bool {
+        self.has_hit_limit.load(Ordering::SeqCst)
+    }
+
+    // cpu time of all the commands and tests, and the peak memory of the biggest one
+    pub(crate) fn get_usage(&self) -> ResourceUsage {
+        *self.usage.lock().unwrap()
+    }
+}
+
+pub(crate) struct Cgroup<'a> {
+    dir: PathBuf,
+    procs_path: CString,
+    limits: &'a ResourceLimits,
+}
+
+impl Cgroup<'_> {
+    // Makes the command start in the cgroup, along with everything it starts
+    pub(crate) fn apply(&self, command: &mut Command) {
+        let procs_path = self.procs_path.clone();
+        unsafe {
+            command.pre_exec(move || {
+                // 0 stands for the process writing it
+                let fd = libc::open(procs_path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let res = libc::write(fd, b"0".as_ptr().cast(), 1);
+                libc::close(fd);
+                if res == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // Called once the command finished. Adds what it used to the task, and tells which limits it hit
+    pub(crate) fn finish(self) -> Option<String> {
+        let dir = self.dir.as_path();
+        let config = self.limits.config;
+        let mut limits_hit = Vec::new();
+        if read_cgroup_counter(dir, "memory.events", "oom_kill").is_some_and(|x| x > 0) {
+            limits_hit.push(format!("killed for going over the memory limit of {} MiB",
+                                    config.memory_max.unwrap_or_default() / (1024 * 1024)));
+        }
+        if read_cgroup_counter(dir, "pids.events", "max").is_some_and(|x| x > 0) {
+            limits_hit.push(format!("failed to start processes over the limit of {}",
+                                    config.pids_max.unwrap_or_default()));
+        }
+        // not an error, only slower
+        if let Some(throttled_usecs) = read_cgroup_counter(dir, "cpu.stat", "throttled_usec").filter(|x| *x > 0) {
+            println!("Processes of task {} got throttled for {} ms by the cpu limit", self.limits.task_id, throttled_usecs / 1000);
+        }
+
+        {
+            let mut usage = self.limits.usage.lock().unwrap();
+            // memory.peak only exists since linux 5.19
+            if let Some(peak) = read_cgroup_file(dir, "memory.peak").and_then(|x| x.trim().parse::<u64>().ok()) {
+                usage.peak_memory_bytes = Some(usage.peak_memory_bytes.map_or(peak, |x| x.max(peak)));
+            }
+            usage.cpu_time_ms += read_cgroup_counter(dir, "cpu.stat", "usage_usec").unwrap_or(0) / 1000;
+        }
+
+        if let Err(e) = remove_cgroup(dir) {
+            println!("{e}");
+        }
+
+        if limits_hit.is_empty() {
+            return None;
+        }
+        Some(format!("Resource limit hit: {}", limits_hit.join(", ")))
+    }
+}
@@ -13,12 +13,15 @@ use std::os::unix::raw::pid_t;
 use std::process::{Child, ExitStatus};
 use std::sync::mpsc::{RecvError, Sender};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use kill_tree::blocking::{kill_tree, kill_tree_with_config};
 use nix::errno::Errno;
 use nix::errno::Errno::ESRCH;
 use tracing::error;
 use crate::common::{is_immediate_exit_requested, report_task_data, report_task_error};
+use crate::diagnostics::DiagnosticsCollector;
+use crate::resource_limits::ResourceLimits;
+use crate::sandbox::Sandbox;
 
 #[derive(Clone, Copy)]
 enum ChannelTag {
@@ -29,6 +32,8 @@ enum ChannelTag {
 pub enum Message {
     STDOUT(String),
     STDERR(String),
+    // line read on the serial console of a board
+    SERIAL(String),
 }
 
 fn is_process_running(child: &mut std::process::Child) -> bool {
@@ -95,11 +100,27 @@ fn add_to_leftovers<T: Read>(
     }
 }
 
-fn kill_process_group(pid_to_kill: u32, signal: String) {
-  kill_tree_with_config(pid_to_kill, &kill_tree::Config { signal, include_target: true }).unwrap();
+// The process can exit between checking it still runs and sending the signal, so failing to
+// send it is only logged
+pub(crate) fn kill_process_group(pid_to_kill: u32, signal: String) {
+  if let Err(e) = kill_tree_with_config(pid_to_kill, &kill_tree::Config { signal: signal.clone(), include_target: true }) {
+      println!("Failed to send {signal} to process {pid_to_kill} and its children. Error is [{e:?}]");
+  }
 }
 
-pub fn push_messages(mut proc: Child, tx: Sender<Message>) -> ExitStatus {
+// How a process ended. The exit status alone can't tell whether the worker killed it for not
+// finishing in time, since a process can exit with any code, including the 124 of `timeout`
+pub(crate) struct ProcessOutcome {
+    pub exit_status: ExitStatus,
+    // the process got killed for going past its deadline
+    pub timed_out: bool,
+}
+
+// time given to a process to clean up after SIGTERM, before getting SIGKILL
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// Once past the deadline, the process and its children get killed, and the outcome tells it timed out
+pub(crate) fn push_messages(mut proc: Child, tx: Sender<Message>, deadline: Option<Instant>) -> ProcessOutcome {
     let mut stdout_leftovers: VecDeque<u8> = Default::default();
     let mut stderr_leftovers: VecDeque<u8> = Default::default();
 
@@ -113,6 +134,9 @@ pub fn push_messages(mut proc: Child, tx: Sender<Message>) -> ExitStatus {
     let mut stderr = BufReader::new(stderr);
 
     let mut was_process_manually_stopped = false;
+    // set when the process got SIGTERM for going past the deadline
+    let mut timed_out_at: Option<Instant> = None;
+    let mut was_killed_after_timeout = false;
     if is_process_running(&mut proc) {
         loop {
             add_to_leftovers(&mut stdout_leftovers, &mut stdout, ChannelTag::STDOUT, &tx);
@@ -123,6 +147,19 @@ pub fn push_messages(mut proc: Child, tx: Sender<Message>) -> ExitStatus {
                     was_process_manually_stopped = true;
                     break;
                 }
+                // the output printed while cleaning up still gets reported
+                match timed_out_at {
+                    None if deadline.is_some_and(|x| Instant::now() >= x) => {
+                        tx.send(Message::STDERR(String::from("Stopping process since it didn't finish in time"))).expect("failed to send message into channel");
+                        kill_process_group(proc.id(), String::from("SIGTERM"));
+                        timed_out_at = Some(Instant::now());
+                    }
+                    Some(x) if !was_killed_after_timeout && (x.elapsed() >= TIMEOUT_GRACE_PERIOD) => {
+                        kill_process_group(proc.id(), String::from("SIGKILL"));
+                        was_killed_after_timeout = true;
+                    }
+                    _ => (),
+                }
                 sleep(Duration::from_millis(10)) // don't suck 100% CPU
             } else {
                 break;
@@ -164,9 +201,8 @@ pub fn push_messages(mut proc: Child, tx: Sender<Message>) -> ExitStatus {
     }
     drop(tx);
 
-    let ret_code = proc.wait().unwrap();
-
-    ret_code
+    let exit_status = proc.wait().unwrap();
+    ProcessOutcome { exit_status, timed_out: timed_out_at.is_some() }
 }
 
 // taken from https://stackoverflow.com/a/66292796
@@ -199,39 +235,79 @@ pub fn prepend_channel(msg: Message) -> String {
         Message::STDERR(s) => {
             format!("stderr: {s}\n")
         }
+        Message::SERIAL(s) => {
+            format!("serial: {s}\n")
+        }
     }
 }
 
 pub fn run_proc(task_id: i64, command: &OsStr, params: &[&str]) -> ExitStatus {
+    run_proc_with_env(task_id, command, params, &[])
+}
+
+// same as run_proc, with some environment variables added to the ones of the worker
+pub fn run_proc_with_env(task_id: i64, command: &OsStr, params: &[&str], envs: &[(&str, &str)]) -> ExitStatus {
+    run_sandboxed_proc(task_id, None, None, None, command, params, envs, None)
+}
+
+// same as run_proc_with_env, in the sandbox and in a cgroup with the limits of the task if given, and
+// killed once past the deadline. Used for the commands coming from the tested project. The warnings
+// and errors found in the output go to the collector when given
+pub fn run_sandboxed_proc(task_id: i64, sandbox: Option<&Sandbox>, limits: Option<&ResourceLimits>, deadline: Option<Instant>,
+                          command: &OsStr, params: &[&str], envs: &[(&str, &str)],
+                          mut diagnostics: Option<&mut DiagnosticsCollector>) -> ExitStatus {
     if is_immediate_exit_requested() {
         return ExitStatus::from_raw(3);
     }
 
-    let proc = std::process::Command::new(command)
-        .args(params)
+    let cgroup = limits.map(|x| x.create_cgroup()).transpose();
+    let Ok(cgroup) = cgroup else {
+        report_task_error(task_id, cgroup.err().unwrap().as_str(), 2);
+        return ExitStatus::from_raw(3);
+    };
+
+    let mut proc = std::process::Command::new(command);
+    proc.args(params)
+        .envs(envs.iter().copied())
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn();
+        .stderr(std::process::Stdio::piped());
+    // the process must join the cgroup before entering the sandbox
+    if let Some(cgroup) = &cgroup {
+        cgroup.apply(&mut proc);
+    }
+    if let Some(sandbox) = sandbox {
+        sandbox.apply(&mut proc);
+    }
+    let proc = proc.spawn();
     let Ok(proc) = proc else {
         let err = proc.err().unwrap();
         let err_msg = format!("{err}");
-        report_task_error(task_id, err_msg.as_str(), 2).expect("Err: failed to report error");
+        report_task_error(task_id, err_msg.as_str(), 2);
+        if let Some(cgroup) = cgroup {
+            cgroup.finish();
+        }
         return ExitStatus::from_raw(3);
     };
 
 
     let (tx, rx) = std::sync::mpsc::channel();
-    let thread_handle = std::thread::spawn(|| push_messages(proc, tx));
+    let thread_handle = std::thread::spawn(move || push_messages(proc, tx, deadline));
 
     loop {
         match rx.recv() {
             Ok(msg) => {
+                if let Some(diagnostics) = diagnostics.as_deref_mut() {
+                    diagnostics.add_message(&msg);
+                }
                 let mut msg = prepend_channel(msg);
                 while let Ok(msg1) = rx.try_recv() {
+                    if let Some(diagnostics) = diagnostics.as_deref_mut() {
+                        diagnostics.add_message(&msg1);
+                    }
                     msg += prepend_channel(msg1).as_str();
                 }
-                report_task_data(task_id, msg.as_str()).expect("Error occurred when reporting data");
+                report_task_data(task_id, msg.as_str());
             }
             Err(_) => {
                 break;
@@ -239,5 +315,12 @@ pub fn run_proc(task_id: i64, command: &OsStr, params: &[&str]) -> ExitStatus {
         }
     }
 
-    thread_handle.join().unwrap()
+    // going past the deadline is noticed by the caller, from the deadline of the task
+    let exit_status = thread_handle.join().unwrap().exit_status;
+    let limit_hit = cgroup.and_then(|x| x.finish());
+    if let (Some(limit_hit), Some(limits)) = (limit_hit, limits) {
+        report_task_data(task_id, limit_hit.as_str());
+        limits.mark_limit_hit();
+    }
+    exit_status
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::borrow::Cow;
-use crate::common::{Compiler, FinishStatus, is_immediate_exit_requested, MINICI_SERVER_ADD_TEST_TEST_LIST, MINICI_SERVER_REPORT_TEST_CHANGE, report_task_data, report_task_error, report_task_finish, report_task_started, RequestedTest, RUN_CLANG_TIDY_SCRIPT_IN_TESTED_PROJECT, STATIC_ANALYSER_SCRIPT_IN_TESTED_PROJECT, Task, TaskKind, TestSetup};
+use crate::common::{Compiler, FinishStatus, is_immediate_exit_requested, report_task_data, report_task_error, report_task_finish, report_task_started, report_test_list, RequestedTest, RUN_CLANG_TIDY_SCRIPT_IN_TESTED_PROJECT, STATIC_ANALYSER_SCRIPT_IN_TESTED_PROJECT, Task, TaskKind, TestSetup};
 use crate::update_git_repo::{
-    get_commit_desc, get_git_checkout_in, run_git_clone_in, run_git_remote_update_in,
+    get_commit_desc, get_git_checkout_in, GIT_MIRROR_LOCK, remove_git_worktree, update_git_checkout_in, run_git_clone_in, run_git_remote_update_in,
 };
 use std::ffi::OsStr;
 use std::fs::File;
@@ -9,9 +9,21 @@ use std::io::{BufReader, Read, Stdout};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::process::{ExitCode, ExitStatus, Output, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tracing::error;
+use crate::build_cache::{CompilerCache, PersistentBuildDir};
+use crate::clang_format::run_clang_format_task;
+use crate::coverage::{collect_coverage, COVERAGE_BUILD_ENVS};
+use crate::diagnostics::DiagnosticsCollector;
+use crate::warnings_baseline::check_new_findings;
 use crate::common;
-use crate::run_command::{run_proc};
+use crate::reporter::{Report, send_report};
+use crate::resource_limits::ResourceLimits;
+use crate::run_command::run_sandboxed_proc;
+use crate::sandbox::Sandbox;
+use crate::target_runner::{get_hardware_runner, get_nr_qemu_slots, QemuRunner, TargetRunner};
+use crate::timeouts::{get_deadline, get_task_timeout, is_past};
 
 
 fn get_file_content(filename: &OsStr) -> Result<String, String> {
@@ -30,7 +42,8 @@ fn get_file_content(filename: &OsStr) -> Result<String, String> {
     Ok(contents)
 }
 
-fn run_static_analyser_task(task_id: i64, task_dir: &Path) -> Result<FinishStatus, String> {
+fn run_static_analyser_task(task_id: i64, task_dir: &Path, sandbox: Option<&Sandbox>, limits: Option<&ResourceLimits>,
+                            deadline: Option<Instant>) -> Result<FinishStatus, String> {
     println!("Running static_analyser in {}", String::from_utf8_lossy(task_dir.as_os_str().as_encoded_bytes()));
 
     let static_analyser_script = task_dir.join(Path::new(STATIC_ANALYSER_SCRIPT_IN_TESTED_PROJECT));
@@ -40,139 +53,108 @@ fn run_static_analyser_task(task_id: i64, task_dir: &Path) -> Result<FinishStatu
     let logs_file = task_dir.join(Path::new("static_analyser_logs_output"));
     let metrics_file = task_dir.join(Path::new("static_analyser_metrics_output"));
 
-    let task_output = run_proc(task_id, static_analyser_script,
-                               &["--output-log-file", logs_file.as_os_str().to_str().unwrap(),
-                                 "--metric-output-path", metrics_file.as_os_str().to_str().unwrap()].as_ref());
+    let task_output = run_sandboxed_proc(task_id, sandbox, limits, deadline, static_analyser_script,
+                                         &["--output-log-file", logs_file.as_os_str().to_str().unwrap(),
+                                           "--metric-output-path", metrics_file.as_os_str().to_str().unwrap()].as_ref(), &[], None);
 
     if !task_output.success() {
-        report_task_data(task_id, "Failed to run static_analyser command with err")?;
+        report_task_data(task_id, "Failed to run static_analyser command with err");
         return Ok(FinishStatus::Failed(2));
     };
 
     let logs = get_file_content(logs_file.as_os_str());
     let Ok(logs) = logs else {
-        report_task_data(task_id, logs.err().unwrap().as_str())?;
+        report_task_data(task_id, logs.err().unwrap().as_str());
         return Ok(FinishStatus::Failed(2));
     };
+    let mut diagnostics = DiagnosticsCollector::new(task_dir, task_dir);
+    for line in logs.lines() {
+        diagnostics.add_line(line);
+    }
+    diagnostics.report(task_id);
     let logs = format!("Logs=[{logs}]");
-    report_task_data(task_id, logs.as_str())?;
+    report_task_data(task_id, logs.as_str());
 
     let metrics = get_file_content(metrics_file.as_os_str());
     let Ok(metrics) = metrics else {
-        report_task_data(task_id, metrics.err().unwrap().as_str()).expect("Failed to report task data");
+        report_task_data(task_id, metrics.err().unwrap().as_str());
         return Ok(FinishStatus::Failed(2));
     };
     let metrics = format!("Metrics=[{metrics}]");
-    let _ = report_task_data(task_id, metrics.as_str());
+    report_task_data(task_id, metrics.as_str());
 
     Ok(FinishStatus::Success)
 }
 
-fn run_clang_tidy_task(task_id: i64, task_dir: &Path) -> Result<FinishStatus, String> {
+fn run_clang_tidy_task(task_id: i64, task_dir: &Path, sandbox: Option<&Sandbox>, limits: Option<&ResourceLimits>,
+                       deadline: Option<Instant>) -> Result<FinishStatus, String> {
     println!("Running clang_tidy in {}", String::from_utf8_lossy(task_dir.as_os_str().as_encoded_bytes()));
 
     let clang_tidy_script = task_dir.join(Path::new(RUN_CLANG_TIDY_SCRIPT_IN_TESTED_PROJECT));
     let clang_tidy_script = clang_tidy_script.as_os_str();
     println!("Script path is {clang_tidy_script:?}");
 
-    let task_output = run_proc(task_id, clang_tidy_script,
-                               &[]);
+    let mut diagnostics = DiagnosticsCollector::new(task_dir, task_dir);
+    let task_output = run_sandboxed_proc(task_id, sandbox, limits, deadline, clang_tidy_script,
+                                         &[], &[], Some(&mut diagnostics));
+    diagnostics.report(task_id);
 
     if !task_output.success() {
-        report_task_data(task_id, "clang_tidy command failed")?;
+        report_task_data(task_id, "clang_tidy command failed");
         return Ok(FinishStatus::Failed(2));
     };
 
     Ok(FinishStatus::Success)
 }
 
-fn report_test_start(test_name: &str, task_id: i64, target: &str) -> Result<(), String> {
-    let task_id = format!("{task_id}");
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(MINICI_SERVER_REPORT_TEST_CHANGE)
-        .form(&[("task_id", task_id.as_str()),
-            ("test_name", test_name),
-            ("operation", "Start"),
-            ("target", target)])
-        .send();
-    let Ok(res) = res else {
-        return Err(format!("failed to tell server we were starting a test {task_id}, err:{}", res.err().unwrap()));
-    };
-
-    match res.text_with_charset("utf-8").unwrap().as_str() {
-        "OK" => (),
-        e => return Err(format!("Error from server: {e}")),
-    }
-    Ok(())
+fn report_test_start(test_name: &str, task_id: i64, target: &'static str) {
+    send_report(Report::Test {
+        task_id,
+        test_name: String::from(test_name),
+        target,
+        operation: "Start",
+        status: None,
+        output: None,
+    });
 }
 
-fn report_test_finished(test_name: &str, task_id: i64, target: &str, status: FinishStatus) -> Result<(), String>
-{
+fn report_test_finished(test_name: &str, task_id: i64, target: &'static str, status: FinishStatus) {
     let status_str = format!("{status:?}");
-    let task_id = format!("{task_id}");
 
     println!("reporting test [{test_name}] finished with status [{status_str}] in task {task_id}");
 
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(MINICI_SERVER_REPORT_TEST_CHANGE)
-        .form(&[("task_id", task_id.as_str()),
-            ("test_name", test_name),
-            ("operation", "Finish"),
-            ("status", &status_str),
-            ("target", target)])
-        .send();
-
-    let Ok(res) = res else {
-        let err_msg = format!("failed to tell server we finished a test {task_id}, err:{}", res.err().unwrap());
-        println!("{}", err_msg);
-        return Err(err_msg);
-    };
-
-    let reply = res.text_with_charset("utf-8").unwrap();
-    let reply = reply.as_str();
-    println!("Server replied with {reply}");
-
-    match reply {
-        "OK" => (),
-        e => return Err(format!("Error from server: {e}")),
-    }
-
-    Ok(())
+    send_report(Report::Test {
+        task_id,
+        test_name: String::from(test_name),
+        target,
+        operation: "Finish",
+        status: Some(status_str),
+        output: None,
+    });
 }
 
-fn report_test_progress(test_name: &str, task_id: i64, target: &str, output: &str) -> Result<(), String>
-{
-    let task_id = format!("{task_id}");
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(MINICI_SERVER_REPORT_TEST_CHANGE)
-        .form(&[("task_id", task_id.as_str()),
-            ("test_name", test_name),
-            ("operation", "Progress"),
-            ("output", output),
-            ("target", target)])
-        .send();
-
-    let Ok(res) = res else {
-        return Err(format!("failed to tell server add output to give it a test {task_id}, err:{}", res.err().unwrap()));
-    };
-
-    match res.text_with_charset("utf-8").unwrap().as_str() {
-        "OK" => (),
-        e => return Err(format!("Error from server: {e}")),
-    }
-
-    Ok(())
+pub(crate) fn report_test_progress(test_name: &str, task_id: i64, target: &'static str, output: &str) {
+    send_report(Report::Test {
+        task_id,
+        test_name: String::from(test_name),
+        target,
+        operation: "Progress",
+        status: None,
+        output: Some(String::from(output)),
+    });
 }
 
 
-pub(crate) fn run_task(task: Task, git_mirror_path: &OsStr) -> Result<(), String> {
+pub(crate) fn run_task(task: Task, git_mirror_path: &OsStr) {
     let git_commit = task.git_hash();
     let task_id = task.id();
 
-    let remote_update_success = run_git_remote_update_in(git_mirror_path);
+    // tasks executed at the same time share the mirror, and git can't update it concurrently.
+    // Worktrees are removed under the same lock since they are recorded in the mirror.
+    let remote_update_success = {
+        let _lock = GIT_MIRROR_LOCK.lock().unwrap();
+        run_git_remote_update_in(git_mirror_path)
+    };
     let git_commit_desc = get_commit_desc(git_mirror_path, git_commit);
     let Ok(git_commit_desc) = git_commit_desc else {
         let err_msg = if let Err(e) = remote_update_success {
@@ -180,56 +162,142 @@ pub(crate) fn run_task(task: Task, git_mirror_path: &OsStr) -> Result<(), String
         } else {
             format!("Error: commit {git_commit} does not exists. Did you forget to push it to the server?")
         };
-        return report_task_error(task_id, err_msg.as_str(), 2);
+        report_task_error(task_id, err_msg.as_str(), 2);
+        return;
     };
     let msg = format!("Using commit {git_commit} with desc {git_commit_desc}");
-    report_task_data(task_id, msg.as_str())?;
-
-    let task_dir = temp_dir::TempDir::with_prefix("Dir_for_mini_worker_task_");
-    let Ok(task_dir) = task_dir else {
-        let err_msg = format!(
-            "failed to create a temporary dir to run a task: {}",
-            task_dir.err().unwrap()
-        );
-        return report_task_error(task_id, err_msg.as_str(), 2);
+    report_task_data(task_id, msg.as_str());
+
+    // test tasks reuse the checkout and build directory of the previous one when enabled. The ones
+    // measuring the coverage need a fresh build, without the coverage data of the previous tests, and
+    // the ones compared with a base branch need all the files compiled, so all their warnings show up.
+    let persistent_build_dir = match task.task_type() {
+        TaskKind::Test(setup) if !setup.with_coverage && task.warnings_baseline().is_none() => {
+            PersistentBuildDir::acquire(git_mirror_path, git_commit, &setup.compiler)
+        }
+        _ => None,
     };
 
-    let path = task_dir.path();
-
-    println!(
-        "Creating directory [{d}] to run task with id {task_id} in it",
-        d = String::from_utf8_lossy(path.as_os_str().as_encoded_bytes()),
-    );
-
-    let success = get_git_checkout_in(task_id, path.as_os_str(), git_mirror_path, git_commit);
+    // the temporary directory is deleted when dropped, at the end of the task
+    let (path, _tmp_dir) = match &persistent_build_dir {
+        Some(persistent_build_dir) => {
+            let path = persistent_build_dir.get_src_dir();
+            let msg = format!("Reusing the checkout and build directory of branch {} in {}",
+                              persistent_build_dir.get_branch(), String::from_utf8_lossy(path.as_os_str().as_encoded_bytes()));
+            println!("{msg} for task with id {task_id}");
+            report_task_data(task_id, msg.as_str());
+            (path, None)
+        }
+        None => {
+            let tmp_dir = temp_dir::TempDir::with_prefix("Dir_for_mini_worker_task_");
+            let Ok(tmp_dir) = tmp_dir else {
+                let err_msg = format!(
+                    "failed to create a temporary dir to run a task: {}",
+                    tmp_dir.err().unwrap()
+                );
+                report_task_error(task_id, err_msg.as_str(), 2);
+                return;
+            };
+            let path = tmp_dir.path().to_path_buf();
+
+            println!(
+                "Creating directory [{d}] to run task with id {task_id} in it",
+                d = String::from_utf8_lossy(path.as_os_str().as_encoded_bytes()),
+            );
+            (path, Some(tmp_dir))
+        }
+    };
+    let path = path.as_path();
+
+    // the build directory of a persistent dir is next to the checkout
+    let writable_dir = persistent_build_dir.as_ref().map_or(path, |x| x.get_dir());
+    let sandbox = Sandbox::from_env(&[writable_dir]);
+    let sandbox = sandbox.as_ref();
+    let limits = ResourceLimits::for_task(task_id);
+    let limits = limits.as_ref();
+
+    let success = match &persistent_build_dir {
+        Some(_) => update_git_checkout_in(task_id, path, git_mirror_path, git_commit),
+        None => get_git_checkout_in(task_id, path.as_os_str(), git_mirror_path, git_commit),
+    };
     let Ok(_) = success else {
         let err_msg = success.err().unwrap();
-        return report_task_error(task_id, err_msg.as_str(), 2);
+        report_task_error(task_id, err_msg.as_str(), 2);
+        return;
     };
 
 
-    report_task_started(task_id)?;
+    report_task_started(task_id);
+    let deadline = get_deadline(get_task_timeout(task.task_type()), None);
     let res = match task.task_type() {
-        TaskKind::StaticAnalyser => run_static_analyser_task(task_id, path),
-        TaskKind::ClangTidy => run_clang_tidy_task(task_id, path),
-        TaskKind::ClangFormat => Ok(FinishStatus::Skipped),
-        TaskKind::Test(setup) => run_tests_task(task_id, path, setup),
+        TaskKind::StaticAnalyser => run_static_analyser_task(task_id, path, sandbox, limits, deadline),
+        TaskKind::ClangTidy => run_clang_tidy_task(task_id, path, sandbox, limits, deadline),
+        TaskKind::ClangFormat => run_clang_format_task(task_id, path, sandbox, limits, deadline),
+        TaskKind::Test(setup) => run_tests_task(task_id, git_mirror_path, path, persistent_build_dir.as_ref(), sandbox, limits, deadline, setup),
     };
+    // the failure of a command killed for going over its limits is about the limits, not the project
+    let res = match res {
+        Ok(FinishStatus::Failed(_)) if limits.is_some_and(|x| x.has_hit_limit()) => Ok(FinishStatus::ResourceLimit),
+        Ok(FinishStatus::Failed(_)) if is_past(deadline) => {
+            report_task_data(task_id, "The task didn't finish in time");
+            Ok(FinishStatus::Timeout)
+        }
+        res => res,
+    };
+    // the findings are compared even when the task failed, it only fails because of them when it succeeded
+    let res = match (res, task.warnings_baseline()) {
+        (Ok(status @ (FinishStatus::Success | FinishStatus::Failed(_))), Some(branch)) => {
+            match check_new_findings(task_id, git_mirror_path, branch) {
+                Ok(true) => {
+                    let msg = format!("The task found warnings the latest successful build of branch {branch} didn't have");
+                    report_task_data(task_id, msg.as_str());
+                    match status {
+                        FinishStatus::Success => Ok(FinishStatus::Failed(1)),
+                        status => Ok(status),
+                    }
+                }
+                Ok(false) => Ok(status),
+                Err(e) => Err(e),
+            }
+        }
+        (res, _) => res,
+    };
+    let resource_usage = limits.map(|x| x.get_usage());
+    if let Some(resource_usage) = &resource_usage {
+        let msg = format!("Resources used by the task: {}", resource_usage.to_text());
+        report_task_data(task_id, msg.as_str());
+    }
     match res {
-        Ok(status) => { report_task_finish(task_id, "", status)?; }
-        Err(msg) => { report_task_finish(task_id, &msg, FinishStatus::Failed(2))? }
+        Ok(status) => { report_task_finish(task_id, "", status, resource_usage); }
+        Err(msg) => { report_task_finish(task_id, &msg, FinishStatus::Failed(2), resource_usage); }
     }
 
+    if persistent_build_dir.is_some() {
+        return;
+    }
+    // otherwise the mirror keeps track of the worktree after its directory got deleted
+    let res = {
+        let _lock = GIT_MIRROR_LOCK.lock().unwrap();
+        remove_git_worktree(git_mirror_path, path.as_os_str())
+    };
+    if let Err(e) = res {
+        println!("{e}");
+    }
     //  task_dir.leak();
-    Ok(())
 }
 
-fn run_tests_task(task_id: i64, task_dir: &Path, test_setup: &TestSetup) -> Result<FinishStatus, String> {
-    let TestSetup { test_setup_id, compiler, tests_to_run, run_tests_on_qemu, run_tests_on_real_hardware } = test_setup;
+// A persistent build directory is the one of a previous task, and only gets what changed rebuilt
+fn run_tests_task(task_id: i64, git_mirror_path: &OsStr, task_dir: &Path, persistent_build_dir: Option<&PersistentBuildDir>, sandbox: Option<&Sandbox>,
+                  limits: Option<&ResourceLimits>, deadline: Option<Instant>, test_setup: &TestSetup) -> Result<FinishStatus, String> {
+    let TestSetup { compiler, test_timeout, with_coverage, .. } = test_setup;
+    let (build_dir, is_fresh_build_dir) = match persistent_build_dir {
+        Some(x) => (x.get_build_dir(), false),
+        None => (task_dir.join("build"), true),
+    };
+    let build_dir = build_dir.as_path();
 
     let src_dir = task_dir;
     let src_dir_str = task_dir.as_os_str().to_str().unwrap();
-    let build_dir = task_dir.join("build");
     let build_dir_str = build_dir.as_os_str().to_str().unwrap();
     let toolchain_file = src_dir.join("cmake/toolchain_for_target_hardware.cmake");
     let linker_script_path = src_dir.join("linkerscripts/matching_layout_from_vendor.ld");
@@ -237,13 +305,12 @@ fn run_tests_task(task_id: i64, task_dir: &Path, test_setup: &TestSetup) -> Resu
     let linker_script_flags = linker_script_flags.as_str();
 
 
-    let args = match &test_setup.compiler {
+    let mut args = match &test_setup.compiler {
         common::Compiler::GccFromHardwareVendor => vec![
             "-S", src_dir_str,
             "-B", build_dir_str,
             "-G", "Ninja",
             "--toolchain", toolchain_file.to_str().unwrap(),
-            "--fresh",
         ],
         common::Compiler::GccFromDistro => {
             vec![
@@ -251,11 +318,27 @@ fn run_tests_task(task_id: i64, task_dir: &Path, test_setup: &TestSetup) -> Resu
                 "-B", build_dir_str,
                 "-G", "Ninja",
                 "--toolchain", toolchain_file.to_str().unwrap(),
-                "--fresh",
                 linker_script_flags,
             ]
         }
     };
+    if is_fresh_build_dir {
+        args.push("--fresh");
+    }
+
+    let compiler_cache = CompilerCache::from_env(&test_setup.compiler, toolchain_file.as_path(), build_dir);
+    let compiler_cache_args = compiler_cache.as_ref().map(|x| x.get_cmake_args()).unwrap_or_default();
+    args.extend(compiler_cache_args.iter().map(String::as_str));
+    let compiler_cache_envs = compiler_cache.as_ref().map(|x| x.get_envs(src_dir)).unwrap_or_default();
+    let compiler_cache_envs = compiler_cache_envs.iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect::<Vec<_>>();
+    // the compilers add their objects to the cache
+    let sandbox = match (sandbox, &compiler_cache) {
+        (Some(sandbox), Some(compiler_cache)) => Some(sandbox.with_writable_dir(compiler_cache.get_dir())),
+        (sandbox, _) => sandbox.cloned(),
+    };
+    let sandbox = sandbox.as_ref();
 
     let args_as_str = args
         .iter()
@@ -265,42 +348,57 @@ fn run_tests_task(task_id: i64, task_dir: &Path, test_setup: &TestSetup) -> Resu
 
     let cmd_as_str = format!("Running cmake with parameters {args_as_str}");
 
-    report_task_data(task_id, cmd_as_str.as_str())?;
+    report_task_data(task_id, cmd_as_str.as_str());
 
-    let task_output = run_proc(task_id, PathBuf::from("cmake").as_os_str(), args.as_ref());
+    let mut cmake_envs = compiler_cache_envs.clone();
+    if *with_coverage {
+        cmake_envs.extend(COVERAGE_BUILD_ENVS);
+    }
+    let task_output = run_sandboxed_proc(task_id, sandbox, limits, deadline, PathBuf::from("cmake").as_os_str(), args.as_ref(), cmake_envs.as_slice(), None);
     if !task_output.success() {
-        report_task_data(task_id, "cmake generation failed")?;
+        report_task_data(task_id, "cmake generation failed");
         return Ok(FinishStatus::Failed(2));
     };
 
     // now compiling
     // todo, compile only some tests if chosen "test only X, Y, Z"
-    report_task_data(task_id, "now compiling using ninja --verbose all")?;
-
-    let task_output = run_proc(task_id, PathBuf::from("ninja").as_os_str(),
-                               &["-C", build_dir_str, "--verbose", "all"]);
+    report_task_data(task_id, "now compiling using ninja --verbose all");
+
+    let compiler_cache_counters = compiler_cache.as_ref().and_then(|x| x.get_counters());
+    // the compilers run in the build directory
+    let mut diagnostics = DiagnosticsCollector::new(src_dir, build_dir);
+    let task_output = run_sandboxed_proc(task_id, sandbox, limits, deadline, PathBuf::from("ninja").as_os_str(),
+                                         &["-C", build_dir_str, "--verbose", "all"], compiler_cache_envs.as_slice(),
+                                         Some(&mut diagnostics));
+    diagnostics.report(task_id);
+    if let Some(compiler_cache) = &compiler_cache {
+        compiler_cache.report_statistics(task_id, compiler_cache_counters);
+    }
     if !task_output.success() {
-        report_task_data(task_id, "ninja command failed")?;
+        report_task_data(task_id, "ninja command failed");
         return Ok(FinishStatus::Failed(2));
     };
 
     if let common::RequestedTest::NoTestsOnlyCompile = &test_setup.tests_to_run {
-        report_task_data(task_id, "compilation finished")?;
+        report_task_data(task_id, "compilation finished");
         return Ok(FinishStatus::Success);
     };
 
-    let available_tests = std::process::Command::new("ctest")
-        .arg("--test-dir")
+    let mut available_tests = std::process::Command::new("ctest");
+    available_tests.arg("--test-dir")
         .arg(build_dir_str)
-        .arg("--show-only=human")
-        .output();
+        .arg("--show-only=human");
+    if let Some(sandbox) = sandbox {
+        sandbox.apply(&mut available_tests);
+    }
+    let available_tests = available_tests.output();
 
     let Ok(available_tests) = available_tests else {
         let err_msg = format!(
             "Failed to call ctest to find available tests. Err={}",
             available_tests.err().unwrap()
         );
-        report_task_data(task_id, err_msg.as_str())?;
+        report_task_data(task_id, err_msg.as_str());
         return Ok(FinishStatus::Failed(2));
     };
 
@@ -324,117 +422,80 @@ fn run_tests_task(task_id: i64, task_dir: &Path, test_setup: &TestSetup) -> Resu
         (false, false) => panic!(),
     };
 
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(MINICI_SERVER_ADD_TEST_TEST_LIST)
-        .form(&[("task_id", format!("{task_id}")),
-            ("tests_to_add", tests_to_execute.join(" ")),
-            ("targets", String::from(targets))])
-        .send();
-    let Ok(res) = res else {
-        return Err(format!("failed to add tests to execute to task with id: {task_id}, err:{}", res.err().unwrap()));
-    };
-
-    let inner_body = res.text_with_charset("utf-8");
-    let Ok(inner_body) = inner_body else {
-        return Err(format!("Error: failed to get text from request's reply. Err: {}", inner_body.err().unwrap()));
-    };
-
-    match inner_body.as_str() {
-        "OK" => (),
-        e => return Err(format!("Error from server: {e}")),
-    }
-
-    let mut has_error = false;
+    // the reports about the tests are sent after the list, so they find their test runs on the server
+    report_test_list(task_id, tests_to_execute.as_slice(), targets);
 
-    for test_name in tests_to_execute {
+    // Tests are dispatched from the shared list to whichever slot is free. Qemu tests are spread
+    // over several slots, while a worker drives a single board, so real hardware gets only one.
+    let qemu_tests = Mutex::new(tests_to_execute.iter());
+    let hardware_tests = Mutex::new(tests_to_execute.iter());
+    let qemu_runner = QemuRunner { sandbox: sandbox.cloned(), limits };
+    let results = std::thread::scope(|scope| {
+        let mut slots = Vec::new();
         if test_setup.run_tests_on_qemu {
-            report_test_start(&test_name, task_id, "Qemu")?;
-            if is_immediate_exit_requested() {
-                has_error = true;
-                let _ = report_test_progress(&test_name, task_id, "Qemu", "Not executing the test since the user requested to stop the worker immediately");
-                let _ = report_test_finished(&test_name, task_id, "Qemu", FinishStatus::Failed(4));
-            } else {
-                let (tx, rx) = std::sync::mpsc::channel();
-                let test_name_regexp = format!("^{test_name}$");
-                let proc = std::process::Command::new(PathBuf::from("ctest").as_os_str())
-                    .args(&[
-                        "--test-dir",
-                        build_dir_str,
-                        "--verbose",
-                        "--no-tests=error",
-                        "--tests-regex",
-                        &test_name_regexp])
-                    .stdin(std::process::Stdio::null())
-                    .stdout(std::process::Stdio::piped())
-                    .stderr(std::process::Stdio::piped())
-                    .spawn()
-                    .unwrap();
-
-                let thread_handle = std::thread::spawn(|| crate::run_command::push_messages(proc, tx));
-
-                let mut has_timed_out = false;
-                // todo: hardcoded string. Corresponds to the scripts that gets executed through ctest.
-                let timeout_msg = "/usr/bin/timeout: sending signal TERM to command";
-                loop {
-                    match rx.recv() {
-                        Ok(msg) => {
-                            let mut msg = crate::run_command::prepend_channel(msg);
-                            has_timed_out = has_timed_out || msg.contains(timeout_msg);
-
-                            while let Ok(msg1) = rx.try_recv() {
-                                let with_channel = crate::run_command::prepend_channel(msg1);
-                                has_timed_out = has_timed_out || with_channel.contains(timeout_msg);
-                                msg += with_channel.as_str();
-                            }
-                            report_test_progress(&test_name, task_id, "Qemu", msg.as_str()).expect("failed to update test");
-                        }
-                        Err(_) => {
-                            break;
-                        }
-                    }
-                }
-
-                let task_output = thread_handle.join().unwrap();
-                let finish_status = if has_timed_out {
-                    FinishStatus::Timeout
-                } else {
-                    if is_immediate_exit_requested() {
-                        FinishStatus::Failed(3) // do not send skipped. Skipped is used to tell can't be executed
-                        // on the hardware used, e.g. if it requires real hardware and is executed on qemu.
-                    } else {
-                        match task_output.code() {
-                            None => { FinishStatus::Failed(2) }
-                            Some(124) => {
-                                has_timed_out = true;
-                                has_error = true;
-                                FinishStatus::Timeout
-                            }
-                            Some(0) => { FinishStatus::Success }
-                            Some(n) => {
-                                has_error = true;
-                                FinishStatus::Failed(i64::from(n))
-                            }
-                        }
-                    }
-                };
-
-                let _ = report_test_finished(&test_name, task_id, "Qemu", finish_status);
+            for _ in 0..get_nr_qemu_slots() {
+                slots.push(scope.spawn(|| run_tests_on(&qemu_runner, task_id, build_dir_str, &qemu_tests, *test_timeout, deadline)));
             }
         }
-
         if test_setup.run_tests_on_real_hardware {
-            report_test_start(&test_name, task_id, "RealHardware")?;
-            report_test_finished(&test_name, task_id, "RealHardware", FinishStatus::Skipped)?;
+            let runner = get_hardware_runner();
+            let hardware_tests = &hardware_tests;
+            slots.push(scope.spawn(move || run_tests_on(runner.as_ref(), task_id, build_dir_str, hardware_tests, *test_timeout, deadline)));
+        }
+        slots.into_iter()
+            .map(|x| x.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let mut has_error = false;
+    for res in results {
+        has_error = res || has_error;
+    }
+
+    // the status of the task stays the one of the tests, whatever happens to the coverage
+    if *with_coverage {
+        if let Err(e) = collect_coverage(task_id, src_dir, build_dir, compiler, git_mirror_path, sandbox, limits, deadline) {
+            let msg = format!("Error: no coverage for this task, {e}");
+            report_task_data(task_id, msg.as_str());
         }
     }
 
     let end_status = if has_error { FinishStatus::Failed(2) } else { FinishStatus::Success };
     let msg = format!("Done with task {task_id}");
-    report_task_data(task_id, msg.as_str())?;
+    report_task_data(task_id, msg.as_str());
     Ok(end_status)
 }
 
+// Runs tests taken from the list until there are none left. Returns whether one of them failed.
+// Each test can run for the timeout given by the job, or else the one of the runner, but not past the
+// deadline of the task.
+fn run_tests_on(runner: &dyn TargetRunner, task_id: i64, build_dir_str: &str, tests: &Mutex<std::slice::Iter<String>>,
+                test_timeout: Option<Duration>, task_deadline: Option<Instant>) -> bool {
+    let target = runner.target_name();
+    let mut has_error = false;
+    loop {
+        let Some(test_name) = tests.lock().unwrap().next() else {
+            break;
+        };
+        report_test_start(test_name, task_id, target);
+        let finish_status = if is_immediate_exit_requested() {
+            report_test_progress(test_name, task_id, target, "Not executing the test since the user requested to stop the worker immediately");
+            FinishStatus::Failed(4)
+        } else if is_past(task_deadline) {
+            report_test_progress(test_name, task_id, target, "Not executing the test since the task ran out of time");
+            FinishStatus::Timeout
+        } else {
+            let deadline = get_deadline(test_timeout.or(runner.get_test_timeout()), task_deadline);
+            runner.run_test(task_id, build_dir_str, test_name, deadline)
+        };
+        if let FinishStatus::Failed(_) | FinishStatus::Timeout | FinishStatus::ResourceLimit = finish_status {
+            has_error = true;
+        }
+        report_test_finished(test_name, task_id, target, finish_status);
+    }
+    has_error
+}
+
 fn get_tests_to_execute(test_setup: &&TestSetup, available_tests: &Vec<&str>) -> Vec<String> {
     let tests_to_execute = match &test_setup.tests_to_run {
         RequestedTest::AllTest => {
@@ -479,7 +540,7 @@ fn ensure_all_requested_tests_are_available(task_id: i64, available_tests: &Vec<
             .reduce(|a, b| format!("{a}\n{b}"))
             .unwrap();
         let msg = format!("Error: following tests requested but not found: [\n{err_msg}\n]");
-        report_task_data(task_id, msg.as_str())?;
+        report_task_data(task_id, msg.as_str());
         Err(msg)
     } else {
         Ok(())
@@ -506,7 +567,7 @@ fn get_available_tests<'a>(task_id: i64, available_tests: &'a Cow<'a, str>) -> R
         .reduce(|a, b| format!("{a}\n{b}"))
         .unwrap();
     let available_tests_str = format!("Found following tests: [\n{available_tests_str}\n]");
-    report_task_data(task_id, available_tests_str.as_str())?;
+    report_task_data(task_id, available_tests_str.as_str());
     drop(available_tests_str);
     Ok(available_tests)
 }
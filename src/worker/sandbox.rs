@@ -0,0 +1,305 @@
+use std::ffi::CString;
+use std::io::Error;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// The commands coming from the tested project, like cmake, ninja or the tests, run in their own user,
+// mount, pid and network namespaces. They see the whole file system read-only, except the directory of
+// the task and an empty /tmp, can't see or signal the processes of the worker, and have no network
+// unless allowed. No privileges are needed, unprivileged user namespaces are enough.
+pub(crate) const SANDBOX_ENV_VAR: &'static str = "MINI_WORKER_SANDBOX";
+pub(crate) const SANDBOX_NETWORK_ENV_VAR: &'static str = "MINI_WORKER_SANDBOX_NETWORK";
+
+#[derive(Clone)]
+pub(crate) struct Sandbox {
+    writable_dirs: Vec<PathBuf>,
+    allow_network: bool,
+}
+
+fn is_env_var_set_to(name: &str, value: &str) -> bool {
+    std::env::var(name).is_ok_and(|x| x == value)
+}
+
+impl Sandbox {
+    // Returns None when sandboxing got disabled with MINI_WORKER_SANDBOX=0
+    pub(crate) fn from_env(writable_dirs: &[&Path]) -> Option<Sandbox> {
+        if is_env_var_set_to(SANDBOX_ENV_VAR, "0") {
+            return None;
+        }
+        Some(Sandbox {
+            writable_dirs: writable_dirs.iter().map(|x| x.to_path_buf()).collect(),
+            allow_network: is_env_var_set_to(SANDBOX_NETWORK_ENV_VAR, "1"),
+        })
+    }
+
+    pub(crate) fn with_writable_dir(&self, dir: &Path) -> Sandbox {
+        let mut res = self.clone();
+        res.writable_dirs.push(dir.to_path_buf());
+        res
+    }
+
+    // Tells whether the sandbox can be set up on this machine, e.g. it fails when unprivileged user
+    // namespaces are disabled
+    pub(crate) fn check(&self) -> Result<(), String> {
+        let mut command = Command::new("true");
+        self.apply(&mut command);
+        let status = command.status();
+        match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("a command in the sandbox failed with {status}")),
+            Err(e) => Err(format!("failed to set up the sandbox. Err={e}")),
+        }
+    }
+
+    // Makes the command start in the sandbox
+    pub(crate) fn apply(&self, command: &mut Command) {
+        // nothing can be allocated between fork and exec, so everything is prepared here
+        for dir in &self.writable_dirs {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                println!("Error: failed to create {dir:?} to make it writable in the sandbox. Err={e}");
+            }
+        }
+        let writable_dirs = self.writable_dirs.iter()
+            .map(|x| get_dir_and_ancestors(x.as_path()))
+            .collect::<Vec<_>>();
+        let uid_map = format!("{uid} {uid} 1", uid = unsafe { libc::getuid() });
+        let gid_map = format!("{gid} {gid} 1", gid = unsafe { libc::getgid() });
+        let allow_network = self.allow_network;
+
+        unsafe {
+            command.pre_exec(move || enter_sandbox(writable_dirs.as_slice(), uid_map.as_bytes(), gid_map.as_bytes(), allow_network));
+        }
+    }
+}
+
+// e.g. "/tmp/a/b" gives ["/tmp", "/tmp/a", "/tmp/a/b"]
+fn get_dir_and_ancestors(dir: &Path) -> Vec<CString> {
+    let mut res = dir.ancestors()
+        .filter(|x| x.parent().is_some())
+        .map(|x| CString::new(x.as_os_str().as_bytes()).unwrap())
+        .collect::<Vec<_>>();
+    res.reverse();
+    res
+}
+
+fn check(res: libc::c_long) -> std::io::Result<libc::c_long> {
+    if res == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(res)
+}
+
+fn write_file(path: &[u8], content: &[u8]) -> std::io::Result<()> {
+    let fd = check(unsafe { libc::open(path.as_ptr().cast(), libc::O_WRONLY | libc::O_CLOEXEC) }.into())?;
+    let res = check(unsafe { libc::write(fd as libc::c_int, content.as_ptr().cast(), content.len()) } as libc::c_long);
+    unsafe { libc::close(fd as libc::c_int) };
+    res.map(|_| ())
+}
+
+fn mount(source: &[u8], target: &[u8], fs_type: &[u8], flags: libc::c_ulong) -> std::io::Result<()> {
+    check(unsafe { libc::mount(source.as_ptr().cast(), target.as_ptr().cast(), fs_type.as_ptr().cast(), flags, std::ptr::null()) }.into())
+        .map(|_| ())
+}
+
+fn set_read_only_recursively(path: &[u8]) -> std::io::Result<()> {
+    let attr = libc::mount_attr { attr_set: libc::MOUNT_ATTR_RDONLY, attr_clr: 0, propagation: 0, userns_fd: 0 };
+    check(unsafe {
+        libc::syscall(libc::SYS_mount_setattr, libc::AT_FDCWD, path.as_ptr(), libc::AT_RECURSIVE,
+                      &attr as *const libc::mount_attr, std::mem::size_of::<libc::mount_attr>())
+    }).map(|_| ())
+}
+
+// the network namespace starts with the loopback interface down, while tests may use it
+fn set_loopback_up() -> std::io::Result<()> {
+    let fd = check(unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) }.into())? as libc::c_int;
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    request.ifr_name[0] = b'l' as libc::c_char;
+    request.ifr_name[1] = b'o' as libc::c_char;
+    let res = check(unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut request) }.into())
+        .and_then(|_| {
+            unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+            check(unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS, &request) }.into())
+        });
+    unsafe { libc::close(fd) };
+    res.map(|_| ())
+}
+
+// the command, as seen from the first process of the pid namespace, which forwards it the signals
+static COMMAND_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = COMMAND_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        // the interrupted waitpid reads errno
+        unsafe {
+            let errno = *libc::__errno_location();
+            libc::kill(pid, signal);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+// closes everything but `fd`, e.g. the pipes of the outputs of the command, which the worker reads
+// until they get closed
+fn close_all_fds_but(fd: libc::c_int) {
+    unsafe {
+        libc::close_range(0, (fd - 1) as libc::c_uint, 0);
+        libc::close_range((fd + 1) as libc::c_uint, libc::c_uint::MAX, 0);
+    }
+}
+
+// Runs as the first process of the pid namespace. The kernel drops the signals sent from outside of
+// the namespace to that process unless it handles them, so the command can't be that process, or the
+// SIGTERM of the worker would never reach it. This one forwards SIGTERM to the command, reaps the
+// processes left orphaned in the namespace, and sends the wait status of the command through the pipe
+// once it ends. It can't exit like the command: the first process of a namespace ignores the signals
+// it sends itself.
+fn run_init(command_pid: libc::pid_t, status_fd: libc::c_int) -> ! {
+    unsafe {
+        for signal in 1..libc::SIGRTMIN() {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+        COMMAND_PID.store(command_pid, Ordering::SeqCst);
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            libc::signal(signal, forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+        close_all_fds_but(status_fd);
+
+        let mut command_status = 0;
+        loop {
+            let mut status = 0;
+            let pid = libc::waitpid(-1, &mut status, 0);
+            if pid == command_pid {
+                command_status = status;
+                break;
+            }
+            if pid == -1 && *libc::__errno_location() == libc::ECHILD {
+                break;
+            }
+        }
+        libc::write(status_fd, (&command_status as *const libc::c_int).cast(), std::mem::size_of::<libc::c_int>());
+        // the processes still running in the namespace get killed when this one ends
+        libc::_exit(0);
+    }
+}
+
+// Waits for the first process of the pid namespace and exits the way the command did, so the
+// worker sees the command's status
+fn wait_and_exit_like(init_pid: libc::pid_t, status_fd: libc::c_int) -> ! {
+    unsafe {
+        // the handlers of the worker got inherited, this process must die when asked to
+        for signal in 1..libc::SIGRTMIN() {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+        // except for the SIGTERM sent to the whole process tree when stopping the command, which must
+        // reach the command, and not kill it right away through PR_SET_PDEATHSIG
+        libc::signal(libc::SIGTERM, libc::SIG_IGN);
+        close_all_fds_but(status_fd);
+
+        let mut status = 0;
+        while libc::waitpid(init_pid, &mut status, 0) == -1 && *libc::__errno_location() == libc::EINTR {}
+        // nothing comes through the pipe when the first process got killed before the command ended
+        let mut command_status: libc::c_int = 0;
+        let size = std::mem::size_of::<libc::c_int>();
+        let mut nr_read = 0;
+        while nr_read < size {
+            let res = libc::read(status_fd, (&mut command_status as *mut libc::c_int).cast::<u8>().add(nr_read).cast(), size - nr_read);
+            if res > 0 {
+                nr_read += res as usize;
+            } else if res == 0 || *libc::__errno_location() != libc::EINTR {
+                break;
+            }
+        }
+        if nr_read == size {
+            status = command_status;
+        }
+
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+        }
+        libc::_exit(if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 2 });
+    }
+}
+
+// Runs between fork and exec. The process stays outside of the new pid namespace, so it forks once
+// more: the child, first process of the namespace, forks the command and waits for it, while this
+// process waits for the child. When the first process of a pid namespace ends, all the others get killed.
+fn enter_sandbox(writable_dirs: &[Vec<CString>], uid_map: &[u8], gid_map: &[u8], allow_network: bool) -> std::io::Result<()> {
+    let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+    if !allow_network {
+        flags |= libc::CLONE_NEWNET;
+    }
+    check(unsafe { libc::unshare(flags) }.into())?;
+    write_file(b"/proc/self/setgroups\0", b"deny")?;
+    write_file(b"/proc/self/uid_map\0", uid_map)?;
+    write_file(b"/proc/self/gid_map\0", gid_map)?;
+
+    // the mounts made from now on don't show outside of the sandbox
+    mount(b"\0", b"/\0", b"\0", libc::MS_REC | libc::MS_PRIVATE)?;
+
+    // copies of the writable dirs are taken before everything becomes read-only, and put back on top
+    let mut writable_trees = [-1; 16];
+    if writable_dirs.len() > writable_trees.len() {
+        return Err(Error::from_raw_os_error(libc::E2BIG));
+    }
+    for (dir, tree) in writable_dirs.iter().zip(writable_trees.iter_mut()) {
+        let Some(dir) = dir.last() else {
+            continue;
+        };
+        *tree = check(unsafe {
+            libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, dir.as_ptr(),
+                          libc::OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint | libc::AT_RECURSIVE as libc::c_uint)
+        })? as libc::c_int;
+    }
+
+    set_read_only_recursively(b"/\0")?;
+    mount(b"tmpfs\0", b"/tmp\0", b"tmpfs\0", libc::MS_NOSUID | libc::MS_NODEV)?;
+    match mount(b"tmpfs\0", b"/dev/shm\0", b"tmpfs\0", libc::MS_NOSUID | libc::MS_NODEV) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => (),
+        res => res?,
+    }
+
+    for (dir, tree) in writable_dirs.iter().zip(writable_trees) {
+        let Some(last) = dir.last() else {
+            continue;
+        };
+        // the dir may be hidden by the new /tmp
+        for ancestor in dir {
+            unsafe { libc::mkdir(ancestor.as_ptr(), 0o755) };
+        }
+        check(unsafe {
+            libc::syscall(libc::SYS_move_mount, tree, b"\0".as_ptr(), libc::AT_FDCWD, last.as_ptr(), libc::MOVE_MOUNT_F_EMPTY_PATH)
+        })?;
+        unsafe { libc::close(tree) };
+    }
+
+    // closed on exec, so the command doesn't get it
+    let mut status_pipe = [-1; 2];
+    check(unsafe { libc::pipe2(status_pipe.as_mut_ptr(), libc::O_CLOEXEC) }.into())?;
+    let [status_read_fd, status_write_fd] = status_pipe;
+
+    let init_pid = check(unsafe { libc::fork() }.into())? as libc::pid_t;
+    if init_pid != 0 {
+        wait_and_exit_like(init_pid, status_read_fd);
+    }
+
+    // from here on, in the new pid namespace
+    unsafe { libc::close(status_read_fd) };
+    check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) }.into())?;
+    // otherwise the processes of the worker would still show, and could be signaled
+    mount(b"proc\0", b"/proc\0", b"proc\0", libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC)?;
+    if !allow_network {
+        set_loopback_up()?;
+    }
+
+    let command_pid = check(unsafe { libc::fork() }.into())? as libc::pid_t;
+    if command_pid != 0 {
+        run_init(command_pid, status_write_fd);
+    }
+    Ok(())
+}
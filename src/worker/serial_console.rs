@@ -0,0 +1,325 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::mpsc::Sender;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use nix::sys::termios::{self, BaudRate, ControlFlags, FlushArg, SetArg};
+use crate::common::{FinishStatus, is_immediate_exit_requested};
+use crate::run_command::Message;
+
+// Boards report the result of their tests on their serial console. When one is configured, the
+// worker reads it directly instead of relying on the `capture` command of the hardware runner.
+pub(crate) const SERIAL_DEVICE_ENV_VAR: &'static str = "MINI_WORKER_SERIAL_DEVICE";
+pub(crate) const SERIAL_BAUD_RATE_ENV_VAR: &'static str = "MINI_WORKER_SERIAL_BAUD_RATE";
+// data bits, parity and stop bits, e.g. 8N1 or 7E2
+pub(crate) const SERIAL_FRAMING_ENV_VAR: &'static str = "MINI_WORKER_SERIAL_FRAMING";
+// a test ends as soon as a line contains one of the markers
+pub(crate) const SERIAL_PASS_MARKER_ENV_VAR: &'static str = "MINI_WORKER_SERIAL_PASS_MARKER";
+pub(crate) const SERIAL_FAIL_MARKER_ENV_VAR: &'static str = "MINI_WORKER_SERIAL_FAIL_MARKER";
+
+const DEFAULT_BAUD_RATE: u32 = 115200;
+const DEFAULT_FRAMING: &'static str = "8N1";
+const DEFAULT_PASS_MARKER: &'static str = "TEST PASSED";
+const DEFAULT_FAIL_MARKER: &'static str = "TEST FAILED";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SerialConsole {
+    device: OsString,
+    baud_rate: BaudRate,
+    data_bits: ControlFlags,
+    parity: Parity,
+    two_stop_bits: bool,
+    pass_marker: String,
+    fail_marker: String,
+}
+
+pub(crate) enum SerialOutcome {
+    Passed,
+    Failed,
+    TimedOut,
+    Interrupted,
+    Error(String),
+}
+
+impl SerialOutcome {
+    pub(crate) fn finish_status(&self) -> FinishStatus {
+        match self {
+            SerialOutcome::Passed => FinishStatus::Success,
+            SerialOutcome::Failed => FinishStatus::Failed(1),
+            SerialOutcome::TimedOut => FinishStatus::Timeout,
+            // do not send skipped. Skipped is used to tell can't be executed on the hardware used
+            SerialOutcome::Interrupted => FinishStatus::Failed(3),
+            SerialOutcome::Error(_) => FinishStatus::Failed(2),
+        }
+    }
+}
+
+fn get_baud_rate(value: u32) -> Option<BaudRate> {
+    let baud_rate = match value {
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        460800 => BaudRate::B460800,
+        921600 => BaudRate::B921600,
+        1000000 => BaudRate::B1000000,
+        1500000 => BaudRate::B1500000,
+        2000000 => BaudRate::B2000000,
+        3000000 => BaudRate::B3000000,
+        _ => return None,
+    };
+    Some(baud_rate)
+}
+
+fn get_framing(text: &str) -> Option<(ControlFlags, Parity, bool)> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let [data_bits, parity, stop_bits] = chars[..] else {
+        return None;
+    };
+    let data_bits = match data_bits {
+        '5' => ControlFlags::CS5,
+        '6' => ControlFlags::CS6,
+        '7' => ControlFlags::CS7,
+        '8' => ControlFlags::CS8,
+        _ => return None,
+    };
+    let parity = match parity.to_ascii_uppercase() {
+        'N' => Parity::None,
+        'E' => Parity::Even,
+        'O' => Parity::Odd,
+        _ => return None,
+    };
+    let two_stop_bits = match stop_bits {
+        '1' => false,
+        '2' => true,
+        _ => return None,
+    };
+    Some((data_bits, parity, two_stop_bits))
+}
+
+impl SerialConsole {
+    // Returns None when no serial device is configured
+    pub(crate) fn from_env() -> Result<Option<SerialConsole>, String> {
+        let Some(device) = std::env::var_os(SERIAL_DEVICE_ENV_VAR).filter(|x| !x.is_empty()) else {
+            return Ok(None);
+        };
+
+        let baud_rate = match std::env::var(SERIAL_BAUD_RATE_ENV_VAR) {
+            Err(_) => DEFAULT_BAUD_RATE,
+            Ok(x) => match x.parse::<u32>() {
+                Ok(x) => x,
+                Err(_) => return Err(format!("Error: invalid baud rate [{x}] in {SERIAL_BAUD_RATE_ENV_VAR}")),
+            },
+        };
+        let Some(baud_rate) = get_baud_rate(baud_rate) else {
+            return Err(format!("Error: unsupported baud rate [{baud_rate}] in {SERIAL_BAUD_RATE_ENV_VAR}"));
+        };
+
+        let framing = std::env::var(SERIAL_FRAMING_ENV_VAR).unwrap_or(String::from(DEFAULT_FRAMING));
+        let Some((data_bits, parity, two_stop_bits)) = get_framing(framing.as_str()) else {
+            return Err(format!("Error: invalid framing [{framing}] in {SERIAL_FRAMING_ENV_VAR}. Expected something like 8N1"));
+        };
+
+        let pass_marker = std::env::var(SERIAL_PASS_MARKER_ENV_VAR).unwrap_or(String::from(DEFAULT_PASS_MARKER));
+        let fail_marker = std::env::var(SERIAL_FAIL_MARKER_ENV_VAR).unwrap_or(String::from(DEFAULT_FAIL_MARKER));
+        if pass_marker.is_empty() || fail_marker.is_empty() {
+            return Err(format!("Error: {SERIAL_PASS_MARKER_ENV_VAR} and {SERIAL_FAIL_MARKER_ENV_VAR} can't be empty"));
+        }
+
+        Ok(Some(SerialConsole { device, baud_rate, data_bits, parity, two_stop_bits, pass_marker, fail_marker }))
+    }
+
+    // Opens and configures the device, dropping whatever the board printed before, e.g. by a
+    // previous test. Must be called before resetting the board, so its first lines aren't lost.
+    pub(crate) fn open(&self) -> Result<File, String> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&self.device);
+        let Ok(file) = file else {
+            return Err(format!("Failed to open serial device {:?}. Err={}", self.device, file.err().unwrap()));
+        };
+
+        let res = self.configure(&file);
+        if let Err(e) = res {
+            return Err(format!("Failed to configure serial device {:?}. Err={e}", self.device));
+        }
+        Ok(file)
+    }
+
+    fn configure(&self, file: &File) -> nix::Result<()> {
+        let mut settings = termios::tcgetattr(file)?;
+        termios::cfmakeraw(&mut settings);
+
+        let flags = &mut settings.control_flags;
+        flags.remove(ControlFlags::CSIZE | ControlFlags::PARENB | ControlFlags::PARODD | ControlFlags::CSTOPB | ControlFlags::CRTSCTS);
+        flags.insert(self.data_bits | ControlFlags::CREAD | ControlFlags::CLOCAL);
+        match self.parity {
+            Parity::None => (),
+            Parity::Even => flags.insert(ControlFlags::PARENB),
+            Parity::Odd => flags.insert(ControlFlags::PARENB | ControlFlags::PARODD),
+        }
+        if self.two_stop_bits {
+            flags.insert(ControlFlags::CSTOPB);
+        }
+
+        // after the flags, since the speed is stored in them too
+        termios::cfsetspeed(&mut settings, self.baud_rate)?;
+
+        termios::tcsetattr(file, SetArg::TCSANOW, &settings)?;
+        termios::tcflush(file, FlushArg::TCIFLUSH)?;
+        Ok(())
+    }
+
+    // Sends each line read on the console into the channel, like push_messages does for the output
+    // of processes, until a line contains one of the markers or the deadline passes.
+    pub(crate) fn push_lines(&self, mut file: File, tx: Sender<Message>, deadline: Instant) -> SerialOutcome {
+        let mut leftovers: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            if is_immediate_exit_requested() {
+                return SerialOutcome::Interrupted;
+            }
+            if Instant::now() >= deadline {
+                if !leftovers.is_empty() {
+                    let line = String::from_utf8_lossy(leftovers.as_slice()).into_owned();
+                    tx.send(Message::SERIAL(line)).expect("sending message in channel failed");
+                }
+                return SerialOutcome::TimedOut;
+            }
+
+            let nr_bytes = match file.read(&mut buf) {
+                Ok(n) => n,
+                // a pseudo terminal reports EIO while nothing is connected on the other side
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) || e.raw_os_error() == Some(libc::EIO) => 0,
+                Err(e) => return SerialOutcome::Error(format!("Failed to read from serial device {:?}. Err={e}", self.device)),
+            };
+            if nr_bytes == 0 {
+                sleep(Duration::from_millis(10)); // don't suck 100% CPU
+                continue;
+            }
+
+            leftovers.extend_from_slice(&buf[..nr_bytes]);
+            while let Some(pos) = leftovers.iter().position(|x| *x == b'\n') {
+                let line = leftovers.drain(..=pos).collect::<Vec<_>>();
+                let line = String::from_utf8_lossy(&line[..pos]);
+                let line = line.trim_end_matches('\r');
+
+                let outcome = if line.contains(self.fail_marker.as_str()) {
+                    Some(SerialOutcome::Failed)
+                } else if line.contains(self.pass_marker.as_str()) {
+                    Some(SerialOutcome::Passed)
+                } else {
+                    None
+                };
+                tx.send(Message::SERIAL(String::from(line))).expect("sending message in channel failed");
+                if let Some(outcome) = outcome {
+                    return outcome;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::fd::AsRawFd;
+    use std::sync::atomic::AtomicU8;
+    use std::sync::mpsc::Receiver;
+    use nix::pty::{openpty, OpenptyResult};
+    use super::*;
+    use crate::common::TERM;
+
+    // A pseudo terminal pair stands in for the cable: the board writes on the master side, and the
+    // worker reads the slave side as if it was the serial device
+    fn make_console(pass_marker: &str, fail_marker: &str) -> (SerialConsole, OpenptyResult) {
+        // no exit requested
+        unsafe { TERM.get_or_init(|| AtomicU8::new(0)) };
+        let pty = openpty(None, None).unwrap();
+        let device = std::fs::read_link(format!("/proc/self/fd/{}", pty.slave.as_raw_fd())).unwrap();
+        let console = SerialConsole {
+            device: device.into_os_string(),
+            baud_rate: BaudRate::B115200,
+            data_bits: ControlFlags::CS8,
+            parity: Parity::None,
+            two_stop_bits: false,
+            pass_marker: String::from(pass_marker),
+            fail_marker: String::from(fail_marker),
+        };
+        (console, pty)
+    }
+
+    // Opens the console, lets the board write its chunks, and reads the console until it is done
+    fn read_console(console: &SerialConsole, pty: OpenptyResult, chunks: &[&str], timeout: Duration) -> (SerialOutcome, Vec<String>) {
+        let file = console.open().unwrap();
+        let mut board = File::from(pty.master);
+        for chunk in chunks {
+            board.write_all(chunk.as_bytes()).unwrap();
+            board.flush().unwrap();
+            sleep(Duration::from_millis(20));
+        }
+        let (tx, rx) = std::sync::mpsc::channel();
+        let outcome = console.push_lines(file, tx, Instant::now() + timeout);
+        (outcome, get_lines(rx))
+    }
+
+    fn get_lines(rx: Receiver<Message>) -> Vec<String> {
+        rx.try_iter()
+            .map(|x| match x {
+                Message::SERIAL(line) => line,
+                _ => panic!("expected only lines of the serial console"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_pass_marker_ends_the_test() {
+        let (console, pty) = make_console(DEFAULT_PASS_MARKER, DEFAULT_FAIL_MARKER);
+        let (outcome, lines) = read_console(&console, pty, &["boot", "ing\r\nrunning test\r\n", "TEST PASSED\r\nafter the end\n"],
+                                            Duration::from_secs(5));
+        assert!(matches!(outcome, SerialOutcome::Passed));
+        assert_eq!(lines, ["booting", "running test", "TEST PASSED"]);
+    }
+
+    #[test]
+    fn the_fail_marker_ends_the_test() {
+        let (console, pty) = make_console(DEFAULT_PASS_MARKER, DEFAULT_FAIL_MARKER);
+        let (outcome, lines) = read_console(&console, pty, &["booting\n", "assert failed: TEST FAILED\n"], Duration::from_secs(5));
+        assert!(matches!(outcome, SerialOutcome::Failed));
+        assert_eq!(lines, ["booting", "assert failed: TEST FAILED"]);
+    }
+
+    #[test]
+    fn the_markers_can_be_changed() {
+        let (console, pty) = make_console("<OK>", "<KO>");
+        let (outcome, lines) = read_console(&console, pty, &["TEST PASSED\n", "<KO>\n"], Duration::from_secs(5));
+        assert!(matches!(outcome, SerialOutcome::Failed));
+        assert_eq!(lines, ["TEST PASSED", "<KO>"]);
+    }
+
+    #[test]
+    fn a_board_without_marker_times_out() {
+        let (console, pty) = make_console(DEFAULT_PASS_MARKER, DEFAULT_FAIL_MARKER);
+        let (outcome, lines) = read_console(&console, pty, &["booting\n", "stuck in a loo"], Duration::from_millis(300));
+        assert!(matches!(outcome, SerialOutcome::TimedOut));
+        // the unfinished line is still reported
+        assert_eq!(lines, ["booting", "stuck in a loo"]);
+    }
+}
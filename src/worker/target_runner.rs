@@ -0,0 +1,381 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Stdio};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::common::{FinishStatus, is_immediate_exit_requested};
+use crate::resource_limits::{Cgroup, ResourceLimits};
+use crate::run_command::{kill_process_group, Message, prepend_channel, ProcessOutcome, push_messages};
+use crate::run_task::report_test_progress;
+use crate::sandbox::Sandbox;
+use crate::serial_console::{SerialConsole, SerialOutcome};
+use crate::timeouts::get_qemu_test_timeout;
+
+// A target runner executes one test, already compiled, on one kind of target and tells how it went.
+// Whatever gets printed while doing so is reported as the output of the test. Several tests can
+// run at the same time, each one in its own thread.
+pub(crate) trait TargetRunner: Send + Sync {
+    // name of the target, as known by the server
+    fn target_name(&self) -> &'static str;
+    // time a test can run when the job doesn't tell
+    fn get_test_timeout(&self) -> Option<Duration>;
+    // the test gets killed, and reported as timed out, once past the deadline
+    fn run_test(&self, task_id: i64, build_dir: &str, test_name: &str, deadline: Option<Instant>) -> FinishStatus;
+}
+
+// Real hardware is driven through an external command, so each worker can plug in whatever flashes
+// and talks to its board. The command is called as:
+//   <runner> flash <build_dir> <test_name>    writes the image of the test on the board
+//   <runner> capture <build_dir> <test_name>  prints the output of the board, exits 0 if the test passed
+//   <runner> reset                            restarts the board so it runs what was flashed
+// The capture is started before the reset so that nothing printed at boot is missed. When a serial
+// console is configured, the worker reads it itself and the runner doesn't need to implement `capture`.
+pub(crate) const HARDWARE_RUNNER_ENV_VAR: &'static str = "MINI_WORKER_HARDWARE_RUNNER";
+// how long a test on real hardware can run before being considered as hanging
+pub(crate) const HARDWARE_TEST_TIMEOUT_ENV_VAR: &'static str = "MINI_WORKER_HARDWARE_TEST_TIMEOUT_SECS";
+const DEFAULT_HARDWARE_TEST_TIMEOUT_SECS: u64 = 300;
+
+// number of tests executed at the same time on qemu
+pub(crate) const QEMU_SLOTS_ENV_VAR: &'static str = "MINI_WORKER_QEMU_SLOTS";
+
+pub(crate) fn get_nr_qemu_slots() -> usize {
+    match std::env::var(QEMU_SLOTS_ENV_VAR) {
+        Err(_) => 1,
+        Ok(x) => match x.parse::<usize>() {
+            Ok(nr_slots) if nr_slots > 0 => nr_slots,
+            _ => {
+                println!("Ignoring invalid value [{x}] of {QEMU_SLOTS_ENV_VAR}, running the tests on qemu one at a time");
+                1
+            }
+        },
+    }
+}
+
+pub(crate) fn is_hardware_runner_configured() -> bool {
+    std::env::var_os(HARDWARE_RUNNER_ENV_VAR).is_some_and(|x| !x.is_empty())
+}
+
+pub(crate) fn get_hardware_runner() -> Box<dyn TargetRunner> {
+    let Some(runner) = std::env::var_os(HARDWARE_RUNNER_ENV_VAR).filter(|x| !x.is_empty()) else {
+        let reason = format!("No runner for real hardware configured on this worker. Set {HARDWARE_RUNNER_ENV_VAR} to use one");
+        return Box::new(UnavailableHardwareRunner { reason, status: FinishStatus::Skipped });
+    };
+
+    let serial_console = match SerialConsole::from_env() {
+        Ok(x) => x,
+        Err(reason) => return Box::new(UnavailableHardwareRunner { reason, status: FinishStatus::Failed(2) }),
+    };
+
+    let timeout_secs = match std::env::var(HARDWARE_TEST_TIMEOUT_ENV_VAR) {
+        Err(_) => DEFAULT_HARDWARE_TEST_TIMEOUT_SECS,
+        Ok(x) => match x.parse::<u64>() {
+            Ok(secs) if secs > 0 => secs,
+            _ => {
+                println!("Ignoring invalid value [{x}] of {HARDWARE_TEST_TIMEOUT_ENV_VAR}, using {DEFAULT_HARDWARE_TEST_TIMEOUT_SECS} seconds instead");
+                DEFAULT_HARDWARE_TEST_TIMEOUT_SECS
+            }
+        },
+    };
+
+    Box::new(ExternalCommandRunner { runner, timeout_secs, serial_console })
+}
+
+fn spawn_with_output_channel(sandbox: Option<&Sandbox>, cgroup: Option<&Cgroup>, deadline: Option<Instant>,
+                             command: &OsStr, args: &[&OsStr]) -> Result<(u32, Receiver<Message>, JoinHandle<ProcessOutcome>), String> {
+    let mut proc = std::process::Command::new(command);
+    proc.args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // the process must join the cgroup before entering the sandbox
+    if let Some(cgroup) = cgroup {
+        cgroup.apply(&mut proc);
+    }
+    if let Some(sandbox) = sandbox {
+        sandbox.apply(&mut proc);
+    }
+    let proc = proc.spawn();
+    let Ok(proc) = proc else {
+        return Err(format!("Failed to start {command:?}. Err={}", proc.err().unwrap()));
+    };
+
+    let pid = proc.id();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let thread_handle = std::thread::spawn(move || push_messages(proc, tx, deadline));
+    Ok((pid, rx, thread_handle))
+}
+
+// Reports what the thread sends as the test's output, batching what is already available
+// in a single request. `on_output` gets to see each batch.
+fn report_output_until_done<T>(task_id: i64, test_name: &str, target: &'static str,
+                               rx: Receiver<Message>, thread_handle: JoinHandle<T>,
+                               on_output: &mut dyn FnMut(&str)) -> T {
+    loop {
+        match rx.recv() {
+            Ok(msg) => {
+                let mut msg = prepend_channel(msg);
+                while let Ok(msg1) = rx.try_recv() {
+                    msg += prepend_channel(msg1).as_str();
+                }
+                on_output(msg.as_str());
+                report_test_progress(test_name, task_id, target, msg.as_str());
+            }
+            Err(_) => {
+                break;
+            }
+        }
+    }
+
+    thread_handle.join().unwrap()
+}
+
+fn run_reporting_output(task_id: i64, test_name: &str, target: &'static str, sandbox: Option<&Sandbox>, cgroup: Option<&Cgroup>,
+                        deadline: Option<Instant>, command: &OsStr, args: &[&OsStr], on_output: &mut dyn FnMut(&str)) -> ProcessOutcome {
+    match spawn_with_output_channel(sandbox, cgroup, deadline, command, args) {
+        Ok((_, rx, thread_handle)) => report_output_until_done(task_id, test_name, target, rx, thread_handle, on_output),
+        Err(e) => {
+            report_test_progress(test_name, task_id, target, e.as_str());
+            ProcessOutcome { exit_status: ExitStatus::from_raw(3), timed_out: false }
+        }
+    }
+}
+
+fn get_finish_status(outcome: ProcessOutcome) -> FinishStatus {
+    if is_immediate_exit_requested() {
+        // do not send skipped. Skipped is used to tell can't be executed on the hardware used,
+        // e.g. if it requires real hardware and is executed on qemu.
+        return FinishStatus::Failed(3);
+    }
+    if outcome.timed_out {
+        return FinishStatus::Timeout;
+    }
+    match outcome.exit_status.code() {
+        None => FinishStatus::Failed(2),
+        Some(0) => FinishStatus::Success,
+        Some(n) => FinishStatus::Failed(i64::from(n)),
+    }
+}
+
+// The tests come from the tested project, and run in the sandbox, each one in its own cgroup
+pub(crate) struct QemuRunner<'a> {
+    pub sandbox: Option<Sandbox>,
+    pub limits: Option<&'a ResourceLimits>,
+}
+
+impl TargetRunner for QemuRunner<'_> {
+    fn target_name(&self) -> &'static str {
+        "Qemu"
+    }
+
+    fn get_test_timeout(&self) -> Option<Duration> {
+        get_qemu_test_timeout()
+    }
+
+    fn run_test(&self, task_id: i64, build_dir: &str, test_name: &str, deadline: Option<Instant>) -> FinishStatus {
+        let test_name_regexp = format!("^{test_name}$");
+        let args = ["--test-dir", build_dir, "--verbose", "--no-tests=error", "--tests-regex", test_name_regexp.as_str()]
+            .map(OsStr::new);
+
+        let cgroup = self.limits.map(|x| x.create_cgroup()).transpose();
+        let Ok(cgroup) = cgroup else {
+            report_test_progress(test_name, task_id, self.target_name(), cgroup.err().unwrap().as_str());
+            return FinishStatus::Failed(2);
+        };
+
+        let outcome = run_reporting_output(task_id, test_name, self.target_name(), self.sandbox.as_ref(), cgroup.as_ref(),
+                                           deadline, OsStr::new("ctest"), &args, &mut |_| ());
+
+        let limit_hit = cgroup.and_then(|x| x.finish());
+        if let Some(limit_hit) = &limit_hit {
+            report_test_progress(test_name, task_id, self.target_name(), limit_hit.as_str());
+        }
+        match get_finish_status(outcome) {
+            FinishStatus::Failed(_) if limit_hit.is_some() => FinishStatus::ResourceLimit,
+            status => status,
+        }
+    }
+}
+
+pub(crate) struct ExternalCommandRunner {
+    runner: OsString,
+    timeout_secs: u64,
+    serial_console: Option<SerialConsole>,
+}
+
+impl ExternalCommandRunner {
+    fn capture_with_command(&self, task_id: i64, build_dir: &OsStr, test_name: &str, deadline: Instant) -> FinishStatus {
+        let target = self.target_name();
+        let runner = self.runner.as_os_str();
+        let test = OsStr::new(test_name);
+
+        // the capture is stopped if the test doesn't finish in time
+        let capture = spawn_with_output_channel(None, None, Some(deadline), runner, &[OsStr::new("capture"), build_dir, test]);
+        let (capture_pid, rx, thread_handle) = match capture {
+            Ok(x) => x,
+            Err(e) => {
+                report_test_progress(test_name, task_id, target, e.as_str());
+                return FinishStatus::Failed(2);
+            }
+        };
+
+        // what the board prints meanwhile waits in the channel of the capture
+        let reset_status = run_reporting_output(task_id, test_name, target, None, None, Some(deadline), runner, &[OsStr::new("reset")], &mut |_| ()).exit_status;
+        if !reset_status.success() {
+            kill_process_group(capture_pid, String::from("SIGTERM"));
+            let _ = report_output_until_done(task_id, test_name, target, rx, thread_handle, &mut |_| ());
+            report_test_progress(test_name, task_id, target, "Failed to reset the board");
+            return FinishStatus::Failed(2);
+        }
+
+        let outcome = report_output_until_done(task_id, test_name, target, rx, thread_handle, &mut |_| ());
+        get_finish_status(outcome)
+    }
+
+    fn capture_from_serial_console(&self, task_id: i64, test_name: &str, serial_console: &SerialConsole, deadline: Instant) -> FinishStatus {
+        let target = self.target_name();
+
+        let console = serial_console.open();
+        let Ok(console) = console else {
+            report_test_progress(test_name, task_id, target, console.err().unwrap().as_str());
+            return FinishStatus::Failed(2);
+        };
+
+        // what the board prints meanwhile stays in the buffer of the serial device
+        let reset_status = run_reporting_output(task_id, test_name, target, None, None, Some(deadline), self.runner.as_os_str(), &[OsStr::new("reset")], &mut |_| ()).exit_status;
+        if !reset_status.success() {
+            report_test_progress(test_name, task_id, target, "Failed to reset the board");
+            return FinishStatus::Failed(2);
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let serial_console = serial_console.clone();
+        let thread_handle = std::thread::spawn(move || serial_console.push_lines(console, tx, deadline));
+        let outcome = report_output_until_done(task_id, test_name, target, rx, thread_handle, &mut |_| ());
+
+        if let SerialOutcome::Error(e) = &outcome {
+            report_test_progress(test_name, task_id, target, e.as_str());
+        }
+        outcome.finish_status()
+    }
+}
+
+impl TargetRunner for ExternalCommandRunner {
+    fn target_name(&self) -> &'static str {
+        "RealHardware"
+    }
+
+    fn get_test_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.timeout_secs))
+    }
+
+    fn run_test(&self, task_id: i64, build_dir: &str, test_name: &str, deadline: Option<Instant>) -> FinishStatus {
+        let target = self.target_name();
+        let build_dir = OsStr::new(build_dir);
+        // reading the board never goes on forever
+        let deadline = deadline.unwrap_or(Instant::now() + Duration::from_secs(self.timeout_secs));
+
+        let outcome = run_reporting_output(task_id, test_name, target, None, None, Some(deadline), self.runner.as_os_str(),
+                                           &[OsStr::new("flash"), build_dir, OsStr::new(test_name)], &mut |_| ());
+        if outcome.timed_out {
+            report_test_progress(test_name, task_id, target, "Flashing the board didn't finish in time");
+            return FinishStatus::Timeout;
+        }
+        if !outcome.exit_status.success() {
+            report_test_progress(test_name, task_id, target, "Failed to flash the board");
+            return FinishStatus::Failed(2);
+        }
+
+        match &self.serial_console {
+            Some(serial_console) => self.capture_from_serial_console(task_id, test_name, serial_console, deadline),
+            None => self.capture_with_command(task_id, build_dir, test_name, deadline),
+        }
+    }
+}
+
+// Used when the worker can't drive real hardware. Workers without a runner don't accept tests on
+// real hardware, so this only happens if the server sends them anyway, or if the runner is misconfigured.
+struct UnavailableHardwareRunner {
+    reason: String,
+    status: FinishStatus,
+}
+
+impl TargetRunner for UnavailableHardwareRunner {
+    fn target_name(&self) -> &'static str {
+        "RealHardware"
+    }
+
+    fn get_test_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn run_test(&self, task_id: i64, _build_dir: &str, test_name: &str, _deadline: Option<Instant>) -> FinishStatus {
+        report_test_progress(test_name, task_id, self.target_name(), self.reason.as_str());
+        self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::sync::atomic::AtomicU8;
+    use super::*;
+    use crate::common::TERM;
+    use crate::reporter::{Report, take_reports_of_task};
+
+    // the simulated board keeps its state in its own directory, to not mix with other runs
+    fn make_simulated_device_runner(dir: &Path) -> ExternalCommandRunner {
+        let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts/simulated_device.sh");
+        let runner = dir.join("runner.sh");
+        let content = format!("#!/bin/sh\nSIMULATED_DEVICE_STATE_DIR={:?} exec {:?} \"$@\"\n", dir.join("state"), script);
+        std::fs::write(runner.as_path(), content).unwrap();
+        std::fs::set_permissions(runner.as_path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        ExternalCommandRunner { runner: runner.into_os_string(), timeout_secs: 10, serial_console: None }
+    }
+
+    fn get_test_output(task_id: i64) -> String {
+        take_reports_of_task(task_id).into_iter()
+            .filter_map(|x| match x {
+                Report::Test { output, .. } => output,
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tests_on_the_simulated_device() {
+        // no exit requested
+        unsafe { TERM.get_or_init(|| AtomicU8::new(0)) };
+        let dir = temp_dir::TempDir::with_prefix("mini_worker_test_").unwrap();
+        let runner = make_simulated_device_runner(dir.path());
+        let deadline = || Some(Instant::now() + Duration::from_secs(10));
+
+        let status = runner.run_test(1001, "build", "ok_test", deadline());
+        assert!(matches!(status, FinishStatus::Success), "got {status:?}");
+        let output = get_test_output(1001);
+        assert!(output.contains("flashing test ok_test from build"), "got [{output}]");
+        assert!(output.contains("TEST PASSED"), "got [{output}]");
+
+        let status = runner.run_test(1002, "build", "fail_test", deadline());
+        assert!(matches!(status, FinishStatus::Failed(1)), "got {status:?}");
+        assert!(get_test_output(1002).contains("TEST FAILED"));
+
+        let status = runner.run_test(1003, "build", "hang_test", Some(Instant::now() + Duration::from_secs(2)));
+        assert!(matches!(status, FinishStatus::Timeout), "got {status:?}");
+    }
+
+    #[test]
+    fn exiting_with_the_code_of_timeout_is_a_failure() {
+        unsafe { TERM.get_or_init(|| AtomicU8::new(0)) };
+        let dir = temp_dir::TempDir::with_prefix("mini_worker_test_").unwrap();
+        // like a test script running its own `timeout`, which expired
+        let runner = dir.path().join("runner.sh");
+        std::fs::write(runner.as_path(), "#!/bin/sh\n[ \"$1\" = capture ] && exit 124\nexit 0\n").unwrap();
+        std::fs::set_permissions(runner.as_path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let runner = ExternalCommandRunner { runner: runner.into_os_string(), timeout_secs: 10, serial_console: None };
+
+        let status = runner.run_test(1004, "build", "own_timeout_test", Some(Instant::now() + Duration::from_secs(10)));
+        assert!(matches!(status, FinishStatus::Failed(124)), "got {status:?}");
+    }
+}
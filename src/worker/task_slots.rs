@@ -0,0 +1,154 @@
+use std::thread::JoinHandle;
+use crate::common::Task;
+
+// A worker executes several tasks at once, each one in a slot. Slots can be dedicated to a class of
+// tasks, e.g. a machine with many cores and a single board can have 4 slots for the tasks not using
+// the board, and 1 for the ones running tests on it. Whatever the slots, only one task at a time
+// uses the board.
+//
+// Configured as either a number of slots taking any task, e.g. `4`, or as a number of slots per
+// class, e.g. `compile=4,real_hardware=1`. A single slot taking any task by default.
+pub(crate) const TASK_SLOTS_ENV_VAR: &'static str = "MINI_WORKER_TASK_SLOTS";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum SlotClass {
+    Any,
+    // every task but the ones running tests on real hardware
+    Compile,
+    RealHardware,
+}
+
+struct RunningTask {
+    task_id: i64,
+    uses_real_hardware: bool,
+    thread_handle: JoinHandle<()>,
+}
+
+struct Slot {
+    class: SlotClass,
+    running_task: Option<RunningTask>,
+}
+
+// How many tasks to ask the server for
+pub(crate) struct RequestLimits {
+    pub(crate) max_tasks: usize,
+    pub(crate) max_real_hardware_tasks: usize,
+    pub(crate) max_other_tasks: usize,
+}
+
+pub(crate) struct TaskSlots {
+    slots: Vec<Slot>,
+}
+
+impl TaskSlots {
+    pub(crate) fn from_env() -> Result<TaskSlots, String> {
+        let config = std::env::var(TASK_SLOTS_ENV_VAR).unwrap_or(String::from("1"));
+
+        let mut classes = Vec::new();
+        if let Ok(nr_slots) = config.trim().parse::<usize>() {
+            classes.extend(std::iter::repeat_n(SlotClass::Any, nr_slots));
+        } else {
+            for entry in config.split(',') {
+                let Some((class, nr_slots)) = entry.split_once('=') else {
+                    return Err(format!("Error: invalid entry [{entry}] in {TASK_SLOTS_ENV_VAR}. Expected something like compile=4"));
+                };
+                let class = match class.trim() {
+                    "any" => SlotClass::Any,
+                    "compile" => SlotClass::Compile,
+                    "real_hardware" => SlotClass::RealHardware,
+                    x => return Err(format!("Error: unknown slot class [{x}] in {TASK_SLOTS_ENV_VAR}. Expected any, compile or real_hardware")),
+                };
+                let Ok(nr_slots) = nr_slots.trim().parse::<usize>() else {
+                    return Err(format!("Error: invalid number of slots [{nr_slots}] in {TASK_SLOTS_ENV_VAR}"));
+                };
+                classes.extend(std::iter::repeat_n(class, nr_slots));
+            }
+        }
+
+        if classes.is_empty() {
+            return Err(format!("Error: {TASK_SLOTS_ENV_VAR} must give at least one slot"));
+        }
+
+        let slots = classes
+            .into_iter()
+            .map(|class| Slot { class, running_task: None })
+            .collect::<Vec<_>>();
+        Ok(TaskSlots { slots })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    // Frees the slots whose task is over. Returns how many got freed
+    pub(crate) fn reap_finished(&mut self) -> usize {
+        let mut nr_freed = 0;
+        for slot in self.slots.iter_mut() {
+            if slot.running_task.as_ref().is_some_and(|x| x.thread_handle.is_finished()) {
+                let running_task = slot.running_task.take().unwrap();
+                if running_task.thread_handle.join().is_err() {
+                    println!("Error: the thread executing task {} panicked", running_task.task_id);
+                }
+                nr_freed += 1;
+            }
+        }
+        nr_freed
+    }
+
+    pub(crate) fn is_idle(&self) -> bool {
+        self.slots.iter().all(|x| x.running_task.is_none())
+    }
+
+    pub(crate) fn get_running_task_ids(&self) -> Vec<i64> {
+        self.slots
+            .iter()
+            .filter_map(|x| x.running_task.as_ref())
+            .map(|x| x.task_id)
+            .collect()
+    }
+
+    // None when all the slots are busy
+    pub(crate) fn get_request_limits(&self) -> Option<RequestLimits> {
+        let nr_free = |class: SlotClass| self.slots
+            .iter()
+            .filter(|x| (x.class == class) && x.running_task.is_none())
+            .count();
+        let is_board_in_use = self.slots
+            .iter()
+            .filter_map(|x| x.running_task.as_ref())
+            .any(|x| x.uses_real_hardware);
+
+        let max_real_hardware_tasks = if is_board_in_use {
+            0
+        } else {
+            std::cmp::min(1, nr_free(SlotClass::RealHardware) + nr_free(SlotClass::Any))
+        };
+        let max_other_tasks = nr_free(SlotClass::Compile) + nr_free(SlotClass::Any);
+        let max_tasks = std::cmp::min(max_other_tasks + max_real_hardware_tasks,
+                                      nr_free(SlotClass::Compile) + nr_free(SlotClass::Any) + nr_free(SlotClass::RealHardware));
+
+        if max_tasks == 0 {
+            return None;
+        }
+        Some(RequestLimits { max_tasks, max_real_hardware_tasks, max_other_tasks })
+    }
+
+    // Executes the task in a new thread, in a free slot of the right class.
+    // Gives back the task if there is no such slot.
+    pub(crate) fn start(&mut self, task: Task, run: impl FnOnce(Task) + Send + 'static) -> Result<(), Task> {
+        let uses_real_hardware = task.uses_real_hardware();
+        let preferred_class = if uses_real_hardware { SlotClass::RealHardware } else { SlotClass::Compile };
+
+        let slot_pos = [preferred_class, SlotClass::Any]
+            .iter()
+            .find_map(|class| self.slots.iter().position(|x| (x.class == *class) && x.running_task.is_none()));
+        let Some(slot_pos) = slot_pos else {
+            return Err(task);
+        };
+
+        let task_id = task.id();
+        let thread_handle = std::thread::spawn(move || run(task));
+        self.slots[slot_pos].running_task = Some(RunningTask { task_id, uses_real_hardware, thread_handle });
+        Ok(())
+    }
+}
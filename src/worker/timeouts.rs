@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+use crate::common::TaskKind;
+
+// Each kind of task has a time limit, after which the command it runs gets killed and the task ends
+// as timed out. So does each test, which never runs past the time limit of its task. The time limit
+// of the tests can be overridden by the job. Setting one of these variables to 0 removes the limit.
+pub(crate) const STATIC_ANALYSER_TIMEOUT_ENV_VAR: &'static str = "MINI_WORKER_STATIC_ANALYSER_TIMEOUT_SECS";
+pub(crate) const CLANG_TIDY_TIMEOUT_ENV_VAR: &'static str = "MINI_WORKER_CLANG_TIDY_TIMEOUT_SECS";
+pub(crate) const CLANG_FORMAT_TIMEOUT_ENV_VAR: &'static str = "MINI_WORKER_CLANG_FORMAT_TIMEOUT_SECS";
+// covers compiling and running all the tests of the task
+pub(crate) const TESTS_TASK_TIMEOUT_ENV_VAR: &'static str = "MINI_WORKER_TESTS_TASK_TIMEOUT_SECS";
+pub(crate) const QEMU_TEST_TIMEOUT_ENV_VAR: &'static str = "MINI_WORKER_QEMU_TEST_TIMEOUT_SECS";
+
+const DEFAULT_STATIC_ANALYSER_TIMEOUT_SECS: u64 = 3600;
+const DEFAULT_CLANG_TIDY_TIMEOUT_SECS: u64 = 3600;
+const DEFAULT_CLANG_FORMAT_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_TESTS_TASK_TIMEOUT_SECS: u64 = 4 * 3600;
+// same as the default timeout of ctest
+const DEFAULT_QEMU_TEST_TIMEOUT_SECS: u64 = 1500;
+
+fn get_timeout_from_env(name: &str, default_secs: u64) -> Option<Duration> {
+    let secs = match std::env::var(name) {
+        Err(_) => default_secs,
+        Ok(x) => match x.parse::<u64>() {
+            Ok(secs) => secs,
+            Err(_) => {
+                println!("Ignoring invalid value [{x}] of {name}, using {default_secs} seconds instead");
+                default_secs
+            }
+        },
+    };
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+pub(crate) fn get_task_timeout(task_kind: &TaskKind) -> Option<Duration> {
+    match task_kind {
+        TaskKind::StaticAnalyser => get_timeout_from_env(STATIC_ANALYSER_TIMEOUT_ENV_VAR, DEFAULT_STATIC_ANALYSER_TIMEOUT_SECS),
+        TaskKind::ClangTidy => get_timeout_from_env(CLANG_TIDY_TIMEOUT_ENV_VAR, DEFAULT_CLANG_TIDY_TIMEOUT_SECS),
+        TaskKind::ClangFormat => get_timeout_from_env(CLANG_FORMAT_TIMEOUT_ENV_VAR, DEFAULT_CLANG_FORMAT_TIMEOUT_SECS),
+        TaskKind::Test(_) => get_timeout_from_env(TESTS_TASK_TIMEOUT_ENV_VAR, DEFAULT_TESTS_TASK_TIMEOUT_SECS),
+    }
+}
+
+pub(crate) fn get_qemu_test_timeout() -> Option<Duration> {
+    get_timeout_from_env(QEMU_TEST_TIMEOUT_ENV_VAR, DEFAULT_QEMU_TEST_TIMEOUT_SECS)
+}
+
+// Time at which something started now has to be done, given its own timeout and the deadline of
+// what it is part of
+pub(crate) fn get_deadline(timeout: Option<Duration>, outer_deadline: Option<Instant>) -> Option<Instant> {
+    let deadline = timeout.map(|x| Instant::now() + x);
+    match (deadline, outer_deadline) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+pub(crate) fn is_past(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|x| Instant::now() >= x)
+}
@@ -1,7 +1,8 @@
 use crate::common::report_task_data;
-use crate::run_command::run_proc;
+use crate::run_command::{run_proc, run_proc_with_env};
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::process::ExitStatus;
 
 pub fn run_git_clone_in(dst_dir: &OsStr, git_source_url: &OsStr) -> Result<(), String> {
@@ -39,46 +40,474 @@ pub fn run_git_clone_in(dst_dir: &OsStr, git_source_url: &OsStr) -> Result<(), S
     Ok(())
 }
 
+// The mirror is kept across restarts of the worker, so it only fetches what changed since. It
+// belongs to a single worker, which adds a worktree in it for each task.
+pub(crate) const GIT_MIRROR_ENV_VAR: &'static str = "MINI_WORKER_GIT_MIRROR";
+const DEFAULT_GIT_MIRROR: &'static str = "/var/tmp/mini_worker_git_mirror";
+
+pub fn get_git_mirror_path() -> PathBuf {
+    std::env::var_os(GIT_MIRROR_ENV_VAR)
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from(DEFAULT_GIT_MIRROR))
+}
+
+fn is_git_mirror(path: &OsStr) -> bool {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("rev-parse")
+        .arg("--is-bare-repository")
+        .output();
+    matches!(output, Ok(x) if x.status.success() && x.stdout.trim_ascii() == b"true")
+}
+
+// Reuses the mirror left by a previous run when there is one, and clones it otherwise. The
+// worktrees under `kept_worktrees_dir` are meant to be reused by the next tasks.
+pub fn prepare_git_mirror(mirror_path: &OsStr, git_source_url: &OsStr, kept_worktrees_dir: Option<&Path>) -> Result<(), String> {
+    if !is_git_mirror(mirror_path) {
+        let is_empty_or_missing = match std::fs::read_dir(mirror_path) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+        };
+        if !is_empty_or_missing {
+            return Err(format!("{mirror_path:?} exists but is not a git mirror. Remove it, or set {GIT_MIRROR_ENV_VAR} to another path"));
+        }
+        println!("Creating git mirror in {mirror_path:?}");
+        return run_git_clone_in(mirror_path, git_source_url);
+    }
+
+    println!("Reusing git mirror in {mirror_path:?}");
+    remove_stale_worktrees(mirror_path, kept_worktrees_dir)?;
+    if let Err(e) = run_git_remote_update_in(mirror_path) {
+        // the mirror is still usable for the commits it already has, and each task fetches again
+        println!("Failed to update the git mirror: {e}");
+    }
+    Ok(())
+}
+
+// Worktrees of tasks that were running when the worker stopped are left behind. No task runs yet
+// when this is called, so they can all go.
+fn remove_stale_worktrees(mirror_path: &OsStr, kept_worktrees_dir: Option<&Path>) -> Result<(), String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(mirror_path)
+        .arg("worktree")
+        .arg("list")
+        .arg("--porcelain")
+        .output();
+    let Ok(output) = output else {
+        return Err(format!("fail to list the worktrees of {mirror_path:?}: error={}", output.err().unwrap()));
+    };
+    if !output.status.success() {
+        return Err(format!("listing the worktrees of {mirror_path:?} failed with {}", String::from_utf8_lossy(output.stderr.as_ref())));
+    }
+
+    // one paragraph per worktree, the first one being the mirror itself
+    let worktrees = String::from_utf8_lossy(output.stdout.as_ref()).into_owned();
+    for worktree in worktrees.split("\n\n").skip(1) {
+        let Some(path) = worktree.lines().find_map(|x| x.strip_prefix("worktree ")) else {
+            continue;
+        };
+        if kept_worktrees_dir.is_some_and(|x| Path::new(path).starts_with(x)) {
+            continue;
+        }
+        println!("Removing stale worktree {path}");
+        if let Err(e) = remove_git_worktree(mirror_path, OsStr::new(path)) {
+            println!("{e}");
+        }
+    }
+
+    // forgets about the worktrees whose directory got deleted by other means
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(mirror_path)
+        .arg("worktree")
+        .arg("prune")
+        .output();
+    match output {
+        Ok(x) if x.status.success() => Ok(()),
+        _ => Err(format!("fail to prune the worktrees of {mirror_path:?}")),
+    }
+}
+
+// Git can't change the mirror from several processes at once, which happens when several tasks
+// are executed at the same time. This also covers the mirrors of the submodules.
+pub(crate) static GIT_MIRROR_LOCK: Mutex<()> = Mutex::new(());
+
+// Large files tracked with git lfs are fetched from that endpoint. When it isn't set, git lfs
+// finds the endpoint of each repository from its url.
+pub(crate) const LFS_URL_ENV_VAR: &'static str = "MINI_WORKER_LFS_URL";
+
+// lfs objects are fetched in a separate step, reporting its progress, instead of during the checkout
+const SKIP_LFS_DOWNLOAD_DURING_CHECKOUT: (&'static str, &'static str) = ("GIT_LFS_SKIP_SMUDGE", "1");
+
 pub fn get_git_checkout_in(
     task_id: i64,
     dst_dir: &OsStr,
-    git_source_url: &OsStr,
+    mirror_path: &OsStr,
     commit: &str,
 ) -> Result<(), String> {
-    // todo: use worktree
-    let task_output = run_proc(
-        task_id,
-        PathBuf::from("git").as_os_str(),
-        &["clone", git_source_url.to_str().unwrap(),
-            dst_dir.to_str().unwrap()],
-    );
+    // the worktree shares the objects of the mirror, only the files of the commit get written
+    let task_output = {
+        let _lock = GIT_MIRROR_LOCK.lock().unwrap();
+        run_proc_with_env(
+            task_id,
+            PathBuf::from("git").as_os_str(),
+            &["-C", mirror_path.to_str().unwrap(),
+                "worktree", "add", "--detach",
+                dst_dir.to_str().unwrap(),
+                commit],
+            &[SKIP_LFS_DOWNLOAD_DURING_CHECKOUT],
+        )
+    };
     if !task_output.success() {
-        report_task_data(task_id, "git clone failed")?;
+        report_task_data(task_id, "git worktree add failed");
         return Err(format!(
-            "fail to run git clone {git_source_url:?} in {dst_dir:?} for commit {commit}"
+            "fail to add a worktree of {mirror_path:?} in {dst_dir:?} for commit {commit}"
         ));
     };
 
-    let task_output = run_proc(
+    check_out_lfs_objects_and_submodules(task_id, Path::new(dst_dir), mirror_path)
+}
+
+// Moves a worktree kept from a previous task to the commit, only rewriting the files which
+// changed. Falls back to adding the worktree if it doesn't exist, or is broken.
+pub fn update_git_checkout_in(
+    task_id: i64,
+    dir: &Path,
+    mirror_path: &OsStr,
+    commit: &str,
+) -> Result<(), String> {
+    let dir_str = dir.to_str().unwrap();
+    let is_worktree = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("rev-parse")
+        .arg("--is-inside-work-tree")
+        .output()
+        .is_ok_and(|x| x.status.success() && x.stdout.trim_ascii() == b"true");
+    if !is_worktree || !dir.join(".git").is_file() {
+        if dir.exists() {
+            report_task_data(task_id, format!("Removing {dir_str} since it is not a worktree anymore").as_str());
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        {
+            // forgets the worktree if it got registered before its directory got removed
+            let _lock = GIT_MIRROR_LOCK.lock().unwrap();
+            let _ = std::process::Command::new("git").arg("-C").arg(mirror_path).arg("worktree").arg("prune").output();
+        }
+        return get_git_checkout_in(task_id, dir.as_os_str(), mirror_path, commit);
+    }
+
+    let task_output = run_proc_with_env(
         task_id,
         PathBuf::from("git").as_os_str(),
-        &[
-            "-c",
-            "advice.detachedHead=false",
-            "-C",
-            dst_dir.to_str().unwrap(),
-            "checkout",
-            commit,
-        ],
+        &["-c", "advice.detachedHead=false", "-C", dir_str, "checkout", "--force", "--detach", commit],
+        &[SKIP_LFS_DOWNLOAD_DURING_CHECKOUT],
     );
     if !task_output.success() {
-        report_task_data(task_id, "git checkout failed")?;
-        return Err(String::from("fail to run git checkout"));
+        report_task_data(task_id, "git checkout failed");
+        return Err(format!("fail to check out commit {commit} in {dir_str}"));
+    }
+
+    // leftovers of the previous task, like submodules removed since
+    let task_output = run_proc(task_id, PathBuf::from("git").as_os_str(), &["-C", dir_str, "clean", "-ffdx"]);
+    if !task_output.success() {
+        report_task_data(task_id, "git clean failed");
+        return Err(format!("fail to clean {dir_str}"));
+    }
+
+    check_out_lfs_objects_and_submodules(task_id, dir, mirror_path)
+}
+
+fn check_out_lfs_objects_and_submodules(task_id: i64, dir: &Path, mirror_path: &OsStr) -> Result<(), String> {
+    let Some(url) = get_remote_url(mirror_path) else {
+        return Err(format!("fail to get the url the mirror {mirror_path:?} was cloned from"));
+    };
+    fetch_lfs_objects(task_id, dir, url.as_str())?;
+    check_out_submodules(task_id, dir, url.as_str(), mirror_path)
+}
+
+// Branch the commit is on, preferring the ones pointing to it. None if it isn't on any branch.
+pub fn get_branch_of(mirror_path: &OsStr, commit: &str) -> Option<String> {
+    for filter in ["--points-at", "--contains"] {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(mirror_path)
+            .arg("for-each-ref")
+            .arg(filter)
+            .arg(commit)
+            .arg("--format=%(refname:short)")
+            .arg("refs/heads")
+            .output();
+        let Ok(output) = output else {
+            return None;
+        };
+        let branches = String::from_utf8_lossy(output.stdout.as_ref()).into_owned();
+        if let Some(branch) = branches.lines().next() {
+            return Some(String::from(branch));
+        }
+    }
+    None
+}
+
+fn get_remote_url(git_dir: &OsStr) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(git_dir)
+        .arg("config")
+        .arg("remote.origin.url")
+        .output();
+    match output {
+        Ok(x) if x.status.success() => Some(String::from_utf8_lossy(x.stdout.trim_ascii()).into_owned()),
+        _ => None,
+    }
+}
+
+fn uses_lfs(dir: &Path) -> bool {
+    std::fs::read_to_string(dir.join(".gitattributes")).is_ok_and(|x| x.contains("filter=lfs"))
+}
+
+fn fetch_lfs_objects(task_id: i64, dir: &Path, url: &str) -> Result<(), String> {
+    if !uses_lfs(dir) {
+        return Ok(());
+    }
+
+    // otherwise, the checkouts of submodules come from the local mirrors, where git lfs can't find the objects
+    let lfs_url = match std::env::var(LFS_URL_ENV_VAR) {
+        Ok(x) if !x.is_empty() => x,
+        _ if url.starts_with("http://") || url.starts_with("https://") => {
+            let url = url.trim_end_matches('/');
+            if url.ends_with(".git") { format!("{url}/info/lfs") } else { format!("{url}.git/info/lfs") }
+        }
+        _ => String::new(),
+    };
+
+    let dir_str = dir.to_str().unwrap();
+    let msg = if lfs_url.is_empty() {
+        format!("Fetching git lfs objects of {dir_str}")
+    } else {
+        format!("Fetching git lfs objects of {dir_str} from {lfs_url}")
+    };
+    report_task_data(task_id, msg.as_str());
+
+    let lfs_url_config = format!("lfs.url={lfs_url}");
+    let mut args = vec!["-C", dir_str];
+    if !lfs_url.is_empty() {
+        args.extend(["-c", lfs_url_config.as_str()]);
+    }
+    args.extend(["lfs", "pull"]);
+    let task_output = run_proc(task_id, PathBuf::from("git").as_os_str(), args.as_slice());
+    if !task_output.success() {
+        report_task_data(task_id, "git lfs pull failed");
+        return Err(format!("fail to fetch the git lfs objects of {dir_str}. Is git lfs installed on the worker?"));
+    }
+    Ok(())
+}
+
+struct Submodule {
+    name: String,
+    path: String,
+    url: String,
+}
+
+fn get_submodules(dir: &Path) -> Result<Vec<Submodule>, String> {
+    let gitmodules = dir.join(".gitmodules");
+    if !gitmodules.exists() {
+        return Ok(Vec::new());
+    }
+
+    let output = std::process::Command::new("git")
+        .arg("config")
+        .arg("--file")
+        .arg(&gitmodules)
+        .arg("--get-regexp")
+        .arg(r"^submodule\..*\.(path|url)$")
+        .output();
+    let Ok(output) = output else {
+        return Err(format!("fail to read {gitmodules:?}: error={}", output.err().unwrap()));
+    };
+
+    // lines look like `submodule.<name>.path <path>`, and the name can contain dots
+    let mut submodules: Vec<Submodule> = Vec::new();
+    for line in String::from_utf8_lossy(output.stdout.as_ref()).lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+            continue;
+        };
+        let Some(key) = key.strip_prefix("submodule.") else {
+            continue;
+        };
+        let (name, is_path) = match (key.strip_suffix(".path"), key.strip_suffix(".url")) {
+            (Some(name), _) => (name, true),
+            (None, Some(name)) => (name, false),
+            (None, None) => continue,
+        };
+        let submodule = match submodules.iter_mut().find(|x| x.name == name) {
+            Some(x) => x,
+            None => {
+                submodules.push(Submodule { name: String::from(name), path: String::new(), url: String::new() });
+                submodules.last_mut().unwrap()
+            }
+        };
+        if is_path {
+            submodule.path = String::from(value);
+        } else {
+            submodule.url = String::from(value);
+        }
+    }
+
+    for submodule in &submodules {
+        if submodule.path.is_empty() || submodule.url.is_empty() {
+            return Err(format!("submodule {} has no path or no url in {gitmodules:?}", submodule.name));
+        }
+    }
+    Ok(submodules)
+}
+
+// Urls starting with ./ or ../ are relative to the one of the repository containing the submodule
+fn resolve_submodule_url(parent_url: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return String::from(url);
+    }
+
+    let mut res = String::from(parent_url.trim_end_matches('/'));
+    for component in url.split('/') {
+        match component {
+            "." | "" => (),
+            ".." => {
+                // also handles scp like urls, e.g. host:path
+                let end = res.rfind(['/', ':']).unwrap_or(0);
+                res.truncate(end);
+            }
+            component => {
+                res.push('/');
+                res.push_str(component);
+            }
+        }
+    }
+    res
+}
+
+// commit of the submodule recorded in the repository containing it
+fn get_submodule_commit(dir: &Path, path: &str) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("ls-tree")
+        .arg("HEAD")
+        .arg("--")
+        .arg(path)
+        .output();
+    let Ok(output) = output else {
+        return None;
     };
+    // e.g. `160000 commit <hash>\t<path>`
+    let output = String::from_utf8_lossy(output.stdout.as_ref()).into_owned();
+    let mut fields = output.split_whitespace();
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some("160000"), Some("commit"), Some(hash)) => Some(String::from(hash)),
+        _ => None,
+    }
+}
+
+fn has_commit(git_dir: &Path, commit: &str) -> bool {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(git_dir)
+        .arg("cat-file")
+        .arg("-e")
+        .arg(format!("{commit}^{{commit}}"))
+        .output();
+    matches!(output, Ok(x) if x.status.success())
+}
 
+// Submodules get their own mirror, one per url, next to the one of the project. They are kept
+// across tasks, and only fetched when they miss the commit to check out.
+fn get_submodule_mirror(task_id: i64, mirror_path: &OsStr, url: &str, commit: &str) -> Result<PathBuf, String> {
+    let mut mirrors_dir = mirror_path.to_os_string();
+    mirrors_dir.push("-submodules");
+    let dir_name = url.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect::<String>();
+    let submodule_mirror = PathBuf::from(mirrors_dir).join(dir_name);
+
+    let _lock = GIT_MIRROR_LOCK.lock().unwrap();
+    if is_git_mirror(submodule_mirror.as_os_str()) {
+        if has_commit(submodule_mirror.as_path(), commit) {
+            return Ok(submodule_mirror);
+        }
+        report_task_data(task_id, format!("Updating the mirror of {url}").as_str());
+        if let Err(e) = run_git_remote_update_in(submodule_mirror.as_os_str()) {
+            report_task_data(task_id, e.as_str());
+        }
+    } else {
+        report_task_data(task_id, format!("Creating a mirror of {url}").as_str());
+        let _ = std::fs::remove_dir_all(&submodule_mirror);
+        run_git_clone_in(submodule_mirror.as_os_str(), OsStr::new(url))?;
+    }
+
+    if !has_commit(submodule_mirror.as_path(), commit) {
+        return Err(format!("commit {commit} not found in {url}. Did you forget to push the submodule?"));
+    }
+    Ok(submodule_mirror)
+}
+
+// Checks out the submodules recursively, from their mirror instead of their url. The url is
+// given on the command line, so it doesn't end up in the config shared by all the worktrees.
+fn check_out_submodules(task_id: i64, dir: &Path, dir_url: &str, mirror_path: &OsStr) -> Result<(), String> {
+    for submodule in get_submodules(dir)? {
+        let Submodule { name, path, url } = submodule;
+        let Some(commit) = get_submodule_commit(dir, path.as_str()) else {
+            report_task_data(task_id, format!("Ignoring submodule {name} since no commit is recorded for {path}").as_str());
+            continue;
+        };
+        let url = resolve_submodule_url(dir_url, url.as_str());
+        let submodule_mirror = get_submodule_mirror(task_id, mirror_path, url.as_str(), commit.as_str())?;
+
+        let msg = format!("Checking out submodule {path} at {commit} from {url}");
+        report_task_data(task_id, msg.as_str());
+        let url_config = format!("submodule.{name}.url={}", submodule_mirror.to_str().unwrap());
+        let active_config = format!("submodule.{name}.active=true");
+        let task_output = run_proc_with_env(
+            task_id,
+            PathBuf::from("git").as_os_str(),
+            &["-C", dir.to_str().unwrap(),
+                "-c", "protocol.file.allow=always",
+                "-c", url_config.as_str(),
+                "-c", active_config.as_str(),
+                "submodule", "update", "--", path.as_str()],
+            &[SKIP_LFS_DOWNLOAD_DURING_CHECKOUT],
+        );
+        if !task_output.success() {
+            report_task_data(task_id, "git submodule update failed");
+            return Err(format!("fail to check out submodule {path} of {dir:?}"));
+        }
+
+        let submodule_dir = dir.join(path.as_str());
+        fetch_lfs_objects(task_id, submodule_dir.as_path(), url.as_str())?;
+        check_out_submodules(task_id, submodule_dir.as_path(), url.as_str(), mirror_path)?;
+    }
     Ok(())
 }
 
+// Deletes the directory of the worktree too
+pub fn remove_git_worktree(mirror_path: &OsStr, worktree_dir: &OsStr) -> Result<(), String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(mirror_path)
+        .arg("worktree")
+        .arg("remove")
+        .arg("--force")
+        .arg(worktree_dir)
+        .output();
+    match output {
+        Ok(x) if x.status.success() => Ok(()),
+        Ok(x) => Err(format!("git worktree remove {worktree_dir:?} failed with {}", String::from_utf8_lossy(x.stderr.as_ref()))),
+        Err(e) => Err(format!("fail to run git worktree remove {worktree_dir:?}: error={e}")),
+    }
+}
+
 pub fn run_git_remote_update_in(directory_with_git: &OsStr) -> Result<(), String> {
     let output = std::process::Command::new("git")
         .arg("-C")
@@ -0,0 +1,87 @@
+use std::ffi::OsStr;
+use crate::common::{get_http_client, MINICI_SERVER_COMPARE_TASK_DIAGNOSTICS, report_task_data};
+use crate::reporter::wait_for_reports_delivery;
+
+// A job can ask for its findings (the warnings and errors of the compilers, clang-tidy and the
+// static analyser) to be compared with the ones of the latest successful build of a branch. The
+// server picks that build among the last commits of the branch, as known by the mirror, and
+// tells which findings are new.
+
+// how far back in the history of the branch to look for a successful build
+const MAX_BASELINE_COMMITS: usize = 100;
+
+// Most recent first, following the first parent to stay on the branch
+pub(crate) fn get_branch_commits(git_mirror_path: &OsStr, branch: &str) -> Result<Vec<String>, String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(git_mirror_path)
+        .arg("rev-list")
+        .arg("--first-parent")
+        .arg(format!("--max-count={MAX_BASELINE_COMMITS}"))
+        .arg(format!("refs/heads/{branch}"))
+        .arg("--")
+        .output();
+    let Ok(output) = output else {
+        return Err(format!("fail to list the commits of branch {branch}: error={}", output.err().unwrap()));
+    };
+    if !output.status.success() {
+        return Err(format!("listing the commits of branch {branch} failed with {}", String::from_utf8_lossy(output.stderr.as_ref())));
+    }
+    Ok(String::from_utf8_lossy(output.stdout.as_ref())
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+// Whether the task found warnings or errors the baseline didn't have. Without a successful build
+// of the branch to compare with, nothing is new. When some diagnostics of the task got dropped, the
+// findings can't be compared and the task fails.
+pub(crate) fn check_new_findings(task_id: i64, git_mirror_path: &OsStr, branch: &str) -> Result<bool, String> {
+    let commits = get_branch_commits(git_mirror_path, branch)?;
+
+    // the server compares what it got, the diagnostics of the task must be there first, and not
+    // waiting in the journal
+    if !wait_for_reports_delivery() {
+        return Err(String::from("Not comparing the findings since the user requested to stop the worker immediately"));
+    }
+
+    let res = get_http_client()
+        .post(MINICI_SERVER_COMPARE_TASK_DIAGNOSTICS)
+        .form(&[("task_id", format!("{task_id}")),
+            ("baseline_commits", commits.join("\n"))])
+        .send();
+    let Ok(res) = res else {
+        return Err(format!("failed to compare the findings of task {task_id} with branch {branch}, err:{}", res.err().unwrap()));
+    };
+
+    let inner_body = res.text_with_charset("utf-8");
+    let Ok(inner_body) = inner_body else {
+        return Err(format!("Error: failed to get text from request's reply. Err: {}", inner_body.err().unwrap()));
+    };
+
+    if inner_body.starts_with("Error") {
+        return Err(format!("Error from server: {inner_body}"));
+    }
+    // some diagnostics got dropped, which ones depends on the order of the output of the commands, so
+    // the ones left can't tell what is new
+    if inner_body.starts_with("Baseline: not compared") {
+        return Err(format!("The findings can't be compared with the ones of branch {branch}\n{inner_body}"));
+    }
+    if inner_body.starts_with("Baseline: none") {
+        let msg = format!("No successful build among the last {MAX_BASELINE_COMMITS} commits of branch {branch}, the findings are not compared");
+        report_task_data(task_id, msg.as_str());
+        return Ok(false);
+    }
+
+    let nr_new_findings = inner_body
+        .lines()
+        .find_map(|x| x.strip_prefix("New findings: "))
+        .and_then(|x| x.trim().parse::<usize>().ok());
+    let Some(nr_new_findings) = nr_new_findings else {
+        return Err(format!("Error: can't find the number of new findings in the reply of the server [{inner_body}]"));
+    };
+
+    let msg = format!("Comparing the findings with the ones of branch {branch}\n{inner_body}");
+    report_task_data(task_id, msg.as_str());
+    Ok(nr_new_findings > 0)
+}